@@ -0,0 +1,182 @@
+// Keyframed animation, values that change smoothly over time between a few set points
+
+use std::ops::{Add, Mul, Sub};
+use glam::{Mat4, Quat, Vec3};
+
+/// How to fill in values between keyframes
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    // Straight lines between keyframes, which turn sharply at every keyframe
+    Linear,
+    // A smooth curve through every keyframe, shaped by the keyframes on either side
+    CatmullRom
+}
+
+/// Where the camera is, what it looks at and how wide it sees at some point in time
+#[derive(Clone, Copy)]
+pub struct CameraKeyframe {
+    // Time in seconds
+    pub time: f32,
+    pub position: Vec3,
+    pub look_at: Vec3,
+    // Vertical field of view in degrees
+    pub vfov: f32
+}
+
+impl CameraKeyframe {
+    pub fn new(time: f32, position: Vec3, look_at: Vec3, vfov: f32) -> CameraKeyframe {
+        CameraKeyframe { time, position, look_at, vfov }
+    }
+}
+
+/// A camera moving through a list of keyframes
+pub struct CameraPath {
+    // Sorted by time
+    keyframes: Vec<CameraKeyframe>,
+    pub interpolation: Interpolation
+}
+
+impl CameraPath {
+    pub fn new(interpolation: Interpolation) -> CameraPath {
+        CameraPath { keyframes: vec![], interpolation }
+    }
+
+    /// Adds a keyframe, in any order
+    pub fn add(&mut self, keyframe: CameraKeyframe) {
+        let index = self.keyframes.partition_point(|other| other.time <= keyframe.time);
+        self.keyframes.insert(index, keyframe);
+    }
+
+    pub fn with(mut self, keyframe: CameraKeyframe) -> CameraPath {
+        self.add(keyframe);
+        self
+    }
+
+    /// Time of the first and last keyframes
+    pub fn duration(&self) -> (f32, f32) {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => (first.time, last.time),
+            _ => (0.0, 0.0)
+        }
+    }
+
+    /// The camera at a point in time, held still before the first keyframe and after the last
+    /// Panics if there are no keyframes
+    pub fn sample(&self, time: f32) -> CameraKeyframe {
+        let (index, u) = segment(&self.keyframes.iter().map(|keyframe| keyframe.time).collect::<Vec<f32>>(), time);
+        let key = |offset: isize| self.keyframes[(index as isize + offset).clamp(0, self.keyframes.len() as isize - 1) as usize];
+        let (k0, k1, k2, k3) = (key(-1), key(0), key(1), key(2));
+        CameraKeyframe {
+            time,
+            position: interpolate(self.interpolation, k0.position, k1.position, k2.position, k3.position, u),
+            look_at: interpolate(self.interpolation, k0.look_at, k1.look_at, k2.look_at, k3.look_at, u),
+            vfov: interpolate(self.interpolation, k0.vfov, k1.vfov, k2.vfov, k3.vfov, u)
+        }
+    }
+}
+
+/// Translation, rotation and scale of an object over time, each keyed on its own
+/// Rotations always turn the shortest way between keyframes, even with smooth interpolation
+#[derive(Clone)]
+pub struct TransformTrack {
+    // Keyframes as (time, value), sorted by time
+    translation: Vec<(f32, Vec3)>,
+    rotation: Vec<(f32, Quat)>,
+    scale: Vec<(f32, Vec3)>,
+    pub interpolation: Interpolation
+}
+
+impl TransformTrack {
+    pub fn new(interpolation: Interpolation) -> TransformTrack {
+        TransformTrack { translation: vec![], rotation: vec![], scale: vec![], interpolation }
+    }
+
+    pub fn with_translation(mut self, time: f32, translation: Vec3) -> TransformTrack {
+        insert_key(&mut self.translation, time, translation);
+        self
+    }
+
+    pub fn with_rotation(mut self, time: f32, rotation: Quat) -> TransformTrack {
+        insert_key(&mut self.rotation, time, rotation);
+        self
+    }
+
+    pub fn with_scale(mut self, time: f32, scale: Vec3) -> TransformTrack {
+        insert_key(&mut self.scale, time, scale);
+        self
+    }
+
+    /// Every time any of the tracks has a keyframe at, in order
+    pub fn keyframe_times(&self) -> Vec<f32> {
+        let mut times: Vec<f32> = self.translation.iter().map(|key| key.0)
+            .chain(self.rotation.iter().map(|key| key.0))
+            .chain(self.scale.iter().map(|key| key.0))
+            .collect();
+        times.sort_by(f32::total_cmp);
+        times.dedup();
+        return times;
+    }
+
+    /// The object to world matrix at a point in time. Tracks without keyframes leave that part untouched
+    pub fn sample(&self, time: f32) -> Mat4 {
+        let translation = sample_keys(&self.translation, self.interpolation, time).unwrap_or(Vec3::ZERO);
+        let scale = sample_keys(&self.scale, self.interpolation, time).unwrap_or(Vec3::ONE);
+        let rotation = match self.rotation.is_empty() {
+            true => Quat::IDENTITY,
+            false => {
+                let times: Vec<f32> = self.rotation.iter().map(|key| key.0).collect();
+                let (index, u) = segment(&times, time);
+                let next = (index + 1).min(self.rotation.len() - 1);
+                self.rotation[index].1.slerp(self.rotation[next].1, u)
+            }
+        };
+        Mat4::from_scale_rotation_translation(scale, rotation, translation)
+    }
+}
+
+fn insert_key<T>(keys: &mut Vec<(f32, T)>, time: f32, value: T) {
+    let index = keys.partition_point(|key| key.0 <= time);
+    keys.insert(index, (time, value));
+}
+
+// The value of a list of (time, value) keyframes at a point in time, or None if there are none
+fn sample_keys<T>(keys: &[(f32, T)], interpolation: Interpolation, time: f32) -> Option<T>
+where T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T> {
+    if keys.is_empty() {
+        return None;
+    }
+    let times: Vec<f32> = keys.iter().map(|key| key.0).collect();
+    let (index, u) = segment(&times, time);
+    let key = |offset: isize| keys[(index as isize + offset).clamp(0, keys.len() as isize - 1) as usize].1;
+    return Some(interpolate(interpolation, key(-1), key(0), key(1), key(2), u));
+}
+
+/// Finds the keyframe a time comes after and how far it is towards the next one, from 0 to 1
+/// times must be sorted and not empty
+pub fn segment(times: &[f32], time: f32) -> (usize, f32) {
+    let next = times.partition_point(|&other| other <= time);
+    if next == 0 {
+        return (0, 0.0);
+    }
+    if next == times.len() {
+        return (times.len() - 1, 0.0);
+    }
+    let (start, end) = (times[next - 1], times[next]);
+    return (next - 1, (time - start) / (end - start));
+}
+
+/// Interpolates between p1 and p2, with p0 before and p3 after shaping the curve if it's smooth
+pub fn interpolate<T>(interpolation: Interpolation, p0: T, p1: T, p2: T, p3: T, u: f32) -> T
+where T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T> {
+    match interpolation {
+        Interpolation::Linear => p1 + (p2 - p1) * u,
+        Interpolation::CatmullRom => {
+            // Uniform Catmull-Rom spline, the tangent at each keyframe points from its previous to its next one
+            let (u2, u3) = (u * u, u * u * u);
+            (p1 * 2.0
+                + (p2 - p0) * u
+                + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * u2
+                + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * u3) * 0.5
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Render passes besides the beauty image, each written into a file of its own or a layer of an EXR file for compositing and denoising
+
+use glam::{Vec2, Vec3};
+use crate::color::SrgbColor;
+use crate::integrator::heat;
+use crate::object::Object;
+use crate::output::RGB_CHANNELS;
+
+type Color = Vec3;
+
+// Pixels of motion that reach black or full in the motion pass
+const MOTION_RANGE: f32 = 64.0;
+// Noise that's shown red in the noise pass, a tenth of full brightness
+const NOISE_RANGE: f32 = 0.1;
+
+/// A render pass, an image of one thing about what the camera sees in every pixel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aov {
+    /// The outward normal of the first surface seen, with x, y and z as red, green and blue from -1 at black to 1 at full
+    Normal,
+    /// How far away the first surface seen is, white up close, half as bright one unit away and black for the background
+    Depth,
+    /// The color of the first surface seen without any lighting
+    Albedo,
+    /// Light reaching the first surface seen straight from the lights and the background
+    Direct,
+    /// Light reaching the first surface seen after bouncing off something else
+    Indirect,
+    /// Light given off by whatever is seen straight on, lights and the background
+    Emission,
+    /// The id of the object seen, counting the objects of the scene from 1 with 0 for the background.
+    /// Red is the lowest byte of the id, green the next and blue the highest, so ids can be read straight out of the image
+    ObjectId,
+    /// The id of the material of the object seen, stored like object ids
+    MaterialId,
+    /// Object ids hashed into bright colors that are easy to tell apart, for looking at rather than masking
+    ObjectColor,
+    /// Material ids hashed into colors the same way
+    MaterialColor,
+    /// How many pixels right and up what's seen moves while the shutter is open, or until the next frame of a sequence,
+    /// as red and green from -64 at black to 64 at full with no motion in between. Like the ids it comes from the first sample
+    Motion,
+    /// How noisy the pixel still is, measured like adaptive sampling does, from blue for none through green to red
+    /// for a tenth of full brightness or more. Shows where more samples would help
+    Noise
+}
+
+impl Aov {
+    const ALL: [Aov; 12] = [
+        Aov::Normal, Aov::Depth, Aov::Albedo, Aov::Direct, Aov::Indirect, Aov::Emission,
+        Aov::ObjectId, Aov::MaterialId, Aov::ObjectColor, Aov::MaterialColor, Aov::Motion, Aov::Noise
+    ];
+
+    /// A pass by name: normal, depth, albedo, direct, indirect, emission, object_id, material_id, object_color, material_color, motion or noise
+    pub fn parse(name: &str) -> Result<Aov, String> {
+        Aov::ALL.iter()
+            .find(|aov| aov.name() == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown render pass \"{}\", expected normal, depth, albedo, direct, indirect, emission, \
+                object_id, material_id, object_color, material_color, motion or noise", name))
+    }
+
+    /// A comma separated list of passes, or all for every one of them
+    pub fn parse_list(spec: &str) -> Result<Vec<Aov>, String> {
+        match spec {
+            "all" => Ok(Aov::ALL.to_vec()),
+            _ => spec.split(',').map(Aov::parse).collect()
+        }
+    }
+
+    /// The name of the pass, which is appended to the name of its file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Albedo => "albedo",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectColor => "object_color",
+            Aov::MaterialColor => "material_color",
+            Aov::Motion => "motion",
+            Aov::Noise => "noise"
+        }
+    }
+
+    /// Whether the pass holds light, which can be dithered, rather than values that have to come out exactly
+    pub fn is_light(&self) -> bool {
+        matches!(self, Aov::Direct | Aov::Indirect | Aov::Emission)
+    }
+
+    /// Whether the pass holds ids, which are whole numbers in layered images
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::ObjectId | Aov::MaterialId)
+    }
+
+    /// The channels of the pass in a layered image, named the way compositing programs expect them
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Motion => &["X", "Y"],
+            Aov::Noise => &["error"],
+            _ => RGB_CHANNELS
+        }
+    }
+
+    /// The pass of a pixel as it is for layered images, which can hold any value, in the order of its channels
+    /// Normals go from -1 to 1, depth is the distance with 0 for the background, ids are whole numbers and motion is in pixels
+    pub fn data(&self, sums: &AovSums) -> Vec3 {
+        match self {
+            Aov::Normal => sums.normal,
+            Aov::Depth => Vec3::splat(sums.depth),
+            Aov::Albedo => sums.albedo,
+            Aov::ObjectId => Vec3::splat(sums.object_id as f32),
+            Aov::MaterialId => Vec3::splat(sums.material_id as f32),
+            Aov::Motion => sums.motion.extend(0.0),
+            Aov::Noise => Vec3::splat(sums.noise),
+            _ => self.display(sums)
+        }
+    }
+
+    /// The pass of a pixel from the averages of what its samples saw, as light ready to be encoded like the beauty image
+    /// Light is shown as it is, everything else is decoded so it shows up as it is once encoded
+    /// Ids can't be averaged, so they come from the first sample of the pixel and keep hard edges
+    pub fn display(&self, sums: &AovSums) -> Color {
+        match self {
+            Aov::Normal => unencoded((sums.normal + Vec3::ONE) / 2.0),
+            Aov::Depth => unencoded(Color::splat(sums.coverage / (1.0 + sums.depth))),
+            Aov::Albedo => unencoded(sums.albedo),
+            Aov::Direct => sums.direct,
+            Aov::Indirect => sums.indirect,
+            Aov::Emission => sums.emission,
+            Aov::ObjectId => unencoded(id_bytes(sums.object_id)),
+            Aov::MaterialId => unencoded(id_bytes(sums.material_id)),
+            Aov::ObjectColor => unencoded(id_color(sums.object_id)),
+            Aov::MaterialColor => unencoded(id_color(sums.material_id)),
+            Aov::Motion => {
+                let motion = (sums.motion / MOTION_RANGE + Vec2::ONE) / 2.0;
+                unencoded(Color::new(motion.x, motion.y, 0.0).clamp(Color::ZERO, Color::ONE))
+            },
+            Aov::Noise => unencoded(heat(sums.noise / NOISE_RANGE))
+        }
+    }
+}
+
+/// What the samples of a pixel saw for the passes, added up while sampling and averaged once all samples are in
+#[derive(Default, Clone, Copy)]
+pub struct AovSums {
+    pub albedo: Color,
+    pub normal: Vec3,
+    // Distance to the first surface seen, only counting the samples that saw one
+    pub depth: f32,
+    // How many samples saw a surface, or the share of them once averaged
+    pub coverage: f32,
+    pub direct: Color,
+    pub indirect: Color,
+    pub emission: Color,
+    // Ids of the object and the material the first sample saw, 0 for the background
+    pub object_id: u32,
+    pub material_id: u32,
+    // How many pixels the first sample's point moves across the image
+    pub motion: Vec2,
+    // How far off the brightness of the pixel might still be once encoded, worked out once all samples are in
+    pub noise: f32,
+    // How many samples saw something that isn't the background or a holdout, or the share of them once averaged.
+    // Only counted when the image has an alpha channel
+    pub alpha: f32
+}
+
+impl AovSums {
+    /// The averages of the sums of count samples, with light multiplied by light_scale
+    pub fn averaged(&self, count: u32, light_scale: f32) -> AovSums {
+        let count = count.max(1) as f32;
+        AovSums {
+            albedo: self.albedo / count,
+            normal: self.normal / count,
+            depth: match self.coverage > 0.0 {
+                true => self.depth / self.coverage,
+                false => 0.0
+            },
+            coverage: self.coverage / count,
+            direct: self.direct * light_scale,
+            indirect: self.indirect * light_scale,
+            emission: self.emission * light_scale,
+            object_id: self.object_id,
+            material_id: self.material_id,
+            motion: self.motion,
+            noise: self.noise,
+            alpha: self.alpha / count
+        }
+    }
+}
+
+/// The object and material ids of a scene, which stay the same from render to render as long as the objects are listed in the same order
+pub struct SceneIds {
+    // Material id of every object
+    materials: Vec<u32>
+}
+
+impl SceneIds {
+    /// Numbers the materials in the order they're first seen in, every unnamed one getting a number of its own
+    pub fn new(objects: &[Box<dyn Object>]) -> SceneIds {
+        // Names seen so far and their ids
+        let mut named: Vec<(&str, u32)> = vec![];
+        let mut next_id = 1;
+        let mut materials = Vec::with_capacity(objects.len());
+        for object in objects {
+            let known = object.material_name().and_then(|name| named.iter().find(|(other, _)| *other == name));
+            let id = match known {
+                Some(&(_, id)) => id,
+                None => {
+                    let id = next_id;
+                    next_id += 1;
+                    if let Some(name) = object.material_name() {
+                        named.push((name, id));
+                    }
+                    id
+                }
+            };
+            materials.push(id);
+        }
+        SceneIds { materials }
+    }
+
+    /// The id of the object at index in the scene
+    pub fn object(&self, index: usize) -> u32 {
+        index as u32 + 1
+    }
+
+    /// The id of the material of the object at index in the scene
+    pub fn material(&self, index: usize) -> u32 {
+        self.materials.get(index).copied().unwrap_or(0)
+    }
+}
+
+fn unencoded(value: Color) -> Color {
+    SrgbColor::from_encoded(value).decode()
+}
+
+// An id as the bytes of a color, each channel landing in the middle of its byte so rounding can't change it
+fn id_bytes(id: u32) -> Color {
+    let byte = |shift: u32| (((id >> shift) & 0xff) as f32 + 0.5) / 255.0;
+    return Color::new(byte(0), byte(8), byte(16));
+}
+
+// A bright color for an id, scrambled so neighbouring ids look nothing alike. The background stays black
+fn id_color(id: u32) -> Color {
+    if id == 0 {
+        return Color::ZERO;
+    }
+    // The finalizer of MurmurHash3
+    let mut hash = id;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    let channel = |shift: u32| 0.25 + 0.75 * ((hash >> shift) & 0xff) as f32 / 255.0;
+    return Color::new(channel(0), channel(8), channel(16));
+}
@@ -0,0 +1,542 @@
+// Bidirectional path tracing, which follows light out from the lights as well as back from the camera and joins the two up
+
+use std::sync::Mutex;
+use glam::Vec3;
+use rand::Rng;
+use crate::camera::Camera;
+use crate::filter::PixelFilter;
+use crate::integrator::Integrator;
+use crate::interval::Interval;
+use crate::material::{sample_cosine_hemisphere, cosine_hemisphere_pdf};
+use crate::object::Object;
+use crate::ray::{Ray, Hit};
+use crate::sampler::StratifiedSampler;
+
+type Color = Vec3;
+
+// How far from t = 1 a ray aimed at a point may hit it, for the point to count as the one aimed at
+const LOOKBACK: f32 = 0.001;
+
+/// Traces a path out from a light as well as one from the camera for every sample, and joins every point of one
+/// to every point of the other, weighting each way of making a path against all the other ways of making it
+/// Finds light that gets through glass or off mirrors before landing on a diffuse surface, like the caustics of
+/// small lights behind glass, which paths from the camera alone hardly ever stumble onto
+/// Points on light paths can land anywhere on the image when they're joined straight to the camera, so those joins are
+/// made for the whole image before any pixel is worked out, from as many light paths as there will be samples, and
+/// spread over the pixels around where they land by the pixel filter. That only works for a pinhole camera,
+/// stereo renders and other projections go without them
+/// Fog and scattering inside objects are left out
+/// The background is only found by running into it, and punctual lights only by aiming at them from the camera's path
+pub struct BidirectionalPathTracer {
+    // The light joined straight to the camera, worked out again at the start of every render
+    image: Mutex<SplatImage>
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn prepare(&self, camera: &mut Camera, objects: &[Box<dyn Object>]) {
+        let (width, height) = (camera.settings.width as usize, camera.settings.height as usize);
+        let mut image = SplatImage::new(width, height, camera.light_group_count(), camera.filter);
+        let Some(center) = camera.pinhole() else {
+            *self.image.lock().expect("the splatted image isn't shared between threads") = image;
+            return;
+        };
+        image.joined = true;
+        // Plain random numbers, the sampler's are laid out for the samples of a pixel
+        let sampler = camera.rng().replace(Box::new(StratifiedSampler::new()));
+        let max_depth = camera.max_depth as usize;
+        let paths = camera.samples as u64 * (width * height) as u64;
+        // Every pixel gets as many light paths as samples on average
+        let scale = 1.0 / camera.samples.max(1) as f32;
+        let eye = [Vertex::camera(center)];
+        print!("\rTracing light into the camera");
+        for _ in 0..paths {
+            let time = camera.shutter_open + (camera.shutter_close - camera.shutter_open) * camera.rng().gen::<f32>();
+            let light = light_path(camera, objects, time, max_depth);
+            for s in 1..=light.len() {
+                if s - 1 > max_depth {
+                    break;
+                }
+                let Some((image_point, contribution)) = connect_to_camera(camera, objects, &light, s, center) else {
+                    continue;
+                };
+                let weighted = contribution * mis_weight(camera, objects, &eye, &light, s, 1, true) * scale;
+                image.splat(image_point, weighted, light[0].object.and_then(|index| camera.object_light_group(index)));
+            }
+        }
+        println!();
+        camera.rng().replace(sampler);
+        *self.image.lock().expect("the splatted image isn't shared between threads") = image;
+    }
+
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let max_depth = camera.max_depth as usize;
+        let image = self.image.lock().expect("the splatted image isn't shared between threads");
+        let joined = image.joined;
+        // The camera's rays are spread evenly over the image as far as weighing paths against the joins to the camera goes
+        let pdf = match joined {
+            true => camera.locate(ray.origin + ray.direction).map_or(0.0, |(_, pdf)| pdf),
+            false => 1.0
+        };
+        let mut eye = vec![Vertex::camera(ray.origin)];
+        let mut total = random_walk(camera, objects, ray, Color::ONE, pdf, &mut eye, max_depth + 1, true);
+        let light = light_path(camera, objects, ray.time, max_depth);
+        // What the light paths joined to the camera left in the pixel, the same for every sample of it
+        let (x, y) = camera.pixel();
+        if let Some(index) = image.index(x as usize, y as usize) {
+            total += image.pixels[index];
+            for (group, pixels) in image.groups.iter().enumerate() {
+                camera.record_light(Some(group), pixels[index]);
+            }
+        }
+        drop(image);
+        for t in 2..=eye.len() {
+            for s in 0..=light.len() {
+                if s + t - 2 > max_depth {
+                    continue;
+                }
+                let contribution = connect(camera, objects, &eye, &light, s, t);
+                if contribution == Color::ZERO {
+                    continue;
+                }
+                let weighted = contribution * mis_weight(camera, objects, &eye, &light, s, t, joined);
+                let light_object = match s {
+                    0 => eye[t - 1].object,
+                    _ => light[0].object
+                };
+                if let Some(index) = light_object {
+                    camera.record_object_light(index, weighted);
+                }
+                total += weighted;
+            }
+        }
+        return total;
+    }
+}
+
+impl BidirectionalPathTracer {
+    pub fn new() -> BidirectionalPathTracer {
+        BidirectionalPathTracer { image: Mutex::new(SplatImage::new(0, 0, 0, PixelFilter::Box { width: 1.0 })) }
+    }
+}
+
+// Light joined straight to the camera, spread over the pixels by the pixel filter, with each light group's share of it
+struct SplatImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    groups: Vec<Vec<Color>>,
+    filter: PixelFilter,
+    // The area under the filter, which the spread light is divided by so it adds up to what landed
+    filter_area: f32,
+    // Whether light paths were joined to the camera at all, which the other ways of making a path are weighted against
+    joined: bool
+}
+
+impl SplatImage {
+    fn new(width: usize, height: usize, group_count: usize, filter: PixelFilter) -> SplatImage {
+        SplatImage {
+            width,
+            height,
+            pixels: vec![Color::ZERO; width * height],
+            groups: vec![vec![Color::ZERO; width * height]; group_count],
+            filter,
+            filter_area: filter.integral().powi(2),
+            joined: false
+        }
+    }
+
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        match x < self.width && y < self.height {
+            true => Some(y * self.width + x),
+            false => None
+        }
+    }
+
+    // Adds light landing at (s, t) on the image to the pixels the filter reaches from there
+    fn splat(&mut self, (s, t): (f32, f32), color: Color, group: Option<usize>) {
+        let (x, y) = (s * self.width as f32, t * self.height as f32);
+        let radius = self.filter.radius();
+        let (left, right) = ((x - radius).floor().max(0.0), (x + radius).floor().min(self.width as f32 - 1.0));
+        let (bottom, top) = ((y - radius).floor().max(0.0), (y + radius).floor().min(self.height as f32 - 1.0));
+        if left > right || bottom > top {
+            return;
+        }
+        for pixel_y in bottom as usize..=top as usize {
+            let weight_y = self.filter.evaluate(y - (pixel_y as f32 + 0.5));
+            for pixel_x in left as usize..=right as usize {
+                let weight = self.filter.evaluate(x - (pixel_x as f32 + 0.5)) * weight_y / self.filter_area;
+                let index = pixel_y * self.width + pixel_x;
+                self.pixels[index] += color * weight;
+                if let Some(group) = group {
+                    self.groups[group][index] += color * weight;
+                }
+            }
+        }
+    }
+}
+
+// A point along a path from the camera or from a light
+struct Vertex {
+    position: Vec3,
+    // The outward normal of the surface, or the normal of the side it shines out of for the first point of a light's path
+    normal: Vec3,
+    tangent: Vec3,
+    u: f32,
+    v: f32,
+    // The ray that arrived here, None where paths start
+    incoming: Option<Ray>,
+    // The object the point is on, None for the camera
+    object: Option<usize>,
+    // What light found here is multiplied by on its way to the camera, or the light that got here from the light,
+    // both over the density of the path so far
+    beta: Color,
+    // Light given off back along the incoming ray
+    emitted: Color,
+    // Densities per unit of area of the point being picked from the one before it on its path, and from the one after it
+    pdf_forward: f32,
+    pdf_reverse: f32,
+    // Mirror-like bounces can't be joined to anything
+    is_specular: bool
+}
+
+impl Vertex {
+    fn camera(position: Vec3) -> Vertex {
+        Vertex {
+            position,
+            normal: Vec3::ZERO,
+            tangent: Vec3::ZERO,
+            u: 0.0,
+            v: 0.0,
+            incoming: None,
+            object: None,
+            beta: Color::ONE,
+            emitted: Color::ZERO,
+            pdf_forward: 1.0,
+            pdf_reverse: 0.0,
+            is_specular: false
+        }
+    }
+
+    fn light(position: Vec3, normal: Vec3, index: usize, pdf: f32) -> Vertex {
+        Vertex {
+            position,
+            normal,
+            tangent: Vec3::ZERO,
+            u: 0.0,
+            v: 0.0,
+            incoming: None,
+            object: Some(index),
+            beta: Color::splat(1.0 / pdf),
+            emitted: Color::ZERO,
+            pdf_forward: pdf,
+            pdf_reverse: 0.0,
+            is_specular: false
+        }
+    }
+
+    fn surface(hit: &Hit, index: usize, ray: &Ray, beta: Color) -> Vertex {
+        Vertex {
+            position: hit.position,
+            normal: hit.outward_normal(),
+            tangent: hit.tangent,
+            u: hit.u,
+            v: hit.v,
+            incoming: Some(ray.clone()),
+            object: Some(index),
+            beta,
+            emitted: hit.emitted,
+            pdf_forward: 0.0,
+            pdf_reverse: 0.0,
+            is_specular: hit.is_specular
+        }
+    }
+
+    // The BSDF times the cosine and the density of scattering towards a point, for light arriving from another point,
+    // or along the incoming ray without one
+    fn scatter_towards(&self, objects: &[Box<dyn Object>], from: Option<Vec3>, point: Vec3) -> (Color, f32) {
+        let (Some(object), Some(incoming)) = (self.object, &self.incoming) else {
+            return (Color::ZERO, 0.0);
+        };
+        let incoming = match from {
+            Some(from) => incoming.continued(from, self.position - from),
+            None => incoming.clone()
+        };
+        let scattered = incoming.continued(self.position, point - self.position);
+        objects[object].evaluate(&incoming, &scattered, self.position, self.normal, self.tangent, self.u, self.v)
+    }
+
+    // The density per solid angle of going on towards a point, for a path arriving from another point or along the incoming ray
+    // Paths leave lights in cosine weighted directions
+    fn pdf_towards(&self, objects: &[Box<dyn Object>], from: Option<Vec3>, point: Vec3) -> f32 {
+        match self.incoming {
+            Some(_) => self.scatter_towards(objects, from, point).1,
+            None => cosine_hemisphere_pdf(self.normal.dot((point - self.position).normalize()))
+        }
+    }
+
+    // A ray towards a point, pushed off the surface to whichever side the point is on
+    fn spawn_towards(&self, point: Vec3, offset: f32) -> Ray {
+        let side = match (point - self.position).dot(self.normal) > 0.0 {
+            true => self.normal,
+            false => -self.normal
+        };
+        let origin = self.position + side * offset;
+        let base = self.incoming.clone().unwrap_or_else(|| Ray::new(origin, Vec3::ZERO));
+        base.continued(origin, point - origin)
+    }
+}
+
+// Extends a path by bouncing a ray around until it leaves the scene or the path has max_length points
+// pdf is the density per solid angle the ray's direction was picked with
+// Returns what the camera's path finds along the way that isn't joined up: the background and punctual lights
+fn random_walk(
+    camera: &mut Camera,
+    objects: &[Box<dyn Object>],
+    ray: &Ray,
+    mut beta: Color,
+    mut pdf: f32,
+    path: &mut Vec<Vertex>,
+    max_length: usize,
+    from_camera: bool
+) -> Color {
+    let mut ray = ray.clone();
+    let mut found = Color::ZERO;
+    while path.len() < max_length {
+        let Some((hit, index)) = camera.intersect_objects(&ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            if from_camera {
+                let background = beta * camera.background_radiance(&ray);
+                camera.record_background_light(background);
+                found += background;
+            }
+            break;
+        };
+        beta *= ray.transmittance(hit.t);
+        let previous = path.len() - 1;
+        let mut vertex = Vertex::surface(&hit, index, &ray, beta);
+        vertex.pdf_forward = to_area(pdf, path[previous].position, &vertex);
+        if from_camera {
+            let object = objects[index].as_ref();
+            let evaluate = |scattered: &Ray| object.evaluate(&ray, scattered, hit.position, hit.outward_normal(), hit.tangent, hit.u, hit.v);
+            let path_weight = camera.swap_path_weight(beta);
+            found += beta * camera.punctual_light(&ray, &hit, &evaluate, objects);
+            camera.swap_path_weight(path_weight);
+        }
+        // Mirror-like bounces have no density to speak of, in either direction
+        let reverse = match hit.is_specular {
+            true => 0.0,
+            false => vertex.pdf_towards(objects, Some(hit.position + hit.outgoing.direction), path[previous].position)
+        };
+        path[previous].pdf_reverse = to_area(reverse, hit.position, &path[previous]);
+        pdf = match hit.is_specular {
+            true => 0.0,
+            false => hit.pdf
+        };
+        path.push(vertex);
+        beta *= hit.albedo;
+        if beta == Color::ZERO {
+            break;
+        }
+        ray = hit.spawn_ray(&hit.outgoing, camera.normal_offset);
+    }
+    return found;
+}
+
+// A path from a light picked by its power, starting on the light and going on wherever its light bounces to
+fn light_path(camera: &mut Camera, objects: &[Box<dyn Object>], time: f32, max_length: usize) -> Vec<Vertex> {
+    let mut path = vec![];
+    let u = camera.rng().gen::<f32>();
+    let Some((index, probability)) = camera.pick_light_by_power(u) else {
+        return path;
+    };
+    let Some((point, normal, area_pdf)) = objects[index].light().and_then(|light| light.sample_surface(camera.rng(), time)) else {
+        return path;
+    };
+    let pdf = probability * area_pdf;
+    if !(pdf > 0.0 && pdf.is_finite()) {
+        return path;
+    }
+    path.push(Vertex::light(point, normal, index, pdf));
+    let (direction, direction_pdf) = sample_cosine_hemisphere(camera.rng(), normal);
+    let emitted = objects[index].emission_towards(camera.rng(), point, point + direction, time);
+    if emitted == Color::ZERO || direction_pdf <= 0.0 {
+        return path;
+    }
+    let beta = emitted * normal.dot(direction) / (pdf * direction_pdf);
+    let ray = Ray::new(point + normal * camera.normal_offset, direction).at_time(time);
+    random_walk(camera, objects, &ray, beta, direction_pdf, &mut path, max_length, false);
+    return path;
+}
+
+// The light reaching the camera along the path made by joining the first s points of the light's path
+// to the first t of the camera's, before it's weighted against the other ways of making the same path
+fn connect(camera: &mut Camera, objects: &[Box<dyn Object>], eye: &[Vertex], light: &[Vertex], s: usize, t: usize) -> Color {
+    let pt = &eye[t - 1];
+    if s == 0 {
+        return pt.beta * pt.emitted;
+    }
+    let qs = &light[s - 1];
+    if pt.is_specular || qs.is_specular {
+        return Color::ZERO;
+    }
+    let (bsdf, _) = pt.scatter_towards(objects, None, qs.position);
+    if bsdf == Color::ZERO {
+        return Color::ZERO;
+    }
+    let shadow_ray = pt.spawn_towards(qs.position, camera.normal_offset);
+    let offset = qs.position - pt.position;
+    if s == 1 {
+        // The light is only seen from the side it was picked to shine out of, and only if the point on it is the first thing hit
+        if qs.normal.dot(-offset) <= 0.0 {
+            return Color::ZERO;
+        }
+        let Some((light_hit, index)) = camera.intersect_objects(&shadow_ray, objects, &Interval::new(camera.hit_epsilon, 1.0 + LOOKBACK)) else {
+            return Color::ZERO;
+        };
+        if Some(index) != qs.object || light_hit.t < 1.0 - LOOKBACK {
+            return Color::ZERO;
+        }
+        let cosine = qs.normal.dot(offset.normalize()).abs();
+        return pt.beta * bsdf * light_hit.emitted * shadow_ray.shadow_transmittance(light_hit.t) * cosine / offset.length_squared() * qs.beta;
+    }
+    let (light_bsdf, _) = qs.scatter_towards(objects, None, pt.position);
+    if light_bsdf == Color::ZERO {
+        return Color::ZERO;
+    }
+    if camera.intersect_objects(&shadow_ray, objects, &Interval::new(camera.hit_epsilon, 1.0 - LOOKBACK)).is_some() {
+        return Color::ZERO;
+    }
+    return pt.beta * bsdf * light_bsdf * shadow_ray.shadow_transmittance(1.0) / offset.length_squared() * qs.beta;
+}
+
+// Joins the first s points of the light's path straight to the camera, returning where on the image the last one is seen
+// and the light it sends there, before it's weighted against the other ways of making the same path
+fn connect_to_camera(camera: &mut Camera, objects: &[Box<dyn Object>], light: &[Vertex], s: usize, center: Vec3) -> Option<((f32, f32), Color)> {
+    let qs = &light[s - 1];
+    if qs.is_specular {
+        return None;
+    }
+    let (image_point, pdf) = camera.locate(qs.position)?;
+    let offset = center - qs.position;
+    let shadow_ray = qs.spawn_towards(center, camera.normal_offset);
+    if camera.intersect_objects(&shadow_ray, objects, &Interval::new(camera.hit_epsilon, 1.0)).is_some() {
+        return None;
+    }
+    // The light or the BSDF times the cosine at the point, towards the camera
+    let towards = match s {
+        1 => {
+            // Only seen from the side it was picked to shine out of
+            if qs.normal.dot(offset) <= 0.0 {
+                return None;
+            }
+            let index = qs.object?;
+            objects[index].emission_towards(camera.rng(), qs.position, center, shadow_ray.time) * qs.normal.dot(offset.normalize()).abs()
+        },
+        _ => qs.scatter_towards(objects, None, center).0
+    };
+    if towards == Color::ZERO {
+        return None;
+    }
+    // The camera's density of rays towards the point takes the place of the BSDF on the camera's side
+    return Some((image_point, qs.beta * towards * shadow_ray.shadow_transmittance(1.0) * pdf / offset.length_squared()));
+}
+
+// The power heuristic weight of joining s points of the light's path to t of the camera's, against every other way the
+// same path could have been made, from how much more or less likely each of them would have been to make it
+// Joined says whether light paths are joined straight to the camera too, which is one of the other ways if they are
+fn mis_weight(camera: &Camera, objects: &[Box<dyn Object>], eye: &[Vertex], light: &[Vertex], s: usize, t: usize, joined: bool) -> f32 {
+    // The densities of every point from either side, and whether it's mirror-like, as they are along this path
+    let mut eye_pdfs: Vec<(f32, f32, bool)> = eye[..t].iter().map(|vertex| (vertex.pdf_forward, vertex.pdf_reverse, vertex.is_specular)).collect();
+    let mut light_pdfs: Vec<(f32, f32, bool)> = light[..s].iter().map(|vertex| (vertex.pdf_forward, vertex.pdf_reverse, vertex.is_specular)).collect();
+    if t == 1 {
+        // Joined straight to the camera, which would have sent a ray towards the point this often
+        let (qs, center) = (&light[s - 1], eye[0].position);
+        let pdf = camera.locate(qs.position).map_or(0.0, |(_, pdf)| pdf);
+        light_pdfs[s - 1].1 = to_area(pdf, center, qs);
+        if s > 1 {
+            let qs_minus = &light[s - 2];
+            light_pdfs[s - 2].1 = to_area(qs.pdf_towards(objects, Some(center), qs_minus.position), qs.position, qs_minus);
+        }
+        light_pdfs[s - 1].2 = false;
+        return weigh(&eye_pdfs, &light_pdfs, joined);
+    }
+    let (pt, pt_minus) = (&eye[t - 1], &eye[t - 2]);
+    match s {
+        0 => {
+            // Run into a light, which paths from the light would have started from this often
+            let Some(index) = pt.object else {
+                return 1.0;
+            };
+            let direction = pt_minus.position - pt.position;
+            let time = pt.incoming.as_ref().map_or(0.0, |ray| ray.time);
+            let origin_pdf = objects[index].light().map_or(0.0, |light| light.surface_pdf(pt.position, pt.normal, direction, time))
+                * camera.light_power_probability(index);
+            // Lights that paths can't start from are only ever found this way
+            if origin_pdf <= 0.0 {
+                return 1.0;
+            }
+            let side = match pt.normal.dot(direction) > 0.0 {
+                true => pt.normal,
+                false => -pt.normal
+            };
+            eye_pdfs[t - 1].1 = origin_pdf;
+            eye_pdfs[t - 2].1 = to_area(cosine_hemisphere_pdf(side.dot(direction.normalize())), pt.position, pt_minus);
+        },
+        _ => {
+            let qs = &light[s - 1];
+            eye_pdfs[t - 1].1 = to_area(qs.pdf_towards(objects, None, pt.position), qs.position, pt);
+            eye_pdfs[t - 2].1 = to_area(pt.pdf_towards(objects, Some(qs.position), pt_minus.position), pt.position, pt_minus);
+            light_pdfs[s - 1].1 = to_area(pt.pdf_towards(objects, None, qs.position), pt.position, qs);
+            if s > 1 {
+                let qs_minus = &light[s - 2];
+                light_pdfs[s - 2].1 = to_area(qs.pdf_towards(objects, Some(pt.position), qs_minus.position), qs.position, qs_minus);
+            }
+            // The joined points weren't mirror-like, or there'd be nothing to weigh
+            eye_pdfs[t - 1].2 = false;
+            light_pdfs[s - 1].2 = false;
+        }
+    }
+    return weigh(&eye_pdfs, &light_pdfs, joined);
+}
+
+// The power heuristic weight from the densities of every point of a path from either side, and whether it's mirror-like
+fn weigh(eye_pdfs: &[(f32, f32, bool)], light_pdfs: &[(f32, f32, bool)], joined: bool) -> f32 {
+    let (s, t) = (light_pdfs.len(), eye_pdfs.len());
+    // Mirror-like bounces have no density, but every way of making the path goes through them alike
+    let remap = |pdf: f32| match pdf == 0.0 {
+        true => 1.0,
+        false => pdf
+    };
+    let mut sum = 0.0;
+    // Each step moves one more point of the camera's path over to the light's, down to the first one past the camera
+    // if light paths are joined straight to the camera, and leaving it on the camera's side otherwise
+    let last = match joined {
+        true => 1,
+        false => 2
+    };
+    let mut ratio = 1.0;
+    for i in (last..t).rev() {
+        ratio *= remap(eye_pdfs[i].1) / remap(eye_pdfs[i].0);
+        if !eye_pdfs[i].2 && !eye_pdfs[i - 1].2 {
+            sum += ratio * ratio;
+        }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+        let previous_specular = i > 0 && light_pdfs[i - 1].2;
+        if !light_pdfs[i].2 && !previous_specular {
+            sum += ratio * ratio;
+        }
+    }
+    return 1.0 / (1.0 + sum);
+}
+
+// Turns a density per solid angle of going from a point towards a vertex into one per unit of area around the vertex
+fn to_area(pdf: f32, from: Vec3, to: &Vertex) -> f32 {
+    let offset = to.position - from;
+    let distance_squared = offset.length_squared();
+    match distance_squared > 0.0 {
+        true => pdf * to.normal.dot(offset / distance_squared.sqrt()).abs() / distance_squared,
+        false => 0.0
+    }
+}
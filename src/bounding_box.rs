@@ -0,0 +1,106 @@
+use glam::Vec3;
+use crate::interval::Interval;
+use crate::ray::Ray;
+
+// Axes thinner than this get padded, so flat objects like rects still have a box rays can hit
+const MIN_AXIS_SIZE: f32 = 0.0001;
+
+/// An axis-aligned bounding box, stored as one interval per axis
+#[derive(Clone, Copy)]
+pub struct BoundingBox {
+    pub x: Interval,
+    pub y: Interval,
+    pub z: Interval
+}
+
+impl BoundingBox {
+    pub const EMPTY: BoundingBox = BoundingBox {
+        x: Interval::EMPTY,
+        y: Interval::EMPTY,
+        z: Interval::EMPTY
+    };
+
+    pub fn new(x: Interval, y: Interval, z: Interval) -> BoundingBox {
+        BoundingBox { x, y, z }.pad()
+    }
+
+    /// The box with two opposite corners at a and b, in any order
+    pub fn from_points(a: Vec3, b: Vec3) -> BoundingBox {
+        let min = a.min(b);
+        let max = a.max(b);
+        BoundingBox::new(
+            Interval::new(min.x, max.x),
+            Interval::new(min.y, max.y),
+            Interval::new(min.z, max.z)
+        )
+    }
+
+    /// The smallest box containing both boxes
+    pub fn union(a: &BoundingBox, b: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            x: Interval::union(&a.x, &b.x),
+            y: Interval::union(&a.y, &b.y),
+            z: Interval::union(&a.z, &b.z)
+        }
+    }
+
+    /// Widens any degenerate axis to a minimum size
+    pub fn pad(&self) -> BoundingBox {
+        let pad_axis = |axis: Interval| match axis.size() < MIN_AXIS_SIZE {
+            true => axis.expand(MIN_AXIS_SIZE),
+            false => axis
+        };
+        BoundingBox {
+            x: pad_axis(self.x),
+            y: pad_axis(self.y),
+            z: pad_axis(self.z)
+        }
+    }
+
+    /// Gets the interval of axis 0, 1 or 2 (x, y or z)
+    pub fn axis(&self, index: usize) -> &Interval {
+        match index {
+            1 => &self.y,
+            2 => &self.z,
+            _ => &self.x
+        }
+    }
+
+    /// The index of the axis along which the box is the largest
+    pub fn longest_axis(&self) -> usize {
+        let sizes = [self.x.size(), self.y.size(), self.z.size()];
+        if sizes[0] > sizes[1] {
+            return if sizes[0] > sizes[2] { 0 } else { 2 };
+        }
+        return if sizes[1] > sizes[2] { 1 } else { 2 };
+    }
+
+    pub fn min(&self) -> Vec3 {
+        Vec3::new(self.x.min, self.y.min, self.z.min)
+    }
+
+    pub fn max(&self) -> Vec3 {
+        Vec3::new(self.x.max, self.y.max, self.z.max)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min() + self.max()) / 2.0
+    }
+
+    /// Slab test: does the ray pass through the box somewhere within the hit interval?
+    pub fn hit(&self, ray: &Ray, hit_interval: &Interval) -> bool {
+        let mut t_interval = *hit_interval;
+        for axis in 0..3 {
+            let bounds = self.axis(axis);
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let t0 = (bounds.min - ray.origin[axis]) * inverse_direction;
+            let t1 = (bounds.max - ray.origin[axis]) * inverse_direction;
+            t_interval.min = t_interval.min.max(t0.min(t1));
+            t_interval.max = t_interval.max.min(t0.max(t1));
+            if t_interval.max <= t_interval.min {
+                return false;
+            }
+        }
+        return true;
+    }
+}
@@ -1,168 +1,1360 @@
-use std::io::Error;
-use rand::{thread_rng, rngs::ThreadRng, Rng};
-use crate::output::{write_bmp, write_tga, Format};
-use glam::Vec3;
-use crate::ray::{Ray, Hit};
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use rand::Rng;
+use crate::sampler::{Sampler, SamplerRng, StratifiedSampler};
+use crate::output::{open_writer, unpremultiplied, write_float_image, write_image, write_layers, Dither, Format, ImageLayer, RGB_CHANNELS};
+use crate::framebuffer::Framebuffer;
+use crate::color::{srgb_slope, SrgbColor};
+use crate::denoise::{DenoiseBuffers, Denoiser};
+use crate::exposure::Exposure;
+use crate::filter::{FilterTable, PixelFilter};
+use crate::post_process::PostProcess;
+use crate::aov::{Aov, AovSums, SceneIds};
+use glam::{Vec2, Vec3};
+use crate::ray::{Ray, Hit, DEFAULT_HIT_EPSILON};
 use crate::interval::Interval;
 use crate::object::*;
+use crate::light::{LightTree, PunctualLight};
+use crate::environment::{Background, LightPortal, sample_portals, portals_pdf};
+use crate::sky::Sun;
+use crate::material::{random_unit_vector, henyey_greenstein, sample_henyey_greenstein, ScatterRecord, LUMINANCE_WEIGHTS};
+use crate::fog::HeightFog;
+use crate::integrator::{Integrator, PathTracer};
+use crate::preview::{print_summary, print_thumbnail};
+use crate::settings::RenderSettings;
+use crate::animation::CameraPath;
+use crate::projection::{CameraFrame, Projection, Pinhole, Stereo, StereoMode};
 
 type Color = Vec3;
+// Every pixel gets at least this many samples before adaptive sampling can stop, so rare bright paths get a chance to show up
+const ADAPTIVE_MIN_SAMPLES: u32 = 16;
+// Samples taken between each check of whether a pixel is done
+const ADAPTIVE_BATCH: u32 = 8;
+// How far out the background is taken to be for motion vectors
+const BACKGROUND_DISTANCE: f32 = 1.0e6;
+// Auto exposure meters a grid of up to this many pixels across and up, averaging this many samples in each
+const METERING_GRID: u32 = 32;
+const METERING_SAMPLES: u32 = 16;
+// Added to every luminance before taking the log, so black pixels don't pull the average down to nothing
+const METERING_FLOOR: f32 = 1e-4;
+
+/// A named camera placement and projection, so a scene can keep several standard angles around
+pub struct View {
+    pub name: String,
+    pub frame: CameraFrame,
+    pub projection: Box<dyn Projection>
+}
+
+impl View {
+    /// A view at from, looking towards at, turned so that up points up in the image
+    pub fn new(name: &str, from: Vec3, at: Vec3, up: Vec3, projection: Box<dyn Projection>) -> View {
+        View { name: name.to_owned(), frame: CameraFrame::look_at(from, at, up), projection }
+    }
+}
 
 pub struct Camera {
-    pub image_width: u16,
-    pub image_height: u16,
-    center: Vec3,
-    pixel_delta_u: Vec3,
-    pixel_delta_v: Vec3,
-    viewport_pixel_origin: Vec3,
-    image_data: Vec<Vec<u8>>,
+    pub settings: RenderSettings,
+    // Where the camera is and which way it's turned
+    frame: CameraFrame,
+    // How points on the image turn into rays
+    pub projection: Box<dyn Projection>,
+    framebuffer: Framebuffer,
     filename: String,
-    rng: ThreadRng,
+    rng: SamplerRng,
+    // Samples per pixel, or the most any pixel gets when sampling adaptively
     pub samples: u32,
-    max_depth: u32,
+    // How the samples are spread over and around every pixel and weighed
+    pub filter: PixelFilter,
+    filter_table: FilterTable,
+    // How the color seen along every camera ray is worked out
+    pub integrator: Arc<dyn Integrator>,
+    // Stop sampling a pixel once its color is known to within this much of the 0 to 1 range of the image,
+    // with 95% confidence. None takes every sample for every pixel
+    pub noise_threshold: Option<f32>,
+    // Brightest a single sample of the light reaching the first hit straight from a light, and of the light
+    // reaching it after bouncing around, can be once exposed. Lower limits get rid of fireflies but darken the image
+    pub clamp_direct: Option<f32>,
+    pub clamp_indirect: Option<f32>,
+    // Drop samples of a pixel this many times brighter than the average of its other samples
+    pub outlier_rejection: Option<f32>,
+    // Samples actually taken over the whole image, which adaptive sampling keeps below samples per pixel
+    samples_taken: u64,
+    // Which sample of the current pixel is being taken
+    sample_index: u32,
+    // What the filter weighs the current sample by
+    sample_weight: f32,
+    // The pixel being sampled
+    pixel: (u32, u32),
+    pub max_depth: u32,
+    // Rays ignore hits closer than this, to avoid hitting the surface they start on
+    pub hit_epsilon: f32,
+    // Distance bounced rays are pushed away from the surface along the geometric normal
+    pub normal_offset: f32,
+    // Print a preview of the result to the terminal once done
+    pub thumbnail: bool,
+    // Also write a copy of the image with the noise taken out by this, into a file with _denoised appended
+    pub denoiser: Option<Denoiser>,
+    // How light is rounded to bytes, to hide banding in smooth gradients
+    pub dither: Dither,
+    // Effects applied in order to the finished image and its denoised copy, before they're encoded
+    pub post_process: Vec<PostProcess>,
+    // Render passes written next to the image, into files with their names appended
+    pub aovs: Vec<Aov>,
+    // What the samples of the current pixel saw for the passes and the denoiser
+    aov_sums: AovSums,
+    // Ids of the objects and materials for the id passes, numbered at the start of every render
+    scene_ids: SceneIds,
+    // When the shutter of the next frame of a sequence opens and where the camera is then, which motion vectors point towards
+    // Still renders show how things move while the shutter is open instead
+    next_frame: Option<(f32, CameraFrame)>,
+    // The time and the eye frame motion vectors of the current pixel point towards
+    motion_end: (f32, CameraFrame),
+    // Write every row to disk as soon as it's done instead of keeping the whole image in memory
+    pub stream_to_disk: bool,
+    // Give the image an alpha channel, leaving the background and holdouts transparent and shadow catchers as dark as their shadows
+    pub alpha: bool,
+    // Times the shutter opens and closes. Objects moving in between are blurred
+    // When rendering a sequence of frames, these are relative to the start of each frame
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    // How the light is exposed, for scenes lit in physical units. None leaves the colors as they are
+    pub exposure: Option<Exposure>,
+    // Log-average luminance of the scene, metered at the start of every render for auto exposure
+    metered_luminance: f32,
+    // Render a left and a right eye instead of a single view
+    pub stereo: Option<Stereo>,
+    // Focus on whatever is seen at this point on the image before rendering, (0.5, 0.5) being the center
+    pub autofocus: Option<(f32, f32)>,
+    // Other cameras that can be rendered by name instead of the main one
+    pub views: Vec<View>,
+    // What rays that escape the scene see, an image of the surroundings also lights it from far away
+    pub background: Background,
+    // A small bright disc in the sky on top of the background, sampled on its own
+    pub sun: Option<Sun>,
+    // Openings the background shines into the scene through, which it's sampled through if there are any
+    pub light_portals: Vec<LightPortal>,
+    // Lights without a size, which are all aimed at from every hit since rays can't find them by bouncing
+    pub punctual_lights: Vec<PunctualLight>,
+    // The light group the background and the sun are gathered into, if they get an image of their own
+    pub background_light_group: Option<String>,
+    // Haze in the air between objects, which every ray outside of them goes through
+    pub fog: Option<HeightFog>,
+    // The objects that give off light, sorted into a tree again at the start of every render
+    lights: LightTree,
+    // Which light group every light is in, also worked out again at the start of every render
+    light_groups: LightGroups,
 }
 
 impl Camera {
     pub fn default() -> Camera {
-        let image_width: u16 = 256;
-        let image_height: u16 = 256;
-        let viewport_height: f32 = 2.0;
-        let viewport_width: f32 = viewport_height * (image_width as f32 / image_height as f32);
-        let focal_length: f32 = 1.0;
-        let center= Vec3::new(0.0, 0.0, 0.0);
-        let viewport_u = Vec3::new(viewport_width, 0.0, 0.0);
-        let viewport_v = Vec3::new(0.0, viewport_height, 0.0);
-        let pixel_delta_u = viewport_u / image_width as f32;
-        let pixel_delta_v = viewport_v / image_height as f32;
-        let viewport_lower_left = center - Vec3::new(0.0, 0.0, focal_length) - viewport_u / 2.0 - viewport_v / 2.0;
-        let viewport_pixel_origin = viewport_lower_left + (pixel_delta_u + pixel_delta_v) / 2.0;
-        let mut image_data = vec![];
-        image_data.resize(image_height as usize, vec![]);
         Camera {
-            image_width,
-            image_height,
-            center,
-            pixel_delta_u,
-            pixel_delta_v,
-            viewport_pixel_origin,
-            image_data,
+            settings: RenderSettings::default(),
+            frame: CameraFrame::look_at(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), Vec3::Y),
+            projection: Box::new(Pinhole::new(90.0)),
+            framebuffer: Framebuffer::new(0, 0),
             filename: "output".to_owned(),
-            rng: thread_rng(),
+            rng: SamplerRng::new(Box::new(StratifiedSampler::new())),
             samples: 10,
+            filter: PixelFilter::Box { width: 1.0 },
+            filter_table: FilterTable::new(PixelFilter::Box { width: 1.0 }),
+            integrator: Arc::new(PathTracer),
+            noise_threshold: None,
+            clamp_direct: None,
+            clamp_indirect: None,
+            outlier_rejection: None,
+            samples_taken: 0,
+            sample_index: 0,
+            sample_weight: 1.0,
+            pixel: (0, 0),
             max_depth: 15,
+            hit_epsilon: DEFAULT_HIT_EPSILON,
+            normal_offset: 0.0001,
+            thumbnail: false,
+            denoiser: None,
+            dither: Dither::None,
+            post_process: vec![],
+            aovs: vec![],
+            aov_sums: AovSums::default(),
+            scene_ids: SceneIds::new(&[]),
+            next_frame: None,
+            motion_end: (0.0, CameraFrame::look_at(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), Vec3::Y)),
+            stream_to_disk: false,
+            alpha: false,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            exposure: None,
+            metered_luminance: 0.0,
+            stereo: None,
+            autofocus: None,
+            views: vec![],
+            background: Background::default(),
+            sun: None,
+            light_portals: vec![],
+            punctual_lights: vec![],
+            background_light_group: None,
+            fog: None,
+            lights: LightTree::default(),
+            light_groups: LightGroups::default(),
+        }
+    }
+
+    /// Pick where the numbers for every sample come from, like a HaltonSampler for faster convergence
+    pub fn set_sampler(&mut self, sampler: Box<dyn Sampler>) {
+        self.rng = SamplerRng::new(sampler);
+    }
+
+    /// Place the camera at from, looking towards at, turned so that up points up in the image
+    pub fn look_at(&mut self, from: Vec3, at: Vec3, up: Vec3) {
+        self.frame = CameraFrame::look_at(from, at, up);
+    }
+
+    /// Focus on the first thing seen through (s, t) on the image, which go from 0 to 1 left to right and bottom to top
+    /// Returns the new focus distance, or None if the ray hits nothing and the focus is left as it was
+    pub fn focus_at(&mut self, objects: &[Box<dyn Object>], s: f32, t: f32) -> Option<f32> {
+        let ray = self.projection.center_ray(&mut self.rng, &self.frame, s, t, self.settings.aspect())?;
+        let hit = self.get_intersection(&ray, objects, &Interval::new(self.hit_epsilon, f32::MAX))?;
+        // The focus plane faces the camera, so the distance is measured along the view direction
+        let distance = (hit.position - self.frame.center).dot(-self.frame.w);
+        self.projection.set_focus_distance(distance);
+        return Some(distance);
+    }
+
+    /// Render from the view with the given name, to a file with the name appended
+    pub fn render_view(&mut self, name: &str, objects: &[Box<dyn Object>], format: Format) -> Result<(), Error> {
+        let index = self.views.iter().position(|view| view.name == name).ok_or_else(|| Error::new(
+            ErrorKind::NotFound,
+            format!("no camera named {}", name)
+        ))?;
+        // Swap the view in for the main camera, and back again once done
+        let mut view = self.views.remove(index);
+        let filename = format!("{}_{}", self.filename, view.name);
+        std::mem::swap(&mut self.frame, &mut view.frame);
+        std::mem::swap(&mut self.projection, &mut view.projection);
+        let filename = std::mem::replace(&mut self.filename, filename);
+        let result = self.render(objects, format);
+        std::mem::swap(&mut self.frame, &mut view.frame);
+        std::mem::swap(&mut self.projection, &mut view.projection);
+        self.filename = filename;
+        self.views.insert(index, view);
+        return result;
+    }
+
+    /// Render every view into its own file
+    pub fn render_all_views(&mut self, objects: &[Box<dyn Object>], format: Format) -> Result<(), Error> {
+        let names: Vec<String> = self.views.iter().map(|view| view.name.clone()).collect();
+        for name in names {
+            self.render_view(&name, objects, format)?;
+        }
+        Ok(())
+    }
+
+    /// Render frames orbiting target once at a fixed radius and elevation in degrees, into numbered files
+    pub fn render_turntable(&mut self, objects: &[Box<dyn Object>], format: Format, target: Vec3, radius: f32, elevation: f32, frames: u32) -> Result<(), Error> {
+        let (frame, filename) = (self.frame, self.filename.clone());
+        let elevation = elevation.to_radians();
+        let mut result = Ok(());
+        // Start in front of the target, looking along -z like the default camera
+        let frame_at = |i: u32| {
+            let angle = 2.0 * PI * i as f32 / frames as f32;
+            let offset = Vec3::new(elevation.cos() * angle.sin(), elevation.sin(), elevation.cos() * angle.cos());
+            CameraFrame::look_at(target + radius * offset, target, Vec3::Y)
+        };
+        for i in 0..frames {
+            self.frame = frame_at(i);
+            // Time stands still, only the camera moves on to the next frame
+            self.next_frame = Some((self.shutter_open, frame_at(i + 1)));
+            self.filename = format!("{}_{:04}", filename, i);
+            result = self.render(objects, format);
+            if result.is_err() {
+                break;
+            }
         }
+        self.frame = frame;
+        self.filename = filename;
+        self.next_frame = None;
+        return result;
     }
 
-    pub fn set_width(&mut self, width: u16) {
-        self.image_width = width;
-        let viewport_height: f32 = 2.0;
-        let viewport_width: f32 = viewport_height * (width as f32 / self.image_height as f32);
-        let focal_length: f32 = 1.0;
-        let center= Vec3::new(0.0, 0.0, 0.0);
-        let viewport_u = Vec3::new(viewport_width, 0.0, 0.0);
-        let viewport_v = Vec3::new(0.0, viewport_height, 0.0);
-        self.pixel_delta_u = viewport_u / width as f32;
-        self.pixel_delta_v = viewport_v / self.image_height as f32;
-        let viewport_lower_left = center - Vec3::new(0.0, 0.0, focal_length) - viewport_u / 2.0 - viewport_v / 2.0;
-        self.viewport_pixel_origin = viewport_lower_left + (self.pixel_delta_u + self.pixel_delta_v) / 2.0;
-    }
-
-    pub fn set_height(&mut self, height: u16) {
-        self.image_height = height;
-        self.image_data.resize(height as usize, vec![]);
-        let viewport_height: f32 = 2.0;
-        let viewport_width: f32 = viewport_height * (self.image_width as f32 / height as f32);
-        let focal_length: f32 = 1.0;
-        let center= Vec3::new(0.0, 0.0, 0.0);
-        let viewport_u = Vec3::new(viewport_width, 0.0, 0.0);
-        let viewport_v = Vec3::new(0.0, viewport_height, 0.0);
-        self.pixel_delta_u = viewport_u / self.image_width as f32;
-        self.pixel_delta_v = viewport_v / height as f32;
-        let viewport_lower_left = center - Vec3::new(0.0, 0.0, focal_length) - viewport_u / 2.0 - viewport_v / 2.0;
-        self.viewport_pixel_origin = viewport_lower_left + (self.pixel_delta_u + self.pixel_delta_v) / 2.0;
-    }
-
-    pub fn render(&mut self, objects: &Vec<Box<dyn Object>>, format: Format) -> Result<(), Error> {
+    /// Render the camera moving along a path, at frames_per_second from its first keyframe to its last, into numbered files
+    /// The projection is left at the field of view of the last frame
+    pub fn render_path(&mut self, objects: &[Box<dyn Object>], format: Format, path: &CameraPath, frames_per_second: f32) -> Result<(), Error> {
+        let (start, end) = path.duration();
+        self.render_frames(objects, format, start, end, frames_per_second, Some(path))
+    }
+
+    /// Render the scene from start to end at frames_per_second into numbered files, for animated objects
+    pub fn render_sequence(&mut self, objects: &[Box<dyn Object>], format: Format, start: f32, end: f32, frames_per_second: f32) -> Result<(), Error> {
+        self.render_frames(objects, format, start, end, frames_per_second, None)
+    }
+
+    // Every frame opens the shutter at its own time, keeping the shutter interval for motion blur
+    fn render_frames(&mut self, objects: &[Box<dyn Object>], format: Format, start: f32, end: f32, frames_per_second: f32, path: Option<&CameraPath>) -> Result<(), Error> {
+        let (frame, filename) = (self.frame, self.filename.clone());
+        let (shutter_open, shutter_close) = (self.shutter_open, self.shutter_close);
+        let frames = ((end - start) * frames_per_second).floor() as u32 + 1;
+        let mut result = Ok(());
+        for i in 0..frames {
+            let time = start + i as f32 / frames_per_second;
+            if let Some(path) = path {
+                let keyframe = path.sample(time);
+                self.look_at(keyframe.position, keyframe.look_at, Vec3::Y);
+                self.projection.set_vfov(keyframe.vfov);
+            }
+            self.shutter_open = time + shutter_open;
+            self.shutter_close = time + shutter_close;
+            // The next frame's projection is taken to be the same, only the camera moves
+            let next_time = time + 1.0 / frames_per_second;
+            let next_frame = match path {
+                Some(path) => {
+                    let keyframe = path.sample(next_time);
+                    CameraFrame::look_at(keyframe.position, keyframe.look_at, Vec3::Y)
+                },
+                None => self.frame
+            };
+            self.next_frame = Some((next_time + shutter_open, next_frame));
+            self.filename = format!("{}_{:04}", filename, i);
+            result = self.render(objects, format);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.frame = frame;
+        self.filename = filename;
+        self.next_frame = None;
+        (self.shutter_open, self.shutter_close) = (shutter_open, shutter_close);
+        return result;
+    }
+
+    pub fn render(&mut self, objects: &[Box<dyn Object>], format: Format) -> Result<(), Error> {
+        let start = Instant::now();
+        if let Some(denoiser) = self.denoiser {
+            denoiser.check_available()?;
+        }
+        if self.alpha {
+            format.check_alpha()?;
+        }
+        if let Some((s, t)) = self.autofocus {
+            self.focus_at(objects, s, t);
+        }
+        self.lights = LightTree::new(objects);
+        self.light_groups = LightGroups::new(objects, &self.punctual_lights, &self.background_light_group);
+        self.scene_ids = SceneIds::new(objects);
+        self.filter_table = FilterTable::new(self.filter);
+        Arc::clone(&self.integrator).prepare(self, objects);
+        let metering = self.exposure.is_some_and(|exposure| exposure.is_auto()) && self.integrator.is_radiance();
+        if metering {
+            self.metered_luminance = self.meter(objects);
+        }
+        let (image_width, image_height) = (self.settings.width, self.settings.height);
+        let path = format!("{}.{}", self.filename, format.extension());
+        // Every light group and render pass goes into a file with its name appended, like the named views,
+        // or into a layer of its own for layered formats
+        let layered = format.is_layered();
+        let pass_names: Vec<String> = self.light_groups.names.iter().cloned().chain(self.aovs.iter().map(|aov| aov.name().to_owned())).collect();
+        let pass_paths: Vec<String> = pass_names.iter()
+            .map(|name| format!("{}_{}.{}", self.filename, name, format.extension()))
+            .collect();
+        let pass_dithered: Vec<bool> = self.light_groups.names.iter().map(|_| true).chain(self.aovs.iter().map(Aov::is_light)).collect();
+        // Side by side stereo puts both eyes next to each other in an image twice as wide
+        let output_width = match self.stereo {
+            Some(stereo) if stereo.mode == StereoMode::SideBySide => image_width * 2,
+            _ => image_width
+        };
+        let eyes = match self.stereo {
+            Some(stereo) => {
+                let (left, right) = stereo.eyes(&self.frame);
+                vec![left, right]
+            },
+            None => vec![self.frame]
+        };
+        let (motion_time, motion_frame) = self.next_frame.unwrap_or((self.shutter_close, self.frame));
+        let motion_eyes = match self.stereo {
+            Some(stereo) => {
+                let (left, right) = stereo.eyes(&motion_frame);
+                vec![left, right]
+            },
+            None => vec![motion_frame]
+        };
+        // When streaming, rows go straight to disk and the framebuffer is never filled
+        // Float images keep the light of every pixel instead, and are only written once they're done
+        // So does post processing, which needs the whole image
+        let post_processing = !self.post_process.is_empty();
+        let stream = self.stream_to_disk && !format.is_float() && !post_processing;
+        let mut writer = match stream {
+            true => Some(open_writer(&format, &path, output_width, image_height, self.alpha)?),
+            false => None
+        };
+        let mut pass_writers = match stream {
+            true => pass_paths.iter().map(|path| open_writer(&format, path, output_width, image_height, false)).collect::<Result<Vec<_>, Error>>()?,
+            false => vec![]
+        };
+        let buffered_rows = if stream { 0 } else { image_height as usize };
+        // Only the image itself has an alpha channel, the passes cover every pixel
+        self.framebuffer = match self.alpha {
+            true => Framebuffer::with_alpha(output_width as usize, buffered_rows),
+            false => Framebuffer::new(output_width as usize, buffered_rows)
+        };
+        let pass_rows_buffered = if layered { 0 } else { buffered_rows };
+        let mut pass_framebuffers: Vec<Framebuffer> = pass_paths.iter().map(|_| Framebuffer::new(output_width as usize, pass_rows_buffered)).collect();
+        // The denoiser needs the whole image as light, even when the bytes are streamed to disk
+        let mut denoise_buffers = self.denoiser.map(|_| DenoiseBuffers::new(output_width as usize, image_height as usize));
+        // The exposed light of the image and then of every pass, bottom to top, for float formats
+        // Layered images hold the values of the passes instead of how they look
+        let pixel_count = output_width as usize * image_height as usize;
+        // Post processed images in the other formats only keep the light of the image itself, which is encoded once it's processed
+        let mut float_images = match (format.is_float(), post_processing) {
+            (true, _) => vec![vec![Color::ZERO; pixel_count]; 1 + pass_paths.len()],
+            (false, true) => vec![vec![Color::ZERO; pixel_count]],
+            (false, false) => vec![]
+        };
+        // How much of every pixel is covered, bottom to top, for the alpha channel
+        let mut alpha_image = match self.alpha {
+            true => vec![0.0; pixel_count],
+            false => vec![]
+        };
+        let mut row = vec![0; output_width as usize * self.framebuffer.channels];
+        let mut pass_rows = vec![vec![0; output_width as usize * 3]; pass_paths.len()];
+        // Tracing camera rays specially is only worth it if there are shadow catchers or holdouts, or to tell what's background for alpha
+        let needs_camera_pass = self.alpha || objects.iter().any(|object| object.is_shadow_catcher() || object.is_holdout());
+        self.samples_taken = 0;
         // Scan left to right, bottom to top
-        for image_y in 0..self.image_height {
-            print!("\r{:3} lines remaining", self.image_height - image_y);
-            for image_x in 0..self.image_width {
-                // Sums to average the colors later
-                let mut total_color = Color::new(0.0, 0.0, 0.0);
-                for _i in 0..self.samples {
-                    let ray = self.get_random_ray(image_x, image_y);
-                    total_color += self.ray_to_color(&ray, &objects, self.max_depth);
+        for image_y in 0..image_height {
+            print!("\r{:3} lines remaining", image_height - image_y);
+            for image_x in 0..image_width {
+                let mut colors = vec![];
+                let mut aov_sums = vec![];
+                // The light of every group and every render pass, for every eye
+                let mut pass_colors = vec![vec![]; pass_rows.len()];
+                for (eye, &motion_eye) in eyes.iter().zip(&motion_eyes) {
+                    self.motion_end = (motion_time, motion_eye);
+                    colors.push(self.pixel_color(eye, objects, image_x, image_y, needs_camera_pass));
+                    aov_sums.push(self.aov_sums);
+                    let groups = self.light_groups.sums.iter().copied();
+                    let aovs = self.aovs.iter().map(|aov| match layered {
+                        true => aov.data(&self.aov_sums),
+                        false => aov.display(&self.aov_sums)
+                    });
+                    for (pass, color) in groups.chain(aovs).enumerate() {
+                        pass_colors[pass].push(color);
+                    }
                 }
-                // Average and add to image in LE order
-                let average_color = gamma_correct(total_color / self.samples as f32);
-                let bytes = color_to_bytes(average_color);
-                self.image_data[image_y as usize].push(bytes.2);
-                self.image_data[image_y as usize].push(bytes.1);
-                self.image_data[image_y as usize].push(bytes.0);
+                let alphas: Vec<f32> = aov_sums.iter().map(|sums| sums.alpha).collect();
+                let image_alphas = match self.alpha {
+                    true => Some(alphas.as_slice()),
+                    false => None
+                };
+                self.place_pixels(&mut row, image_x, image_y, &colors, image_alphas, true);
+                if self.alpha {
+                    for ((x, _), &alpha) in self.output_pixels(image_x, &colors).into_iter().zip(&alphas) {
+                        alpha_image[image_y as usize * output_width as usize + x as usize] = alpha;
+                    }
+                }
+                // Anaglyphs mix the colors of the eyes, but only the left eye's albedo and normals make sense
+                if let Some(buffers) = denoise_buffers.as_mut() {
+                    for ((x, color), eye) in self.output_pixels(image_x, &colors).into_iter().zip(&aov_sums) {
+                        buffers.set(x as usize, image_y as usize, color, eye.albedo, eye.normal);
+                    }
+                }
+                if !layered {
+                    for ((pass_row, colors), &dithered) in pass_rows.iter_mut().zip(&pass_colors).zip(&pass_dithered) {
+                        self.place_pixels(pass_row, image_x, image_y, colors, None, dithered);
+                    }
+                }
+                for (image, colors) in float_images.iter_mut().zip(std::iter::once(&colors).chain(&pass_colors)) {
+                    for (x, color) in self.output_pixels(image_x, colors) {
+                        image[image_y as usize * output_width as usize + x as usize] = color;
+                    }
+                }
+            }
+            match writer.as_mut() {
+                Some(writer) => writer.write_row(&row)?,
+                None => self.framebuffer.row_mut(image_y as usize).copy_from_slice(&row)
+            }
+            for (pass, pass_row) in pass_rows.iter().enumerate() {
+                match (pass_writers.get_mut(pass), layered) {
+                    (Some(writer), _) => writer.write_row(pass_row)?,
+                    (None, false) => pass_framebuffers[pass].row_mut(image_y as usize).copy_from_slice(pass_row),
+                    (None, true) => ()
+                }
+            }
+        }
+        let (width, height) = (output_width as usize, image_height as usize);
+        let mut denoised = match (self.denoiser, &denoise_buffers) {
+            (Some(denoiser), Some(buffers)) => Some(denoiser.denoise(buffers)?),
+            _ => None
+        };
+        // The image and its denoised copy share the alpha channel
+        let alpha = match self.alpha {
+            true => Some(alpha_image.as_slice()),
+            false => None
+        };
+        if post_processing {
+            for effect in &self.post_process {
+                effect.apply(width, height, &mut float_images[0]);
+                if let Some(denoised) = denoised.as_mut() {
+                    effect.apply(width, height, denoised);
+                }
+            }
+            if !format.is_float() {
+                self.framebuffer = encode_image(width, height, &float_images[0], alpha, &self.dither);
+            }
+        }
+        match layered {
+            true => {
+                // The beauty image, the light groups, the passes and the denoised image, in that order
+                let mut layers = vec![ImageLayer { name: "beauty", channels: RGB_CHANNELS, pixels: &float_images[0], integer: false, alpha }];
+                let groups = self.light_groups.names.iter().map(|_| (RGB_CHANNELS, false));
+                let aovs = self.aovs.iter().map(|aov| (aov.channels(), aov.is_id()));
+                for (((channels, integer), name), pixels) in groups.chain(aovs).zip(&pass_names).zip(&float_images[1..]) {
+                    layers.push(ImageLayer { name, channels, pixels, integer, alpha: None });
+                }
+                if let Some(denoised) = &denoised {
+                    layers.push(ImageLayer { name: "denoised", channels: RGB_CHANNELS, pixels: denoised, integer: false, alpha });
+                }
+                write_layers(width, height, &layers, &format, &path)?;
+            },
+            false => {
+                match (writer.as_mut(), float_images.first()) {
+                    (Some(writer), _) => writer.finish()?,
+                    (None, Some(image)) if format.is_float() => write_float_image(width, height, image, alpha, &format, &path)?,
+                    (None, _) => write_image(&self.framebuffer, &format, &path)?
+                }
+                for (pass, pass_path) in pass_paths.iter().enumerate() {
+                    match (pass_writers.get_mut(pass), float_images.get(pass + 1)) {
+                        (Some(writer), _) => writer.finish()?,
+                        (None, Some(image)) => write_float_image(width, height, image, None, &format, pass_path)?,
+                        (None, None) => write_image(&pass_framebuffers[pass], &format, pass_path)?
+                    }
+                }
+            }
+        }
+        let denoised_into = match (&denoised, layered) {
+            (Some(_), true) => Some(format!("the denoised layer of {}", path)),
+            (Some(denoised), false) => {
+                let denoised_path = format!("{}_denoised.{}", self.filename, format.extension());
+                match format.is_float() {
+                    true => write_float_image(width, height, denoised, alpha, &format, &denoised_path)?,
+                    false => write_image(&encode_image(width, height, denoised, alpha, &self.dither), &format, &denoised_path)?
+                }
+                Some(denoised_path)
+            },
+            (None, _) => None
+        };
+        print_summary(output_width, image_height, self.samples, self.max_depth, start.elapsed(), &path);
+        if let Some(denoised_into) = denoised_into {
+            println!("Denoised into {}", denoised_into);
+        }
+        if self.noise_threshold.is_some() {
+            let pixels = (image_width * image_height) as u64 * eyes.len() as u64;
+            println!("Sampled adaptively, {:.1} samples per pixel on average", self.samples_taken as f64 / pixels.max(1) as f64);
+        }
+        if let (Some(exposure), true) = (self.exposure, metering) {
+            println!("Metered at EV {:.1}", exposure.ev100(self.metered_luminance));
+        }
+        if self.thumbnail {
+            match stream {
+                true => println!("No thumbnail, the image was streamed to disk"),
+                false => print_thumbnail(&self.framebuffer)
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the colors of every eye for a pixel into a row, in LE order, along with their alphas if the row has them
+    // Only light is dithered, the passes holding values like ids have to keep them exactly
+    fn place_pixels(&self, row: &mut [u8], image_x: u32, image_y: u32, colors: &[Color], alphas: Option<&[f32]>, dithered: bool) {
+        for (eye, (x, color)) in self.output_pixels(image_x, colors).into_iter().enumerate() {
+            let threshold = match dithered {
+                true => self.dither.threshold(x, image_y),
+                false => 0.0
+            };
+            place_color(row, x as usize, color, alphas.map(|alphas| alphas[eye]), threshold);
+        }
+    }
+
+    // Where in the output row the colors of every eye for a pixel go, and what color they are there
+    fn output_pixels(&self, image_x: u32, colors: &[Color]) -> Vec<(u32, Color)> {
+        match self.stereo.map(|stereo| stereo.mode) {
+            Some(StereoMode::SideBySide) => vec![(image_x, colors[0]), (image_x + self.settings.width, colors[1])],
+            Some(StereoMode::Anaglyph) => vec![(image_x, Color::new(colors[0].x, colors[1].y, colors[1].z))],
+            None => vec![(image_x, colors[0])]
+        }
+    }
+
+    /// The averaged, exposed color of a pixel as seen from the given camera frame
+    /// The light of each light group is left in the group sums, averaged and exposed the same way,
+    /// and what the samples saw for the render passes in the pass sums
+    fn pixel_color(&mut self, frame: &CameraFrame, objects: &[Box<dyn Object>], image_x: u32, image_y: u32, needs_camera_pass: bool) -> Color {
+        // Sums to average the colors later
+        let mut total_color = Color::new(0.0, 0.0, 0.0);
+        let mut catcher = ShadowCatcherSums::default();
+        // Sums of the luminance of every sample and its square, for how noisy the pixel still is
+        let (mut luminance_sum, mut luminance_squares) = (0.0, 0.0);
+        let mut count = 0;
+        // The filter's weights of the samples, which the light is divided by so flat areas come out flat even with negative weights
+        let mut weight_sum = 0.0;
+        // Every sample and its light groups' share of it, kept when outliers are rejected once they're all in
+        let mut samples: Vec<(Color, Vec<Color>)> = vec![];
+        self.light_groups.sums.fill(Color::ZERO);
+        self.aov_sums = AovSums::default();
+        let integrator = Arc::clone(&self.integrator);
+        self.pixel = (image_x, image_y);
+        while count < self.samples {
+            self.rng.start_sample(image_x, image_y, count, self.samples);
+            self.sample_index = count;
+            // Samples the projection doesn't cover stay black
+            let (ray, weight) = self.get_random_ray(frame, image_x, image_y);
+            self.sample_weight = weight;
+            self.light_groups.weight = Color::splat(weight);
+            weight_sum += weight;
+            let color = match (&ray, needs_camera_pass) {
+                (Some(ray), true) => self.trace_camera_ray(ray, objects, &mut catcher, integrator.as_ref()) * weight,
+                (Some(ray), false) => integrator.radiance(self, ray, objects) * weight,
+                (None, _) => Color::ZERO
+            };
+            if let (Some(ray), true) = (&ray, self.denoiser.is_some() || !self.aovs.is_empty()) {
+                self.record_features(ray, frame, objects);
+            }
+            match self.outlier_rejection.is_some() {
+                true => samples.push((color, self.light_groups.sums.iter_mut().map(std::mem::take).collect())),
+                false => total_color += color
+            }
+            let luminance = color.dot(LUMINANCE_WEIGHTS);
+            luminance_sum += luminance;
+            luminance_squares += luminance * luminance;
+            count += 1;
+            if self.is_converged(count, luminance_sum, luminance_squares) {
+                break;
+            }
+        }
+        self.samples_taken += count as u64;
+        if let Some(factor) = self.outlier_rejection {
+            // The rejected samples are filled in with the average of the rest
+            let kept = reject_outliers(&samples, factor);
+            let kept_count = kept.iter().filter(|&&keep| keep).count().max(1) as f32;
+            let fill = count as f32 / kept_count;
+            for ((color, groups), _) in samples.iter().zip(&kept).filter(|(_, &keep)| keep) {
+                total_color += *color * fill;
+                for (sum, group) in self.light_groups.sums.iter_mut().zip(groups) {
+                    *sum += *group * fill;
+                }
+            }
+        }
+        match self.alpha {
+            true => self.aov_sums.alpha += catcher.shadow_alpha(),
+            false => total_color += catcher.resolve()
+        }
+        // The weights average out to 1, so a sum that isn't positive only happens with a handful of samples
+        let scale = self.exposure_scale() / match weight_sum > 0.0 {
+            true => weight_sum,
+            false => count.max(1) as f32
+        };
+        for sum in self.light_groups.sums.iter_mut() {
+            *sum *= scale;
+        }
+        self.aov_sums = self.aov_sums.averaged(count, scale);
+        self.aov_sums.noise = self.noise(count, luminance_sum, luminance_squares);
+        return total_color * scale;
+    }
 
+    // Adds the albedo, the normal and the distance of the first thing a camera ray hits to the pixel's pass sums,
+    // and keeps its ids and how it moves if it's the first sample. The background has no normal, distance or ids,
+    // and its color clamped to white as its albedo
+    fn record_features(&mut self, ray: &Ray, frame: &CameraFrame, objects: &[Box<dyn Object>]) {
+        let hit = self.intersect_objects(ray, objects, &Interval::new(self.hit_epsilon, f32::MAX));
+        match &hit {
+            Some((hit, index)) => {
+                self.aov_sums.albedo += objects[*index].albedo(hit.u, hit.v, hit.position, ray.time).clamp(Color::ZERO, Color::ONE);
+                self.aov_sums.normal += hit.normal;
+                self.aov_sums.depth += hit.t * ray.direction.length();
+                self.aov_sums.coverage += 1.0;
+            },
+            None => self.aov_sums.albedo += self.background_radiance(ray).clamp(Color::ZERO, Color::ONE)
+        }
+        if self.sample_index != 0 {
+            return;
+        }
+        // The background is so far away only turning the camera moves it
+        let (start, end) = match &hit {
+            Some((hit, index)) => {
+                self.aov_sums.object_id = self.scene_ids.object(*index);
+                self.aov_sums.material_id = self.scene_ids.material(*index);
+                let object = &objects[*index];
+                (object.moved(hit.position, ray.time, self.shutter_open), object.moved(hit.position, ray.time, self.motion_end.0))
+            },
+            None => {
+                let far = ray.origin + ray.direction.normalize() * BACKGROUND_DISTANCE;
+                (far, far)
             }
+        };
+        let aspect = self.settings.aspect();
+        let size = Vec2::new(self.settings.width as f32, self.settings.height as f32);
+        let start = self.projection.project(frame, start, aspect);
+        let end = self.projection.project(&self.motion_end.1, end, aspect);
+        // Points the camera can't see at one end of the motion don't have any
+        if let (Some(start), Some(end)) = (start, end) {
+            self.aov_sums.motion = (Vec2::from(end) - Vec2::from(start)) * size;
         }
-        match format {
-            Format::BMP => write_bmp(&self.image_data, &(self.filename.clone() + ".bmp")),
-            Format::TGA => write_tga(&self.image_data, &(self.filename.clone() + ".tga"))
+    }
+
+    // Whether a pixel with count samples, whose luminances sum up and square up to these, is known well enough to stop
+    // The error is measured once encoded, where the eye sees it, since noise in dark areas stands out more
+    fn is_converged(&self, count: u32, luminance_sum: f32, luminance_squares: f32) -> bool {
+        let Some(threshold) = self.noise_threshold else {
+            return false;
+        };
+        if count < ADAPTIVE_MIN_SAMPLES || !count.is_multiple_of(ADAPTIVE_BATCH) {
+            return false;
         }
+        return self.noise(count, luminance_sum, luminance_squares) < threshold;
+    }
+
+    // How far off the brightness of a pixel with count samples, whose luminances sum up and square up to these, might still be
+    // Half the width of the 95% confidence interval of the mean, carried through the sRGB curve
+    fn noise(&self, count: u32, luminance_sum: f32, luminance_squares: f32) -> f32 {
+        let n = count.max(1) as f32;
+        let scale = self.exposure_scale();
+        let mean = luminance_sum / n * scale;
+        let variance = ((luminance_squares / n - (luminance_sum / n).powi(2)) * scale * scale).max(0.0);
+        return 1.96 * (variance / n).sqrt() * srgb_slope(mean);
+    }
+
+    // The scale that maps the brightest luminance the exposure can show to white
+    // Integrators that don't give light are left unexposed
+    fn exposure_scale(&self) -> f32 {
+        match (self.exposure, self.integrator.is_radiance()) {
+            (Some(exposure), true) => exposure.scale(self.metered_luminance),
+            _ => 1.0
+        }
+    }
+
+    // The log-average luminance of the scene from a grid of pixels spread over the image
+    // The log average keeps a small bright light from darkening everything else, like a camera's matrix metering
+    // It's taken of whole pixels rather than single samples, since the noise of single paths would drag it far down
+    fn meter(&mut self, objects: &[Box<dyn Object>]) -> f32 {
+        let integrator = Arc::clone(&self.integrator);
+        let frame = self.frame;
+        let (width, height) = (self.settings.width, self.settings.height);
+        let (columns, rows) = (width.min(METERING_GRID), height.min(METERING_GRID));
+        let (mut log_sum, mut count) = (0.0, 0);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x, y) = (column * width / columns, row * height / rows);
+                self.pixel = (x, y);
+                let (mut pixel_sum, mut pixel_count) = (0.0, 0);
+                for sample in 0..METERING_SAMPLES {
+                    self.rng.start_sample(x, y, sample, METERING_SAMPLES);
+                    self.sample_index = sample;
+                    let (Some(ray), weight) = self.get_random_ray(&frame, x, y) else {
+                        continue;
+                    };
+                    self.sample_weight = weight;
+                    self.light_groups.weight = Color::splat(weight);
+                    let luminance = integrator.radiance(self, &ray, objects).dot(LUMINANCE_WEIGHTS) * weight;
+                    // Paths that went wrong somewhere can't throw the average off
+                    if luminance.is_finite() {
+                        pixel_sum += luminance.max(0.0);
+                        pixel_count += 1;
+                    }
+                }
+                // Nor can black pixels
+                if pixel_count > 0 {
+                    log_sum += (pixel_sum / pixel_count as f32 + METERING_FLOOR).ln();
+                    count += 1;
+                }
+            }
+        }
+        return match count {
+            0 => 0.0,
+            _ => (log_sum / count as f32).exp()
+        };
+    }
+
+    // A ray through a random point placed by the filter around the middle of the pixel, from the first two dimensions of the sample,
+    // and what the filter weighs it by
+    fn get_random_ray(&mut self, frame: &CameraFrame, image_x: u32, image_y: u32) -> (Option<Ray>, f32) {
+        let (offset_x, weight_x) = self.filter_table.sample(self.rng.gen::<f32>());
+        let (offset_y, weight_y) = self.filter_table.sample(self.rng.gen::<f32>());
+        let s = (image_x as f32 + 0.5 + offset_x) / self.settings.width as f32;
+        let t = (image_y as f32 + 0.5 + offset_y) / self.settings.height as f32;
+        let weight = match self.integrator.is_filtered() {
+            true => weight_x * weight_y,
+            false => 1.0
+        };
+        return (self.ray_through(frame, s, t), weight);
+    }
+
+    /// A ray from the main camera through (s, t) on the image, which go from 0 to 1 left to right and bottom to top,
+    /// at a random time while the shutter is open. None where the projection doesn't cover the image
+    pub fn image_ray(&mut self, s: f32, t: f32) -> Option<Ray> {
+        let frame = self.frame;
+        self.ray_through(&frame, s, t)
+    }
+
+    /// Where every ray of the main camera starts, for integrators that trace light straight into it
+    /// None when the rays don't all start from one point, like with a lens, and for stereo renders, which have two
+    pub fn pinhole(&self) -> Option<Vec3> {
+        let aspect = self.settings.aspect();
+        let ahead = self.frame.center - self.frame.w;
+        match self.stereo.is_none() && self.projection.solid_angle_per_area(&self.frame, ahead, aspect).is_some() {
+            true => Some(self.frame.center),
+            false => None
+        }
+    }
+
+    /// Where on the image (s, t) the main camera sees a point, and the density per solid angle of its rays towards it
+    /// if they were spread evenly over the whole image. None if the camera can't be traced into or the point is behind it
+    pub fn locate(&self, point: Vec3) -> Option<((f32, f32), f32)> {
+        self.pinhole()?;
+        let aspect = self.settings.aspect();
+        let image_point = self.projection.project(&self.frame, point, aspect)?;
+        let solid_angle = self.projection.solid_angle_per_area(&self.frame, point, aspect)?;
+        return Some((image_point, 1.0 / solid_angle));
+    }
+
+    fn ray_through(&mut self, frame: &CameraFrame, s: f32, t: f32) -> Option<Ray> {
+        let aspect = self.settings.aspect();
+        let time = lerp(self.shutter_open, self.shutter_close, self.rng.gen::<f32>());
+        let ray = self.projection.generate_ray(&mut self.rng, frame, s, t, aspect)?;
+        return Some(ray.at_time(time));
     }
 
-    fn get_random_ray(&mut self, image_x: u16, image_y: u16) -> Ray {
-        let pixel_center = self.viewport_pixel_origin + image_x as f32 * self.pixel_delta_u + image_y as f32 * self.pixel_delta_v; 
-        let sample_offset = (-0.5 + self.rng.gen::<f32>()) * self.pixel_delta_u + (-0.5 + self.rng.gen::<f32>()) * self.pixel_delta_u;
-        let direction = pixel_center - self.center + sample_offset;
-        return Ray::new(self.center, direction);
+    fn get_intersection(&mut self, ray: &Ray, objects: &[Box<dyn Object>], hit_interval: &Interval) -> Option<Hit> {
+        self.intersect_objects(ray, objects, hit_interval).map(|(hit, _)| hit)
     }
 
-    fn get_intersection(&mut self, ray: &Ray, objects: &Vec<Box<dyn Object>>, hit_interval: &Interval) -> Option<Hit> {
-        let mut hit: Option<Hit> = None;
+    /// The closest hit along a ray within an interval of t, and the index of the object hit
+    pub fn intersect_objects(&mut self, ray: &Ray, objects: &[Box<dyn Object>], hit_interval: &Interval) -> Option<(Hit, usize)> {
+        let mut hit: Option<(Hit, usize)> = None;
         let mut closest = hit_interval.max;
-        for object in objects {
+        for (index, object) in objects.iter().enumerate() {
             if let Some(this_hit) = object.intersect(&mut self.rng, ray, &Interval::new(hit_interval.min, closest)) {
                 closest = this_hit.t;
-                hit = Some(this_hit);
+                hit = Some((this_hit, index));
             }
         }
         return hit;
     }
 
-    fn ray_to_color(&mut self, ray: &Ray, objects: &Vec<Box<dyn Object>>, depth: u32) -> Color {
+    /// The random numbers of the current sample
+    pub fn rng(&mut self) -> &mut SamplerRng {
+        &mut self.rng
+    }
+
+    /// Which sample of the current pixel is being taken, counting from 0
+    pub fn sample_index(&self) -> u32 {
+        self.sample_index
+    }
+
+    /// The pixel being sampled, from the left and bottom of the image
+    pub fn pixel(&self) -> (u32, u32) {
+        self.pixel
+    }
+
+    /// Traces a ray from the camera, setting aside the samples that land on shadow catchers and cutting out holdouts
+    /// Everything else is left to the integrator
+    fn trace_camera_ray(&mut self, ray: &Ray, objects: &[Box<dyn Object>], catcher: &mut ShadowCatcherSums, integrator: &dyn Integrator) -> Color {
+        let hit = self.get_intersection(ray, objects, &Interval::new(self.hit_epsilon, f32::MAX));
+        match hit {
+            // Holdouts and the background are left out of images with alpha, to be filled in by whatever they're put over
+            Some(hit) if hit.holdout => match self.alpha {
+                true => Color::ZERO,
+                false => {
+                    let background = self.background_radiance(ray);
+                    self.record_background_light(background);
+                    background
+                }
+            },
+            Some(hit) if hit.shadow_catcher => {
+                // Compare the light reaching the catcher with what would reach it if the scene wasn't there
+                // The shadow is worked out from the whole pixel, so it can't be split up between light groups
+                let weight = std::mem::replace(&mut self.light_groups.weight, Color::ZERO);
+                // The sums are weighted by the filter like every other sample, since the pixel divides them by the summed weights
+                let bounced_ray = hit.spawn_ray(&hit.outgoing, self.normal_offset);
+                let sample_weight = self.sample_weight;
+                catcher.lit += self.ray_to_color(&bounced_ray, objects, self.max_depth.saturating_sub(1), None) * sample_weight;
+                catcher.unoccluded += self.unoccluded_color(&bounced_ray, objects) * sample_weight;
+                catcher.background += self.background_radiance(ray) * sample_weight;
+                catcher.samples += 1;
+                self.light_groups.weight = weight;
+                return Color::ZERO;
+            },
+            None if self.alpha => Color::ZERO,
+            Some(_) => {
+                self.aov_sums.alpha += 1.0;
+                integrator.radiance(self, ray, objects)
+            },
+            None => integrator.radiance(self, ray, objects)
+        }
+    }
+
+    /// The light along a ray if only lights were in the way
+    fn unoccluded_color(&mut self, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let mut closest = f32::MAX;
+        let mut color = self.background_radiance(ray);
+        for object in objects.iter().filter(|object| object.is_emitter()) {
+            if let Some(hit) = object.intersect(&mut self.rng, ray, &Interval::new(self.hit_epsilon, closest)) {
+                closest = hit.t;
+                color = hit.emitted;
+            }
+        }
+        return color;
+    }
+
+    /// The light coming back along a camera ray, path traced
+    pub fn trace_path(&mut self, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        self.ray_to_color(ray, objects, self.max_depth, None)
+    }
+
+    /// The light coming back along a ray
+    /// bsdf_pdf is the density the ray was bounced with, if the light it hits was also sampled directly from where it started
+    fn ray_to_color(&mut self, ray: &Ray, objects: &[Box<dyn Object>], depth: u32, bsdf_pdf: Option<f32>) -> Color {
         if depth == 0 {
             return Color::new(0.0, 0.0, 0.0);
         }
-        if let Some(hit) = self.get_intersection(ray, objects, &Interval::new(0.001, f32::MAX)) {
-            let bounced_ray = hit.outgoing;
+        let hit = self.intersect_objects(ray, objects, &Interval::new(self.hit_epsilon, f32::MAX));
+        // Light is partly absorbed on its way through colored media
+        let mut throughput = hit.as_ref().map_or(Color::ONE, |(hit, _)| ray.transmittance(hit.t));
+        // In scattering media like skin or wax, the ray may bounce off a particle before reaching the next surface
+        if let Some(medium) = ray.media.last().filter(|medium| medium.scatters()) {
+            let length = ray.direction.length();
+            let surface_distance = hit.as_ref().map_or(f32::INFINITY, |(hit, _)| hit.t * length);
+            let (scatter_distance, weight) = medium.sample_distance(&mut self.rng, surface_distance);
+            if let Some(distance) = scatter_distance {
+                // Particles scatter light evenly in all directions, and random_unit_vector picks from all of them alike
+                let scattered_ray = ray.continued(ray.pos(distance / length), random_unit_vector(&mut self.rng));
+                let path_weight = self.light_groups.weight;
+                self.light_groups.weight = path_weight * weight;
+                let color = weight * self.ray_to_color(&scattered_ray, objects, depth - 1, None);
+                self.light_groups.weight = path_weight;
+                return color;
+            }
+            throughput = weight;
+        }
+        // Fog in the air may scatter the ray before it reaches the next surface, or leaves the scene
+        if ray.media.is_empty() && self.fog.is_some() {
+            let u = self.rng.gen::<f32>();
+            let length = ray.direction.length();
+            let surface_distance = hit.as_ref().map_or(f32::INFINITY, |(hit, _)| hit.t * length);
+            let scatter_distance = self.fog.as_ref().and_then(|fog| fog.sample_distance(ray.origin, ray.direction / length, u, surface_distance));
+            if let Some(distance) = scatter_distance {
+                return self.scatter_in_fog(ray, distance / length, objects, depth);
+            }
+        }
+        if let Some((hit, index)) = hit {
+            // Light found by bouncing into it was also found by sampling it, so each only gets its share
+            let emitted = match bsdf_pdf {
+                Some(pdf) if hit.is_emitter => hit.emitted * power_heuristic(pdf, self.light_pdf(objects, index, ray.origin, &hit, ray.time)),
+                _ => hit.emitted
+            };
+            // Light found here reaches the camera dimmed by everything on the way there
+            let path_weight = self.light_groups.weight;
+            self.light_groups.weight = path_weight * throughput;
+            self.record_object_light(index, emitted);
+            let object = objects[index].as_ref();
+            let evaluate = |scattered: &Ray| object.evaluate(ray, scattered, hit.position, hit.outward_normal(), hit.tangent, hit.u, hit.v);
+            let direct = self.sample_light(ray, &hit, &evaluate, objects) + self.punctual_light(ray, &hit, &evaluate, objects);
+            let bounced_ray = hit.spawn_ray(&hit.outgoing, self.normal_offset);
             let albedo = hit.albedo;
-            let bounced = self.ray_to_color(&bounced_ray, objects, depth - 1);
+            self.light_groups.weight = path_weight * throughput * albedo;
+            // Mirror-like bounces can't be found by sampling lights, so whatever they hit counts in full
+            let next_pdf = match hit.is_specular {
+                true => None,
+                false => Some(hit.pdf)
+            };
+            let bounced = self.ray_to_color(&bounced_ray, objects, depth - 1, next_pdf);
+            self.light_groups.weight = path_weight;
             let final_color = Color::new(bounced.x * albedo.x, bounced.y * albedo.y, bounced.z * albedo.z);
-            return final_color + hit.emitted;
+            // Clamping only looks at what the camera's own hit receives, so lights seen straight on keep their brightness
+            let (direct, final_color) = match depth == self.max_depth {
+                true => (self.clamp_radiance(direct, self.clamp_direct), self.clamp_radiance(final_color, self.clamp_indirect)),
+                false => (direct, final_color)
+            };
+            if depth == self.max_depth {
+                self.record_split(throughput * emitted, throughput * direct, throughput * final_color);
+            }
+            return throughput * (final_color + direct + emitted);
+        }
+        // Escaping after a bounce could also have been found by sampling the environment or the sun
+        let background = throughput * match bsdf_pdf {
+            Some(pdf) => self.background_radiance(ray) * power_heuristic(pdf, self.distant_pdf(ray.origin, ray.direction)),
+            None => self.background_radiance(ray)
+        };
+        if depth == self.max_depth {
+            self.record_split(background, Color::ZERO, Color::ZERO);
+        }
+        self.record_background_light(background);
+        return background;
+    }
+
+    /// The light coming back from a particle of fog hit t along a ray, which scatters it by the fog's phase function
+    fn scatter_in_fog(&mut self, ray: &Ray, t: f32, objects: &[Box<dyn Object>], depth: u32) -> Color {
+        let (albedo, g) = self.fog.as_ref().map_or((Color::ZERO, 0.0), |fog| (fog.albedo, fog.anisotropy));
+        let position = ray.pos(t);
+        let direction = ray.direction.normalize();
+        // There's no surface, so the normal just faces back along the ray
+        let normal = -direction;
+        let scattered = sample_henyey_greenstein(&mut self.rng, direction, g);
+        let scatter = ScatterRecord::new(ray.continued(position, scattered), albedo, henyey_greenstein(direction.dot(scattered), g));
+        let hit = Hit::new(ray, t, position, normal, scatter, false, Color::ZERO);
+        let evaluate = |scattered: &Ray| {
+            let phase = henyey_greenstein(direction.dot(scattered.direction.normalize()), g);
+            (albedo * phase, phase)
+        };
+        let direct = self.sample_light(ray, &hit, &evaluate, objects) + self.punctual_light(ray, &hit, &evaluate, objects);
+        let bounced_ray = hit.spawn_ray(&hit.outgoing, self.normal_offset);
+        let path_weight = self.light_groups.weight;
+        self.light_groups.weight = path_weight * albedo;
+        let bounced = self.ray_to_color(&bounced_ray, objects, depth - 1, Some(hit.pdf));
+        self.light_groups.weight = path_weight;
+        let (direct, bounced) = match depth == self.max_depth {
+            true => (self.clamp_radiance(direct, self.clamp_direct), self.clamp_radiance(albedo * bounced, self.clamp_indirect)),
+            false => (direct, albedo * bounced)
+        };
+        if depth == self.max_depth {
+            self.record_split(Color::ZERO, direct, bounced);
         }
-        return background_gradient(ray);
+        return bounced + direct;
+    }
+
+    // Scales a color down so no channel is brighter than limit once exposed, keeping its hue
+    fn clamp_radiance(&self, color: Color, limit: Option<f32>) -> Color {
+        let Some(limit) = limit else {
+            return color;
+        };
+        let brightest = color.max_element() * self.exposure_scale();
+        match brightest > limit {
+            true => color * (limit / brightest),
+            false => color
+        }
+    }
+
+    /// Share of the light that gets through the fog along a ray as far as t, or all of it inside objects
+    pub fn fog_transmittance(&self, ray: &Ray, t: f32) -> f32 {
+        match (&self.fog, ray.media.is_empty()) {
+            (Some(fog), true) => {
+                let length = ray.direction.length();
+                fog.transmittance(ray.origin, ray.direction / length, t * length)
+            },
+            _ => 1.0
+        }
+    }
+
+    /// The light arriving along a ray that leaves the scene
+    pub fn background_radiance(&self, ray: &Ray) -> Color {
+        return self.background.radiance(ray.direction) + self.sun.as_ref().map_or(Color::ZERO, |sun| sun.radiance(ray.direction));
+    }
+
+    /// Light reaching a hit straight from the environment, the sun or a light picked by how much it's likely to contribute,
+    /// weighted against finding the light by bouncing. evaluate gives the BSDF and its density for light arriving along a ray
+    pub fn sample_light(&mut self, ray: &Ray, hit: &Hit, evaluate: &dyn Fn(&Ray) -> (Color, f32), objects: &[Box<dyn Object>]) -> Color {
+        let (environment_probability, sun_probability) = self.distant_probabilities();
+        let strategy = self.rng.gen::<f32>();
+        // The object index of the light, or None for the environment and the sun
+        let (direction, light_pdf, light) = match strategy < environment_probability + sun_probability {
+            true => {
+                let (u1, u2, u3) = (self.rng.gen(), self.rng.gen(), self.rng.gen());
+                let sampled = match (strategy < environment_probability, self.light_portals.is_empty()) {
+                    (true, true) => self.background.environment().map(|environment| environment.sample_direction(u1, u2).0),
+                    (true, false) => sample_portals(&self.light_portals, hit.position, u1, u2, u3),
+                    (false, _) => self.sun.as_ref().map(|sun| sun.sample_direction(u1, u2).0)
+                };
+                let Some(direction) = sampled else {
+                    return Color::ZERO;
+                };
+                // Both light up the background, so what it's divided by is how likely either is to pick the direction
+                (direction, self.distant_pdf(hit.position, direction), None)
+            },
+            false => {
+                let Some((light, probability)) = self.lights.pick(hit.position, self.rng.gen()) else {
+                    return Color::ZERO;
+                };
+                let Some((point, pdf)) = objects[light].light().and_then(|light| light.sample_point(&mut self.rng, hit.position, ray.time)) else {
+                    return Color::ZERO;
+                };
+                (point - hit.position, pdf * probability * self.light_probability(), Some(light))
+            }
+        };
+        let shadow_ray = hit.spawn_ray(&ray.continued(hit.position, direction), self.normal_offset);
+        let (bsdf, bsdf_pdf) = evaluate(&shadow_ray);
+        if bsdf == Color::ZERO || !(light_pdf > 0.0 && light_pdf.is_finite()) {
+            return Color::ZERO;
+        }
+        // The light is only seen if it's the first thing the shadow ray hits, and the environment if nothing is
+        let radiance = match (self.intersect_objects(&shadow_ray, objects, &Interval::new(self.hit_epsilon, f32::MAX)), light) {
+            (Some((light_hit, index)), Some(light)) if index == light => {
+                light_hit.emitted * shadow_ray.shadow_transmittance(light_hit.t) * self.fog_transmittance(&shadow_ray, light_hit.t)
+            },
+            (None, None) => self.background_radiance(&shadow_ray) * self.fog_transmittance(&shadow_ray, f32::INFINITY),
+            _ => return Color::ZERO
+        };
+        let contribution = bsdf * radiance * power_heuristic(light_pdf, bsdf_pdf) / light_pdf;
+        match light {
+            Some(index) => self.record_object_light(index, contribution),
+            None => self.record_background_light(contribution)
+        }
+        return contribution;
+    }
+
+    /// Light reaching a hit from every punctual light that isn't blocked
+    pub fn punctual_light(&mut self, ray: &Ray, hit: &Hit, evaluate: &dyn Fn(&Ray) -> (Color, f32), objects: &[Box<dyn Object>]) -> Color {
+        let mut total = Color::ZERO;
+        for light_index in 0..self.punctual_lights.len() {
+            let light = &self.punctual_lights[light_index];
+            let (to_light, radiance) = light.illuminate(hit.position);
+            let is_distant = light.is_distant();
+            if radiance == Color::ZERO {
+                continue;
+            }
+            let shadow_ray = hit.spawn_ray(&ray.continued(hit.position, to_light), self.normal_offset);
+            let (bsdf, _) = evaluate(&shadow_ray);
+            if bsdf == Color::ZERO {
+                continue;
+            }
+            // The light is at t = 1 along the shadow ray, unless it's infinitely far away
+            let end = match is_distant {
+                true => f32::MAX,
+                false => 1.0 - self.hit_epsilon
+            };
+            if self.intersect_objects(&shadow_ray, objects, &Interval::new(self.hit_epsilon, end)).is_some() {
+                continue;
+            }
+            let transmittance = match is_distant {
+                true => Color::ONE * self.fog_transmittance(&shadow_ray, f32::INFINITY),
+                false => shadow_ray.shadow_transmittance(1.0) * self.fog_transmittance(&shadow_ray, 1.0)
+            };
+            let contribution = bsdf * radiance * transmittance;
+            self.record_light(self.light_groups.punctual_lights.get(light_index).copied().flatten(), contribution);
+            total += contribution;
+        }
+        return total;
+    }
+
+    // Adds the light seen along a camera ray to the emission, direct and indirect passes, split up by where it came from
+    // Only the path tracer splits up its light, the passes stay dark for the other integrators
+    fn record_split(&mut self, emission: Color, direct: Color, indirect: Color) {
+        self.aov_sums.emission += emission * self.sample_weight;
+        self.aov_sums.direct += direct * self.sample_weight;
+        self.aov_sums.indirect += indirect * self.sample_weight;
+    }
+
+    /// Adds light found along the current path to its light group, if it's in one
+    pub fn record_light(&mut self, group: Option<usize>, light: Color) {
+        if let Some(group) = group {
+            self.light_groups.sums[group] += self.light_groups.weight * light;
+        }
+    }
+
+    /// Sets what light found from here on is multiplied by on its way to the camera, for the light groups,
+    /// and returns what it was before
+    pub fn swap_path_weight(&mut self, weight: Color) -> Color {
+        std::mem::replace(&mut self.light_groups.weight, weight)
+    }
+
+    /// Adds light given off by the object with an index to its light group, if it's in one
+    pub fn record_object_light(&mut self, index: usize, light: Color) {
+        self.record_light(self.object_light_group(index), light);
+    }
+
+    /// The light group of the object with an index, if it's in one
+    pub fn object_light_group(&self, index: usize) -> Option<usize> {
+        self.light_groups.objects.get(index).copied().flatten()
+    }
+
+    /// How many light groups the render has
+    pub fn light_group_count(&self) -> usize {
+        self.light_groups.names.len()
+    }
+
+    /// Adds light from the background and the sun to their light group, if they're in one
+    pub fn record_background_light(&mut self, light: Color) {
+        self.record_light(self.light_groups.background, light);
+    }
+
+    // How often the environment and the sun are sampled instead of a light
+    // Each of the environment, the sun and the scene's lights that are there gets an even share
+    fn distant_probabilities(&self) -> (f32, f32) {
+        let strategies = [self.background.environment().is_some(), self.sun.is_some(), !self.lights.is_empty()];
+        let count = strategies.iter().filter(|&&present| present).count().max(1) as f32;
+        let share = |present: bool| match present {
+            true => 1.0 / count,
+            false => 0.0
+        };
+        return (share(strategies[0]), share(strategies[1]));
+    }
+
+    /// The density of sample_light picking a direction towards the environment or the sun
+    pub fn distant_pdf(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let (environment_probability, sun_probability) = self.distant_probabilities();
+        let environment = match (self.background.environment(), self.light_portals.is_empty()) {
+            (Some(environment), true) => environment.pdf(direction),
+            (Some(_), false) => portals_pdf(&self.light_portals, origin, direction),
+            (None, _) => 0.0
+        };
+        let sun = self.sun.as_ref().map_or(0.0, |sun| sun.pdf(direction));
+        return environment * environment_probability + sun * sun_probability;
+    }
+
+    // How often sample_light picks one of the scene's lights
+    fn light_probability(&self) -> f32 {
+        let (environment_probability, sun_probability) = self.distant_probabilities();
+        return 1.0 - environment_probability - sun_probability;
+    }
+
+    /// The density of sample_light picking a point on an object, as seen from origin
+    pub fn light_pdf(&self, objects: &[Box<dyn Object>], index: usize, origin: Vec3, hit: &Hit, time: f32) -> f32 {
+        match objects[index].light() {
+            Some(light) => light.pdf(origin, hit.position, hit.normal, time) * self.lights.probability(origin, index) * self.light_probability(),
+            None => 0.0
+        }
+    }
+
+    /// Picks one of the scene's lights for a point by how much it's likely to light it up,
+    /// returning its object index and the probability of picking it
+    pub fn pick_light(&self, point: Vec3, u: f32) -> Option<(usize, f32)> {
+        self.lights.pick(point, u)
+    }
+
+    /// Picks one of the scene's lights in proportion to its power, for paths that start on a light,
+    /// returning its object index and the probability of picking it
+    pub fn pick_light_by_power(&self, u: f32) -> Option<(usize, f32)> {
+        self.lights.pick_by_power(u)
+    }
+
+    /// The probability of pick_light_by_power picking the object with an index
+    pub fn light_power_probability(&self, index: usize) -> f32 {
+        self.lights.power_probability(index)
     }
 }
 
-/// Accepts a color in vector form and returns it as (red, green, blue) bytes
-fn color_to_bytes(color: Color) -> (u8, u8, u8) {
-    let color = color.clamp(Vec3::ZERO, Vec3::ONE);
-    let red = lerp(0.0, 255.0, color.x) as u8;
-    let green = lerp(0.0, 255.0, color.y) as u8;
-    let blue = lerp(0.0, 255.0, color.z) as u8;
-    return (red, green, blue);
+/// The light groups of a render, and what each of them has lit up so far in the current pixel
+#[derive(Default)]
+struct LightGroups {
+    names: Vec<String>,
+    // Group index of every object, punctual light and the background, None for those outside any group
+    objects: Vec<Option<usize>>,
+    punctual_lights: Vec<Option<usize>>,
+    background: Option<usize>,
+    // Light gathered by every group
+    sums: Vec<Color>,
+    // What light found at the current point of the path is multiplied by on its way to the camera
+    weight: Color
 }
 
-fn background_gradient(ray: &Ray) -> Color {
-    // let direction = ray.direction.normalize();
-    // let t = direction.y;
-    // let blue = lerp(155.0, 235.0, t) as u8;
-    // let green = lerp(155.0, 206.0, t) as u8;
-    // let red = lerp(155.0, 135.0, t) as u8;
-    // return (blue, green, red);
-    return Color::new(0.0, 0.0, 0.0);
+impl LightGroups {
+    /// Numbers the groups in the order they're first seen in
+    fn new(objects: &[Box<dyn Object>], punctual_lights: &[PunctualLight], background: &Option<String>) -> LightGroups {
+        let mut names: Vec<String> = vec![];
+        let mut index_of = |name: Option<&str>| name.map(|name| match names.iter().position(|other| other == name) {
+            Some(index) => index,
+            None => {
+                names.push(name.to_owned());
+                names.len() - 1
+            }
+        });
+        let objects = objects.iter().map(|object| index_of(object.light_group())).collect();
+        let punctual_lights = punctual_lights.iter().map(|light| index_of(light.group.as_deref())).collect();
+        let background = index_of(background.as_deref());
+        let sums = vec![Color::ZERO; names.len()];
+        LightGroups { names, objects, punctual_lights, background, sums, weight: Color::ONE }
+    }
+}
+
+/// Shadow catcher samples of a pixel, kept apart so the shadow can be worked out from all of them at once
+/// The light is summed times the filter weight of every sample
+#[derive(Default)]
+struct ShadowCatcherSums {
+    // Light reaching the catcher
+    lit: Color,
+    // Light that would reach the catcher if nothing but lights were in the scene
+    unoccluded: Color,
+    // The background behind the catcher
+    background: Color,
+    // How many samples hit the catcher
+    samples: u32
+}
+
+impl ShadowCatcherSums {
+    /// The weighted sum of the color of all catcher samples: the background darkened by how much light the scene blocks
+    fn resolve(&self) -> Color {
+        return self.background * self.ratio();
+    }
+
+    /// The summed alpha of all catcher samples, for images where the shadow is laid over the background later:
+    /// black, as opaque as the shadow is dark
+    fn shadow_alpha(&self) -> f32 {
+        let shadow = 1.0 - self.ratio().dot(LUMINANCE_WEIGHTS);
+        return self.samples as f32 * shadow.clamp(0.0, 1.0);
+    }
+
+    // How much of the light the scene lets through to the catcher, in every channel
+    fn ratio(&self) -> Color {
+        Color::select(self.unoccluded.cmpgt(Color::ZERO), self.lit / self.unoccluded, Color::ONE)
+    }
+}
+
+/// Which samples of a pixel to keep, dropping any brighter than factor times the average of all the others
+/// A firefly is one sample far brighter than the rest of its pixel, which would take thousands more to average out
+fn reject_outliers(samples: &[(Color, Vec<Color>)], factor: f32) -> Vec<bool> {
+    let luminances: Vec<f32> = samples.iter().map(|(color, _)| color.dot(LUMINANCE_WEIGHTS)).collect();
+    let total: f32 = luminances.iter().sum();
+    let others = (samples.len().max(2) - 1) as f32;
+    luminances.iter()
+        .map(|&luminance| samples.len() < 2 || luminance <= factor * (total - luminance) / others)
+        .collect()
 }
 
-fn gamma_correct(color: Color) -> Color {
-    Color::new(color.x.sqrt(), color.y.sqrt(), color.z.sqrt())
+/// Veach's power heuristic with an exponent of 2, the weight of a sample taken with density pdf
+/// when other_pdf is how likely the other strategy was to take it
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (squared, other_squared) = (pdf * pdf, other_pdf * other_pdf);
+    match squared + other_squared > 0.0 && squared.is_finite() {
+        true => squared / (squared + other_squared),
+        false => 1.0
+    }
+}
+
+// Encodes linear colors, stored bottom to top, into a dithered image ready to be written, with an alpha channel if there are alphas
+fn encode_image(width: usize, height: usize, colors: &[Color], alpha: Option<&[f32]>, dither: &Dither) -> Framebuffer {
+    let mut image = match alpha {
+        Some(_) => Framebuffer::with_alpha(width, height),
+        None => Framebuffer::new(width, height)
+    };
+    for (y, row_colors) in colors.chunks_exact(width.max(1)).enumerate().take(height) {
+        let row = image.row_mut(y);
+        for (x, &color) in row_colors.iter().enumerate() {
+            let pixel_alpha = alpha.map(|alpha| alpha[y * width + x]);
+            place_color(row, x, color, pixel_alpha, dither.threshold(x as u32, y as u32));
+        }
+    }
+    return image;
+}
+
+// Encodes a color into a row at pixel x, in LE order, adding threshold of a byte before rounding down
+// With an alpha the row has four bytes per pixel, and the color is that of whatever covers the pixel
+fn place_color(row: &mut [u8], x: usize, color: Color, alpha: Option<f32>, threshold: f32) {
+    match alpha {
+        Some(alpha) => {
+            let bytes = color_to_bytes(SrgbColor::encode(unpremultiplied(color, alpha)), threshold);
+            let alpha_byte = (lerp(0.0, 255.0, alpha.clamp(0.0, 1.0)) + threshold) as u8;
+            row[x * 4..x * 4 + 4].copy_from_slice(&[bytes.2, bytes.1, bytes.0, alpha_byte]);
+        },
+        None => {
+            let bytes = color_to_bytes(SrgbColor::encode(color), threshold);
+            row[x * 3..x * 3 + 3].copy_from_slice(&[bytes.2, bytes.1, bytes.0]);
+        }
+    }
+}
+
+/// Accepts an encoded color and returns it as (red, green, blue) bytes
+fn color_to_bytes(color: SrgbColor, threshold: f32) -> (u8, u8, u8) {
+    let color = color.values().clamp(Vec3::ZERO, Vec3::ONE);
+    let red = (lerp(0.0, 255.0, color.x) + threshold) as u8;
+    let green = (lerp(0.0, 255.0, color.y) + threshold) as u8;
+    let blue = (lerp(0.0, 255.0, color.z) + threshold) as u8;
+    return (red, green, blue);
 }
 
 // Linearly interpolates t ∈ [0, 1] to the range [v0, v1]
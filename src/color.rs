@@ -0,0 +1,94 @@
+// Light as it is and as it's stored in images, kept apart so nothing gets encoded twice or written out without being encoded
+// Everything the renderer works out is linear light in plain Vec3s, and only the last step before bytes encodes it
+
+use glam::Vec3;
+
+type Color = Vec3;
+
+/// A color encoded with the sRGB transfer function, spaced evenly to the eye rather than by how much light there is,
+/// which is what 8 and 16 bit images store. Only made by encoding light or from values that are already encoded,
+/// so it can't be mistaken for light and light can't be written out without it
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct SrgbColor(Vec3);
+
+impl SrgbColor {
+    /// Encodes linear light
+    pub fn encode(linear: Color) -> SrgbColor {
+        SrgbColor(Vec3::new(linear_to_srgb(linear.x), linear_to_srgb(linear.y), linear_to_srgb(linear.z)))
+    }
+
+    /// Values that are already encoded, like bytes of an image divided by 255 or a color picked in a paint program
+    pub fn from_encoded(values: Vec3) -> SrgbColor {
+        SrgbColor(values)
+    }
+
+    /// The light the color stands for
+    pub fn decode(self) -> Color {
+        Color::new(srgb_to_linear(self.0.x), srgb_to_linear(self.0.y), srgb_to_linear(self.0.z))
+    }
+
+    /// The encoded values, from 0 to 1 for colors an image can hold
+    pub fn values(self) -> Vec3 {
+        self.0
+    }
+}
+
+/// The sRGB transfer curve images are stored with, a short straight line near black and a curve close to a gamma of 2.2 after it
+pub fn linear_to_srgb(value: f32) -> f32 {
+    match value <= 0.003_130_8 {
+        true => value * 12.92,
+        false => 1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Undo the sRGB transfer curve images are stored with
+pub fn srgb_to_linear(value: f32) -> f32 {
+    match value <= 0.04045 {
+        true => value / 12.92,
+        false => ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// How fast the encoded value grows with the light at a value, which is how much a small error in the light is magnified once encoded
+pub fn srgb_slope(value: f32) -> f32 {
+    match value <= 0.003_130_8 {
+        true => 12.92,
+        false => 1.055 / 2.4 * value.powf(1.0 / 2.4 - 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_known_values() {
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-6);
+        // Middle gray, 18% of the light, is stored a little below the middle
+        assert!((linear_to_srgb(0.18) - 0.4614).abs() < 1e-4);
+        // The byte 128 stands for about a fifth of the light
+        assert!((srgb_to_linear(128.0 / 255.0) - 0.2158).abs() < 1e-4);
+        // On the straight part near black
+        assert!((linear_to_srgb(0.001) - 0.01292).abs() < 1e-7);
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for byte in 0..=255 {
+            let encoded = byte as f32 / 255.0;
+            assert!((linear_to_srgb(srgb_to_linear(encoded)) - encoded).abs() < 1e-5, "byte {} doesn't round trip", byte);
+        }
+        let color = Vec3::new(0.02, 0.5, 3.0);
+        assert!((SrgbColor::encode(color).decode() - color).length() < 1e-4);
+    }
+
+    // The two pieces of the curve meet without a jump, where the slope changes
+    #[test]
+    fn srgb_is_continuous_at_the_knee() {
+        let knee = 0.003_130_8_f32;
+        assert!((linear_to_srgb(knee) - linear_to_srgb(knee + 1e-7)).abs() < 1e-5);
+        assert!((srgb_to_linear(0.04045) - srgb_to_linear(0.04045 + 1e-6)).abs() < 1e-6);
+        assert_eq!(srgb_slope(knee / 2.0), 12.92);
+    }
+}
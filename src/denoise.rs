@@ -0,0 +1,223 @@
+// Taking the noise out of a finished render, guided by what the camera sees first in every pixel
+
+use std::io::{Error, ErrorKind};
+use glam::Vec3;
+use crate::color::SrgbColor;
+
+type Color = Vec3;
+
+/// The linear, exposed color of every pixel of a render along with the albedo and the normal of the first thing seen in it,
+/// bottom to top. The albedo and the normals have hardly any noise, so they tell the denoiser where the edges are
+pub struct DenoiseBuffers {
+    pub width: usize,
+    pub height: usize,
+    pub color: Vec<Color>,
+    pub albedo: Vec<Color>,
+    pub normal: Vec<Vec3>
+}
+
+impl DenoiseBuffers {
+    pub fn new(width: usize, height: usize) -> DenoiseBuffers {
+        DenoiseBuffers {
+            width,
+            height,
+            color: vec![Color::ZERO; width * height],
+            albedo: vec![Color::ZERO; width * height],
+            normal: vec![Vec3::ZERO; width * height]
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Color, albedo: Color, normal: Vec3) {
+        let index = y * self.width + x;
+        self.color[index] = color;
+        self.albedo[index] = albedo;
+        self.normal[index] = normal;
+    }
+}
+
+/// What takes the noise out of a render
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Denoiser {
+    /// Intel's Open Image Denoise, a neural network trained on renders, which has to be installed and built in with the oidn feature
+    Oidn,
+    /// An edge-avoiding à-trous wavelet filter (Dammertz et al), blurring further and further in a few passes
+    /// while keeping pixels with different albedos, normals or colors apart. Built in, but softer than Open Image Denoise
+    ATrous
+}
+
+impl Denoiser {
+    /// Open Image Denoise if it's built in, the à-trous filter otherwise
+    pub fn best_available() -> Denoiser {
+        match cfg!(feature = "oidn") {
+            true => Denoiser::Oidn,
+            false => Denoiser::ATrous
+        }
+    }
+
+    /// A denoiser by name: oidn or atrous
+    pub fn parse(name: &str) -> Result<Denoiser, String> {
+        match name {
+            "oidn" => Ok(Denoiser::Oidn),
+            "atrous" => Ok(Denoiser::ATrous),
+            _ => Err(format!("Unknown denoiser \"{}\", expected oidn or atrous", name))
+        }
+    }
+
+    /// Whether the denoiser is built in, so renders can fail before they start instead of once they're done
+    pub fn check_available(&self) -> Result<(), Error> {
+        match (self, cfg!(feature = "oidn")) {
+            (Denoiser::Oidn, false) => Err(Error::new(ErrorKind::Unsupported, "denoising with oidn needs Open Image Denoise, build with --features oidn")),
+            _ => Ok(())
+        }
+    }
+
+    /// The colors with the noise taken out
+    pub fn denoise(&self, buffers: &DenoiseBuffers) -> Result<Vec<Color>, Error> {
+        self.check_available()?;
+        match self {
+            #[cfg(feature = "oidn")]
+            Denoiser::Oidn => oidn::run_filter(buffers),
+            #[cfg(not(feature = "oidn"))]
+            Denoiser::Oidn => Ok(vec![]),
+            Denoiser::ATrous => Ok(a_trous(buffers))
+        }
+    }
+}
+
+// Passes of the à-trous filter, the last one reaching 2 * 2^(passes - 1) pixels out
+const A_TROUS_PASSES: u32 = 5;
+// B3 spline weights of the 5 pixels along each side of the filter, spread further apart with every pass
+const A_TROUS_KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// How far apart the encoded light of two pixels can be before they hardly blur into each other,
+// halved with every pass so the wide passes only smooth out what's left of the noise
+const A_TROUS_COLOR_SIGMA: f32 = 1.0;
+// The same for the albedo and the normals, which keep edges and textures sharp
+const A_TROUS_ALBEDO_SIGMA: f32 = 0.1;
+const A_TROUS_NORMAL_SIGMA: f32 = 0.3;
+// Darkest albedo the light is divided by, so black surfaces don't blow up
+const A_TROUS_ALBEDO_FLOOR: f32 = 0.01;
+
+// Blurs the light falling on every pixel with the pixels around it that look like they're on the same surface
+// The colors are divided by the albedo first and multiplied by it again after, so textures aren't blurred along with the noise
+fn a_trous(buffers: &DenoiseBuffers) -> Vec<Color> {
+    let (width, height) = (buffers.width, buffers.height);
+    let floor = Color::splat(A_TROUS_ALBEDO_FLOOR);
+    let mut light: Vec<Color> = buffers.color.iter().zip(&buffers.albedo).map(|(&color, &albedo)| color / albedo.max(floor)).collect();
+    let mut color_sigma = A_TROUS_COLOR_SIGMA;
+    for pass in 0..A_TROUS_PASSES {
+        let step = 1_i64 << pass;
+        let encoded: Vec<Color> = light.iter().map(|&color| SrgbColor::encode(color.max(Color::ZERO)).values()).collect();
+        let mut filtered = vec![Color::ZERO; light.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let center = y * width + x;
+                let (mut sum, mut weight_sum) = (Color::ZERO, 0.0);
+                for (j, &weight_y) in A_TROUS_KERNEL.iter().enumerate() {
+                    let other_y = y as i64 + (j as i64 - 2) * step;
+                    if other_y < 0 || other_y >= height as i64 {
+                        continue;
+                    }
+                    for (i, &weight_x) in A_TROUS_KERNEL.iter().enumerate() {
+                        let other_x = x as i64 + (i as i64 - 2) * step;
+                        if other_x < 0 || other_x >= width as i64 {
+                            continue;
+                        }
+                        let other = other_y as usize * width + other_x as usize;
+                        let difference = (encoded[other] - encoded[center]).length_squared() / (color_sigma * color_sigma)
+                            + (buffers.albedo[other] - buffers.albedo[center]).length_squared() / (A_TROUS_ALBEDO_SIGMA * A_TROUS_ALBEDO_SIGMA)
+                            + (buffers.normal[other] - buffers.normal[center]).length_squared() / (A_TROUS_NORMAL_SIGMA * A_TROUS_NORMAL_SIGMA);
+                        let weight = weight_x * weight_y * (-difference).exp();
+                        sum += light[other] * weight;
+                        weight_sum += weight;
+                    }
+                }
+                // The pixel itself always counts, so there's something to divide by
+                filtered[center] = sum / weight_sum;
+            }
+        }
+        light = filtered;
+        color_sigma /= 2.0;
+    }
+    return light.iter().zip(&buffers.albedo).map(|(&light, &albedo)| light * albedo.max(floor)).collect();
+}
+
+// The few calls of the Open Image Denoise C API it takes to run its ray tracing filter on images in memory
+#[cfg(feature = "oidn")]
+mod oidn {
+    use std::ffi::{c_char, c_void, CStr};
+    use std::io::Error;
+    use super::{Color, DenoiseBuffers};
+
+    type Device = *mut c_void;
+    type Filter = *mut c_void;
+
+    const DEVICE_TYPE_DEFAULT: i32 = 0;
+    const FORMAT_FLOAT3: i32 = 3;
+    const ERROR_NONE: i32 = 0;
+
+    #[link(name = "OpenImageDenoise")]
+    extern "C" {
+        fn oidnNewDevice(device_type: i32) -> Device;
+        fn oidnCommitDevice(device: Device);
+        fn oidnGetDeviceError(device: Device, message: *mut *const c_char) -> i32;
+        fn oidnReleaseDevice(device: Device);
+        fn oidnNewFilter(device: Device, filter_type: *const c_char) -> Filter;
+        fn oidnSetSharedFilterImage(
+            filter: Filter,
+            name: *const c_char,
+            pointer: *mut c_void,
+            format: i32,
+            width: usize,
+            height: usize,
+            byte_offset: usize,
+            byte_pixel_stride: usize,
+            byte_row_stride: usize
+        );
+        fn oidnSetFilterBool(filter: Filter, name: *const c_char, value: bool);
+        fn oidnCommitFilter(filter: Filter);
+        fn oidnExecuteFilter(filter: Filter);
+        fn oidnReleaseFilter(filter: Filter);
+    }
+
+    pub fn run_filter(buffers: &DenoiseBuffers) -> Result<Vec<Color>, Error> {
+        let mut output = vec![Color::ZERO; buffers.color.len()];
+        // Vec3 is three packed f32s, the layout of FLOAT3 images. The inputs are only read from
+        let images: [(&CStr, *mut c_void); 4] = [
+            (c"color", buffers.color.as_ptr() as *mut c_void),
+            (c"albedo", buffers.albedo.as_ptr() as *mut c_void),
+            (c"normal", buffers.normal.as_ptr() as *mut c_void),
+            (c"output", output.as_mut_ptr() as *mut c_void)
+        ];
+        // SAFETY: every image is width * height packed FLOAT3 pixels and outlives the filter, which is released before returning
+        unsafe {
+            let device = oidnNewDevice(DEVICE_TYPE_DEFAULT);
+            if device.is_null() {
+                return Err(Error::other("Open Image Denoise couldn't create a device"));
+            }
+            oidnCommitDevice(device);
+            let filter = oidnNewFilter(device, c"RT".as_ptr());
+            for (name, pointer) in images {
+                oidnSetSharedFilterImage(filter, name.as_ptr(), pointer, FORMAT_FLOAT3, buffers.width, buffers.height, 0, 0, 0);
+            }
+            // The colors are light, not values between 0 and 1
+            oidnSetFilterBool(filter, c"hdr".as_ptr(), true);
+            oidnCommitFilter(filter);
+            oidnExecuteFilter(filter);
+            let mut message: *const c_char = std::ptr::null();
+            let error = oidnGetDeviceError(device, &mut message);
+            oidnReleaseFilter(filter);
+            let result = match error {
+                ERROR_NONE => Ok(output),
+                _ => {
+                    let message = match message.is_null() {
+                        true => "unknown error".to_owned(),
+                        false => CStr::from_ptr(message).to_string_lossy().into_owned()
+                    };
+                    Err(Error::other(format!("Open Image Denoise failed: {}", message)))
+                }
+            };
+            oidnReleaseDevice(device);
+            return result;
+        }
+    }
+}
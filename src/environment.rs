@@ -0,0 +1,336 @@
+// Light coming from infinitely far away in every direction, for rays that escape the scene
+
+use std::f32::consts::PI;
+use glam::Vec3;
+use crate::color::{srgb_to_linear, SrgbColor};
+
+type Color = Vec3;
+
+/// What rays that escape the scene see
+pub enum Background {
+    // The same color in every direction
+    Solid(Color),
+    // Blends from one color at the horizon to another straight up, staying the horizon color below it
+    Gradient { horizon: Color, zenith: Color },
+    // An image of the surroundings, which lights the scene and is sampled like a light
+    Image(EnvironmentMap)
+}
+
+impl Background {
+    /// The pale blue sky the renderer started out with, a light gray at the horizon turning sky blue straight up
+    pub fn sky() -> Background {
+        // The colors were picked as bytes of the final image
+        let linear = |red: f32, green: f32, blue: f32| SrgbColor::from_encoded(Color::new(red, green, blue) / 255.0).decode();
+        Background::Gradient { horizon: linear(155.0, 155.0, 155.0), zenith: linear(135.0, 206.0, 235.0) }
+    }
+
+    /// A background from text: black, sky, a solid color as r,g,b or a gradient as r,g,b:r,g,b from the horizon up
+    pub fn parse(text: &str) -> Result<Background, String> {
+        let color = |text: &str| -> Result<Color, String> {
+            let channels = text.split(',').map(|channel| channel.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>();
+            match channels.as_deref() {
+                Ok([red, green, blue]) => Ok(Color::new(*red, *green, *blue)),
+                _ => Err(format!("invalid color {}, expected r,g,b", text))
+            }
+        };
+        match text.to_lowercase().as_str() {
+            "black" => Ok(Background::Solid(Color::ZERO)),
+            "sky" => Ok(Background::sky()),
+            _ => match text.split_once(':') {
+                Some((horizon, zenith)) => Ok(Background::Gradient { horizon: color(horizon)?, zenith: color(zenith)? }),
+                None => Ok(Background::Solid(color(text)?))
+            }
+        }
+    }
+
+    /// The light arriving from a direction, which doesn't need to be normalized
+    pub fn radiance(&self, direction: Vec3) -> Color {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { horizon, zenith } => horizon.lerp(*zenith, direction.normalize().y.max(0.0)),
+            Background::Image(environment) => environment.radiance(direction)
+        }
+    }
+
+    /// The image, if the background is one that can be sampled
+    pub fn environment(&self) -> Option<&EnvironmentMap> {
+        match self {
+            Background::Image(environment) => Some(environment),
+            _ => None
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Solid(Color::ZERO)
+    }
+}
+
+/// An equirectangular image of the surroundings, usually a high dynamic range photo, that lights the scene
+/// The middle of the image is straight down -z and the top edge is straight up, like the Equirectangular projection
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    // Linear radiance, row by row from the top
+    pixels: Vec<Color>,
+    // Multiplies the radiance, for images that aren't in the units the scene is lit in
+    pub intensity: f32,
+    // How far the image is turned around the y axis, in radians
+    rotation: f32,
+    // For picking pixels in proportion to how much light comes from them
+    distribution: Distribution2D
+}
+
+impl EnvironmentMap {
+    /// Loads an image, keeping the full range of Radiance .hdr files. Other formats are taken to be sRGB
+    pub fn load(path: &str) -> Result<EnvironmentMap, image::ImageError> {
+        let image = image::open(path)?;
+        let is_hdr = matches!(image, image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_));
+        let image = image.into_rgb32f();
+        let decode = |value: f32| match is_hdr {
+            true => value,
+            false => srgb_to_linear(value)
+        };
+        let pixels = image.pixels().map(|pixel| Color::new(decode(pixel[0]), decode(pixel[1]), decode(pixel[2]))).collect();
+        Ok(EnvironmentMap::new(image.width() as usize, image.height() as usize, pixels))
+    }
+
+    /// An environment from linear pixels, row by row from the top
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> EnvironmentMap {
+        assert_eq!(pixels.len(), width * height, "An environment map needs exactly width * height pixels");
+        // Rows near the poles are squeezed into less solid angle, so they count for less
+        let weights: Vec<f32> = (0..width * height)
+            .map(|index| {
+                let theta = ((index / width) as f32 + 0.5) / height as f32 * PI;
+                pixels[index].dot(Color::ONE) / 3.0 * theta.sin()
+            })
+            .collect();
+        let distribution = Distribution2D::new(&weights, width, height);
+        EnvironmentMap { width, height, pixels, intensity: 1.0, rotation: 0.0, distribution }
+    }
+
+    /// An environment filled in by the light arriving from every direction, like a procedural sky
+    pub fn from_radiance(width: usize, height: usize, radiance: impl Fn(Vec3) -> Color) -> EnvironmentMap {
+        let pixels = (0..width * height)
+            .map(|index| {
+                let (u, v) = (((index % width) as f32 + 0.5) / width as f32, ((index / width) as f32 + 0.5) / height as f32);
+                radiance(direction_at(u, v, 0.0))
+            })
+            .collect();
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> EnvironmentMap {
+        self.intensity = intensity;
+        self
+    }
+
+    /// Turns the image around the y axis by degrees, to move the sun or a window to where it's wanted
+    pub fn with_rotation(mut self, degrees: f32) -> EnvironmentMap {
+        self.rotation = degrees.to_radians();
+        self
+    }
+
+    /// The light arriving from a direction, which doesn't need to be normalized
+    pub fn radiance(&self, direction: Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(direction);
+        return self.bilinear(u, v) * self.intensity;
+    }
+
+    /// Picks a direction with two uniform random numbers, brighter parts of the image more often
+    /// Returns the direction and its density per solid angle
+    pub fn sample_direction(&self, u1: f32, u2: f32) -> (Vec3, f32) {
+        let (u, v, pdf) = self.distribution.sample(u1, u2);
+        let direction = self.uv_to_direction(u, v);
+        return (direction, self.solid_angle_pdf(pdf, v));
+    }
+
+    /// The density per solid angle of sample_direction picking a direction
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        let (u, v) = self.direction_to_uv(direction);
+        return self.solid_angle_pdf(self.distribution.pdf(u, v), v);
+    }
+
+    // A pixel spans less solid angle closer to the poles, making the density per solid angle higher there
+    fn solid_angle_pdf(&self, image_pdf: f32, v: f32) -> f32 {
+        let sin_theta = (v * PI).sin();
+        match sin_theta > 0.0 {
+            true => image_pdf / (2.0 * PI * PI * sin_theta),
+            false => 0.0
+        }
+    }
+
+    // The direction a point on the image shows, the opposite of direction_to_uv
+    fn uv_to_direction(&self, u: f32, v: f32) -> Vec3 {
+        direction_at(u, v, self.rotation)
+    }
+
+    // Where a direction lands on the image, u from the left edge and v from the top
+    fn direction_to_uv(&self, direction: Vec3) -> (f32, f32) {
+        let direction = direction.normalize();
+        let longitude = direction.x.atan2(-direction.z) - self.rotation;
+        let latitude = direction.y.clamp(-1.0, 1.0).asin();
+        let u = (longitude / (2.0 * PI) + 0.5).rem_euclid(1.0);
+        let v = 0.5 - latitude / PI;
+        return (u, v);
+    }
+
+    // Blends the four nearest pixels, wrapping around horizontally and stopping at the poles
+    fn bilinear(&self, u: f32, v: f32) -> Color {
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |px: i64, py: i64| {
+            let px = px.rem_euclid(self.width as i64) as usize;
+            let py = py.clamp(0, self.height as i64 - 1) as usize;
+            self.pixels[py * self.width + px]
+        };
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = pixel(x0, y0).lerp(pixel(x0 + 1, y0), fx);
+        let bottom = pixel(x0, y0 + 1).lerp(pixel(x0 + 1, y0 + 1), fx);
+        return top.lerp(bottom, fy);
+    }
+}
+
+// The direction a point on an equirectangular image turned by rotation radians around the y axis shows
+fn direction_at(u: f32, v: f32, rotation: f32) -> Vec3 {
+    let longitude = (u - 0.5) * 2.0 * PI + rotation;
+    let latitude = (0.5 - v) * PI;
+    return Vec3::new(latitude.cos() * longitude.sin(), latitude.sin(), -latitude.cos() * longitude.cos());
+}
+
+// A piecewise constant density over a grid, sampled by picking a row from how much each row holds in total,
+// then a column from how much each pixel of that row holds
+struct Distribution2D {
+    width: usize,
+    height: usize,
+    // Running totals of each row, and of the row totals, normalized to end at 1
+    conditional: Vec<Vec<f32>>,
+    marginal: Vec<f32>,
+    // Weight of every cell over the average weight, which is its density over the unit square
+    densities: Vec<f32>
+}
+
+impl Distribution2D {
+    // Weights row by row from the top. An image with no weight anywhere is sampled evenly
+    fn new(weights: &[f32], width: usize, height: usize) -> Distribution2D {
+        let total: f32 = weights.iter().sum();
+        let weights: Vec<f32> = match total > 0.0 {
+            true => weights.to_vec(),
+            false => vec![1.0; width * height]
+        };
+        let total: f32 = weights.iter().sum();
+        let conditional = weights.chunks(width).map(cumulative).collect();
+        let row_totals: Vec<f32> = weights.chunks(width).map(|row| row.iter().sum()).collect();
+        let marginal = cumulative(&row_totals);
+        let densities = weights.iter().map(|weight| weight * (width * height) as f32 / total).collect();
+        Distribution2D { width, height, conditional, marginal, densities }
+    }
+
+    // Picks a point in the unit square, returning it with its density
+    fn sample(&self, u1: f32, u2: f32) -> (f32, f32, f32) {
+        let (row, v) = sample_cumulative(&self.marginal, u1);
+        let (column, u) = sample_cumulative(&self.conditional[row], u2);
+        return (u, v, self.densities[row * self.width + column]);
+    }
+
+    fn pdf(&self, u: f32, v: f32) -> f32 {
+        let column = ((u * self.width as f32) as usize).min(self.width - 1);
+        let row = ((v * self.height as f32) as usize).min(self.height - 1);
+        return self.densities[row * self.width + column];
+    }
+}
+
+// Running totals of weights, divided by the total so the last one is 1
+fn cumulative(weights: &[f32]) -> Vec<f32> {
+    let total: f32 = weights.iter().sum();
+    let mut sum = 0.0;
+    weights.iter()
+        .map(|weight| {
+            sum += match total > 0.0 {
+                true => weight / total,
+                false => 1.0 / weights.len() as f32
+            };
+            sum
+        })
+        .collect()
+}
+
+// Picks a cell of a cumulative distribution with a uniform random number, and where in [0, 1) the number lands across all cells
+fn sample_cumulative(cumulative: &[f32], u: f32) -> (usize, f32) {
+    let index = cumulative.partition_point(|&sum| sum <= u).min(cumulative.len() - 1);
+    let start = if index == 0 { 0.0 } else { cumulative[index - 1] };
+    let width = cumulative[index] - start;
+    let offset = match width > 0.0 {
+        true => ((u - start) / width).clamp(0.0, 1.0 - f32::EPSILON),
+        false => 0.5
+    };
+    return (index, (index as f32 + offset) / cumulative.len() as f32);
+}
+
+/// A window or other opening the environment shines into an interior through, so directions towards the
+/// environment can be picked through the opening instead of mostly hitting walls. It's never hit by rays
+/// Every bit of environment light reaching the scene should pass through a portal, or it's only found by bouncing
+/// Pays off for openings that are small seen from inside, for a whole missing wall the environment's own sampling does better
+pub struct LightPortal {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3
+}
+
+impl LightPortal {
+    /// A rectangle from origin along u and v, like a Rect
+    pub fn new(origin: Vec3, u: Vec3, v: Vec3) -> LightPortal {
+        LightPortal { origin, u, v, normal: u.cross(v).normalize() }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.u.cross(self.v).length()
+    }
+
+    // How far along a unit direction from origin the portal is passed through, and the cosine it's passed at
+    fn crossing(&self, origin: Vec3, direction: Vec3) -> Option<(f32, f32)> {
+        let cosine = self.normal.dot(direction);
+        if cosine.abs() < 0.000001 {
+            return None;
+        }
+        let t = self.normal.dot(self.origin - origin) / cosine;
+        if t <= 0.0 {
+            return None;
+        }
+        // Where on the rectangle it's crossed, in units of u and v
+        let offset = origin + direction * t - self.origin;
+        let (a, b) = (offset.dot(self.u) / self.u.length_squared(), offset.dot(self.v) / self.v.length_squared());
+        match (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b) {
+            true => Some((t, cosine.abs())),
+            false => None
+        }
+    }
+}
+
+/// Picks a direction from origin through one of the portals with three uniform random numbers,
+/// each portal as often as its share of the total area and every point of it equally likely
+pub fn sample_portals(portals: &[LightPortal], origin: Vec3, u1: f32, u2: f32, u3: f32) -> Option<Vec3> {
+    let total: f32 = portals.iter().map(|portal| portal.area()).sum();
+    let mut remaining = u3 * total;
+    let portal = portals.iter().find(|portal| {
+        remaining -= portal.area();
+        remaining < 0.0
+    }).or(portals.last())?;
+    let direction = (portal.origin + portal.u * u1 + portal.v * u2 - origin).normalize();
+    return direction.is_finite().then_some(direction);
+}
+
+/// The density per solid angle of sample_portals picking a direction from origin, adding up every portal it passes through
+pub fn portals_pdf(portals: &[LightPortal], origin: Vec3, direction: Vec3) -> f32 {
+    let total: f32 = portals.iter().map(|portal| portal.area()).sum();
+    let direction = direction.normalize();
+    portals.iter()
+        .filter_map(|portal| portal.crossing(origin, direction))
+        // Turned from area to solid angle, which grows with distance and shrinks as the portal is seen edge on
+        .map(|(distance, cosine)| distance * distance / (total * cosine))
+        .sum()
+}
@@ -0,0 +1,71 @@
+// How much of the light reaching the camera it takes to turn a pixel white, set like a real camera or worked out from the scene
+
+// Reflected light meter calibration constant, which puts the average of a scene at middle gray
+const METER_CALIBRATION: f32 = 12.5;
+
+/// How bright the image is exposed, for scenes lit in physical units
+/// Everything comes down to an exposure value at ISO 100, which is applied to the light before it's encoded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Exposure {
+    /// An exposure value at ISO 100, like 15 for a sunny day or 7 for a bright room. One more halves the brightness
+    Ev(f32),
+    /// The settings of a camera: ISO sensitivity, shutter time in seconds and the f-number of the aperture
+    /// They only change the brightness, not the motion blur or the depth of field
+    Manual { iso: f32, shutter: f32, f_number: f32 },
+    /// Metered from the log-average luminance of the scene like a camera's auto exposure, so the average lands on middle gray,
+    /// then brightened by compensation stops
+    Auto { compensation: f32 }
+}
+
+impl Exposure {
+    /// Parses an exposure value, auto or auto:<compensation> for metering, or <iso>,<shutter>,<f-number> where the shutter
+    /// time can be a fraction like 1/125
+    pub fn parse(spec: &str) -> Result<Exposure, String> {
+        let number = |text: &str| -> Result<f32, String> {
+            let value = match text.split_once('/') {
+                Some((numerator, denominator)) => numerator.trim().parse::<f32>().ok()
+                    .zip(denominator.trim().parse::<f32>().ok())
+                    .map(|(numerator, denominator)| numerator / denominator),
+                None => text.trim().parse::<f32>().ok()
+            };
+            value.ok_or_else(|| format!("Invalid number \"{}\" in exposure \"{}\"", text, spec))
+        };
+        if let Some(compensation) = spec.strip_prefix("auto") {
+            let compensation = match compensation.strip_prefix(':') {
+                Some(compensation) => number(compensation)?,
+                None if compensation.is_empty() => 0.0,
+                None => return Err(format!("Invalid exposure \"{}\", expected auto or auto:<compensation>", spec))
+            };
+            return Ok(Exposure::Auto { compensation });
+        }
+        let values = spec.split(',').map(number).collect::<Result<Vec<f32>, String>>()?;
+        match values.as_slice() {
+            [ev] => Ok(Exposure::Ev(*ev)),
+            [iso, shutter, f_number] if *iso > 0.0 && *shutter > 0.0 && *f_number > 0.0 => {
+                Ok(Exposure::Manual { iso: *iso, shutter: *shutter, f_number: *f_number })
+            },
+            _ => Err(format!("Invalid exposure \"{}\", expected an exposure value, auto[:<compensation>] or <iso>,<shutter>,<f-number>", spec))
+        }
+    }
+
+    /// Whether the scene has to be metered before rendering
+    pub fn is_auto(&self) -> bool {
+        matches!(self, Exposure::Auto { .. })
+    }
+
+    /// The exposure value at ISO 100, given the log-average luminance of the scene in cd/m² for auto exposure
+    pub fn ev100(&self, average_luminance: f32) -> f32 {
+        match self {
+            Exposure::Ev(ev) => *ev,
+            // A higher ISO needs less light, and so does a slower shutter or a wider aperture
+            Exposure::Manual { iso, shutter, f_number } => (f_number * f_number / shutter).log2() - (iso / 100.0).log2(),
+            Exposure::Auto { compensation } => (average_luminance.max(f32::MIN_POSITIVE) * 100.0 / METER_CALIBRATION).log2() - compensation
+        }
+    }
+
+    /// The scale that maps the brightest luminance the exposure can show to white
+    /// A saturation-based sensor clips at 1.2 * 2^EV cd/m², for the standard calibration constant
+    pub fn scale(&self, average_luminance: f32) -> f32 {
+        1.0 / (1.2 * 2.0_f32.powf(self.ev100(average_luminance)))
+    }
+}
@@ -0,0 +1,204 @@
+// How the samples of a pixel are spread over and around it, which decides how sharp edges come out and how much they alias
+
+// Bins the filter is split into across its width for picking where samples go
+const TABLE_SIZE: usize = 256;
+// The B and C of the Mitchell-Netravali filter, the pair its authors found to look best
+const MITCHELL_B: f32 = 1.0 / 3.0;
+const MITCHELL_C: f32 = 1.0 / 3.0;
+
+/// A reconstruction filter, how much a sample counts towards a pixel by how far from the middle of the pixel it lands.
+/// The width is in pixels, so filters wider than 1 reach into the pixels around and blend them a little
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PixelFilter {
+    /// Every sample inside the pixel counts the same and none outside it do. Sharp, but edges alias the most
+    Box { width: f32 },
+    /// Falls off in a straight line from the middle of the pixel
+    Tent { width: f32 },
+    /// A gaussian cut off at the width, soft and without any aliasing to speak of
+    Gaussian { width: f32 },
+    /// The Mitchell-Netravali filter, sharper than the gaussian. Dips below zero near its edges, which keeps
+    /// edges crisp but can leave a faint dark ring around very bright things
+    Mitchell { width: f32 }
+}
+
+impl PixelFilter {
+    /// Parses box, tent, gaussian or mitchell, with an optional width in pixels after a colon like gaussian:2
+    pub fn parse(spec: &str) -> Result<PixelFilter, String> {
+        let (name, width) = spec.split_once(':').unwrap_or((spec, ""));
+        let width = match width {
+            "" => None,
+            _ => match width.trim().parse::<f32>() {
+                Ok(width) if width > 0.0 && width.is_finite() => Some(width),
+                _ => return Err(format!("Invalid filter width \"{}\", expected a positive number of pixels", width))
+            }
+        };
+        match name {
+            "box" => Ok(PixelFilter::Box { width: width.unwrap_or(1.0) }),
+            "tent" => Ok(PixelFilter::Tent { width: width.unwrap_or(2.0) }),
+            "gaussian" => Ok(PixelFilter::Gaussian { width: width.unwrap_or(3.0) }),
+            "mitchell" => Ok(PixelFilter::Mitchell { width: width.unwrap_or(4.0) }),
+            _ => Err(format!("Unknown filter \"{}\", expected box, tent, gaussian or mitchell", name))
+        }
+    }
+
+    /// How far from the middle of the pixel the filter reaches, in pixels
+    pub fn radius(&self) -> f32 {
+        match *self {
+            PixelFilter::Box { width } | PixelFilter::Tent { width } | PixelFilter::Gaussian { width } | PixelFilter::Mitchell { width } => width / 2.0
+        }
+    }
+
+    /// The area under the filter along one axis, which weights are divided by for light that's spread over the pixels
+    /// by the filter instead of averaged from samples
+    pub fn integral(&self) -> f32 {
+        let radius = self.radius();
+        let step = 2.0 * radius / TABLE_SIZE as f32;
+        (0..TABLE_SIZE).map(|bin| self.evaluate(-radius + (bin as f32 + 0.5) * step)).sum::<f32>() * step
+    }
+
+    /// How much a sample offset by x pixels from the middle of the pixel counts, along one axis
+    /// The filters are separable, so a sample counts the product of its weights across and up
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        match self {
+            PixelFilter::Box { .. } => 1.0,
+            PixelFilter::Tent { .. } => radius - x,
+            PixelFilter::Gaussian { .. } => {
+                // Three standard deviations fit in the radius, and the gaussian is lowered to reach zero right at it
+                let sigma = radius / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(radius)
+            },
+            PixelFilter::Mitchell { .. } => {
+                // The filter is defined from 0 to 2, stretched to the radius
+                let x = 2.0 * x / radius;
+                let (b, c) = (MITCHELL_B, MITCHELL_C);
+                let value = match x < 1.0 {
+                    true => (12.0 - 9.0 * b - 6.0 * c) * x.powi(3) + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2) + (6.0 - 2.0 * b),
+                    false => (-b - 6.0 * c) * x.powi(3) + (6.0 * b + 30.0 * c) * x.powi(2) + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+                };
+                value / 6.0
+            }
+        }
+    }
+}
+
+/// Places the samples of a pixel by how much the filter weighs them, so every sample counts about the same and
+/// pixels can still be averaged on their own. Filters that dip below zero give the samples there a negative weight
+pub struct FilterTable {
+    radius: f32,
+    // How much of the filter lies before the end of every bin, going from 0 to 1
+    cdf: Vec<f32>,
+    // Whether the filter is negative in every bin
+    negative: Vec<bool>,
+    // What every sample counts, the area under the filter's absolute value over the area under it,
+    // so negative samples are made up for and the weights average out to 1
+    weight: f32
+}
+
+impl FilterTable {
+    pub fn new(filter: PixelFilter) -> FilterTable {
+        let radius = filter.radius();
+        let bin_width = 2.0 * radius / TABLE_SIZE as f32;
+        let values: Vec<f32> = (0..TABLE_SIZE).map(|bin| filter.evaluate(-radius + (bin as f32 + 0.5) * bin_width)).collect();
+        let absolute_total: f32 = values.iter().map(|value| value.abs()).sum();
+        let total: f32 = values.iter().sum();
+        let mut cdf = Vec::with_capacity(TABLE_SIZE);
+        let mut sum = 0.0;
+        for value in &values {
+            sum += value.abs() / absolute_total;
+            cdf.push(sum);
+        }
+        FilterTable {
+            radius,
+            cdf,
+            negative: values.iter().map(|&value| value < 0.0).collect(),
+            weight: absolute_total / total
+        }
+    }
+
+    /// Turns a random number from 0 to 1 into an offset in pixels from the middle of the pixel, and the weight of a sample there
+    pub fn sample(&self, random: f32) -> (f32, f32) {
+        let bin = self.cdf.partition_point(|&end| end <= random).min(TABLE_SIZE - 1);
+        let start = match bin {
+            0 => 0.0,
+            _ => self.cdf[bin - 1]
+        };
+        // Spread evenly inside the bin
+        let within = match self.cdf[bin] > start {
+            true => (random - start) / (self.cdf[bin] - start),
+            false => 0.5
+        };
+        let offset = -self.radius + (bin as f32 + within) * 2.0 * self.radius / TABLE_SIZE as f32;
+        let weight = match self.negative[bin] {
+            true => -self.weight,
+            false => self.weight
+        };
+        return (offset, weight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Evenly spaced random numbers, so the tests don't depend on luck
+    fn samples(table: &FilterTable, count: usize) -> Vec<(f32, f32)> {
+        (0..count).map(|i| table.sample((i as f32 + 0.5) / count as f32)).collect()
+    }
+
+    #[test]
+    fn box_samples_cover_the_pixel_evenly() {
+        let offsets: Vec<(f32, f32)> = samples(&FilterTable::new(PixelFilter::Box { width: 1.0 }), 1000);
+        assert!(offsets.iter().all(|&(offset, weight)| (-0.5..=0.5).contains(&offset) && weight == 1.0));
+        assert!((offsets[0].0 + 0.4995).abs() < 1e-4 && (offsets[999].0 - 0.4995).abs() < 1e-4);
+        assert!((offsets[500].0 - 0.0005).abs() < 1e-4);
+    }
+
+    #[test]
+    fn samples_follow_the_filter() {
+        let filter = PixelFilter::Tent { width: 2.0 };
+        let table = FilterTable::new(filter);
+        let count = 100_000;
+        // Bins of a tenth of a pixel should get as many samples as the filter has area over them
+        let mut bins = [0; 20];
+        for (offset, _) in samples(&table, count) {
+            bins[(((offset + 1.0) * 10.0) as usize).min(19)] += 1;
+        }
+        for (bin, &hits) in bins.iter().enumerate() {
+            let middle = -1.0 + (bin as f32 + 0.5) / 10.0;
+            let expected = filter.evaluate(middle) / filter.integral() / 10.0;
+            assert!((hits as f32 / count as f32 - expected).abs() < 0.002, "bin {} got {} samples", bin, hits);
+        }
+    }
+
+    #[test]
+    fn mitchell_weights_are_negative_at_the_edges_and_average_to_one() {
+        let filter = PixelFilter::Mitchell { width: 4.0 };
+        let offsets = samples(&FilterTable::new(filter), 100_000);
+        for &(offset, weight) in &offsets {
+            assert_eq!(weight < 0.0, filter.evaluate(offset) < 0.0, "weight {} at {}", weight, offset);
+        }
+        let average = offsets.iter().map(|(_, weight)| weight).sum::<f32>() / offsets.len() as f32;
+        assert!((average - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn integrals_of_known_filters() {
+        assert!((PixelFilter::Box { width: 1.0 }.integral() - 1.0).abs() < 1e-5);
+        assert!((PixelFilter::Tent { width: 2.0 }.integral() - 1.0).abs() < 1e-4);
+        assert!((PixelFilter::Box { width: 3.0 }.integral() - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn parses_names_and_widths() {
+        assert_eq!(PixelFilter::parse("gaussian"), Ok(PixelFilter::Gaussian { width: 3.0 }));
+        assert_eq!(PixelFilter::parse("tent:1.5"), Ok(PixelFilter::Tent { width: 1.5 }));
+        assert!(PixelFilter::parse("box:0").is_err());
+        assert!(PixelFilter::parse("lanczos").is_err());
+    }
+}
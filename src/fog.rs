@@ -0,0 +1,98 @@
+// Haze filling the whole scene, thinning out with height like the air does
+
+use glam::Vec3;
+
+type Color = Vec3;
+
+/// Fog everywhere outside of objects, thickest at and below a height and fading away exponentially above it
+/// Distant things fade into it, and light scattered off it shows the beams of bright lights
+/// Its density along a ray is integrated exactly, so how far light gets through needs no stepping
+pub struct HeightFog {
+    // Chance of hitting a particle per unit of distance at the base height
+    density: f32,
+    height: f32,
+    // How quickly the fog thins out going up, 0 for the same density everywhere
+    falloff: f32,
+    // Share of the light hitting a particle that is scattered instead of absorbed
+    pub albedo: Color,
+    // The Henyey-Greenstein g of the particles, above 0 to scatter light mostly onwards like mist does
+    pub anisotropy: f32
+}
+
+impl HeightFog {
+    pub fn new(density: f32, height: f32, falloff: f32) -> HeightFog {
+        HeightFog { density: density.max(0.0), height, falloff: falloff.max(0.0), albedo: Color::ONE, anisotropy: 0.0 }
+    }
+
+    pub fn with_albedo(mut self, albedo: Color) -> HeightFog {
+        self.albedo = albedo;
+        self
+    }
+
+    pub fn with_anisotropy(mut self, anisotropy: f32) -> HeightFog {
+        self.anisotropy = anisotropy.clamp(-0.99, 0.99);
+        self
+    }
+
+    /// Parses a fog from "density[,falloff[,height[,anisotropy]]]", thinning out from the floor of the default scene if there's no height
+    pub fn parse(spec: &str) -> Result<HeightFog, String> {
+        let values = spec.split(',')
+            .map(|value| value.trim().parse::<f32>().map_err(|_| format!("Invalid fog value \"{}\"", value)))
+            .collect::<Result<Vec<f32>, String>>()?;
+        match values[..] {
+            [density] => Ok(HeightFog::new(density, -1.0, 0.0)),
+            [density, falloff] => Ok(HeightFog::new(density, -1.0, falloff)),
+            [density, falloff, height] => Ok(HeightFog::new(density, height, falloff)),
+            [density, falloff, height, anisotropy] => Ok(HeightFog::new(density, height, falloff).with_anisotropy(anisotropy)),
+            _ => Err(format!("Expected density[,falloff[,height[,anisotropy]]], got \"{}\"", spec))
+        }
+    }
+
+    /// How many particles a ray passes on its way along a unit direction for a distance, which may be infinite
+    pub fn optical_depth(&self, origin: Vec3, direction: Vec3, distance: f32) -> f32 {
+        let (start, rate) = self.start_and_rate(origin, direction);
+        // Far enough above the fog there's nothing left of it
+        if start <= 0.0 {
+            return 0.0;
+        }
+        // Nearly level rays see the same density the whole way
+        match rate.abs() < 1e-5 {
+            true => start * distance,
+            false => start * -(-rate * distance).exp_m1() / rate
+        }
+    }
+
+    /// Share of the light that makes it along a unit direction for a distance without hitting anything
+    pub fn transmittance(&self, origin: Vec3, direction: Vec3, distance: f32) -> f32 {
+        (-self.optical_depth(origin, direction, distance)).exp()
+    }
+
+    /// Picks how far along a unit direction a particle is hit with a uniform random number, by inverting the optical depth
+    /// Returns None if the ray gets further than max_distance, or out of the top of the fog, first
+    pub fn sample_distance(&self, origin: Vec3, direction: Vec3, u: f32, max_distance: f32) -> Option<f32> {
+        if self.density <= 0.0 {
+            return None;
+        }
+        let depth = -(1.0 - u).ln();
+        let (start, rate) = self.start_and_rate(origin, direction);
+        let distance = match rate.abs() < 1e-5 {
+            true => depth / start,
+            false => {
+                let remaining = 1.0 - depth * rate / start;
+                match remaining > 0.0 {
+                    true => -remaining.ln() / rate,
+                    false => return None
+                }
+            }
+        };
+        match distance < max_distance {
+            true => Some(distance),
+            false => None
+        }
+    }
+
+    // The density where the ray starts, and how quickly it changes per unit of distance along it
+    fn start_and_rate(&self, origin: Vec3, direction: Vec3) -> (f32, f32) {
+        (self.density * (-self.falloff * (origin.y - self.height)).exp(), self.falloff * direction.y)
+    }
+}
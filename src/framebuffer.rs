@@ -0,0 +1,54 @@
+/// A flat, preallocated 24-bit image, or 32-bit with an alpha channel
+/// Rows are stored bottom to top and pixels in BGR or BGRA order, the same layout BMP and TGA expect
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    // Bytes per pixel, 3 or 4 with alpha
+    pub channels: usize,
+    data: Vec<u8>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            channels: 3,
+            data: vec![0; width * height * 3]
+        }
+    }
+
+    /// An image with an alpha byte after the color of every pixel
+    pub fn with_alpha(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            channels: 4,
+            data: vec![0; width * height * 4]
+        }
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.channels == 4
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        let stride = self.width * self.channels;
+        &self.data[y * stride..(y + 1) * stride]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [u8] {
+        let stride = self.width * self.channels;
+        &mut self.data[y * stride..(y + 1) * stride]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.data.chunks_exact(self.width * self.channels)
+    }
+
+    /// Gets the (red, green, blue) color at the given pixel
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let bytes = &self.row(y)[x * self.channels..x * self.channels + 3];
+        (bytes[2], bytes[1], bytes[0])
+    }
+}
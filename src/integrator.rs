@@ -0,0 +1,260 @@
+// Ways of working out the color seen along a camera ray, from full path tracing to quick looks at the scene
+
+use std::sync::Arc;
+use glam::Vec3;
+use crate::bdpt::BidirectionalPathTracer;
+use crate::camera::{Camera, power_heuristic};
+use crate::color::SrgbColor;
+use crate::interval::Interval;
+use crate::material::sample_cosine_hemisphere;
+use crate::metropolis::MetropolisLightTransport;
+use crate::object::Object;
+use crate::photon::ProgressivePhotonMapper;
+use crate::restir::ResampledDirectLighting;
+use crate::ray::{Ray, Hit};
+
+type Color = Vec3;
+
+/// Works out the color seen along a ray from the camera, with the camera's lights, background and random numbers
+/// New algorithms can be added as integrators without changing how the camera renders
+pub trait Integrator {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color;
+    // Called at the start of every render, for integrators that work something out about the whole scene first
+    fn prepare(&self, _camera: &mut Camera, _objects: &[Box<dyn Object>]) {}
+    // Whether the colors are light to expose like a photo, rather than values to be shown as they are
+    fn is_radiance(&self) -> bool {
+        true
+    }
+    // Whether the colors depend on where in the pixel the ray goes, so the samples are weighed by the pixel filter
+    fn is_filtered(&self) -> bool {
+        true
+    }
+}
+
+/// Picks an integrator by name: path, bdpt, mlt or mlt:<chains>[,<large step probability>], sppm or sppm:<photons per pass>[,<radius>], direct, restir or restir:<candidates>, ao or ao:<distance>, facing, normals, albedo, depth or depth:<distance>, or boxes
+pub fn parse_integrator(name: &str) -> Result<Arc<dyn Integrator>, String> {
+    match name.split_once(':') {
+        None if name == "path" => Ok(Arc::new(PathTracer)),
+        None if name == "bdpt" => Ok(Arc::new(BidirectionalPathTracer::new())),
+        None if name == "mlt" => Ok(Arc::new(MetropolisLightTransport::new(1000, 0.3))),
+        Some(("mlt", spec)) => MetropolisLightTransport::parse(spec).map(|integrator| Arc::new(integrator) as Arc<dyn Integrator>),
+        None if name == "sppm" => Ok(Arc::new(ProgressivePhotonMapper::new(10000, None))),
+        Some(("sppm", spec)) => ProgressivePhotonMapper::parse(spec).map(|integrator| Arc::new(integrator) as Arc<dyn Integrator>),
+        None if name == "direct" => Ok(Arc::new(DirectLighting)),
+        None if name == "restir" => Ok(Arc::new(ResampledDirectLighting::new(8))),
+        Some(("restir", candidates)) => candidates.parse()
+            .map(|candidates| Arc::new(ResampledDirectLighting::new(candidates)) as Arc<dyn Integrator>)
+            .map_err(|_| format!("Invalid number of candidates \"{}\"", candidates)),
+        None if name == "ao" => Ok(Arc::new(AmbientOcclusion::new(0.5))),
+        Some(("ao", distance)) => distance.parse()
+            .map(|distance| Arc::new(AmbientOcclusion::new(distance)) as Arc<dyn Integrator>)
+            .map_err(|_| format!("Invalid occlusion distance \"{}\"", distance)),
+        None if name == "facing" => Ok(Arc::new(Facing)),
+        None if name == "normals" => Ok(Arc::new(Normals)),
+        None if name == "albedo" => Ok(Arc::new(Albedo)),
+        None if name == "depth" => Ok(Arc::new(Depth::new(5.0))),
+        Some(("depth", distance)) => distance.parse()
+            .map(|distance| Arc::new(Depth::new(distance)) as Arc<dyn Integrator>)
+            .map_err(|_| format!("Invalid depth distance \"{}\"", distance)),
+        None if name == "boxes" => Ok(Arc::new(BoxesHit)),
+        _ => Err(format!("Unknown integrator \"{}\"", name))
+    }
+}
+
+/// Follows light bouncing around the scene as far as the camera's max depth, the default
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        camera.trace_path(ray, objects)
+    }
+}
+
+/// Only light reaching the first hit straight from the lights and the background, without any bounces
+/// Much less noisy than path tracing, for checking how a scene is lit
+pub struct DirectLighting;
+
+impl Integrator for DirectLighting {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, index)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            let background = camera.background_radiance(ray);
+            camera.record_background_light(background);
+            return background;
+        };
+        DirectLighting::at_hit(camera, ray, &hit, index, objects)
+    }
+}
+
+impl DirectLighting {
+    /// The light given off at a hit on the object with an index and reaching it straight from the lights and the background
+    pub fn at_hit(camera: &mut Camera, ray: &Ray, hit: &Hit, index: usize, objects: &[Box<dyn Object>]) -> Color {
+        camera.record_object_light(index, hit.emitted);
+        let object = objects[index].as_ref();
+        let evaluate = |scattered: &Ray| object.evaluate(ray, scattered, hit.position, hit.outward_normal(), hit.tangent, hit.u, hit.v);
+        let direct = camera.sample_light(ray, hit, &evaluate, objects) + camera.punctual_light(ray, hit, &evaluate, objects);
+        // Sampling the lights only gets its share of their light, bouncing once finds the rest
+        let bounced_ray = hit.spawn_ray(&hit.outgoing, camera.normal_offset);
+        let found = match camera.intersect_objects(&bounced_ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) {
+            Some((light_hit, light)) if light_hit.is_emitter => {
+                let weight = match hit.is_specular {
+                    true => 1.0,
+                    false => power_heuristic(hit.pdf, camera.light_pdf(objects, light, bounced_ray.origin, &light_hit, bounced_ray.time))
+                };
+                let light_color = hit.albedo * light_hit.emitted * weight;
+                camera.record_object_light(light, light_color);
+                light_color
+            },
+            Some(_) => Color::ZERO,
+            None => {
+                let weight = match hit.is_specular {
+                    true => 1.0,
+                    false => power_heuristic(hit.pdf, camera.distant_pdf(bounced_ray.origin, bounced_ray.direction))
+                };
+                let background = hit.albedo * camera.background_radiance(&bounced_ray) * weight;
+                camera.record_background_light(background);
+                background
+            }
+        };
+        return hit.emitted + direct + found;
+    }
+}
+
+/// How much of the sky above every point is blocked by things within a distance, white for open and black for enclosed
+/// Shows the shape of a scene without any lights
+pub struct AmbientOcclusion {
+    distance: f32
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, _)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            return Color::ONE;
+        };
+        // Cosine weighted, so blockers straight above count for more than those at grazing angles
+        let (direction, _) = sample_cosine_hemisphere(camera.rng(), hit.normal);
+        let occlusion_ray = hit.spawn_ray(&ray.continued(hit.position, direction), camera.normal_offset);
+        match camera.intersect_objects(&occlusion_ray, objects, &Interval::new(camera.hit_epsilon, self.distance)) {
+            Some(_) => Color::ZERO,
+            None => Color::ONE
+        }
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+impl AmbientOcclusion {
+    pub fn new(distance: f32) -> AmbientOcclusion {
+        AmbientOcclusion { distance }
+    }
+}
+
+/// Front faces in blue and back faces in red, brighter the more they face the camera
+/// Shows surfaces facing the wrong way and holes in closed objects
+pub struct Facing;
+
+impl Integrator for Facing {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, _)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            return Color::ZERO;
+        };
+        let brightness = hit.normal.dot(-ray.direction.normalize()).abs();
+        match hit.front_face {
+            true => Color::new(0.1, 0.3, 1.0) * brightness,
+            false => Color::new(1.0, 0.1, 0.1) * brightness
+        }
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+/// The outward normal of the first hit, with x, y and z as red, green and blue from -1 at black to 1 at full
+pub struct Normals;
+
+impl Integrator for Normals {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, _)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            return Color::ZERO;
+        };
+        return unencoded((hit.outward_normal() + Vec3::ONE) / 2.0);
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+/// The color of the first hit's surface, without any lighting
+pub struct Albedo;
+
+impl Integrator for Albedo {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, index)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            return Color::ZERO;
+        };
+        return unencoded(objects[index].albedo(hit.u, hit.v, hit.position, ray.time).clamp(Color::ZERO, Color::ONE));
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+/// How far away the first hit is, from white up close to black at a distance and beyond
+pub struct Depth {
+    distance: f32
+}
+
+impl Integrator for Depth {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, _)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            return Color::ZERO;
+        };
+        let distance = hit.t * ray.direction.length();
+        return unencoded(Color::splat((1.0 - distance / self.distance).clamp(0.0, 1.0)));
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+impl Depth {
+    pub fn new(distance: f32) -> Depth {
+        Depth { distance: distance.max(f32::EPSILON) }
+    }
+}
+
+/// How many objects' bounding boxes a camera ray passes through, from blue for none through green to red for all of them
+/// There's no acceleration structure to count the steps of, every ray is tested against every object anyway. This is how
+/// many of those tests get past the box, which is where meshes start going through all their triangles
+pub struct BoxesHit;
+
+impl Integrator for BoxesHit {
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let interval = Interval::new(camera.hit_epsilon, f32::MAX);
+        let count = objects.iter().filter(|object| object.bounding_box().hit(ray, &interval)).count();
+        return unencoded(heat(count as f32 / objects.len().max(1) as f32));
+    }
+
+    fn is_radiance(&self) -> bool {
+        false
+    }
+}
+
+/// A heat map color for a value from 0 to 1, going from blue through green to red
+pub fn heat(value: f32) -> Color {
+    let value = value.clamp(0.0, 1.0);
+    match value < 0.5 {
+        true => Color::new(0.0, 0.0, 1.0).lerp(Color::new(0.0, 1.0, 0.0), value * 2.0),
+        false => Color::new(0.0, 1.0, 0.0).lerp(Color::new(1.0, 0.0, 0.0), value * 2.0 - 1.0)
+    }
+}
+
+// Debug values are decoded, so they show up as they are once the image is encoded
+fn unencoded(value: Color) -> Color {
+    SrgbColor::from_encoded(value).decode()
+}
@@ -1,9 +1,15 @@
+#[derive(Clone, Copy)]
 pub struct Interval {
     pub min: f32,
     pub max: f32
 }
 
 impl Interval {
+    // Contains nothing, since min is larger than max
+    pub const EMPTY: Interval = Interval { min: f32::INFINITY, max: f32::NEG_INFINITY };
+    // Contains everything
+    pub const UNIVERSE: Interval = Interval { min: f32::NEG_INFINITY, max: f32::INFINITY };
+
     pub fn new(min: f32, max: f32) -> Interval {
         Interval {
             min,
@@ -11,6 +17,14 @@ impl Interval {
         }
     }
 
+    /// The smallest interval containing both intervals
+    pub fn union(a: &Interval, b: &Interval) -> Interval {
+        Interval {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max)
+        }
+    }
+
     pub fn contains(&self, num: f32) -> bool {
         num >= self.min && num <= self.max 
     }
@@ -18,4 +32,14 @@ impl Interval {
     pub fn surrounds(&self, num: f32) -> bool {
         num > self.min && num < self.max
     }
-}
\ No newline at end of file
+
+    pub fn size(&self) -> f32 {
+        self.max - self.min
+    }
+
+    /// Returns the interval grown by delta, split evenly between both ends
+    pub fn expand(&self, delta: f32) -> Interval {
+        let padding = delta / 2.0;
+        Interval::new(self.min - padding, self.max + padding)
+    }
+}
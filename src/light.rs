@@ -0,0 +1,266 @@
+// Emitters that light can be sampled from directly, and the tree a scene's lights are picked from
+
+use glam::Vec3;
+use crate::sampler::SamplerRng;
+use crate::object::Object;
+use crate::bounding_box::BoundingBox;
+
+type Color = Vec3;
+
+/// Something giving off light that a point on can be picked from, so the light reaching a surface
+/// can be found by aiming at it instead of waiting for a bounce to stumble into it
+pub trait Light {
+    // Pick a point on the light as seen from origin, with the density per solid angle
+    // Returns None if no point can be seen from there
+    fn sample_point(&self, rng: &mut SamplerRng, origin: Vec3, time: f32) -> Option<(Vec3, f32)>;
+    // The density per solid angle of sample_point picking a point with a normal, as seen from origin
+    fn pdf(&self, origin: Vec3, point: Vec3, normal: Vec3, time: f32) -> f32;
+    // Total power given off, as the average of the color channels. Only compared between lights
+    fn power(&self) -> f32;
+    // Pick a point anywhere on the light and the normal of the side it's picked to shine out of, with the density
+    // per unit of area of picking both, for paths that start on the light instead of ending on it
+    fn sample_surface(&self, rng: &mut SamplerRng, time: f32) -> Option<(Vec3, Vec3, f32)>;
+    // The density of sample_surface picking a point with an outward normal, shining out of the side facing direction
+    fn surface_pdf(&self, point: Vec3, normal: Vec3, direction: Vec3, time: f32) -> f32;
+}
+
+/// Every light of a scene in a hierarchy of boxes, so lights can be picked by how much they're likely to
+/// contribute to a point without looking at every single one. Each step down the tree picks one of two
+/// children in proportion to their power over their squared distance to the point
+#[derive(Default)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    // Leaf node of every object, None for everything that isn't a light
+    leaves: Vec<Option<usize>>
+}
+
+struct LightNode {
+    bounds: BoundingBox,
+    power: f32,
+    parent: Option<usize>,
+    content: LightNodeContent
+}
+
+enum LightNodeContent {
+    // Object index of the light
+    Leaf(usize),
+    // Node indices of the two halves
+    Split(usize, usize)
+}
+
+impl LightTree {
+    /// Finds every object that can be sampled as a light, and splits them up by where they are
+    pub fn new(objects: &[Box<dyn Object>]) -> LightTree {
+        let mut tree = LightTree { nodes: vec![], leaves: vec![None; objects.len()] };
+        let mut lights: Vec<(usize, BoundingBox, f32)> = (0..objects.len())
+            .filter_map(|index| objects[index].light().map(|light| (index, objects[index].bounding_box(), light.power().max(0.0))))
+            .collect();
+        if !lights.is_empty() {
+            tree.build(&mut lights, None);
+        }
+        return tree;
+    }
+
+    // Adds a node for the lights, splitting them in half along the axis their centers are most spread out on, and returns its index
+    fn build(&mut self, lights: &mut [(usize, BoundingBox, f32)], parent: Option<usize>) -> usize {
+        let bounds = lights.iter().fold(BoundingBox::EMPTY, |bounds, light| BoundingBox::union(&bounds, &light.1));
+        let power = lights.iter().map(|light| light.2).sum();
+        let index = self.nodes.len();
+        self.nodes.push(LightNode { bounds, power, parent, content: LightNodeContent::Leaf(lights[0].0) });
+        if lights.len() == 1 {
+            self.leaves[lights[0].0] = Some(index);
+            return index;
+        }
+        let centers = lights.iter().fold(BoundingBox::EMPTY, |centers, light| {
+            let center = light.1.centroid();
+            BoundingBox::union(&centers, &BoundingBox::from_points(center, center))
+        });
+        let axis = centers.longest_axis();
+        lights.sort_by(|a, b| a.1.centroid()[axis].total_cmp(&b.1.centroid()[axis]));
+        let (left, right) = lights.split_at_mut(lights.len() / 2);
+        let left = self.build(left, Some(index));
+        let right = self.build(right, Some(index));
+        self.nodes[index].content = LightNodeContent::Split(left, right);
+        return index;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Picks a light for a point with a uniform random number, returning its object index and the probability of picking it
+    pub fn pick(&self, point: Vec3, u: f32) -> Option<(usize, f32)> {
+        self.walk(Some(point), u)
+    }
+
+    /// Picks a light in proportion to its power alone, for paths leaving the lights instead of arriving at a point
+    pub fn pick_by_power(&self, u: f32) -> Option<(usize, f32)> {
+        self.walk(None, u)
+    }
+
+    /// The probability of picking the object with an index for a point
+    pub fn probability(&self, point: Vec3, index: usize) -> f32 {
+        self.walk_probability(Some(point), index)
+    }
+
+    /// The probability of pick_by_power picking the object with an index
+    pub fn power_probability(&self, index: usize) -> f32 {
+        self.walk_probability(None, index)
+    }
+
+    // Walks down the tree to a light, weighing the children by what they're likely to send to a point, or by power without one
+    fn walk(&self, point: Option<Vec3>, mut u: f32) -> Option<(usize, f32)> {
+        let mut node = self.nodes.first()?;
+        let mut probability = 1.0;
+        loop {
+            match node.content {
+                LightNodeContent::Leaf(index) => return Some((index, probability)),
+                LightNodeContent::Split(left, right) => {
+                    // Reuse the random number, stretched back out to the whole range, for the next step
+                    let left_probability = self.left_probability(point, left, right);
+                    match u < left_probability {
+                        true => {
+                            u /= left_probability;
+                            probability *= left_probability;
+                            node = &self.nodes[left];
+                        },
+                        false => {
+                            u = (u - left_probability) / (1.0 - left_probability);
+                            probability *= 1.0 - left_probability;
+                            node = &self.nodes[right];
+                        }
+                    }
+                    u = u.min(1.0 - f32::EPSILON);
+                }
+            }
+        }
+    }
+
+    // The probability of walk ending up at the object with an index
+    fn walk_probability(&self, point: Option<Vec3>, index: usize) -> f32 {
+        let Some(mut node) = self.leaves.get(index).copied().flatten() else {
+            return 0.0;
+        };
+        let mut probability = 1.0;
+        // Walk up to the root, taking the chance of every step down on the way
+        while let Some(parent) = self.nodes[node].parent {
+            let LightNodeContent::Split(left, right) = self.nodes[parent].content else {
+                unreachable!("light tree parents are always split");
+            };
+            let left_probability = self.left_probability(point, left, right);
+            probability *= match node == left {
+                true => left_probability,
+                false => 1.0 - left_probability
+            };
+            node = parent;
+        }
+        return probability;
+    }
+
+    // The chance of going down the left child at a split, for a point if there is one
+    fn left_probability(&self, point: Option<Vec3>, left: usize, right: usize) -> f32 {
+        let (left, right) = (self.importance(point, left), self.importance(point, right));
+        match left + right > 0.0 {
+            true => left / (left + right),
+            false => 0.5
+        }
+    }
+
+    // How much light a node is likely to send to a point: its power over the squared distance to its center,
+    // which is kept from blowing up for points close to or inside the node's box. Without a point it's just the power
+    fn importance(&self, point: Option<Vec3>, index: usize) -> f32 {
+        let node = &self.nodes[index];
+        let Some(point) = point else {
+            return node.power;
+        };
+        let (min, max) = (node.bounds.min(), node.bounds.max());
+        let distance_squared = (node.bounds.centroid() - point).length_squared();
+        let half_diagonal_squared = ((max - min) / 2.0).length_squared();
+        node.power / distance_squared.max(half_diagonal_squared).max(f32::EPSILON)
+    }
+}
+
+/// A light without any size, which can't be hit by a ray and is only ever reached by aiming at it
+/// Simpler to place than an emitting object, at the cost of perfectly sharp shadows
+pub struct PunctualLight {
+    shape: PunctualShape,
+    // The light group it's gathered into, if it gets an image of its own
+    pub group: Option<String>
+}
+
+enum PunctualShape {
+    // Shines equally in every direction from a point, fading with the squared distance
+    Point { position: Vec3, intensity: Color },
+    // A point light limited to a cone, fading out over the penumbra at the cone's edge
+    // The angles are kept as cosines of the angle from the axis
+    Spot { position: Vec3, direction: Vec3, intensity: Color, cos_outer: f32, cos_inner: f32 },
+    // Parallel light from infinitely far away, with direction pointing towards the light
+    Directional { direction: Vec3, irradiance: Color }
+}
+
+impl PunctualLight {
+    /// A point light with an intensity per steradian
+    pub fn point(position: Vec3, intensity: Color) -> PunctualLight {
+        PunctualLight::new(PunctualShape::Point { position, intensity })
+    }
+
+    /// A spot light at position aimed at target, lighting up to angle degrees from its axis
+    /// The last penumbra degrees inside that fade out smoothly
+    pub fn spot(position: Vec3, target: Vec3, angle: f32, penumbra: f32, intensity: Color) -> PunctualLight {
+        let penumbra = penumbra.clamp(0.0, angle);
+        PunctualLight::new(PunctualShape::Spot {
+            position,
+            direction: (target - position).normalize(),
+            intensity,
+            cos_outer: angle.to_radians().cos(),
+            cos_inner: (angle - penumbra).to_radians().cos()
+        })
+    }
+
+    /// Parallel light coming from direction, lighting surfaces facing it with an irradiance
+    pub fn directional(direction: Vec3, irradiance: Color) -> PunctualLight {
+        PunctualLight::new(PunctualShape::Directional { direction: direction.normalize(), irradiance })
+    }
+
+    fn new(shape: PunctualShape) -> PunctualLight {
+        PunctualLight { shape, group: None }
+    }
+
+    /// Gathers the light into the light group with a name, which gets an image of its own
+    pub fn with_group(mut self, name: &str) -> PunctualLight {
+        self.group = Some(name.to_owned());
+        self
+    }
+
+    /// The way from point to the light, as far as the light or of unit length for directional ones,
+    /// and the light arriving at point if nothing is in the way
+    pub fn illuminate(&self, point: Vec3) -> (Vec3, Color) {
+        match self.shape {
+            PunctualShape::Point { position, intensity } => {
+                let to_light = position - point;
+                (to_light, intensity / to_light.length_squared().max(f32::EPSILON))
+            },
+            PunctualShape::Spot { position, direction, intensity, cos_outer, cos_inner } => {
+                let to_light = position - point;
+                let cosine = (-to_light.normalize()).dot(direction);
+                // A spot without a penumbra has a hard edge
+                let falloff = match (cos_inner > cos_outer, cosine >= cos_outer) {
+                    (true, _) => smoothstep(((cosine - cos_outer) / (cos_inner - cos_outer)).clamp(0.0, 1.0)),
+                    (false, true) => 1.0,
+                    (false, false) => 0.0
+                };
+                (to_light, intensity * falloff / to_light.length_squared().max(f32::EPSILON))
+            },
+            PunctualShape::Directional { direction, irradiance } => (direction, irradiance)
+        }
+    }
+
+    /// Whether the light is infinitely far away, so anything along the way to it blocks it
+    pub fn is_distant(&self) -> bool {
+        matches!(self.shape, PunctualShape::Directional { .. })
+    }
+}
+
+fn smoothstep(x: f32) -> f32 {
+    x * x * (3.0 - 2.0 * x)
+}
@@ -3,48 +3,190 @@
 // Both books can be found at https://raytracing.github.io/
 // I have translated their code into rust, made some structural changes where i saw fit and simplified certain aspects.
 
+// Explicit returns are the house style
+#![allow(clippy::needless_return, clippy::too_many_arguments, clippy::upper_case_acronyms)]
+
 use std::env;
 use glam::Vec3;
 use crate::material::*;
 use crate::object::*;
-use crate::camera::Camera;
+use crate::camera::{Camera, View};
+use crate::projection::Pinhole;
+use crate::animation::{CameraPath, CameraKeyframe, Interpolation, TransformTrack};
+use crate::settings::RenderSettings;
+use crate::sampler::HaltonSampler;
+use crate::environment::{Background, EnvironmentMap};
+use crate::sky::Sky;
+use crate::fog::HeightFog;
+use crate::integrator::parse_integrator;
+use crate::denoise::Denoiser;
+use crate::aov::Aov;
+use crate::output::Dither;
+use crate::exposure::Exposure;
+use crate::filter::PixelFilter;
+use crate::post_process::PostProcess;
 
 mod material;
+mod texture;
+mod perlin;
+mod volume;
+mod texture_cache;
+mod shader_graph;
+mod spectrum;
+mod microfacet;
+mod onb;
 mod ray;
 mod interval;
+mod bounding_box;
 mod object;
+mod light;
+mod environment;
+mod sky;
+mod fog;
 mod camera;
+mod exposure;
+mod filter;
+mod integrator;
+mod bdpt;
+mod photon;
+mod metropolis;
+mod restir;
+mod sampler;
+mod animation;
+mod settings;
+mod projection;
+mod mesh;
+mod portal;
+mod color;
 mod output;
+mod post_process;
+mod framebuffer;
+mod denoise;
+mod aov;
+mod preview;
+mod scene;
 
 fn main() {
     let mut camera = Camera::default();
-    let args = env::args().collect::<Vec<String>>();
+    // Flags can go anywhere, the remaining arguments are positional
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().partition(|arg| arg.starts_with("--"));
+    // The main camera is rendered unless another one is picked by name, or all of them are
+    let mut selected_view: Option<String> = None;
+    let mut all_views = false;
+    let mut turntable_frames: Option<u32> = None;
+    let mut flythrough_fps: Option<f32> = None;
+    let mut animation_fps: Option<f32> = None;
+    let mut clay = false;
+    let mut format = output::Format::BMP;
+    let mut scene_path: Option<String> = None;
+    for flag in flags {
+        match flag.as_str() {
+            "--thumbnail" => camera.thumbnail = true,
+            "--stream" => camera.stream_to_disk = true,
+            "--alpha" => camera.alpha = true,
+            "--halton" => camera.set_sampler(Box::new(HaltonSampler::new())),
+            "--blue-noise" => camera.set_sampler(Box::new(HaltonSampler::with_blue_noise())),
+            "--all-cameras" => all_views = true,
+            "--clay" => clay = true,
+            "--denoise" => camera.denoiser = Some(Denoiser::best_available()),
+            _ if flag.starts_with("--turntable=") => turntable_frames = Some(flag["--turntable=".len()..].parse().expect("Invalid number of frames")),
+            _ if flag.starts_with("--flythrough=") => flythrough_fps = Some(flag["--flythrough=".len()..].parse().expect("Invalid frame rate")),
+            _ if flag.starts_with("--animate=") => animation_fps = Some(flag["--animate=".len()..].parse().expect("Invalid frame rate")),
+            _ if flag.starts_with("--scene=") => scene_path = Some(flag["--scene=".len()..].to_owned()),
+            _ if flag.starts_with("--camera=") => selected_view = Some(flag["--camera=".len()..].to_owned()),
+            _ if flag.starts_with("--environment=") => camera.background = Background::Image(EnvironmentMap::load(&flag["--environment=".len()..]).expect("Failed loading environment map")),
+            _ if flag.starts_with("--background=") => camera.background = Background::parse(&flag["--background=".len()..]).expect("Invalid background"),
+            _ if flag.starts_with("--sky=") => {
+                // The sun's elevation in degrees and optionally the turbidity, shining in through the open front of the box
+                let mut values = flag["--sky=".len()..].split(',').map(|value| value.parse::<f32>().expect("Invalid sky"));
+                let elevation = values.next().expect("Invalid sky");
+                let sky = Sky::new(elevation, 180.0, values.next().unwrap_or(3.0));
+                camera.background = Background::Image(sky.environment(512, 256));
+                camera.sun = Some(sky.sun());
+                // The sky is in physical units, exposed for a sunny day by the sunny 16 rule unless the exposure is set
+                camera.exposure.get_or_insert(Exposure::Ev(15.0));
+            },
+            _ if flag.starts_with("--adaptive=") => camera.noise_threshold = Some(flag["--adaptive=".len()..].parse().expect("Invalid noise threshold")),
+            _ if flag.starts_with("--clamp=") => {
+                // One limit for both, or the direct and the indirect limit
+                let mut values = flag["--clamp=".len()..].split(',').map(|value| value.parse::<f32>().expect("Invalid clamp"));
+                camera.clamp_direct = values.next();
+                camera.clamp_indirect = values.next().or(camera.clamp_direct);
+            },
+            _ if flag.starts_with("--reject-outliers=") => camera.outlier_rejection = Some(flag["--reject-outliers=".len()..].parse().expect("Invalid outlier factor")),
+            _ if flag.starts_with("--epsilon=") => camera.hit_epsilon = flag["--epsilon=".len()..].parse().expect("Invalid hit epsilon"),
+            _ if flag.starts_with("--normal-offset=") => camera.normal_offset = flag["--normal-offset=".len()..].parse().expect("Invalid normal offset"),
+            _ if flag.starts_with("--denoise=") => camera.denoiser = Some(Denoiser::parse(&flag["--denoise=".len()..]).expect("Invalid denoiser")),
+            _ if flag.starts_with("--exposure=") => camera.exposure = Some(Exposure::parse(&flag["--exposure=".len()..]).expect("Invalid exposure")),
+            _ if flag.starts_with("--post=") => camera.post_process.push(PostProcess::parse(&flag["--post=".len()..]).expect("Invalid post processing effect")),
+            _ if flag.starts_with("--filter=") => camera.filter = PixelFilter::parse(&flag["--filter=".len()..]).expect("Invalid filter"),
+            _ if flag.starts_with("--dither=") => camera.dither = Dither::parse(&flag["--dither=".len()..]).expect("Invalid dither"),
+            _ if flag.starts_with("--format=") => format = output::Format::parse(&flag["--format=".len()..]).expect("Invalid image format"),
+            _ if flag.starts_with("--aov=") => camera.aovs = Aov::parse_list(&flag["--aov=".len()..]).expect("Invalid render passes"),
+            _ if flag.starts_with("--integrator=") => camera.integrator = parse_integrator(&flag["--integrator=".len()..]).expect("Invalid integrator"),
+            _ if flag.starts_with("--fog=") => camera.fog = Some(HeightFog::parse(&flag["--fog=".len()..]).expect("Invalid fog")),
+            _ => panic!("Unknown flag {}", flag)
+        }
+    }
 
     if args.len() > 1 {
         let samples: u32 = args[1].parse().expect("Invalid number of samples");
         camera.samples = samples;
-        if args.len() == 4 {
-            camera.set_width(args[2].parse().unwrap());
-            camera.set_height(args[3].parse().unwrap());
-        }
+        // Either a width and a height or an aspect ratio like 16:9, or the name of a preset like 1080p
+        let settings = match args.len() {
+            4 => RenderSettings::parse(&args[2], &args[3]),
+            3 => RenderSettings::preset(&args[2]),
+            _ => Ok(camera.settings)
+        };
+        camera.settings = settings.expect("Invalid resolution");
     }
     
+    // The scene file is read once every flag is in, since its media look past the camera's hit epsilon
+    let mut scene = match scene_path {
+        Some(path) => scene::load(&path, &mut camera).expect("Failed loading scene"),
+        None => cornell_box(&mut camera)
+    };
+    if clay {
+        scene = scene.into_iter().map(|object| Box::new(Clay::new(object)) as Box<dyn Object>).collect();
+    }
+
+    // Move in through the front of the box, swing around the spheres and zoom in on the metal one
+    let flythrough = CameraPath::new(Interpolation::CatmullRom)
+        .with(CameraKeyframe::new(0.0, Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.8), 60.0))
+        .with(CameraKeyframe::new(1.0, Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, -0.5, -1.8), 90.0))
+        .with(CameraKeyframe::new(2.0, Vec3::new(-0.7, 0.3, -1.0), Vec3::new(0.0, -0.5, -1.8), 80.0))
+        .with(CameraKeyframe::new(3.0, Vec3::new(-0.4, 0.0, -1.2), Vec3::new(0.36, -0.4, -2.3), 40.0));
+    let result = match (flythrough_fps, animation_fps, turntable_frames, all_views, selected_view) {
+        (Some(fps), _, _, _, _) => camera.render_path(&scene, format, &flythrough, fps),
+        (None, Some(fps), _, _, _) => camera.render_sequence(&scene, format, 0.0, 1.0, fps),
+        // Circle the spheres from inside the box
+        (None, None, Some(frames), _, _) => camera.render_turntable(&scene, format, Vec3::new(0.0, -0.5, -1.8), 0.9, 20.0, frames),
+        (None, None, None, true, _) => camera.render(&scene, format).and_then(|_| camera.render_all_views(&scene, format)),
+        (None, None, None, false, Some(name)) => camera.render_view(&name, &scene, format),
+        (None, None, None, false, None) => camera.render(&scene, format)
+    };
+    result.expect("Failed outputting image");
+}
+
+// The scene rendered without a scene file, with a few standard angles on it
+fn cornell_box(camera: &mut Camera) -> Vec<Box<dyn Object>> {
     // Create a cornell box
+    // The floor and the ceiling share a material, which shows in the material id pass
     let mut scene: Vec<Box<dyn Object>> = vec![
         // Floor
-        Box::new(Rect::new(
+        Box::new(NamedMaterial::new(Rect::new(
             Vec3::new(-1.0, -1.0, -0.8),
             Vec3::new(2.0, 0.0, 0.0),
             Vec3::new(0.0, 0.0, -2.0),
             Lambertian::new(0.85, 0.85, 0.85)
-        )),
+        ), "white")),
         // Ceiling
-        Box::new(Rect::new(
+        Box::new(NamedMaterial::new(Rect::new(
             Vec3::new(-1.0, 1.0, -2.8),
             Vec3::new(2.0, 0.0, 0.0),
             Vec3::new(0.0, 0.0, 2.0),
             Lambertian::new(0.85, 0.85, 0.85)
-        )),
+        ), "white")),
         //Left wall
         Box::new(Rect::new(
             Vec3::new(-1.0, -1.0, -0.8),
@@ -76,11 +218,25 @@ fn main() {
     ];
 
     let mut objects: Vec<Box<dyn Object>> = vec![
-        Box::new(Sphere::new(Vec3::new(-0.5, -0.5, -1.5), 0.5, Lambertian::new(0.9, 0.2, 0.9))),
+        // Hops once a second, which only shows when rendering an animation
+        Box::new(Animated::new(
+            Sphere::new(Vec3::ZERO, 0.5, Lambertian::new(0.9, 0.2, 0.9)),
+            TransformTrack::new(Interpolation::CatmullRom)
+                .with_translation(0.0, Vec3::new(-0.5, -0.5, -1.5))
+                .with_translation(0.5, Vec3::new(-0.5, -0.1, -1.5))
+                .with_translation(1.0, Vec3::new(-0.5, -0.5, -1.5))
+        )),
         Box::new(Sphere::new(Vec3::new(0.36, -0.4, -2.3), 0.6, Metal::new(Vec3::new(1.0, 1.0, 1.0), 0.03))),
         Box::new(Sphere::new(Vec3::new(0.1, -0.9, -1.15), 0.10, DiffuseLight::new(0.5, 1.0, 0.5)))
     ];
 
     scene.append(&mut objects);
-    camera.render(&scene, output::Format::BMP).expect("Failed outputting image");
+
+    // Standard angles on the box besides the main camera looking straight in
+    camera.views = vec![
+        View::new("left", Vec3::new(-0.9, 0.2, -0.4), Vec3::new(0.0, -0.5, -1.8), Vec3::Y, Box::new(Pinhole::new(70.0))),
+        View::new("right", Vec3::new(0.9, 0.2, -0.4), Vec3::new(0.0, -0.5, -1.8), Vec3::Y, Box::new(Pinhole::new(70.0))),
+        View::new("top", Vec3::new(0.0, 0.9, -0.6), Vec3::new(0.0, -1.0, -1.8), Vec3::Y, Box::new(Pinhole::new(80.0))),
+    ];
+    return scene;
 }
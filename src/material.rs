@@ -3,149 +3,1515 @@
 // Both books can be found at https://raytracing.github.io/
 // I have translated their code into rust, made some structural changes where i saw fit and simplified certain aspects.
 
-use rand::{rngs::ThreadRng, Rng};
+use std::f32::consts::PI;
+use std::rc::Rc;
+use std::sync::Arc;
+use rand::Rng;
+use crate::sampler::SamplerRng;
 use glam::Vec3;
-use crate::ray::Ray;
-use crate::interval::Interval;
+use crate::ray::{Ray, Medium, dispersed_ior};
+use crate::microfacet;
+use crate::onb::Onb;
+use crate::spectrum;
+use crate::texture::{Texture, SolidColor};
 
 type Color = Vec3;
 
+/// Where light arriving at a surface goes next, and how much of it makes it
+pub struct ScatterRecord {
+    // Proportion of light the scattered ray carries: the BSDF times the cosine, over the pdf
+    pub attenuation: Color,
+    pub scattered_ray: Ray,
+    // Density the direction was picked with, per solid angle. Not meaningful for specular bounces
+    pub pdf: f32,
+    // Mirror-like bounces only ever go one way, so no other direction can be weighed against them
+    pub is_specular: bool
+}
+
+impl ScatterRecord {
+    pub fn new(scattered_ray: Ray, attenuation: Color, pdf: f32) -> ScatterRecord {
+        ScatterRecord { attenuation, scattered_ray, pdf, is_specular: false }
+    }
+
+    pub fn specular(scattered_ray: Ray, attenuation: Color) -> ScatterRecord {
+        ScatterRecord { attenuation, scattered_ray, pdf: 0.0, is_specular: true }
+    }
+
+    pub fn with_attenuation(mut self, attenuation: Color) -> ScatterRecord {
+        self.attenuation = attenuation;
+        self
+    }
+
+    // Mixtures pick one lobe, but the direction could have come from any of them
+    fn with_pdf(mut self, pdf: f32) -> ScatterRecord {
+        if !self.is_specular {
+            self.pdf = pdf;
+        }
+        self
+    }
+}
+
 pub trait Material {
-    // Get the bounced ray direction given an incoming ray and an outward normal
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray;
-    // Get the proportion of bounced blue, green and red light
-    fn albedo(&self) -> Color;
+    // Scatter a ray arriving at a point on the surface with an outward normal
+    // The tangent points along increasing u, for materials that perturb the normal
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord;
+    // The density scatter picks the scattered direction with for a ray arriving along incoming
+    // Specular materials can't pick any direction but their own, and leave it at 0
+    fn scattering_pdf(&self, _incoming: &Ray, _scattered: &Ray, _position: Vec3, _normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        0.0
+    }
+    // The BSDF times the cosine for light arriving along scattered and leaving back along incoming,
+    // which is what scatter's attenuation comes to before it's divided by the pdf
+    // Specular materials only scatter into directions that can't be picked any other way, and leave it at 0
+    fn evaluate(&self, _incoming: &Ray, _scattered: &Ray, _position: Vec3, _normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> Color {
+        Color::ZERO
+    }
+    // Get the proportion of bounced blue, green and red light at a point on the surface
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color;
     // Does the material emit light?
     fn is_emitter(&self) -> bool {
         false
     }
-    // If it is, what light does it emit?
-    fn emit(&self) -> Color {
+    // If it is, what light does it emit at a point on the surface?
+    fn emit(&self, _u: f32, _v: f32, _position: Vec3) -> Color {
         Color::ZERO
     }
+    // Does the material only show the shadows cast on it?
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+    // How opaque the surface is at a point, from 0 for fully cut out to 1
+    fn alpha(&self, _u: f32, _v: f32, _position: Vec3) -> f32 {
+        1.0
+    }
+    // Should a ray pass straight through the surface here, as if it wasn't hit at all?
+    // Partly transparent points let rays through at random, in proportion to their transparency
+    fn is_cut_out(&self, rng: &mut SamplerRng, u: f32, v: f32, position: Vec3) -> bool {
+        let alpha = self.alpha(u, v, position);
+        alpha < 1.0 && rng.gen::<f32>() >= alpha
+    }
+}
+
+// A material shared by several objects, like the ones a scene file names and uses again
+impl<M: Material + ?Sized> Material for Rc<M> {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        (**self).scatter(rng, incoming, position, normal, tangent, u, v)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        (**self).scattering_pdf(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        (**self).evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        (**self).albedo(u, v, position)
+    }
+
+    fn is_emitter(&self) -> bool {
+        (**self).is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        (**self).emit(u, v, position)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        (**self).is_shadow_catcher()
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        (**self).alpha(u, v, position)
+    }
 }
 
 pub struct Diffuse {
-    color: Color,
+    albedo: Arc<dyn Texture>,
 }
 
 impl Material for Diffuse {
-    fn bounce(&self, rng: &mut ThreadRng, _incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        let direction = random_on_hemisphere(rng, &normal);
-        return Ray::new(position, direction);
+    // Lambertian reflection, albedo / pi times the cosine, over the density of the uniform sample
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let (direction, pdf) = sample_uniform_hemisphere(rng, normal);
+        let weight = self.albedo(u, v, position) / PI * direction.dot(normal) / pdf;
+        return ScatterRecord::new(incoming.continued(position, direction), weight, pdf);
+    }
+
+    fn scattering_pdf(&self, _incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        match scattered.direction.dot(normal) > 0.0 {
+            true => 1.0 / (2.0 * PI),
+            false => 0.0
+        }
+    }
+
+    fn evaluate(&self, _incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        self.albedo(u, v, position) / PI * scattered.direction.normalize().dot(normal).max(0.0)
     }
 
-    fn albedo(&self) -> Color {
-        self.color
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
     }
 }
 
 impl Diffuse {
     pub fn new(red: f32, green: f32, blue: f32) -> Diffuse {
-        Diffuse{color: Color::new(red, green, blue)}
+        Diffuse{albedo: Arc::new(SolidColor::new(red, green, blue))}
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Diffuse {
+        Diffuse{albedo}
     }
 }
 
 pub struct Lambertian {
-    color: Color
+    albedo: Arc<dyn Texture>
 }
 
 impl Material for Lambertian {
-    fn bounce(&self, rng: &mut ThreadRng, _incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        // We risk creating a near-zero vector, in which case it's normalized
-        let direction = normalize_if_tiny(normal + random_unit_vector(rng));
-        return Ray::new(position, direction);
+    // Sampling in proportion to the cosine cancels it out, leaving just the albedo as the weight
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let (direction, pdf) = sample_cosine_hemisphere(rng, normal);
+        return ScatterRecord::new(incoming.continued(position, direction), self.albedo(u, v, position), pdf);
+    }
+
+    fn scattering_pdf(&self, _incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        cosine_hemisphere_pdf(scattered.direction.normalize().dot(normal))
     }
 
-    fn albedo(&self) -> Color {
-        self.color
+    fn evaluate(&self, _incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        self.albedo(u, v, position) / PI * scattered.direction.normalize().dot(normal).max(0.0)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
     }
 }
 
 impl Lambertian {
     pub fn new(red: f32, green: f32, blue: f32) -> Lambertian {
-        Lambertian{color: Color::new(red, green, blue)}
+        Lambertian{albedo: Arc::new(SolidColor::new(red, green, blue))}
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Lambertian {
+        Lambertian{albedo}
+    }
+}
+
+/// Scatters light equally in every direction, like the particles of smoke or fog
+/// Meant for the insides of volumes, so there's no surface and the normal is ignored
+pub struct Isotropic {
+    albedo: Arc<dyn Texture>
+}
+
+impl Material for Isotropic {
+    // The phase function is the same everywhere and random_unit_vector picks directions evenly over the sphere,
+    // so it's sampled exactly, leaving just the albedo as the weight
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, _normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        return ScatterRecord::new(incoming.continued(position, random_unit_vector(rng)), self.albedo(u, v, position), UNIFORM_SPHERE_PDF);
+    }
+
+    fn scattering_pdf(&self, _incoming: &Ray, _scattered: &Ray, _position: Vec3, _normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        UNIFORM_SPHERE_PDF
+    }
+
+    // There's no cosine, light is scattered the same whichever way it arrives from
+    fn evaluate(&self, _incoming: &Ray, _scattered: &Ray, position: Vec3, _normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        self.albedo(u, v, position) * UNIFORM_SPHERE_PDF
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
+    }
+}
+
+impl Isotropic {
+    pub fn new(red: f32, green: f32, blue: f32) -> Isotropic {
+        Isotropic{albedo: Arc::new(SolidColor::new(red, green, blue))}
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Isotropic {
+        Isotropic{albedo}
+    }
+}
+
+/// Particles that scatter light mostly onwards or mostly back, like the water droplets of clouds and fog
+/// which send most light on in about the direction it was going
+pub struct HenyeyGreenstein {
+    albedo: Arc<dyn Texture>,
+    // Average cosine between the incoming and scattered directions, from -1 for all the way back
+    // through 0 for evenly in every direction to 1 for straight on
+    g: f32
+}
+
+impl Material for HenyeyGreenstein {
+    // The phase function is sampled exactly, leaving just the albedo as the weight
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, _normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let direction = sample_henyey_greenstein(rng, incoming.direction.normalize(), self.g);
+        let pdf = henyey_greenstein(incoming.direction.normalize().dot(direction), self.g);
+        return ScatterRecord::new(incoming.continued(position, direction), self.albedo(u, v, position), pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, _normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        henyey_greenstein(incoming.direction.normalize().dot(scattered.direction.normalize()), self.g)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, _normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        self.albedo(u, v, position) * henyey_greenstein(incoming.direction.normalize().dot(scattered.direction.normalize()), self.g)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
     }
 }
 
+impl HenyeyGreenstein {
+    pub fn new(red: f32, green: f32, blue: f32, g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein::textured(Arc::new(SolidColor::new(red, green, blue)), g)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, g: f32) -> HenyeyGreenstein {
+        // Perfectly on or back is a single direction, which can't be weighed against sampling lights
+        HenyeyGreenstein{albedo, g: g.clamp(-0.99, 0.99)}
+    }
+}
+
+/// A conductor with GGX microfacet reflections
 pub struct Metal {
-    color: Color,
+    albedo: Arc<dyn Texture>,
+    // Treated as GGX roughness, 0 is a perfect mirror
     fuzz: f32
 }
 
 impl Material for Metal {
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        let direction = reflect(incoming.direction, normal);
-        let fuzzed_direction = normalize_if_tiny(direction + random_unit_vector(rng) * self.fuzz);
-        return Ray::new(position, fuzzed_direction);
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
     }
 
-    fn albedo(&self) -> Color {
-        self.color
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        ggx_scatter(rng, incoming, position, normal, self.albedo(u, v, position), self.fuzz)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        ggx_pdf(incoming.direction, scattered.direction, normal, self.fuzz)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        ggx_evaluate(incoming.direction, scattered.direction, normal, self.albedo(u, v, position), self.fuzz)
     }
 }
 
 impl Metal {
     pub fn new(color: Color, fuzz: f32) -> Metal {
+        Metal::textured(Arc::new(SolidColor::from_color(color)), fuzz)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: f32) -> Metal {
         Metal{
-            color,
+            albedo,
             fuzz
         }
     }
 }
 
+/// A physically based glossy reflector, using the GGX microfacet distribution
+/// The color is the reflectance head-on, and is Fresnel weighted towards white at grazing angles
+pub struct Ggx {
+    albedo: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>
+}
+
+impl Material for Ggx {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        ggx_scatter(rng, incoming, position, normal, self.albedo(u, v, position), self.roughness.gray(u, v, position))
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> f32 {
+        ggx_pdf(incoming.direction, scattered.direction, normal, self.roughness.gray(u, v, position))
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        ggx_evaluate(incoming.direction, scattered.direction, normal, self.albedo(u, v, position), self.roughness.gray(u, v, position))
+    }
+}
+
+impl Ggx {
+    pub fn new(color: Color, roughness: f32) -> Ggx {
+        Ggx::textured(Arc::new(SolidColor::from_color(color)), roughness)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, roughness: f32) -> Ggx {
+        Ggx{
+            albedo,
+            roughness: Arc::new(SolidColor::from_color(Color::splat(roughness)))
+        }
+    }
+
+    /// Reads the roughness from the gray level of a texture instead
+    pub fn with_roughness_texture(mut self, roughness: Arc<dyn Texture>) -> Ggx {
+        self.roughness = roughness;
+        self
+    }
+}
+
+/// glTF's metallic-roughness material
+/// Metals only reflect, tinted by the base color. Non-metals get a diffuse base color under a 4% reflective coating
+/// Metallic, roughness and emission strength can vary over the surface, read from the gray level of textures
+pub struct PbrMaterial {
+    base_color: Arc<dyn Texture>,
+    metallic: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    emissive: Color,
+    emission_strength: Arc<dyn Texture>
+}
+
+// How much non-metals reflect head-on, the same value glTF uses
+const DIELECTRIC_REFLECTANCE: f32 = 0.04;
+
+impl Material for PbrMaterial {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base_color.value(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let (base_color, metallic, roughness) = self.parameters(u, v, position);
+        metallic_roughness_scatter(rng, incoming, position, normal, base_color, metallic, roughness)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> f32 {
+        let (base_color, metallic, roughness) = self.parameters(u, v, position);
+        metallic_roughness_pdf(incoming.direction, scattered.direction, normal, base_color, metallic, roughness)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        let (base_color, metallic, roughness) = self.parameters(u, v, position);
+        metallic_roughness_evaluate(incoming.direction, scattered.direction, normal, base_color, metallic, roughness)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.emissive != Color::ZERO
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.emissive * self.emission_strength.gray(u, v, position)
+    }
+}
+
+impl PbrMaterial {
+    pub fn new(base_color: Color, metallic: f32, roughness: f32) -> PbrMaterial {
+        PbrMaterial::textured(Arc::new(SolidColor::from_color(base_color)), metallic, roughness)
+    }
+
+    pub fn textured(base_color: Arc<dyn Texture>, metallic: f32, roughness: f32) -> PbrMaterial {
+        PbrMaterial{
+            base_color,
+            metallic: Arc::new(SolidColor::from_color(Color::splat(metallic))),
+            roughness: Arc::new(SolidColor::from_color(Color::splat(roughness))),
+            emissive: Color::ZERO,
+            emission_strength: Arc::new(SolidColor::new(1.0, 1.0, 1.0))
+        }
+    }
+
+    pub fn with_emissive(mut self, emissive: Color) -> PbrMaterial {
+        self.emissive = emissive;
+        self
+    }
+
+    pub fn with_metallic_texture(mut self, metallic: Arc<dyn Texture>) -> PbrMaterial {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness_texture(mut self, roughness: Arc<dyn Texture>) -> PbrMaterial {
+        self.roughness = roughness;
+        self
+    }
+
+    /// Scales the emissive color over the surface
+    pub fn with_emission_strength(mut self, strength: Arc<dyn Texture>) -> PbrMaterial {
+        self.emission_strength = strength;
+        self
+    }
+
+    // Base color, metallic and roughness at a point on the surface
+    fn parameters(&self, u: f32, v: f32, position: Vec3) -> (Color, f32, f32) {
+        let metallic = self.metallic.gray(u, v, position).clamp(0.0, 1.0);
+        let roughness = self.roughness.gray(u, v, position).clamp(0.0, 1.0);
+        return (self.base_color.value(u, v, position), metallic, roughness);
+    }
+}
+
+/// A thin glossy varnish on top of any other material, like car paint or lacquered wood
+pub struct Clearcoat<T: Material> {
+    base: T,
+    // Index of refraction of the coat
+    ior: f32,
+    roughness: f32
+}
+
+impl<T: Material> Material for Clearcoat<T> {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.albedo(u, v, position)
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        self.base.alpha(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let (facing_normal, coat_probability) = self.coat_probability(incoming, normal);
+        // Reflect off the coat with a probability equal to its reflectance, which cancels out the Fresnel weight
+        let record = match coat_probability > rng.gen::<f32>() {
+            true => ggx_scatter(rng, incoming, position, normal, Color::ONE, self.roughness),
+            false => {
+                // Otherwise the light passes through the coat, bounces off the base, and loses some more on its way out
+                let record = self.base.scatter(rng, incoming, position, normal, tangent, u, v);
+                let cos_out = record.scattered_ray.direction.normalize().dot(facing_normal).clamp(0.0, 1.0);
+                let transmitted = 1.0 - schlick(cos_out, 1.0 / self.ior);
+                let attenuation = record.attenuation * transmitted;
+                record.with_attenuation(attenuation)
+            }
+        };
+        let pdf = self.scattering_pdf(incoming, &record.scattered_ray, position, normal, tangent, u, v);
+        return record.with_pdf(pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        let (_, coat_probability) = self.coat_probability(incoming, normal);
+        let coat_pdf = ggx_pdf(incoming.direction, scattered.direction, normal, self.roughness);
+        let base_pdf = self.base.scattering_pdf(incoming, scattered, position, normal, tangent, u, v);
+        coat_probability * coat_pdf + (1.0 - coat_probability) * base_pdf
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        let (facing_normal, coat_probability) = self.coat_probability(incoming, normal);
+        let coat = ggx_evaluate(incoming.direction, scattered.direction, normal, Color::ONE, self.roughness);
+        let cos_out = scattered.direction.normalize().dot(facing_normal).clamp(0.0, 1.0);
+        let transmitted = 1.0 - schlick(cos_out, 1.0 / self.ior);
+        let base = self.base.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        coat_probability * coat + (1.0 - coat_probability) * transmitted * base
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.base.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.emit(u, v, position)
+    }
+}
+
+impl<T: Material> Clearcoat<T> {
+    pub fn new(base: T, ior: f32, roughness: f32) -> Clearcoat<T> {
+        Clearcoat{
+            base,
+            ior,
+            roughness
+        }
+    }
+
+    // The normal on the incoming side, and how likely a ray is to reflect off the coat
+    fn coat_probability(&self, incoming: &Ray, normal: Vec3) -> (Vec3, f32) {
+        let facing_normal = match incoming.direction.dot(normal) < 0.0 {
+            true => normal,
+            false => -normal
+        };
+        let cos_in = (-incoming.direction.normalize()).dot(facing_normal).clamp(0.0, 1.0);
+        return (facing_normal, schlick(cos_in, 1.0 / self.ior));
+    }
+}
+
+/// How much of the second material a Mix uses
+pub enum MixFactor {
+    Constant(f32),
+    // Looked up by the hit position, e.g. for patches of rust on metal
+    Mask(Box<dyn Fn(Vec3) -> f32>)
+}
+
+/// Blends two materials, picking one of them at random for every bounce according to the factor
+pub struct Mix<A: Material, B: Material> {
+    first: A,
+    second: B,
+    factor: MixFactor
+}
+
+impl<A: Material, B: Material> Material for Mix<A, B> {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.first.albedo(u, v, position).lerp(self.second.albedo(u, v, position), self.factor_at(position))
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        let factor = self.factor_at(position);
+        self.first.alpha(u, v, position) * (1.0 - factor) + self.second.alpha(u, v, position) * factor
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        // Choosing with the mix probability means the weights need no correction
+        let record = match rng.gen::<f32>() < self.factor_at(position) {
+            true => self.second.scatter(rng, incoming, position, normal, tangent, u, v),
+            false => self.first.scatter(rng, incoming, position, normal, tangent, u, v)
+        };
+        let pdf = self.scattering_pdf(incoming, &record.scattered_ray, position, normal, tangent, u, v);
+        return record.with_pdf(pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        let first = self.first.scattering_pdf(incoming, scattered, position, normal, tangent, u, v);
+        let second = self.second.scattering_pdf(incoming, scattered, position, normal, tangent, u, v);
+        let factor = self.factor_at(position);
+        first * (1.0 - factor) + second * factor
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        let first = self.first.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        let second = self.second.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        first.lerp(second, self.factor_at(position))
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.first.is_emitter() || self.second.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.first.emit(u, v, position).lerp(self.second.emit(u, v, position), self.factor_at(position))
+    }
+}
+
+impl<A: Material, B: Material> Mix<A, B> {
+    pub fn new(first: A, second: B, factor: f32) -> Mix<A, B> {
+        Mix{
+            first,
+            second,
+            factor: MixFactor::Constant(factor.clamp(0.0, 1.0))
+        }
+    }
+
+    /// Mixes by a mask from hit position to factor, where 0 is only the first material and 1 only the second
+    pub fn masked(first: A, second: B, mask: impl Fn(Vec3) -> f32 + 'static) -> Mix<A, B> {
+        Mix{
+            first,
+            second,
+            factor: MixFactor::Mask(Box::new(mask))
+        }
+    }
+
+    fn factor_at(&self, position: Vec3) -> f32 {
+        match &self.factor {
+            MixFactor::Constant(factor) => *factor,
+            MixFactor::Mask(mask) => mask(position).clamp(0.0, 1.0)
+        }
+    }
+}
+
+/// An invisible diffuse surface that only shows the shadows and reflections other objects cast on it
+/// Used to composite objects into a photograph, by placing it where the ground is in the background
+pub struct ShadowCatcher;
+
+impl Material for ShadowCatcher {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> ScatterRecord {
+        let (direction, pdf) = sample_cosine_hemisphere(rng, facing(incoming, normal));
+        return ScatterRecord::new(incoming.continued(position, direction), Color::ONE, pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        cosine_hemisphere_pdf(scattered.direction.normalize().dot(facing(incoming, normal)))
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> Color {
+        Color::splat(scattered.direction.normalize().dot(facing(incoming, normal)).max(0.0) / PI)
+    }
+
+    fn albedo(&self, _u: f32, _v: f32, _position: Vec3) -> Color {
+        Color::ONE
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        true
+    }
+}
+
+/// A thin diffuse sheet that lets some light through to the other side, like paper, leaves or lampshades
+pub struct ThinTranslucent {
+    albedo: Arc<dyn Texture>,
+    // The proportion of bounces that go through the sheet instead of back
+    transmission: f32
+}
+
+impl Material for ThinTranslucent {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let facing_normal = facing(incoming, normal);
+        // The sheet has no thickness, so transmitted light simply scatters diffusely out of the back side
+        let side = match rng.gen::<f32>() < self.transmission {
+            true => -facing_normal,
+            false => facing_normal
+        };
+        let (direction, _pdf) = sample_cosine_hemisphere(rng, side);
+        let scattered_ray = incoming.continued(position, direction);
+        let pdf = self.scattering_pdf(incoming, &scattered_ray, position, normal, tangent, u, v);
+        return ScatterRecord::new(scattered_ray, self.albedo(u, v, position), pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        let cosine = scattered.direction.normalize().dot(facing(incoming, normal));
+        match cosine > 0.0 {
+            true => (1.0 - self.transmission) * cosine_hemisphere_pdf(cosine),
+            false => self.transmission * cosine_hemisphere_pdf(-cosine)
+        }
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        let cosine = scattered.direction.normalize().dot(facing(incoming, normal));
+        let side = match cosine > 0.0 {
+            true => 1.0 - self.transmission,
+            false => self.transmission
+        };
+        self.albedo(u, v, position) * side * cosine.abs() / PI
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
+    }
+}
+
+impl ThinTranslucent {
+    pub fn new(color: Color, transmission: f32) -> ThinTranslucent {
+        ThinTranslucent::textured(Arc::new(SolidColor::from_color(color)), transmission)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, transmission: f32) -> ThinTranslucent {
+        ThinTranslucent{
+            albedo,
+            transmission: transmission.clamp(0.0, 1.0)
+        }
+    }
+}
+
+// Wavelengths in nanometers standing in for the red, green and blue channels
+pub const RGB_WAVELENGTHS: [f32; 3] = [650.0, 532.0, 450.0];
+
+/// A thin transparent film on top of another material, like soap, oil or a beetle shell
+/// Light reflected off the top and bottom of the film interferes, giving colors that shift with the angle
+pub struct ThinFilm<T: Material> {
+    base: T,
+    // Film thickness in nanometers
+    thickness: f32,
+    film_ior: f32,
+    // Index of refraction of what's under the film, high values behave like metal
+    base_ior: f32
+}
+
+impl<T: Material> Material for ThinFilm<T> {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.albedo(u, v, position)
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        self.base.alpha(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let facing_normal = facing(incoming, normal);
+        let unit_direction = incoming.direction.normalize();
+        let (reflectance, probability) = self.reflect_probability(incoming, normal);
+        // Reflect off the film or pass through to the base in proportion to the average reflectance
+        if rng.gen::<f32>() < probability {
+            let direction = reflect(unit_direction, facing_normal);
+            return ScatterRecord::specular(incoming.continued(position, direction), reflectance / probability);
+        }
+        let record = self.base.scatter(rng, incoming, position, normal, tangent, u, v);
+        let attenuation = record.attenuation * (Color::ONE - reflectance) / (1.0 - probability);
+        let pdf = record.pdf * (1.0 - probability);
+        return record.with_attenuation(attenuation).with_pdf(pdf);
+    }
+
+    // The film reflects like a mirror, so only the base has a density
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        let (_, probability) = self.reflect_probability(incoming, normal);
+        (1.0 - probability) * self.base.scattering_pdf(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        let (reflectance, _) = self.reflect_probability(incoming, normal);
+        (Color::ONE - reflectance) * self.base.evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.base.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.emit(u, v, position)
+    }
+}
+
+impl<T: Material> ThinFilm<T> {
+    pub fn new(base: T, thickness: f32, film_ior: f32, base_ior: f32) -> ThinFilm<T> {
+        ThinFilm{
+            base,
+            thickness,
+            film_ior,
+            base_ior
+        }
+    }
+
+    // The film's reflectance for a ray, and the probability of reflecting off it instead of reaching the base
+    fn reflect_probability(&self, incoming: &Ray, normal: Vec3) -> (Color, f32) {
+        let cos_theta = (-incoming.direction.normalize()).dot(facing(incoming, normal)).clamp(0.0, 1.0);
+        let reflectance = self.reflectance(cos_theta);
+        return (reflectance, (reflectance.dot(Color::ONE) / 3.0).clamp(0.001, 0.999));
+    }
+
+    /// Airy reflectance of the film for each channel, coming from air at the given angle
+    fn reflectance(&self, cos_theta: f32) -> Color {
+        let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
+        let cos_film = (1.0 - (sin_theta / self.film_ior).powi(2)).max(0.0).sqrt();
+        // Fresnel amplitude coefficients at the top and bottom of the film
+        let top = (cos_theta - self.film_ior * cos_film) / (cos_theta + self.film_ior * cos_film);
+        let bottom = match 1.0 - (sin_theta / self.base_ior).powi(2) {
+            // Total internal reflection at the bottom
+            cos_squared if cos_squared <= 0.0 => 1.0,
+            cos_squared => {
+                let cos_base = cos_squared.sqrt();
+                (self.film_ior * cos_film - self.base_ior * cos_base) / (self.film_ior * cos_film + self.base_ior * cos_base)
+            }
+        };
+        let channel = |wavelength: f32| {
+            // Phase difference between the two reflections
+            let phase = 4.0 * PI * self.film_ior * self.thickness * cos_film / wavelength;
+            let interference = 2.0 * top * bottom * phase.cos();
+            (top.powi(2) + bottom.powi(2) + interference) / (1.0 + (top * bottom).powi(2) + interference)
+        };
+        return Color::new(channel(RGB_WAVELENGTHS[0]), channel(RGB_WAVELENGTHS[1]), channel(RGB_WAVELENGTHS[2]))
+            .clamp(Color::ZERO, Color::ONE);
+    }
+}
+
+/// Total power of a light, either radiometric or as perceived brightness
+/// Watts are converted as if all the light was at the wavelength the eye is most sensitive to
+#[derive(Clone, Copy)]
+pub enum LightPower {
+    Watts(f32),
+    Lumens(f32)
+}
+
+// Lumens per watt of light at the peak of the eye's sensitivity, which the photometric units are defined by
+const LUMINOUS_EFFICACY: f32 = 683.0;
+// Contributions of linear red, green and blue to luminance
+pub const LUMINANCE_WEIGHTS: Vec3 = Vec3::new(0.2126, 0.7152, 0.0722);
+
+/// Emits light equally in all directions. The light can come from a texture, for things like screens or stained glass
 pub struct DiffuseLight {
-    light: Color
+    light: Arc<dyn Texture>,
+    // Multiplies the texture, as image texels only go up to 1
+    strength: f32
 }
 
 impl Material for DiffuseLight {
-    fn albedo(&self) -> Color {
+    fn albedo(&self, _u: f32, _v: f32, _position: Vec3) -> Color {
         Vec3::new(1.0, 1.0, 1.0)
     }
 
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        let direction = random_on_hemisphere(rng, &normal);
-        return Ray::new(position, direction);
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> ScatterRecord {
+        let (direction, pdf) = sample_cosine_hemisphere(rng, normal);
+        return ScatterRecord::new(incoming.continued(position, direction), Color::ONE, pdf);
+    }
+
+    fn scattering_pdf(&self, _incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        cosine_hemisphere_pdf(scattered.direction.normalize().dot(normal))
+    }
+
+    fn evaluate(&self, _incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> Color {
+        Color::splat(scattered.direction.normalize().dot(normal).max(0.0) / PI)
     }
 
     fn is_emitter(&self) -> bool {
         true
     }
 
-    fn emit(&self) -> Color {
-        self.light
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.light.value(u, v, position) * self.strength
     }
 }
 
 impl DiffuseLight {
     pub fn new(red: f32, green: f32, blue: f32) -> DiffuseLight {
-        DiffuseLight{light: Color::new(red, green, blue)}
+        DiffuseLight::textured(Arc::new(SolidColor::new(red, green, blue)), 1.0)
+    }
+
+    pub fn textured(light: Arc<dyn Texture>, strength: f32) -> DiffuseLight {
+        DiffuseLight{light, strength}
+    }
+
+    /// A light sending out a total power over its surface area, so resizing it doesn't change how bright the scene is
+    /// The color only sets the hue, as it's scaled to a luminance of 1
+    /// The emitted luminance is in cd/m², assuming scene units are meters, to go with the camera's exposure
+    pub fn with_power(color: Color, power: LightPower, area: f32) -> DiffuseLight {
+        let lumens = match power {
+            LightPower::Watts(watts) => watts * LUMINOUS_EFFICACY,
+            LightPower::Lumens(lumens) => lumens
+        };
+        // A diffuse emitter of luminance L sends out pi * L per unit area
+        let luminance = lumens / (PI * area);
+        DiffuseLight::textured(Arc::new(SolidColor::from_color(color / color.dot(LUMINANCE_WEIGHTS))), luminance)
+    }
+
+    /// A light glowing with the color of a black body at a temperature in kelvin, like 3200 for tungsten
+    pub fn blackbody(kelvin: f32, strength: f32) -> DiffuseLight {
+        DiffuseLight::textured(Arc::new(SolidColor::from_color(spectrum::blackbody(kelvin))), strength)
+    }
+}
+
+/// Glass, water and other clear materials that both reflect and refract
+pub struct Dielectric {
+    // Index of refraction, relative to the surrounding air
+    ior: f32,
+    // How much of each channel is absorbed per unit of distance traveled inside
+    absorption: Color,
+    // How much of each channel is scattered per unit of distance, only used by Subsurface
+    scattering: Color,
+    // Cauchy's B coefficient, 0 if all wavelengths refract the same
+    dispersion: f32
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> ScatterRecord {
+        if self.dispersion == 0.0 || incoming.channel.is_some() {
+            return ScatterRecord::specular(self.bounce(rng, incoming, position, normal), Color::ONE);
+        }
+        // Every wavelength bends differently, so continue with a single randomly picked channel
+        // It's only carrying a third of the paths, so it gets three times the weight
+        let channel = rng.gen_range(0..3);
+        let mut single_channel = incoming.clone();
+        single_channel.channel = Some(channel);
+        let mut weight = Color::ZERO;
+        weight[channel] = 3.0;
+        return ScatterRecord::specular(self.bounce(rng, &single_channel, position, normal), weight);
+    }
+
+    fn albedo(&self, _u: f32, _v: f32, _position: Vec3) -> Color {
+        Color::ONE
+    }
+}
+
+impl Dielectric {
+    // Reflects or refracts the ray, keeping track of which media it ends up inside
+    fn bounce(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
+        let ior = dispersed_ior(self.ior, self.dispersion, incoming.channel);
+        // The normal points outwards, so a ray along it is on its way out of the material
+        let entering = incoming.direction.dot(normal) < 0.0;
+        // Whatever is on the other side of the surface once the ray passes through
+        let mut refracted_media = incoming.media.clone();
+        let (normal, ratio) = match entering {
+            true => {
+                refracted_media.push(self.medium());
+                (normal, incoming.current_ior() / ior)
+            }
+            false => {
+                // Leave this material even if the ray entered something else inside it afterwards
+                if let Some(index) = refracted_media.iter().rposition(|medium| medium.id == self.id()) {
+                    refracted_media.remove(index);
+                }
+                let outside_ior = refracted_media.last().map_or(1.0, |medium| medium.ior_at(incoming.channel));
+                (-normal, ior / outside_ior)
+            }
+        };
+        let unit_direction = incoming.direction.normalize();
+        let cos_theta = (-unit_direction).dot(normal).min(1.0);
+        let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
+        // Snell's law has no solution past the critical angle, so all light is reflected
+        let total_internal_reflection = ratio * sin_theta > 1.0;
+        if total_internal_reflection || schlick(cos_theta, ratio) > rng.gen::<f32>() {
+            return incoming.continued(position, reflect(unit_direction, normal));
+        }
+        let mut refracted = incoming.continued(position, refract(unit_direction, normal, ratio));
+        refracted.media = refracted_media;
+        return refracted;
+    }
+
+    pub fn new(ior: f32) -> Dielectric {
+        Dielectric{ior, absorption: Color::ZERO, scattering: Color::ZERO, dispersion: 0.0}
+    }
+
+    /// Colored glass, where white light turns into the given color after traveling the given distance inside
+    pub fn tinted(ior: f32, color: Color, distance: f32) -> Dielectric {
+        // Solve color = e^(-absorption * distance), keeping fully black channels finite
+        let color = color.clamp(Color::splat(0.0001), Color::ONE);
+        let absorption = Color::new(-color.x.ln(), -color.y.ln(), -color.z.ln()) / distance;
+        Dielectric{ior, absorption, scattering: Color::ZERO, dispersion: 0.0}
+    }
+
+    /// Glass that splits white light into a rainbow, like prisms and diamonds
+    /// The Abbe number says how little it disperses: about 64 for window glass, 55 for diamond, 30 for flint glass
+    pub fn dispersive(ior: f32, abbe_number: f32) -> Dielectric {
+        // The Abbe number is (ior - 1) / (ior at 486.1nm - ior at 656.3nm), solve for B in Cauchy's equation
+        let dispersion = (ior - 1.0) / abbe_number / (1.0 / 486.1_f32.powi(2) - 1.0 / 656.3_f32.powi(2));
+        Dielectric{ior, absorption: Color::ZERO, scattering: Color::ZERO, dispersion}
+    }
+
+    // Every material lives inside exactly one object, so its address tells media apart
+    fn id(&self) -> usize {
+        self as *const Dielectric as usize
+    }
+
+    fn medium(&self) -> Medium {
+        Medium {
+            id: self.id(),
+            ior: self.ior,
+            absorption: self.absorption,
+            scattering: self.scattering,
+            dispersion: self.dispersion
+        }
+    }
+}
+
+/// Translucent materials like skin, wax and marble, where light enters, wanders around inside, and leaves somewhere else
+/// The surface refracts like glass, and the inside is a scattering medium that is random walked through
+pub struct Subsurface {
+    boundary: Dielectric
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        self.boundary.scatter(rng, incoming, position, normal, tangent, u, v)
+    }
+
+    fn albedo(&self, _u: f32, _v: f32, _position: Vec3) -> Color {
+        Color::ONE
+    }
+}
+
+impl Subsurface {
+    /// albedo is the proportion of light that survives each bounce inside, per channel
+    /// mean_free_path is the average distance light travels between bounces, per channel
+    pub fn new(ior: f32, albedo: Color, mean_free_path: Color) -> Subsurface {
+        let extinction = Color::ONE / mean_free_path.max(Color::splat(0.00001));
+        let scattering = extinction * albedo.clamp(Color::ZERO, Color::ONE);
+        Subsurface{
+            boundary: Dielectric{ior, absorption: extinction - scattering, scattering, dispersion: 0.0}
+        }
+    }
+}
+
+/// A diffuse base under a clear glossy coating, like plastic or paint
+/// Light is reflected off the coating by the Fresnel factor, and the rest is scattered by the base
+pub struct Plastic {
+    albedo: Arc<dyn Texture>,
+    // Index of refraction of the coating
+    ior: f32,
+    // How blurry the reflections are, the GGX roughness of the coating
+    roughness: f32
+}
+
+impl Material for Plastic {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.albedo.value(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        // Work on the side of the surface the ray comes from
+        let facing_normal = facing(incoming, normal);
+        // Pick the coating with a probability equal to its reflectance, which cancels out the Fresnel weight
+        let coat_probability = self.coat_probability(incoming, facing_normal);
+        let record = match coat_probability > rng.gen::<f32>() {
+            true => ggx_scatter(rng, incoming, position, normal, Color::ONE, self.roughness),
+            false => {
+                let (direction, _pdf) = sample_cosine_hemisphere(rng, facing_normal);
+                ScatterRecord::new(incoming.continued(position, direction), self.albedo(u, v, position), 0.0)
+            }
+        };
+        // Either lobe could have picked the direction
+        let pdf = self.scattering_pdf(incoming, &record.scattered_ray, position, normal, tangent, u, v);
+        return record.with_pdf(pdf);
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, _position: Vec3, normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> f32 {
+        let facing_normal = facing(incoming, normal);
+        let coat_probability = self.coat_probability(incoming, facing_normal);
+        let coat_pdf = ggx_pdf(incoming.direction, scattered.direction, normal, self.roughness);
+        let base_pdf = cosine_hemisphere_pdf(scattered.direction.normalize().dot(facing_normal));
+        coat_probability * coat_pdf + (1.0 - coat_probability) * base_pdf
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        let facing_normal = facing(incoming, normal);
+        let coat_probability = self.coat_probability(incoming, facing_normal);
+        let coat = ggx_evaluate(incoming.direction, scattered.direction, normal, Color::ONE, self.roughness);
+        let cosine = scattered.direction.normalize().dot(facing_normal).max(0.0);
+        coat_probability * coat + (1.0 - coat_probability) * self.albedo(u, v, position) * cosine / PI
+    }
+}
+
+impl Plastic {
+    pub fn new(color: Color, ior: f32, roughness: f32) -> Plastic {
+        Plastic::textured(Arc::new(SolidColor::from_color(color)), ior, roughness)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, ior: f32, roughness: f32) -> Plastic {
+        Plastic{
+            albedo,
+            ior,
+            roughness
+        }
+    }
+
+    // How likely a ray is to reflect off the coating, given the normal on its side
+    fn coat_probability(&self, incoming: &Ray, facing_normal: Vec3) -> f32 {
+        let cos_theta = (-incoming.direction.normalize()).dot(facing_normal).min(1.0);
+        schlick(cos_theta, 1.0 / self.ior)
+    }
+}
+
+/// Fakes surface detail on any other material by bending its normal with a tangent-space normal map
+/// The map's red, green and blue go from -1 to 1 along the tangent, the bitangent and the normal
+/// Load it with ImageTexture::load_data so the values aren't treated as sRGB colors
+pub struct NormalMapped<T: Material> {
+    base: T,
+    map: Arc<dyn Texture>,
+    // Scales the bend, 0 leaves the normal untouched
+    pub strength: f32
+}
+
+impl<T: Material> Material for NormalMapped<T> {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.albedo(u, v, position)
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        self.base.alpha(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.scatter(rng, incoming, position, shading_normal, tangent, u, v)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.scattering_pdf(incoming, scattered, position, shading_normal, tangent, u, v)
     }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.evaluate(incoming, scattered, position, shading_normal, tangent, u, v)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.base.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.emit(u, v, position)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.base.is_shadow_catcher()
+    }
+}
+
+impl<T: Material> NormalMapped<T> {
+    pub fn new(base: T, map: Arc<dyn Texture>) -> NormalMapped<T> {
+        NormalMapped{
+            base,
+            map,
+            strength: 1.0
+        }
+    }
+
+    fn shading_normal(&self, normal: Vec3, tangent: Vec3, u: f32, v: f32, position: Vec3) -> Vec3 {
+        // Make sure the tangent is perpendicular to the normal, or pick one if it's parallel
+        let tangent = (tangent - normal * normal.dot(tangent)).try_normalize().unwrap_or(microfacet::tangent_frame(normal).0);
+        let bitangent = normal.cross(tangent);
+        let local = self.map.value(u, v, position) * 2.0 - Color::ONE;
+        let bent = tangent * local.x * self.strength + bitangent * local.y * self.strength + normal * local.z;
+        return bent.try_normalize().unwrap_or(normal);
+    }
+}
+
+/// Fakes surface detail on any other material by tilting its normal down the slopes of a grayscale height texture
+/// Cheaper to set up than a normal map, any texture works as a height map, including noise
+pub struct BumpMapped<T: Material> {
+    base: T,
+    height: Arc<dyn Texture>,
+    // Scales the slopes, 0 leaves the normal untouched
+    pub strength: f32,
+    // How far apart heights are read to find the slope, in surface coordinates,
+    // or in world units for textures defined in space
+    pub step: f32
 }
 
-fn random_unit_vector(rng: &mut ThreadRng) -> Vec3 {
-    Vec3::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0)).normalize()
+impl<T: Material> Material for BumpMapped<T> {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.albedo(u, v, position)
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        self.base.alpha(u, v, position)
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.scatter(rng, incoming, position, shading_normal, tangent, u, v)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.scattering_pdf(incoming, scattered, position, shading_normal, tangent, u, v)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        let shading_normal = self.shading_normal(normal, tangent, u, v, position);
+        self.base.evaluate(incoming, scattered, position, shading_normal, tangent, u, v)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.base.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.emit(u, v, position)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.base.is_shadow_catcher()
+    }
+}
+
+impl<T: Material> BumpMapped<T> {
+    pub fn new(base: T, height: Arc<dyn Texture>, strength: f32) -> BumpMapped<T> {
+        BumpMapped{
+            base,
+            height,
+            strength,
+            step: 0.001
+        }
+    }
+
+    fn shading_normal(&self, normal: Vec3, tangent: Vec3, u: f32, v: f32, position: Vec3) -> Vec3 {
+        let tangent = (tangent - normal * normal.dot(tangent)).try_normalize().unwrap_or(microfacet::tangent_frame(normal).0);
+        let bitangent = normal.cross(tangent);
+        let height = |u: f32, v: f32, position: Vec3| self.height.gray(u, v, position);
+        let here = height(u, v, position);
+        let slope_u = (height(u + self.step, v, position + tangent * self.step) - here) / self.step;
+        let slope_v = (height(u, v + self.step, position + bitangent * self.step) - here) / self.step;
+        let bent = normal - self.strength * (slope_u * tangent + slope_v * bitangent);
+        return bent.try_normalize().unwrap_or(normal);
+    }
+}
+
+/// Cuts holes in any other material where a grayscale mask texture is dark, for things like leaves on flat cards
+/// Rays go straight through the holes without counting as a hit, so the cut out parts cast no shadow either
+pub struct AlphaMasked<T: Material> {
+    base: T,
+    mask: Arc<dyn Texture>
+}
+
+impl<T: Material> Material for AlphaMasked<T> {
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        self.base.scatter(rng, incoming, position, normal, tangent, u, v)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> f32 {
+        self.base.scattering_pdf(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> Color {
+        self.base.evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.albedo(u, v, position)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.base.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3) -> Color {
+        self.base.emit(u, v, position)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.base.is_shadow_catcher()
+    }
+
+    fn alpha(&self, u: f32, v: f32, position: Vec3) -> f32 {
+        self.base.alpha(u, v, position) * self.mask.gray(u, v, position).clamp(0.0, 1.0)
+    }
 }
 
-fn random_on_hemisphere(rng: &mut ThreadRng, normal: &Vec3) -> Vec3 {
-    let vector = random_unit_vector(rng);
-    match vector.dot(*normal) > 0.0 {
-        true => vector,
-        false => -vector
+impl<T: Material> AlphaMasked<T> {
+    pub fn new(base: T, mask: Arc<dyn Texture>) -> AlphaMasked<T> {
+        AlphaMasked{
+            base,
+            mask
+        }
     }
 }
 
+/// The density of a direction picked evenly over the whole sphere, per steradian
+pub const UNIFORM_SPHERE_PDF: f32 = 1.0 / (4.0 * PI);
+
+/// A direction picked evenly over the whole sphere, with density UNIFORM_SPHERE_PDF
+pub fn random_unit_vector<R: Rng>(rng: &mut R) -> Vec3 {
+    // Heights are spread evenly on a sphere (Archimedes' hat-box theorem), so only the angle around is left
+    let z = 1.0 - 2.0 * rng.gen::<f32>();
+    let radius = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f32>();
+    return Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+}
+
+/// The Henyey-Greenstein phase function, the density of light going on at a cosine to the way it was going
+pub fn henyey_greenstein(cosine: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cosine;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(f32::EPSILON).sqrt())
+}
+
+/// A direction picked in proportion to the Henyey-Greenstein phase function around the way light was going
+pub fn sample_henyey_greenstein(rng: &mut SamplerRng, direction: Vec3, g: f32) -> Vec3 {
+    let (r1, r2) = (rng.gen::<f32>(), rng.gen::<f32>());
+    // Inverting the phase function's distribution of cosines, which is uniform when it's even
+    let cos_theta = match g.abs() < 1e-3 {
+        true => 1.0 - 2.0 * r1,
+        false => {
+            let ratio = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+            ((1.0 + g * g - ratio * ratio) / (2.0 * g)).clamp(-1.0, 1.0)
+        }
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * r2;
+    return Onb::new(direction).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+}
+
+/// A direction around the normal with density proportional to the cosine to it, and that density
+pub fn sample_cosine_hemisphere(rng: &mut SamplerRng, normal: Vec3) -> (Vec3, f32) {
+    // Uniform points on the disk, projected up onto the hemisphere (Malley's method)
+    let (r1, r2) = (rng.gen::<f32>(), rng.gen::<f32>());
+    let (radius, phi) = (r1.sqrt(), 2.0 * PI * r2);
+    let z = (1.0 - r1).sqrt();
+    let local = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+    return (Onb::new(normal).to_world(local), cosine_hemisphere_pdf(z));
+}
+
+/// The density of sample_cosine_hemisphere for a direction at the given cosine to the normal
+pub fn cosine_hemisphere_pdf(cosine: f32) -> f32 {
+    cosine.max(0.0) / PI
+}
+
+/// A direction evenly distributed over the hemisphere around the normal, and its density
+pub fn sample_uniform_hemisphere(rng: &mut SamplerRng, normal: Vec3) -> (Vec3, f32) {
+    let (r1, r2) = (rng.gen::<f32>(), rng.gen::<f32>());
+    let z = r1;
+    let radius = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * r2;
+    let local = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+    return (Onb::new(normal).to_world(local), 1.0 / (2.0 * PI));
+}
+
 fn reflect(incoming: Vec3, normal: Vec3) -> Vec3 {
     // Since the incoming vector is not normalized, scale the normal to use in reflection
     let scaled_normal = -(incoming.dot(normal) * normal); 
     return incoming + 2.0 * scaled_normal;
 }
 
-/// If a vector is very close to 0, normalize to avoid funny errors
-fn normalize_if_tiny(vec: Vec3) -> Vec3 {
-    let interval = Interval::new(-0.000001, 0.000001);
-    if interval.contains(vec.x) && interval.contains(vec.y) && interval.contains(vec.z) {
-        return vec.normalize();
+/// Bends a unit vector passing through a surface according to Snell's law
+/// ratio is the index of refraction on the incoming side divided by the one on the outgoing side
+fn refract(unit_direction: Vec3, normal: Vec3, ratio: f32) -> Vec3 {
+    let cos_theta = (-unit_direction).dot(normal).min(1.0);
+    let perpendicular = ratio * (unit_direction + cos_theta * normal);
+    let parallel = -(1.0 - perpendicular.length_squared()).abs().sqrt() * normal;
+    return perpendicular + parallel;
+}
+
+/// Schlick's approximation of the Fresnel reflectance at a given incidence angle
+pub fn schlick(cosine: f32, ratio: f32) -> f32 {
+    let r0 = ((1.0 - ratio) / (1.0 + ratio)).powi(2);
+    return r0 + (1.0 - r0) * (1.0 - cosine).powi(5);
+}
+
+/// Reflects off a GGX surface by sampling the visible microfacet normals
+/// The attenuation is the BRDF times the cosine divided by the sampling density
+fn ggx_scatter(rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, color: Color, roughness: f32) -> ScatterRecord {
+    let normal = facing(incoming, normal);
+    let alpha = microfacet::roughness_to_alpha(roughness);
+    let view = microfacet::to_local(-incoming.direction.normalize(), normal);
+    let microfacet_normal = microfacet::sample_visible_normal(view, alpha, rng.gen(), rng.gen());
+    let light = reflect(-view, microfacet_normal);
+    let scattered_ray = incoming.continued(position, microfacet::from_local(light, normal));
+    let pdf = microfacet::reflection_pdf(view, light, alpha);
+    // Reflected into the surface, which is where the microfacet model loses energy
+    if light.z <= 0.0 {
+        return ScatterRecord::new(scattered_ray, Color::ZERO, pdf);
+    }
+    // Most terms cancel out against the sampling density, leaving Fresnel and the shadowing ratio
+    let fresnel = schlick_conductor(color, view.dot(microfacet_normal).max(0.0));
+    let weight = fresnel * microfacet::masking_shadowing(view, light, alpha) / microfacet::masking(view, alpha);
+    return ScatterRecord::new(scattered_ray, weight, pdf);
+}
+
+/// The density ggx_scatter picks the scattered direction with
+fn ggx_pdf(incoming: Vec3, scattered: Vec3, normal: Vec3, roughness: f32) -> f32 {
+    let normal = match incoming.dot(normal) < 0.0 {
+        true => normal,
+        false => -normal
+    };
+    let view = microfacet::to_local(-incoming.normalize(), normal);
+    let light = microfacet::to_local(scattered.normalize(), normal);
+    microfacet::reflection_pdf(view, light, microfacet::roughness_to_alpha(roughness))
+}
+
+/// The GGX BRDF times the cosine, for light reflecting from scattered back along incoming
+fn ggx_evaluate(incoming: Vec3, scattered: Vec3, normal: Vec3, color: Color, roughness: f32) -> Color {
+    let normal = match incoming.dot(normal) < 0.0 {
+        true => normal,
+        false => -normal
+    };
+    let alpha = microfacet::roughness_to_alpha(roughness);
+    let view = microfacet::to_local(-incoming.normalize(), normal);
+    let light = microfacet::to_local(scattered.normalize(), normal);
+    if view.z <= 0.0 || light.z <= 0.0 {
+        return Color::ZERO;
     }
-    return vec;
-}
\ No newline at end of file
+    let half = (view + light).normalize();
+    let fresnel = schlick_conductor(color, view.dot(half).max(0.0));
+    return fresnel * microfacet::distribution(half, alpha) * microfacet::masking_shadowing(view, light, alpha) / (4.0 * view.z);
+}
+
+/// Bounce off glTF's metallic-roughness model, picking the specular or the diffuse lobe in proportion to how much they reflect
+pub fn metallic_roughness_scatter(rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, base_color: Color, metallic: f32, roughness: f32) -> ScatterRecord {
+    let facing_normal = facing(incoming, normal);
+    let lobes = MetallicRoughnessLobes::new(incoming.direction, facing_normal, base_color, metallic);
+    let record = match rng.gen::<f32>() < lobes.specular_probability {
+        true => {
+            let record = ggx_scatter(rng, incoming, position, normal, lobes.reflectance, roughness);
+            let attenuation = record.attenuation / lobes.specular_probability;
+            record.with_attenuation(attenuation)
+        },
+        false => {
+            // Whatever isn't reflected by the coating reaches the diffuse base
+            let (direction, _pdf) = sample_cosine_hemisphere(rng, facing_normal);
+            let weight = (Color::ONE - lobes.fresnel) * lobes.diffuse_color / (1.0 - lobes.specular_probability);
+            ScatterRecord::new(incoming.continued(position, direction), weight, 0.0)
+        }
+    };
+    let pdf = metallic_roughness_pdf(incoming.direction, record.scattered_ray.direction, normal, base_color, metallic, roughness);
+    return record.with_pdf(pdf);
+}
+
+/// The density metallic_roughness_scatter picks the scattered direction with, from either lobe
+pub fn metallic_roughness_pdf(incoming: Vec3, scattered: Vec3, normal: Vec3, base_color: Color, metallic: f32, roughness: f32) -> f32 {
+    let facing_normal = match incoming.dot(normal) < 0.0 {
+        true => normal,
+        false => -normal
+    };
+    let lobes = MetallicRoughnessLobes::new(incoming, facing_normal, base_color, metallic);
+    let specular = ggx_pdf(incoming, scattered, normal, roughness);
+    let diffuse = cosine_hemisphere_pdf(scattered.normalize().dot(facing_normal));
+    lobes.specular_probability * specular + (1.0 - lobes.specular_probability) * diffuse
+}
+
+/// The metallic-roughness BSDF times the cosine, both lobes together
+pub fn metallic_roughness_evaluate(incoming: Vec3, scattered: Vec3, normal: Vec3, base_color: Color, metallic: f32, roughness: f32) -> Color {
+    let facing_normal = match incoming.dot(normal) < 0.0 {
+        true => normal,
+        false => -normal
+    };
+    let lobes = MetallicRoughnessLobes::new(incoming, facing_normal, base_color, metallic);
+    let specular = ggx_evaluate(incoming, scattered, normal, lobes.reflectance, roughness);
+    let cosine = scattered.normalize().dot(facing_normal).max(0.0);
+    specular + (Color::ONE - lobes.fresnel) * lobes.diffuse_color * cosine / PI
+}
+
+// How the metallic-roughness model splits light between its lobes, seen from an incoming direction
+struct MetallicRoughnessLobes {
+    reflectance: Color,
+    diffuse_color: Color,
+    fresnel: Color,
+    specular_probability: f32
+}
+
+impl MetallicRoughnessLobes {
+    fn new(incoming: Vec3, facing_normal: Vec3, base_color: Color, metallic: f32) -> MetallicRoughnessLobes {
+        let reflectance = Color::splat(DIELECTRIC_REFLECTANCE).lerp(base_color, metallic);
+        let diffuse_color = base_color * (1.0 - metallic);
+        // Estimate how much each lobe contributes from this angle, and pick one in proportion
+        let cos_theta = (-incoming.normalize()).dot(facing_normal).clamp(0.0, 1.0);
+        let fresnel = schlick_conductor(reflectance, cos_theta);
+        let specular_weight = fresnel.dot(Color::ONE);
+        let diffuse_weight = ((Color::ONE - fresnel) * diffuse_color).dot(Color::ONE);
+        let specular_probability = match specular_weight + diffuse_weight > 0.0 {
+            true => specular_weight / (specular_weight + diffuse_weight),
+            false => 1.0
+        };
+        MetallicRoughnessLobes { reflectance, diffuse_color, fresnel, specular_probability }
+    }
+}
+
+// The normal on the side of the surface the ray comes from
+fn facing(incoming: &Ray, normal: Vec3) -> Vec3 {
+    match incoming.direction.dot(normal) < 0.0 {
+        true => normal,
+        false => -normal
+    }
+}
+
+/// Schlick's approximation for conductors, where the color is the reflectance head-on
+fn schlick_conductor(color: Color, cosine: f32) -> Color {
+    return color + (Color::ONE - color) * (1.0 - cosine).powi(5);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::sampler::StratifiedSampler;
+
+    // Equal caps around an axis and around a cube diagonal should catch as many directions,
+    // a quarter of the sphere's area each for caps reaching a cosine of 0.5
+    #[test]
+    fn random_unit_vector_is_uniform() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let count = 200_000;
+        let diagonal = Vec3::ONE.normalize();
+        let (mut axis_hits, mut diagonal_hits) = (0, 0);
+        for _ in 0..count {
+            let direction = random_unit_vector(&mut rng);
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            axis_hits += (direction.z > 0.5) as u32;
+            diagonal_hits += (direction.dot(diagonal) > 0.5) as u32;
+        }
+        for hits in [axis_hits, diagonal_hits] {
+            assert!((hits as f32 / count as f32 - 0.25).abs() < 0.005, "{} of {} in the cap", hits, count);
+        }
+    }
+
+    // The coating is a GGX lobe now, so what scatter hands back should average out to the integral of evaluate,
+    // and nothing that carries light should leave below the surface
+    #[test]
+    fn plastic_scatter_matches_evaluate() {
+        let mut rng = SamplerRng::new(Box::new(StratifiedSampler::new()));
+        let plastic = Plastic::new(Color::splat(0.5), 1.5, 0.3);
+        let incoming = Ray::new(Vec3::new(-0.866, 0.0, 0.5), Vec3::new(0.866, 0.0, -0.5));
+        let (position, normal, tangent) = (Vec3::ZERO, Vec3::Z, Vec3::X);
+        let count = 200_000;
+        let mut scattered_sum = Color::ZERO;
+        let mut evaluated_sum = Color::ZERO;
+        for _ in 0..count {
+            let record = plastic.scatter(&mut rng, &incoming, position, normal, tangent, 0.0, 0.0);
+            if record.attenuation != Color::ZERO {
+                assert!(record.scattered_ray.direction.z > 0.0);
+                assert!(record.pdf > 0.0);
+            }
+            scattered_sum += record.attenuation;
+            let (direction, pdf) = sample_uniform_hemisphere(&mut rng, normal);
+            evaluated_sum += plastic.evaluate(&incoming, &Ray::new(position, direction), position, normal, tangent, 0.0, 0.0) / pdf;
+        }
+        let (scattered, evaluated) = (scattered_sum.x / count as f32, evaluated_sum.x / count as f32);
+        assert!((scattered - evaluated).abs() < 0.01, "scatter averages {} but evaluate integrates to {}", scattered, evaluated);
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use glam::{Vec2, Vec3};
+use crate::sampler::SamplerRng;
+use crate::ray::{Ray, Hit};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::bounding_box::BoundingBox;
+use crate::object::Object;
+use crate::light::Light;
+use rand::Rng;
+
+type Color = Vec3;
+
+/// Which ray-triangle test a mesh uses
+#[derive(Clone, Copy, PartialEq)]
+pub enum TriangleIntersection {
+    // Fast, but rays can slip through the shared edge of two triangles
+    MollerTrumbore,
+    // Woop, Benthin and Wald's watertight test, slightly slower but never misses an edge
+    Watertight
+}
+
+/// A triangle mesh made of indexed vertices, all sharing one material
+pub struct Mesh<T: Material> {
+    vertices: Vec<Vec3>,
+    faces: Vec<[usize; 3]>,
+    // Texture coordinates, one per vertex
+    uvs: Option<Vec<Vec2>>,
+    bounding_box: BoundingBox,
+    // Running total of the face areas, for picking faces in proportion to their size
+    cumulative_area: Vec<f32>,
+    pub intersection: TriangleIntersection,
+    material: T
+}
+
+impl<T: Material> Object for Mesh<T> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        if !self.bounding_box.hit(ray, hit_interval) {
+            return None;
+        }
+        let mut closest: Option<(f32, usize, Vec2)> = None;
+        let mut max = hit_interval.max;
+        for (index, face) in self.faces.iter().enumerate() {
+            let [a, b, c] = face.map(|vertex| self.vertices[vertex]);
+            let found = match self.intersection {
+                TriangleIntersection::MollerTrumbore => intersect_moller_trumbore(ray, a, b, c),
+                TriangleIntersection::Watertight => intersect_watertight(ray, a, b, c)
+            };
+            if let Some((t, barycentric)) = found {
+                if Interval::new(hit_interval.min, max).surrounds(t) {
+                    let uv = self.surface_coordinates(index, barycentric);
+                    if self.material.is_cut_out(rng, uv.x, uv.y, ray.pos(t)) {
+                        continue;
+                    }
+                    max = t;
+                    closest = Some((t, index, uv));
+                }
+            }
+        }
+        let (t, index, uv) = closest?;
+        let position = ray.pos(t);
+        let normal = self.face_normal(index);
+        let tangent = self.face_tangent(index);
+        let scatter = self.material.scatter(rng, ray, position, normal, tangent, uv.x, uv.y);
+        let hit = Hit::new(
+            ray,
+            t,
+            position,
+            normal,
+            scatter,
+            self.is_emitter(),
+            self.emit(uv.x, uv.y, position, ray.time)
+        );
+        return Some(hit.with_uv(uv.x, uv.y).with_tangent(tangent).with_shadow_catcher(self.is_shadow_catcher()));
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.albedo(u, v, position)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.emit(u, v, position)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.material.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let bsdf = self.material.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        (bsdf, self.material.scattering_pdf(incoming, scattered, position, normal, tangent, u, v))
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        match self.is_emitter() && self.area() > 0.0 {
+            true => Some(self),
+            false => None
+        }
+    }
+}
+
+// Every point on the surface is as likely as any other, so bigger faces are picked more often
+impl<T: Material> Light for Mesh<T> {
+    fn sample_point(&self, rng: &mut SamplerRng, origin: Vec3, time: f32) -> Option<(Vec3, f32)> {
+        let target = rng.gen::<f32>() * self.area();
+        let index = self.cumulative_area.partition_point(|&sum| sum <= target).min(self.faces.len() - 1);
+        let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+        // Folding the unit square in half along its diagonal covers the triangle evenly
+        let (mut s, mut t) = (rng.gen::<f32>(), rng.gen::<f32>());
+        if s + t > 1.0 {
+            (s, t) = (1.0 - s, 1.0 - t);
+        }
+        let point = a + (b - a) * s + (c - a) * t;
+        let pdf = self.pdf(origin, point, self.face_normal(index), time);
+        match pdf.is_finite() {
+            true => Some((point, pdf)),
+            false => None
+        }
+    }
+
+    fn pdf(&self, origin: Vec3, point: Vec3, normal: Vec3, _time: f32) -> f32 {
+        let to_point = point - origin;
+        let cosine = normal.dot(to_point.normalize()).abs();
+        to_point.length_squared() / (self.area() * cosine)
+    }
+
+    // Every face's emission is taken at its center
+    fn power(&self) -> f32 {
+        let total: f32 = (0..self.faces.len())
+            .map(|index| {
+                let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+                let uv = self.surface_coordinates(index, Vec3::splat(1.0 / 3.0));
+                self.face_area(index) * self.emit(uv.x, uv.y, (a + b + c) / 3.0, 0.0).dot(Color::ONE) / 3.0
+            })
+            .sum();
+        std::f32::consts::PI * total
+    }
+
+    // Faces shine out of both sides, so either is as likely as the other
+    fn sample_surface(&self, rng: &mut SamplerRng, time: f32) -> Option<(Vec3, Vec3, f32)> {
+        let target = rng.gen::<f32>() * self.area();
+        let index = self.cumulative_area.partition_point(|&sum| sum <= target).min(self.faces.len() - 1);
+        let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+        let (mut s, mut t) = (rng.gen::<f32>(), rng.gen::<f32>());
+        if s + t > 1.0 {
+            (s, t) = (1.0 - s, 1.0 - t);
+        }
+        let normal = match rng.gen::<bool>() {
+            true => self.face_normal(index),
+            false => -self.face_normal(index)
+        };
+        Some((a + (b - a) * s + (c - a) * t, normal, self.surface_pdf(Vec3::ZERO, normal, normal, time)))
+    }
+
+    fn surface_pdf(&self, _point: Vec3, _normal: Vec3, _direction: Vec3, _time: f32) -> f32 {
+        0.5 / self.area()
+    }
+}
+
+impl<T: Material> Mesh<T> {
+    /// Faces index into the vertex list, wound counter-clockwise when seen from the front
+    pub fn new(vertices: Vec<Vec3>, faces: Vec<[usize; 3]>, material: T) -> Mesh<T> {
+        let bounding_box = vertices
+            .iter()
+            .fold(BoundingBox::EMPTY, |bounds, &vertex| BoundingBox::union(&bounds, &BoundingBox::from_points(vertex, vertex)));
+        let cumulative_area = faces
+            .iter()
+            .scan(0.0, |sum, face| {
+                let [a, b, c] = face.map(|vertex| vertices[vertex]);
+                *sum += (b - a).cross(c - a).length() / 2.0;
+                Some(*sum)
+            })
+            .collect();
+        Mesh {
+            vertices,
+            faces,
+            uvs: None,
+            bounding_box,
+            cumulative_area,
+            intersection: TriangleIntersection::MollerTrumbore,
+            material
+        }
+    }
+
+    /// Loads the faces of a Wavefront OBJ file, fanning out faces with more than three corners
+    /// Texture coordinates are kept if every corner has one, normals are left out as faces are flat
+    pub fn load(path: &str, material: T) -> Result<Mesh<T>, Error> {
+        let source = fs::read_to_string(path)?;
+        let mut positions: Vec<Vec3> = Vec::new();
+        let mut coordinates: Vec<Vec2> = Vec::new();
+        // OBJ numbers positions and texture coordinates separately, but a mesh has one uv per vertex,
+        // so every pair of them used by a corner becomes a vertex of its own
+        let mut corners: HashMap<(usize, Option<usize>), usize> = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut uvs = Vec::new();
+        let mut faces = Vec::new();
+        let mut textured = true;
+        for (number, line) in source.lines().enumerate() {
+            let invalid = |message: &str| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", number + 1, message));
+            let mut words = line.split_whitespace();
+            let values = |words: std::str::SplitWhitespace| words.map(|word| word.parse::<f32>()).collect::<Result<Vec<f32>, _>>();
+            match words.next() {
+                Some("v") => match values(words).as_deref() {
+                    // A fourth weight coordinate may follow, which only rational curves use
+                    Ok([x, y, z, ..]) => positions.push(Vec3::new(*x, *y, *z)),
+                    _ => return Err(invalid("expected a vertex as v x y z"))
+                },
+                Some("vt") => match values(words).as_deref() {
+                    Ok([u, v, ..]) => coordinates.push(Vec2::new(*u, *v)),
+                    _ => return Err(invalid("expected a texture coordinate as vt u v"))
+                },
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for corner in words {
+                        let mut indices = corner.split('/');
+                        let position = obj_index(indices.next().unwrap_or(""), positions.len())
+                            .ok_or_else(|| invalid(&format!("no vertex {}", corner)))?;
+                        let coordinate = match indices.next().filter(|index| !index.is_empty()) {
+                            Some(index) => Some(obj_index(index, coordinates.len()).ok_or_else(|| invalid(&format!("no texture coordinate {}", corner)))?),
+                            None => None
+                        };
+                        textured &= coordinate.is_some();
+                        let vertex = *corners.entry((position, coordinate)).or_insert_with(|| {
+                            vertices.push(positions[position]);
+                            uvs.push(coordinate.map_or(Vec2::ZERO, |index| coordinates[index]));
+                            vertices.len() - 1
+                        });
+                        face.push(vertex);
+                    }
+                    if face.len() < 3 {
+                        return Err(invalid("faces need at least three corners"));
+                    }
+                    for corner in 1..face.len() - 1 {
+                        faces.push([face[0], face[corner], face[corner + 1]]);
+                    }
+                },
+                // Normals, groups, materials and the rest don't change the shape
+                _ => continue
+            }
+        }
+        if faces.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} has no faces", path)));
+        }
+        let mesh = Mesh::new(vertices, faces, material);
+        return match textured {
+            true => Ok(mesh.with_uvs(uvs)),
+            false => Ok(mesh)
+        };
+    }
+
+    /// Gives every vertex a texture coordinate, interpolated across the faces
+    pub fn with_uvs(mut self, uvs: Vec<Vec2>) -> Mesh<T> {
+        assert_eq!(uvs.len(), self.vertices.len(), "A mesh needs exactly one uv per vertex");
+        self.uvs = Some(uvs);
+        self
+    }
+
+    // Without texture coordinates every face gets its own, with a at (0, 0), b at (1, 0) and c at (0, 1)
+    fn surface_coordinates(&self, index: usize, barycentric: Vec3) -> Vec2 {
+        match &self.uvs {
+            Some(uvs) => {
+                let [a, b, c] = self.faces[index].map(|vertex| uvs[vertex]);
+                a * barycentric.x + b * barycentric.y + c * barycentric.z
+            },
+            None => Vec2::new(barycentric.y, barycentric.z)
+        }
+    }
+
+    // Direction of increasing u across a face, found by solving for how position changes with the uvs
+    fn face_tangent(&self, index: usize) -> Vec3 {
+        let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+        let (edge_1, edge_2) = (b - a, c - a);
+        let Some(uvs) = &self.uvs else {
+            return edge_1.normalize();
+        };
+        let [uv_a, uv_b, uv_c] = self.faces[index].map(|vertex| uvs[vertex]);
+        let (delta_1, delta_2) = (uv_b - uv_a, uv_c - uv_a);
+        let determinant = delta_1.x * delta_2.y - delta_2.x * delta_1.y;
+        // Faces with degenerate uvs have no meaningful tangent
+        if determinant.abs() < 1e-8 {
+            return edge_1.normalize();
+        }
+        return ((edge_1 * delta_2.y - edge_2 * delta_1.y) / determinant).normalize();
+    }
+
+    fn face_area(&self, index: usize) -> f32 {
+        let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+        (b - a).cross(c - a).length() / 2.0
+    }
+
+    /// Total area of all faces
+    pub fn area(&self) -> f32 {
+        self.cumulative_area.last().copied().unwrap_or(0.0)
+    }
+
+    fn face_normal(&self, index: usize) -> Vec3 {
+        let [a, b, c] = self.faces[index].map(|vertex| self.vertices[vertex]);
+        (b - a).cross(c - a).normalize()
+    }
+}
+
+// OBJ indices count from 1, or back from the latest one when negative
+fn obj_index(word: &str, count: usize) -> Option<usize> {
+    let index: i64 = word.parse().ok()?;
+    let index = match index < 0 {
+        true => count as i64 + index,
+        false => index - 1
+    };
+    return (0..count as i64).contains(&index).then_some(index as usize);
+}
+
+/// Möller–Trumbore intersection
+/// Returns t and the barycentric weights of a, b and c at the hit
+pub fn intersect_moller_trumbore(ray: &Ray, a: Vec3, b: Vec3, c: Vec3) -> Option<(f32, Vec3)> {
+    let edge_1 = b - a;
+    let edge_2 = c - a;
+    let p = ray.direction.cross(edge_2);
+    let determinant = edge_1.dot(p);
+    // Ray is parallel to the triangle
+    if determinant.abs() < 1e-8 {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+    let origin_to_a = ray.origin - a;
+    let u = origin_to_a.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = origin_to_a.cross(edge_1);
+    let v = ray.direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge_2.dot(q) * inverse_determinant;
+    return Some((t, Vec3::new(1.0 - u - v, u, v)));
+}
+
+/// Watertight intersection from "Watertight Ray/Triangle Intersection" by Woop, Benthin and Wald
+/// Returns t and the barycentric weights of a, b and c at the hit
+pub fn intersect_watertight(ray: &Ray, a: Vec3, b: Vec3, c: Vec3) -> Option<(f32, Vec3)> {
+    // Pick the largest direction axis as z, and keep the winding by swapping x and y if it's negative
+    let direction = ray.direction;
+    let absolute = direction.abs();
+    let kz = if absolute.x > absolute.y {
+        if absolute.x > absolute.z { 0 } else { 2 }
+    } else if absolute.y > absolute.z { 1 } else { 2 };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if direction[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    // Shear so the ray points straight down z
+    let shear_x = direction[kx] / direction[kz];
+    let shear_y = direction[ky] / direction[kz];
+    let shear_z = 1.0 / direction[kz];
+    let a = a - ray.origin;
+    let b = b - ray.origin;
+    let c = c - ray.origin;
+    let ax = a[kx] - shear_x * a[kz];
+    let ay = a[ky] - shear_y * a[kz];
+    let bx = b[kx] - shear_x * b[kz];
+    let by = b[ky] - shear_y * b[kz];
+    let cx = c[kx] - shear_x * c[kz];
+    let cy = c[ky] - shear_y * c[kz];
+    // Scaled barycentrics through 2D edge functions
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    // Exactly on an edge: redo it in double precision so neighbouring triangles agree
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let determinant = u + v + w;
+    if determinant == 0.0 {
+        return None;
+    }
+    let az = shear_z * a[kz];
+    let bz = shear_z * b[kz];
+    let cz = shear_z * c[kz];
+    let t = (u * az + v * bz + w * cz) / determinant;
+    return Some((t, Vec3::new(u, v, w) / determinant));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+
+    fn load_obj(name: &str, source: &str) -> Result<Mesh<Lambertian>, Error> {
+        let path = std::env::temp_dir().join(format!("sagakar-mesh-test-{}-{}.obj", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let mesh = Mesh::load(path.to_str().unwrap(), Lambertian::new(0.5, 0.5, 0.5));
+        fs::remove_file(&path).unwrap();
+        return mesh;
+    }
+
+    #[test]
+    fn quads_are_fanned_into_triangles() {
+        let mesh = load_obj("quad", "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 4//1\n").unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.uvs.is_none());
+        assert!((mesh.area() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn corners_with_different_uvs_get_their_own_vertices() {
+        // The faces share all three positions, but only two of them with the same texture coordinates
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvt 0.5 0.5\nf 1/1 2/2 3/3\nf -3/-1 -1/-2 -2/-3\n";
+        let mesh = load_obj("seam", source).unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [3, 2, 1]]);
+        assert_eq!(mesh.vertices[3], Vec3::ZERO);
+        assert_eq!(mesh.uvs.as_ref().unwrap()[3], Vec2::new(0.5, 0.5));
+    }
+
+    #[test]
+    fn bad_indices_report_their_line() {
+        let error = load_obj("missing", "v 0 0 0\nv 1 0 0\n\nf 1 2 3\n").err().unwrap();
+        assert_eq!(error.to_string(), "Line 4: no vertex 3");
+    }
+}
@@ -0,0 +1,301 @@
+// Metropolis light transport, which wanders from a path that carries light to others like it instead of starting over every sample
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use glam::Vec3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::camera::Camera;
+use crate::integrator::Integrator;
+use crate::material::LUMINANCE_WEIGHTS;
+use crate::object::Object;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+
+type Color = Vec3;
+
+// Paths traced before the chains start, to tell how bright the image is and to pick where every chain starts
+const BOOTSTRAP_SAMPLES: u64 = 100000;
+// Smallest and largest change of a number in a small step, as Kelemen et al suggest
+const SMALL_STEP_MIN: f32 = 1.0 / 1024.0;
+const SMALL_STEP_MAX: f32 = 1.0 / 64.0;
+
+/// Primary sample space Metropolis light transport, by Kelemen et al. Every path the path tracer follows is made from
+/// a list of random numbers, starting with the point on the image. Instead of drawing new numbers for every path,
+/// chains of paths are made by changing the numbers of the last path a little, keeping the new path with a chance of
+/// how much brighter it is. Once a path through a small gap or off a mirror onto a light is found, paths like it are
+/// explored until the chain wanders off, so scenes lit through hard to find paths clear up much faster than by path tracing
+/// Every so often a chain jumps to completely new numbers, so it can't get stuck on one part of the image
+/// The chains are run for the whole image before any pixel is worked out, as many paths as path tracing would take
+/// Bright spots keep showing up in different places from render to render until there are enough samples,
+/// light groups stay dark and stereo renders show the main camera's view to both eyes
+pub struct MetropolisLightTransport {
+    // Chains run one after the other, each getting its share of the paths
+    chains: u32,
+    // How often a chain jumps to completely new numbers instead of changing them a little
+    large_step_probability: f32,
+    // The light the chains left in every pixel, worked out again at the start of every render
+    image: Mutex<SplatImage>
+}
+
+impl Integrator for MetropolisLightTransport {
+    fn prepare(&self, camera: &mut Camera, objects: &[Box<dyn Object>]) {
+        let (width, height) = (camera.settings.width as usize, camera.settings.height as usize);
+        let mut image = SplatImage { width, height, pixels: vec![Color::ZERO; width * height] };
+        let state = Arc::new(Mutex::new(PrimarySamples::new(0, self.large_step_probability)));
+        let sampler = camera.rng().replace(Box::new(MetropolisSampler { state: Arc::clone(&state) }));
+        // Brightness of paths made from fresh numbers, each seeded with its index so any of them can be made again
+        print!("\rFinding starting paths");
+        let mut brightness = Vec::with_capacity(BOOTSTRAP_SAMPLES as usize);
+        for seed in 0..BOOTSTRAP_SAMPLES {
+            *lock(&state) = PrimarySamples::new(seed, self.large_step_probability);
+            let (_, color) = trace(camera, objects);
+            brightness.push(color.dot(LUMINANCE_WEIGHTS).max(0.0));
+        }
+        let total: f64 = brightness.iter().map(|&brightness| brightness as f64).sum();
+        let mean_brightness = (total / BOOTSTRAP_SAMPLES as f64) as f32;
+        let paths = camera.samples as u64 * (width * height) as u64;
+        if mean_brightness > 0.0 {
+            let mut picker = StdRng::seed_from_u64(BOOTSTRAP_SAMPLES);
+            for chain in 0..self.chains {
+                print!("\rRunning Markov chains, {:4} chains remaining", self.chains - chain);
+                // Chains start on a path picked in proportion to its brightness, which skips the time it'd take to find one
+                let seed = pick(&brightness, total, picker.gen::<f64>());
+                *lock(&state) = PrimarySamples::new(seed, self.large_step_probability);
+                let (mut current, mut current_color) = trace(camera, objects);
+                let mut current_brightness = current_color.dot(LUMINANCE_WEIGHTS);
+                let length = paths / self.chains as u64 + u64::from((chain as u64) < paths % self.chains as u64);
+                for _ in 0..length {
+                    lock(&state).start_iteration();
+                    let (proposed, proposed_color) = trace(camera, objects);
+                    let proposed_brightness = proposed_color.dot(LUMINANCE_WEIGHTS);
+                    let accept = match current_brightness > 0.0 {
+                        true => (proposed_brightness / current_brightness).clamp(0.0, 1.0),
+                        false => 1.0
+                    };
+                    // Both paths leave light in proportion to how likely each is to be the next, which wastes none of them
+                    if accept > 0.0 && proposed_brightness > 0.0 {
+                        image.splat(proposed, proposed_color * accept / proposed_brightness);
+                    }
+                    if current_brightness > 0.0 {
+                        image.splat(current, current_color * (1.0 - accept) / current_brightness);
+                    }
+                    match picker.gen::<f32>() < accept {
+                        true => {
+                            lock(&state).accept();
+                            (current, current_color, current_brightness) = (proposed, proposed_color, proposed_brightness);
+                        },
+                        false => lock(&state).reject()
+                    }
+                }
+            }
+        }
+        println!();
+        camera.rng().replace(sampler);
+        // Every pixel got as many paths as samples on average, each standing for the average brightness
+        let scale = mean_brightness / camera.samples.max(1) as f32;
+        for pixel in image.pixels.iter_mut() {
+            *pixel *= scale;
+        }
+        *self.image.lock().expect("the splatted image isn't shared between threads") = image;
+    }
+
+    // The chains have already worked out every pixel, so every sample of one is the same
+    fn radiance(&self, camera: &mut Camera, _ray: &Ray, _objects: &[Box<dyn Object>]) -> Color {
+        let (x, y) = camera.pixel();
+        let image = self.image.lock().expect("the splatted image isn't shared between threads");
+        image.pixels.get(y as usize * image.width + x as usize).copied().unwrap_or(Color::ZERO)
+    }
+
+    // Paths land in the pixel they're splatted into, wherever the camera's samples go
+    fn is_filtered(&self) -> bool {
+        false
+    }
+}
+
+impl MetropolisLightTransport {
+    /// Runs a number of chains, jumping to new numbers with the given probability
+    pub fn new(chains: u32, large_step_probability: f32) -> MetropolisLightTransport {
+        MetropolisLightTransport {
+            chains: chains.max(1),
+            large_step_probability: large_step_probability.clamp(0.0, 1.0),
+            image: Mutex::new(SplatImage { width: 0, height: 0, pixels: vec![] })
+        }
+    }
+
+    /// Parses "[chains[,large step probability]]", with 1000 chains and a probability of 0.3 by default
+    pub fn parse(spec: &str) -> Result<MetropolisLightTransport, String> {
+        let mut values = spec.split(',').filter(|value| !value.is_empty());
+        let chains = match values.next() {
+            Some(chains) => chains.parse().map_err(|_| format!("Invalid number of chains \"{}\"", chains))?,
+            None => 1000
+        };
+        let large_step_probability = match values.next() {
+            Some(probability) => probability.parse().map_err(|_| format!("Invalid large step probability \"{}\"", probability))?,
+            None => 0.3
+        };
+        return Ok(MetropolisLightTransport::new(chains, large_step_probability));
+    }
+}
+
+// Traces the path made from the current numbers, returning where on the image it lands and the light it brings
+fn trace(camera: &mut Camera, objects: &[Box<dyn Object>]) -> ((f32, f32), Color) {
+    let (s, t) = (camera.rng().gen::<f32>(), camera.rng().gen::<f32>());
+    let color = match camera.image_ray(s, t) {
+        Some(ray) => camera.trace_path(&ray, objects),
+        None => Color::ZERO
+    };
+    // Paths that went wrong somewhere are dropped rather than spreading through the chain
+    match color.is_finite() {
+        true => ((s, t), color),
+        false => ((s, t), Color::ZERO)
+    }
+}
+
+// The index of the value a number from 0 to 1 lands on, when every value gets a share of the range as large as itself
+fn pick(values: &[f32], total: f64, u: f64) -> u64 {
+    let mut target = u * total;
+    for (index, &value) in values.iter().enumerate() {
+        target -= value as f64;
+        if target < 0.0 {
+            return index as u64;
+        }
+    }
+    return values.iter().rposition(|&value| value > 0.0).unwrap_or(0) as u64;
+}
+
+fn lock(state: &Mutex<PrimarySamples>) -> MutexGuard<'_, PrimarySamples> {
+    state.lock().expect("primary samples aren't shared between threads")
+}
+
+// Light left on the image by the chains, added up in every pixel
+struct SplatImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>
+}
+
+impl SplatImage {
+    // Adds light to the pixel under (s, t) on the image
+    fn splat(&mut self, (s, t): (f32, f32), color: Color) {
+        let x = ((s * self.width as f32) as usize).min(self.width - 1);
+        let y = ((t * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] += color;
+    }
+}
+
+// One number of a path, with what it was before the step that's being tried
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f32,
+    // The step it was last brought up to date in
+    modified: u64,
+    backup: f32,
+    modified_backup: u64
+}
+
+// The numbers of the current path of a chain. They're only changed once asked for, so a step costs nothing
+// for numbers the path doesn't get to, and are brought up to date with every step they missed in the meantime
+struct PrimarySamples {
+    samples: Vec<PrimarySample>,
+    rng: StdRng,
+    large_step_probability: f32,
+    // Which step is being tried, and the last step that jumped to new numbers
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    // The number of the path asked for next
+    dimension: usize
+}
+
+impl PrimarySamples {
+    // Fresh numbers, the same for every seed
+    fn new(seed: u64, large_step_probability: f32) -> PrimarySamples {
+        PrimarySamples {
+            samples: vec![],
+            rng: StdRng::seed_from_u64(seed),
+            large_step_probability,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            dimension: 0
+        }
+    }
+
+    // Starts trying a new step from the current path
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.dimension = 0;
+    }
+
+    // Keeps the numbers of the step that was tried
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    // Goes back to the numbers from before the step that was tried
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut().filter(|sample| sample.modified == self.iteration) {
+            sample.value = sample.backup;
+            sample.modified = sample.modified_backup;
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension >= self.samples.len() {
+            // Numbers no path has asked for yet are new to every step before this one
+            let value = self.rng.gen::<f32>();
+            self.samples.push(PrimarySample { value, modified: self.iteration, backup: value, modified_backup: self.iteration });
+            return value;
+        }
+        let mut sample = self.samples[dimension];
+        // A jump the number missed replaces it, the small steps since only change that
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen::<f32>();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.modified_backup = sample.modified;
+        match self.large_step {
+            true => sample.value = self.rng.gen::<f32>(),
+            false => {
+                for _ in sample.modified..self.iteration {
+                    sample.value = self.small_step(sample.value);
+                }
+            }
+        }
+        sample.modified = self.iteration;
+        self.samples[dimension] = sample;
+        return sample.value;
+    }
+
+    // Kelemen et al's change of a number, from tiny to a few percent with a sharp peak at the small end, wrapping around
+    fn small_step(&mut self, value: f32) -> f32 {
+        let change = SMALL_STEP_MAX * (-(SMALL_STEP_MAX / SMALL_STEP_MIN).ln() * self.rng.gen::<f32>()).exp();
+        let value = match self.rng.gen::<bool>() {
+            true => value + change,
+            false => value - change
+        };
+        let value = value - value.floor();
+        // Rounding could land on 1 itself
+        return value.min(1.0 - f32::EPSILON / 2.0);
+    }
+}
+
+/// Hands out the numbers of a chain's current path to the camera
+struct MetropolisSampler {
+    state: Arc<Mutex<PrimarySamples>>
+}
+
+impl Sampler for MetropolisSampler {
+    // Steps are started by the chains, not by the camera
+    fn start_sample(&mut self, _x: u32, _y: u32, _index: u32, _count: u32) {}
+
+    fn next(&mut self) -> f32 {
+        lock(&self.state).next()
+    }
+}
@@ -0,0 +1,86 @@
+// GGX (Trowbridge-Reitz) microfacet functions
+// Everything here works in a local frame where the surface normal is +z
+// Sampling follows "Sampling the GGX Distribution of Visible Normals" by Eric Heitz
+
+use std::f32::consts::PI;
+use glam::Vec3;
+use crate::onb::Onb;
+
+/// Perceptual roughness is squared into alpha, which is clamped so perfectly smooth surfaces stay finite
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    roughness.powi(2).max(0.0001)
+}
+
+/// Builds two tangents that together with the normal form an orthonormal basis
+pub fn tangent_frame(normal: Vec3) -> (Vec3, Vec3) {
+    let basis = Onb::new(normal);
+    return (basis.u, basis.v);
+}
+
+pub fn to_local(vector: Vec3, normal: Vec3) -> Vec3 {
+    Onb::new(normal).to_local(vector)
+}
+
+pub fn from_local(vector: Vec3, normal: Vec3) -> Vec3 {
+    Onb::new(normal).to_world(vector)
+}
+
+/// The density of microfacets facing the half vector m
+pub fn distribution(m: Vec3, alpha: f32) -> f32 {
+    if m.z <= 0.0 {
+        return 0.0;
+    }
+    let alpha_squared = alpha.powi(2);
+    let denominator = m.z.powi(2) * (alpha_squared - 1.0) + 1.0;
+    return alpha_squared / (PI * denominator.powi(2));
+}
+
+/// Smith's auxiliary function, the ratio of hidden to visible microfacet area in a direction
+fn lambda(v: Vec3, alpha: f32) -> f32 {
+    let cos_squared = v.z.powi(2);
+    if cos_squared == 0.0 {
+        return f32::MAX;
+    }
+    let tan_squared = (1.0 - cos_squared).max(0.0) / cos_squared;
+    return (-1.0 + (1.0 + alpha.powi(2) * tan_squared).sqrt()) / 2.0;
+}
+
+/// Smith masking, the proportion of microfacets visible from one direction
+pub fn masking(v: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + lambda(v, alpha))
+}
+
+/// Height-correlated Smith masking-shadowing for a pair of directions
+pub fn masking_shadowing(view: Vec3, light: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + lambda(view, alpha) + lambda(light, alpha))
+}
+
+/// Samples a microfacet normal among the ones visible from view, given two uniform random numbers
+pub fn sample_visible_normal(view: Vec3, alpha: f32, u1: f32, u2: f32) -> Vec3 {
+    // Stretch the view direction so the problem becomes sampling a hemisphere
+    let stretched = Vec3::new(alpha * view.x, alpha * view.y, view.z).normalize();
+    let length_squared = stretched.x.powi(2) + stretched.y.powi(2);
+    let t1_axis = match length_squared > 0.0 {
+        true => Vec3::new(-stretched.y, stretched.x, 0.0) / length_squared.sqrt(),
+        false => Vec3::X
+    };
+    let t2_axis = stretched.cross(t1_axis);
+    // Sample a disk, squashed to the projected area of the visible hemisphere
+    let radius = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let t1 = radius * phi.cos();
+    let s = 0.5 * (1.0 + stretched.z);
+    let t2 = (1.0 - s) * (1.0 - t1.powi(2)).sqrt() + s * radius * phi.sin();
+    let hemisphere_normal = t1 * t1_axis + t2 * t2_axis + (1.0 - t1.powi(2) - t2.powi(2)).max(0.0).sqrt() * stretched;
+    // Unstretch
+    return Vec3::new(alpha * hemisphere_normal.x, alpha * hemisphere_normal.y, hemisphere_normal.z.max(0.0)).normalize();
+}
+
+/// The density of sampling light from view through sample_visible_normal and reflecting, per solid angle
+pub fn reflection_pdf(view: Vec3, light: Vec3, alpha: f32) -> f32 {
+    if view.z <= 0.0 || light.z <= 0.0 {
+        return 0.0;
+    }
+    let half = (view + light).normalize();
+    return masking(view, alpha) * distribution(half, alpha) / (4.0 * view.z);
+}
@@ -1,28 +1,124 @@
-use glam::Vec3;
-use rand::rngs::ThreadRng;
-use crate::ray::{Ray, Hit};
+use glam::{Mat3, Vec3};
+use crate::sampler::SamplerRng;
+use crate::ray::{Ray, Hit, DEFAULT_HIT_EPSILON};
 use crate::interval::Interval;
-use crate::material::Material;
+use crate::material::{Material, DiffuseLight, Lambertian, LightPower, random_unit_vector};
+use crate::onb::Onb;
+use crate::light::Light;
+use rand::Rng;
+use crate::bounding_box::BoundingBox;
+use crate::animation::TransformTrack;
+use crate::volume::{DensityField, BlackbodyEmission};
 
 type Color = Vec3;
 
+// Points a light's emission is averaged over to estimate its power
+const POWER_SAMPLES: usize = 64;
+// Gray that clay objects are painted, light enough for the bounced light to show
+const CLAY_ALBEDO: f32 = 0.8;
+
 
 pub trait Object {
     // If ray intersects, return point of intersection
     // Else return none
-    fn intersect(&self, rng: &mut ThreadRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit>;
-    // Return the unit normal at the given point
-    fn normal(&self, point: Vec3) -> Vec3;
-    // Return the material of the object
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray;
-    // Return the blue, green and red albedos of the object
-    fn albedo(&self) -> Color;
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit>;
+    // Return the blue, green and red albedos of the object at a point on it, at the time the point was hit
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color;
     fn is_emitter(&self) -> bool;
-    fn emit(&self) -> Color;
+    // Return the light emitted at a point on the object, at the time the point was hit
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color;
+    fn is_shadow_catcher(&self) -> bool;
+    // Return an axis-aligned box enclosing the whole object
+    fn bounding_box(&self) -> BoundingBox;
+    // Is the object cut out of the final image?
+    fn is_holdout(&self) -> bool {
+        false
+    }
+    // The BSDF times the cosine for light arriving along scattered and leaving along incoming, and the density
+    // of bouncing that way, at a point with an outward normal. Objects without a material scatter nothing
+    fn evaluate(&self, _incoming: &Ray, _scattered: &Ray, _position: Vec3, _normal: Vec3, _tangent: Vec3, _u: f32, _v: f32) -> (Color, f32) {
+        (Color::ZERO, 0.0)
+    }
+    // The object as a light to sample, if it gives off light and points on it can be picked
+    // Emitters that can't be sampled are only found by bouncing into them
+    fn light(&self) -> Option<&dyn Light> {
+        None
+    }
+    // The name of the light group the object's light is gathered into, for lights that get their own image
+    fn light_group(&self) -> Option<&str> {
+        None
+    }
+    // The name of the object's material, so objects sharing it get the same material id. Unnamed ones get an id of their own
+    fn material_name(&self) -> Option<&str> {
+        None
+    }
+    // Where the point of the object at position at time from has moved to by time to. Objects standing still leave it be
+    fn moved(&self, position: Vec3, _from: f32, _to: f32) -> Vec3 {
+        position
+    }
+    // The light given off at a point on the object towards another point, found by looking back at it from there
+    // The point counts as hit within a thousandth of the way there, either side of it
+    fn emission_towards(&self, rng: &mut SamplerRng, point: Vec3, from: Vec3, time: f32) -> Color {
+        let ray = Ray::new(from, point - from).at_time(time);
+        self.intersect(rng, &ray, &Interval::new(0.999, 1.001)).map_or(Color::ZERO, |hit| hit.emitted)
+    }
+}
+
+// Lets wrappers like Holdout and Animated go around objects whose type is only known once a scene file is read
+impl Object for Box<dyn Object> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        (**self).intersect(rng, ray, hit_interval)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        (**self).albedo(u, v, position, time)
+    }
+
+    fn is_emitter(&self) -> bool {
+        (**self).is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        (**self).emit(u, v, position, time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        (**self).is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        (**self).bounding_box()
+    }
+
+    fn is_holdout(&self) -> bool {
+        (**self).is_holdout()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        (**self).evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        (**self).light()
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        (**self).light_group()
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        (**self).material_name()
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        (**self).moved(position, from, to)
+    }
 }
 
 pub struct Sphere<T: Material> {
+    // Center at time 0, and how far it moves per unit of time
     center: Vec3,
+    velocity: Vec3,
     radius: f32,
     material: T
 }
@@ -31,8 +127,9 @@ impl<T: Material> Object for Sphere<T> {
     // Returns None if no hit, otherwise returns the t value at intersection
     // I used the pq formula for this, because the american formula is like math uncanny valley
     // 
-    fn intersect(&self, rng: &mut ThreadRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
-        let center_to_origin = ray.origin - self.center;
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        let center = self.center_at(ray.time);
+        let center_to_origin = ray.origin - center;
         let half_p = ray.direction.dot(center_to_origin) / ray.direction.length_squared();
         let q = (center_to_origin.length_squared() - self.radius.powi(2)) / ray.direction.length_squared();
         let discriminant = half_p.powi(2) - q;
@@ -40,48 +137,137 @@ impl<T: Material> Object for Sphere<T> {
         if discriminant < 0.0 {
             return None;
         }
-        let mut t = -half_p - discriminant.sqrt();
-        // If the negative root is not in the t interval, flip the root
-        if !hit_interval.surrounds(t) {
-            t = -half_p + discriminant.sqrt();
-            // If it's still not in the interval, it's too far away. Return None
+        // Try the negative root first, and the positive one if it's outside the t interval or cut out
+        for t in [-half_p - discriminant.sqrt(), -half_p + discriminant.sqrt()] {
             if !hit_interval.surrounds(t) {
-                return None;
+                continue;
             }
+            let position = ray.pos(t);
+            let normal = (position - center) / self.radius;
+            let (u, v) = self.surface_coordinates(normal);
+            if self.material.is_cut_out(rng, u, v, position) {
+                continue;
+            }
+            // Along increasing longitude, with any direction in the tangent plane at the poles
+            let tangent = Vec3::new(normal.z, 0.0, -normal.x).try_normalize().unwrap_or(Vec3::X);
+            let scatter = self.material.scatter(rng, ray, position, normal, tangent, u, v);
+            let hit = Hit::new(
+                ray,
+                t,
+                position,
+                normal,
+                scatter,
+                self.is_emitter(),
+                self.emit(u, v, position, ray.time)
+            );
+            return Some(hit.with_uv(u, v).with_tangent(tangent).with_shadow_catcher(self.is_shadow_catcher()));
         }
-        let position = ray.pos(t);
-        let normal = self.normal(position);
-        let outgoing = self.bounce(rng, ray, position, normal);
-        return Some(Hit::new(
-            ray,
-            t,
-            position,
-            normal,
-            self.albedo(),
-            outgoing,
-            self.is_emitter(),
-            self.emit()
-        ));
+        // Neither root was hit, so the sphere is too far away or cut out
+        return None;
     }
 
-    fn normal(&self, point: Vec3) -> Vec3 {
-        (point - self.center).normalize()
+    fn albedo(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.albedo(u, v, position)
     }
 
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        self.material.bounce(rng, incoming, position, normal)
+    fn is_emitter(&self) -> bool {
+        self.material.is_emitter()
     }
 
-    fn albedo(&self) -> Color {
-        self.material.albedo()
+    fn emit(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.emit(u, v, position)
     }
 
-    fn is_emitter(&self) -> bool {
-        self.material.is_emitter()
+    fn is_shadow_catcher(&self) -> bool {
+        self.material.is_shadow_catcher()
     }
 
-    fn emit(&self) -> Color {
-        self.material.emit()
+    fn bounding_box(&self) -> BoundingBox {
+        // Enclose the sphere at both ends of its motion over the usual shutter interval from 0 to 1
+        let radius = Vec3::splat(self.radius);
+        let start = BoundingBox::from_points(self.center - radius, self.center + radius);
+        let end = BoundingBox::from_points(self.center_at(1.0) - radius, self.center_at(1.0) + radius);
+        BoundingBox::union(&start, &end)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let bsdf = self.material.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        (bsdf, self.material.scattering_pdf(incoming, scattered, position, normal, tangent, u, v))
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        match self.is_emitter() {
+            true => Some(self),
+            false => None
+        }
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        position + self.center_at(to) - self.center_at(from)
+    }
+}
+
+impl<T: Material> Light for Sphere<T> {
+    // From outside, pick a direction in the cone the sphere fills and take the near side along it
+    // From inside, every direction sees the sphere, so pick a point evenly over it, which pdf turns into a solid angle density
+    fn sample_point(&self, rng: &mut SamplerRng, origin: Vec3, time: f32) -> Option<(Vec3, f32)> {
+        let center = self.center_at(time);
+        let to_center = center - origin;
+        let distance_squared = to_center.length_squared();
+        if distance_squared <= self.radius.powi(2) {
+            let point = center + random_unit_vector(rng) * self.radius;
+            return Some((point, self.pdf(origin, point, (point - center) / self.radius, time)));
+        }
+        let (cos_max, solid_angle) = self.cone(distance_squared);
+        let cos_theta = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta.powi(2)).max(0.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let direction = Onb::new(to_center.normalize()).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+        // Along the direction, the sphere starts where it's as far from the center as the radius
+        let distance = distance_squared.sqrt();
+        let along = distance * cos_theta - (self.radius.powi(2) - distance_squared * sin_theta.powi(2)).max(0.0).sqrt();
+        return Some((origin + direction * along, 1.0 / solid_angle));
+    }
+
+    fn pdf(&self, origin: Vec3, point: Vec3, normal: Vec3, time: f32) -> f32 {
+        let center = self.center_at(time);
+        let distance_squared = (center - origin).length_squared();
+        if distance_squared <= self.radius.powi(2) {
+            let to_point = point - origin;
+            let cosine = normal.dot(to_point.normalize()).abs();
+            return to_point.length_squared() / (self.area() * cosine);
+        }
+        return 1.0 / self.cone(distance_squared).1;
+    }
+
+    // Averaged over evenly spread points on a Fibonacci spiral
+    fn power(&self) -> f32 {
+        let golden_angle = std::f32::consts::PI * (3.0 - 5.0_f32.sqrt());
+        let total: f32 = (0..POWER_SAMPLES)
+            .map(|i| {
+                let y = 1.0 - 2.0 * (i as f32 + 0.5) / POWER_SAMPLES as f32;
+                let radius = (1.0 - y * y).sqrt();
+                let angle = golden_angle * i as f32;
+                let normal = Vec3::new(radius * angle.cos(), y, radius * angle.sin());
+                let (u, v) = self.surface_coordinates(normal);
+                self.emit(u, v, self.center + normal * self.radius, 0.0).dot(Color::ONE) / 3.0
+            })
+            .sum();
+        std::f32::consts::PI * self.area() * total / POWER_SAMPLES as f32
+    }
+
+    // Only shining outwards, since nothing is usually inside a glowing sphere
+    // Normals are spread evenly over the sphere, so every point has the same density of one over the area
+    fn sample_surface(&self, rng: &mut SamplerRng, time: f32) -> Option<(Vec3, Vec3, f32)> {
+        let normal = random_unit_vector(rng);
+        Some((self.center_at(time) + normal * self.radius, normal, 1.0 / self.area()))
+    }
+
+    fn surface_pdf(&self, _point: Vec3, normal: Vec3, direction: Vec3, _time: f32) -> f32 {
+        match normal.dot(direction) > 0.0 {
+            true => 1.0 / self.area(),
+            false => 0.0
+        }
     }
 }
 
@@ -89,10 +275,45 @@ impl<T: Material> Sphere<T>{
     pub fn new(center: Vec3, radius: f32, material: T) -> Sphere<T> {
         Sphere {
             center,
+            velocity: Vec3::ZERO,
+            radius,
+            material
+        }
+    }
+
+    /// A sphere moving in a straight line from start at time 0 to end at time 1
+    pub fn moving(start: Vec3, end: Vec3, radius: f32, material: T) -> Sphere<T> {
+        Sphere {
+            center: start,
+            velocity: end - start,
             radius,
-            material: material
+            material
         }
     }
+
+    fn center_at(&self, time: f32) -> Vec3 {
+        self.center + self.velocity * time
+    }
+
+    // Cosine of the half angle and the solid angle of the cone the sphere fills, seen from a squared distance outside it
+    fn cone(&self, distance_squared: f32) -> (f32, f32) {
+        let sin_squared = self.radius.powi(2) / distance_squared;
+        let cos_max = (1.0 - sin_squared).max(0.0).sqrt();
+        // 1 - cos_max, without losing precision for small, far away spheres
+        return (cos_max, 2.0 * std::f32::consts::PI * sin_squared / (1.0 + cos_max));
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * std::f32::consts::PI * self.radius.powi(2)
+    }
+
+    // Latitude and longitude of a point on the unit sphere, given by its outward normal
+    // u goes around the y axis starting from -x, v goes from the bottom pole to the top
+    fn surface_coordinates(&self, normal: Vec3) -> (f32, f32) {
+        let theta = (-normal.y).clamp(-1.0, 1.0).acos();
+        let phi = (-normal.z).atan2(normal.x) + std::f32::consts::PI;
+        return (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI);
+    }
 }
 
 /// A rectangle defined by an origin point and two vectors
@@ -105,17 +326,25 @@ pub struct Rect<T: Material> {
     // D is then derived from Ax + By + Cz = D
     // There really is no good descriptive name for it
     d: f32, 
+    // Whether rays hit the back as well, the side u × v points away from
+    two_sided: bool,
+    // Whether an emitting material only shines out of the front, instead of out of both sides
+    one_sided_emission: bool,
     material: T
 }
 
 impl <T: Material> Object for Rect<T> {
-    fn intersect(&self, rng: &mut ThreadRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
         let dividend = self.d - self.normal.dot(ray.origin);
         let divisor = self.normal.dot(ray.direction);
         // If ray is near parallel, return None
         if divisor.abs() < 0.000001 {
             return  None;
         }
+        let front_face = divisor < 0.0;
+        if !front_face && !self.two_sided {
+            return None;
+        }
         let t = dividend / divisor;
         // If t is outside the hit interval, return None
         if !hit_interval.surrounds(t) {
@@ -130,36 +359,118 @@ impl <T: Material> Object for Rect<T> {
             return None;
         }
         //println!("Plane hit!");
-        return Some(Hit::new(
+        if self.material.is_cut_out(rng, beta, alpha, position) {
+            return None;
+        }
+        // beta runs along u and alpha along v, so they're the surface coordinates as they are
+        let scatter = self.material.scatter(rng, ray, position, self.normal, self.u.normalize(), beta, alpha);
+        let emitted = match front_face || !self.one_sided_emission {
+            true => self.emit(beta, alpha, position, ray.time),
+            false => Color::ZERO
+        };
+        let hit = Hit::new(
             ray,
             t,
             position,
             self.normal,
-            self.albedo(),
-            self.bounce(rng, ray, position, self.normal),
+            scatter,
             self.is_emitter(),
-            self.emit()
-        ));
+            emitted
+        );
+        return Some(hit.with_uv(beta, alpha).with_tangent(self.u.normalize()).with_shadow_catcher(self.is_shadow_catcher()));
     }
 
-    fn normal(&self, _point: Vec3) -> Vec3 {
-        self.normal
+    fn albedo(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.albedo(u, v, position)
     }
 
-    fn albedo(&self) -> Color {
-        self.material.albedo()
+    fn is_emitter(&self) -> bool {
+        self.material.is_emitter()
     }
 
-    fn bounce(&self, rng: &mut ThreadRng, incoming: &Ray, position: Vec3, normal: Vec3) -> Ray {
-        self.material.bounce(rng, incoming, position, normal)
+    fn emit(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.material.emit(u, v, position)
     }
 
-    fn is_emitter(&self) -> bool {
-        self.material.is_emitter()
+    fn is_shadow_catcher(&self) -> bool {
+        self.material.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let diagonal = BoundingBox::from_points(self.origin, self.origin + self.u + self.v);
+        let other_diagonal = BoundingBox::from_points(self.origin + self.u, self.origin + self.v);
+        BoundingBox::union(&diagonal, &other_diagonal)
     }
 
-    fn emit(&self) -> Color {
-        self.material.emit()
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let bsdf = self.material.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        (bsdf, self.material.scattering_pdf(incoming, scattered, position, normal, tangent, u, v))
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        match self.is_emitter() {
+            true => Some(self),
+            false => None
+        }
+    }
+}
+
+impl<T: Material> Light for Rect<T> {
+    // Any point on the rectangle is as likely as any other
+    fn sample_point(&self, rng: &mut SamplerRng, origin: Vec3, time: f32) -> Option<(Vec3, f32)> {
+        // Nothing behind a rectangle that only shines forwards is lit by it
+        if self.one_sided_emission && self.normal.dot(origin) <= self.d {
+            return None;
+        }
+        let point = self.origin + self.u * rng.gen::<f32>() + self.v * rng.gen::<f32>();
+        let pdf = self.pdf(origin, point, self.normal, time);
+        match pdf.is_finite() {
+            true => Some((point, pdf)),
+            false => None
+        }
+    }
+
+    // Turned from area to solid angle, which grows with distance and shrinks as the rectangle is seen edge on
+    fn pdf(&self, origin: Vec3, point: Vec3, _normal: Vec3, _time: f32) -> f32 {
+        let to_point = point - origin;
+        let cosine = self.normal.dot(to_point.normalize()).abs();
+        to_point.length_squared() / (self.area() * cosine)
+    }
+
+    // Averaged over a grid of points across the rectangle
+    fn power(&self) -> f32 {
+        let side = (POWER_SAMPLES as f32).sqrt() as usize;
+        let total: f32 = (0..side * side)
+            .map(|i| {
+                let (u, v) = (((i % side) as f32 + 0.5) / side as f32, ((i / side) as f32 + 0.5) / side as f32);
+                self.emit(u, v, self.origin + self.u * u + self.v * v, 0.0).dot(Color::ONE) / 3.0
+            })
+            .sum();
+        let sides = match self.one_sided_emission {
+            true => 1.0,
+            false => 2.0
+        };
+        sides * std::f32::consts::PI * self.area() * total / (side * side) as f32
+    }
+
+    // Either side is as likely as the other if both shine
+    fn sample_surface(&self, rng: &mut SamplerRng, _time: f32) -> Option<(Vec3, Vec3, f32)> {
+        let point = self.origin + self.u * rng.gen::<f32>() + self.v * rng.gen::<f32>();
+        match self.one_sided_emission {
+            true => Some((point, self.normal, 1.0 / self.area())),
+            false => match rng.gen::<bool>() {
+                true => Some((point, self.normal, 0.5 / self.area())),
+                false => Some((point, -self.normal, 0.5 / self.area()))
+            }
+        }
+    }
+
+    fn surface_pdf(&self, _point: Vec3, _normal: Vec3, direction: Vec3, _time: f32) -> f32 {
+        match (self.one_sided_emission, self.normal.dot(direction) > 0.0) {
+            (false, _) => 0.5 / self.area(),
+            (true, true) => 1.0 / self.area(),
+            (true, false) => 0.0
+        }
     }
 }
 
@@ -175,7 +486,668 @@ impl <T: Material> Rect<T> {
             v,
             normal,
             d,
+            two_sided: true,
+            one_sided_emission: false,
             material
         }
     }
-}
\ No newline at end of file
+
+    /// Whether the back can be hit. A one sided rectangle can be seen through from behind, like a wall
+    /// that's only there to be looked at from inside a room
+    pub fn with_two_sided(mut self, two_sided: bool) -> Rect<T> {
+        self.two_sided = two_sided;
+        self
+    }
+
+    /// Whether an emitting rectangle only shines out of the front, the side u × v points to, like a panel set into a ceiling
+    /// Otherwise it lights up both sides, like a panel hanging freely
+    pub fn with_one_sided_emission(mut self, one_sided: bool) -> Rect<T> {
+        self.one_sided_emission = one_sided;
+        self
+    }
+
+    pub fn area(&self) -> f32 {
+        self.u.cross(self.v).length()
+    }
+}
+impl Sphere<DiffuseLight> {
+    /// A spherical light giving off a total power, however big it is
+    pub fn emitter(center: Vec3, radius: f32, color: Color, power: LightPower) -> Sphere<DiffuseLight> {
+        let area = 4.0 * std::f32::consts::PI * radius.powi(2);
+        Sphere::new(center, radius, DiffuseLight::with_power(color, power, area))
+    }
+}
+
+impl Rect<DiffuseLight> {
+    /// A rectangular light giving off a total power, however big it is
+    pub fn emitter(origin: Vec3, u: Vec3, v: Vec3, color: Color, power: LightPower) -> Rect<DiffuseLight> {
+        let area = u.cross(v).length();
+        Rect::new(origin, u, v, DiffuseLight::with_power(color, power, area))
+    }
+}
+
+/// Wraps an object so it's cut out of the image, showing the background where it's seen directly
+/// It still casts shadows and shows up in reflections, for compositing against live footage of the real object
+pub struct Holdout<T: Object> {
+    object: T
+}
+
+impl<T: Object> Object for Holdout<T> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        self.object.intersect(rng, ray, hit_interval).map(|hit| hit.with_holdout(true))
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.albedo(u, v, position, time)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.object.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.emit(u, v, position, time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.object.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.object.bounding_box()
+    }
+
+    fn is_holdout(&self) -> bool {
+        true
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        self.object.evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        self.object.light()
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        self.object.light_group()
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        self.object.material_name()
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.object.moved(position, from, to)
+    }
+}
+
+impl<T: Object> Holdout<T> {
+    pub fn new(object: T) -> Holdout<T> {
+        Holdout { object }
+    }
+}
+
+/// Wraps a light to gather what it lights up into an image of its own besides the full render,
+/// so how bright each group of lights is can be balanced afterwards by adding the images up
+pub struct LightGroup<T: Object> {
+    object: T,
+    name: String
+}
+
+impl<T: Object> Object for LightGroup<T> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        self.object.intersect(rng, ray, hit_interval)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.albedo(u, v, position, time)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.object.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.emit(u, v, position, time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.object.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.object.bounding_box()
+    }
+
+    fn is_holdout(&self) -> bool {
+        self.object.is_holdout()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        self.object.evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        self.object.light()
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        self.object.material_name()
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.object.moved(position, from, to)
+    }
+}
+
+impl<T: Object> LightGroup<T> {
+    pub fn new(object: T, name: &str) -> LightGroup<T> {
+        LightGroup { object, name: name.to_owned() }
+    }
+}
+
+/// Wraps an object to name its material, so every object with a material of the same name
+/// shares one id in the material id pass and can be masked together
+pub struct NamedMaterial<T: Object> {
+    object: T,
+    name: String
+}
+
+impl<T: Object> Object for NamedMaterial<T> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        self.object.intersect(rng, ray, hit_interval)
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.albedo(u, v, position, time)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.object.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.emit(u, v, position, time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.object.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.object.bounding_box()
+    }
+
+    fn is_holdout(&self) -> bool {
+        self.object.is_holdout()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        self.object.evaluate(incoming, scattered, position, normal, tangent, u, v)
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        self.object.light()
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        self.object.light_group()
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.object.moved(position, from, to)
+    }
+}
+
+impl<T: Object> NamedMaterial<T> {
+    pub fn new(object: T, name: &str) -> NamedMaterial<T> {
+        NamedMaterial { object, name: name.to_owned() }
+    }
+}
+
+/// Wraps an object to paint it neutral gray clay instead of its own material, keeping its shape and any light it gives off,
+/// so the lighting and composition of a scene can be judged without the materials getting in the way
+/// Both sides of a clay surface scatter light, so nothing goes black for facing the wrong way
+pub struct Clay {
+    object: Box<dyn Object>,
+    material: Lambertian
+}
+
+impl Object for Clay {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        let mut hit = self.object.intersect(rng, ray, hit_interval)?;
+        if hit.is_emitter {
+            return Some(hit);
+        }
+        let scatter = self.material.scatter(rng, ray, hit.position, hit.normal, hit.tangent, hit.u, hit.v);
+        hit.albedo = scatter.attenuation;
+        hit.outgoing = scatter.scattered_ray;
+        hit.pdf = scatter.pdf;
+        hit.is_specular = scatter.is_specular;
+        return Some(hit);
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        match self.object.is_emitter() {
+            true => self.object.albedo(u, v, position, time),
+            false => self.material.albedo(u, v, position)
+        }
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.object.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.emit(u, v, position, time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.object.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.object.bounding_box()
+    }
+
+    fn is_holdout(&self) -> bool {
+        self.object.is_holdout()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        if self.object.is_emitter() {
+            return self.object.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        }
+        let normal = facing(normal, incoming);
+        let bsdf = self.material.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        return (bsdf, self.material.scattering_pdf(incoming, scattered, position, normal, tangent, u, v));
+    }
+
+    fn light(&self) -> Option<&dyn Light> {
+        self.object.light()
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        self.object.light_group()
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        self.object.material_name()
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.object.moved(position, from, to)
+    }
+}
+
+impl Clay {
+    pub fn new(object: Box<dyn Object>) -> Clay {
+        Clay { object, material: Lambertian::new(CLAY_ALBEDO, CLAY_ALBEDO, CLAY_ALBEDO) }
+    }
+}
+
+// The normal flipped to the side a ray comes from
+fn facing(normal: Vec3, incoming: &Ray) -> Vec3 {
+    match normal.dot(incoming.direction) < 0.0 {
+        true => normal,
+        false => -normal
+    }
+}
+
+/// Wraps an object to move, turn and scale it along a transform track, following the time of each ray
+/// Rays are moved into the object's own space to be intersected, and the hit is moved back out
+pub struct Animated<T: Object> {
+    object: T,
+    track: TransformTrack,
+    bounding_box: BoundingBox
+}
+
+impl<T: Object> Object for Animated<T> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        let to_world = self.track.sample(ray.time);
+        let to_object = to_world.inverse();
+        // The direction isn't normalized, so t means the same in both spaces
+        let local_ray = ray.continued(to_object.transform_point3(ray.origin), to_object.transform_vector3(ray.direction));
+        let mut hit = self.object.intersect(rng, &local_ray, hit_interval)?;
+        hit.position = to_world.transform_point3(hit.position);
+        hit.normal = to_object.transpose().transform_vector3(hit.normal).normalize();
+        hit.tangent = to_world.transform_vector3(hit.tangent).normalize_or_zero();
+        hit.outgoing.origin = to_world.transform_point3(hit.outgoing.origin);
+        hit.outgoing.direction = to_world.transform_vector3(hit.outgoing.direction);
+        return Some(hit);
+    }
+
+    // These see the object where the track has it at the time, and hand the point over in the object's own space
+    fn albedo(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.albedo(u, v, self.track.sample(time).inverse().transform_point3(position), time)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.object.is_emitter()
+    }
+
+    fn emit(&self, u: f32, v: f32, position: Vec3, time: f32) -> Color {
+        self.object.emit(u, v, self.track.sample(time).inverse().transform_point3(position), time)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.object.is_shadow_catcher()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.bounding_box
+    }
+
+    fn is_holdout(&self) -> bool {
+        self.object.is_holdout()
+    }
+
+    // Only the directions matter to the material, so only they are moved into the object's space
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let to_world = self.track.sample(incoming.time);
+        let to_object = to_world.inverse();
+        let local_position = to_object.transform_point3(position);
+        let local_incoming = incoming.continued(to_object.transform_point3(incoming.origin), to_object.transform_vector3(incoming.direction));
+        let local_scattered = scattered.continued(local_position, to_object.transform_vector3(scattered.direction));
+        let local_normal = to_world.transpose().transform_vector3(normal).normalize();
+        let local_tangent = to_object.transform_vector3(tangent).normalize_or_zero();
+        self.object.evaluate(&local_incoming, &local_scattered, local_position, local_normal, local_tangent, u, v)
+    }
+
+    // The wrapped object's light, moved along the track
+    fn light(&self) -> Option<&dyn Light> {
+        self.object.light().map(|_| self as &dyn Light)
+    }
+
+    fn light_group(&self) -> Option<&str> {
+        self.object.light_group()
+    }
+
+    fn material_name(&self) -> Option<&str> {
+        self.object.material_name()
+    }
+
+    // The point is carried along by the track, and by the object's own movement within it
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        let local = self.track.sample(from).inverse().transform_point3(position);
+        self.track.sample(to).transform_point3(self.object.moved(local, from, to))
+    }
+}
+
+// Points and normals are moved into the object's space for the wrapped light, and its densities moved back out
+// The track can stretch the object, so densities are scaled by how much it stretches the directions or the surface there
+impl<T: Object> Light for Animated<T> {
+    fn sample_point(&self, rng: &mut SamplerRng, origin: Vec3, time: f32) -> Option<(Vec3, f32)> {
+        let light = self.object.light()?;
+        let to_world = self.track.sample(time);
+        let local_origin = to_world.inverse().transform_point3(origin);
+        let (local_point, pdf) = light.sample_point(rng, local_origin, time)?;
+        let point = to_world.transform_point3(local_point);
+        return Some((point, pdf * self.solid_angle_scale(time, local_point - local_origin)));
+    }
+
+    fn pdf(&self, origin: Vec3, point: Vec3, normal: Vec3, time: f32) -> f32 {
+        let Some(light) = self.object.light() else {
+            return 0.0;
+        };
+        let to_world = self.track.sample(time);
+        let to_object = to_world.inverse();
+        let (local_origin, local_point) = (to_object.transform_point3(origin), to_object.transform_point3(point));
+        let local_normal = to_world.transpose().transform_vector3(normal).normalize();
+        light.pdf(local_origin, local_point, local_normal, time) * self.solid_angle_scale(time, local_point - local_origin)
+    }
+
+    // Grown or shrunk by how much the track stretches the surface at time 0, which is exact for even scaling
+    fn power(&self) -> f32 {
+        let power = self.object.light().map_or(0.0, |light| light.power());
+        power * Mat3::from_mat4(self.track.sample(0.0)).determinant().abs().powf(2.0 / 3.0)
+    }
+
+    fn sample_surface(&self, rng: &mut SamplerRng, time: f32) -> Option<(Vec3, Vec3, f32)> {
+        let light = self.object.light()?;
+        let to_world = self.track.sample(time);
+        let (local_point, local_normal, pdf) = light.sample_surface(rng, time)?;
+        let normal = to_world.inverse().transpose().transform_vector3(local_normal).normalize();
+        return Some((to_world.transform_point3(local_point), normal, pdf / self.area_scale(time, local_normal)));
+    }
+
+    fn surface_pdf(&self, point: Vec3, normal: Vec3, direction: Vec3, time: f32) -> f32 {
+        let Some(light) = self.object.light() else {
+            return 0.0;
+        };
+        let to_world = self.track.sample(time);
+        let to_object = to_world.inverse();
+        let local_normal = to_world.transpose().transform_vector3(normal).normalize();
+        let pdf = light.surface_pdf(to_object.transform_point3(point), local_normal, to_object.transform_vector3(direction), time);
+        pdf / self.area_scale(time, local_normal)
+    }
+}
+
+impl<T: Object> Animated<T> {
+    pub fn new(object: T, track: TransformTrack) -> Animated<T> {
+        let bounding_box = animated_bounds(&object.bounding_box(), &track);
+        Animated { object, track, bounding_box }
+    }
+
+    // How much smaller a bit of solid angle around a direction in the object's space gets once moved out of it
+    // A linear map A takes a bit of solid angle around d to |det A| / |A d|³ times as much
+    fn solid_angle_scale(&self, time: f32, local_direction: Vec3) -> f32 {
+        let linear = Mat3::from_mat4(self.track.sample(time));
+        (linear * local_direction.normalize()).length().powi(3) / linear.determinant().abs()
+    }
+
+    // How much bigger a bit of surface with a normal in the object's space gets once moved out of it,
+    // |det A| times the length of the normal moved like normals are
+    fn area_scale(&self, time: f32, local_normal: Vec3) -> f32 {
+        let linear = Mat3::from_mat4(self.track.sample(time));
+        linear.determinant().abs() * (linear.inverse().transpose() * local_normal).length()
+    }
+}
+
+/// A volume of smoke or fog filling a closed object, with particles spread evenly throughout it
+/// A ray going through is scattered somewhere inside at random, more likely the denser the volume and the
+/// longer the way through it, and otherwise passes through as if the volume wasn't there
+/// The phase function is the material of the particles, like Isotropic or HenyeyGreenstein
+pub struct ConstantMedium<T: Object, M: Material> {
+    boundary: T,
+    // Chance of hitting a particle per unit of distance
+    density: f32,
+    phase_function: M,
+    // How far past where a ray enters the boundary to look for where it leaves, the camera's hit epsilon
+    hit_epsilon: f32
+}
+
+impl<T: Object, M: Material> Object for ConstantMedium<T, M> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        // Find where the ray enters and leaves the boundary, even if it starts inside
+        let entry = self.boundary.intersect(rng, ray, &Interval::UNIVERSE)?;
+        let exit = self.boundary.intersect(rng, ray, &Interval::new(entry.t + self.hit_epsilon, f32::INFINITY))?;
+        let (start, end) = (entry.t.max(hit_interval.min).max(0.0), exit.t.min(hit_interval.max));
+        if start >= end {
+            return None;
+        }
+        // Distances to the first particle are exponentially distributed
+        let length = ray.direction.length();
+        let hit_distance = -(1.0 - rng.gen::<f32>()).ln() / self.density;
+        if hit_distance > (end - start) * length {
+            return None;
+        }
+        let t = start + hit_distance / length;
+        let position = ray.pos(t);
+        // There's no surface, so the normal just faces back along the ray
+        let normal = -ray.direction / length;
+        let scatter = self.phase_function.scatter(rng, ray, position, normal, Vec3::ZERO, 0.0, 0.0);
+        return Some(Hit::new(ray, t, position, normal, scatter, false, Color::ZERO));
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.phase_function.albedo(u, v, position)
+    }
+
+    fn is_emitter(&self) -> bool {
+        false
+    }
+
+    fn emit(&self, _u: f32, _v: f32, _position: Vec3, _time: f32) -> Color {
+        Color::ZERO
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.boundary.bounding_box()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let phase = self.phase_function.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        (phase, self.phase_function.scattering_pdf(incoming, scattered, position, normal, tangent, u, v))
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.boundary.moved(position, from, to)
+    }
+}
+
+impl<T: Object, M: Material> ConstantMedium<T, M> {
+    /// Fills a closed boundary with particles scattering light by a phase function, the boundary's own material is never seen
+    pub fn new(boundary: T, density: f32, phase_function: M) -> ConstantMedium<T, M> {
+        ConstantMedium { boundary, density, phase_function, hit_epsilon: DEFAULT_HIT_EPSILON }
+    }
+
+    /// Looks for where rays leave the boundary this far past where they enter, which should be the camera's hit epsilon
+    pub fn with_hit_epsilon(mut self, hit_epsilon: f32) -> ConstantMedium<T, M> {
+        self.hit_epsilon = hit_epsilon;
+        self
+    }
+}
+
+/// A volume filling a closed object whose density changes from point to point, like wisps of smoke or clouds
+/// Rays are delta tracked through it: they step ahead as if the volume was at its densest everywhere,
+/// and at each step hit a real particle with the chance that the density there makes up of that
+/// Particles that don't scatter light absorb it, and if the volume is hot they glow as much as they absorb
+pub struct HeterogeneousMedium<T: Object, D: DensityField, M: Material> {
+    boundary: T,
+    density: D,
+    phase_function: M,
+    emission: Option<BlackbodyEmission>,
+    // How far past where a ray enters the boundary to look for where it leaves, the camera's hit epsilon
+    hit_epsilon: f32
+}
+
+impl<T: Object, D: DensityField, M: Material> Object for HeterogeneousMedium<T, D, M> {
+    fn intersect(&self, rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        let entry = self.boundary.intersect(rng, ray, &Interval::UNIVERSE)?;
+        let exit = self.boundary.intersect(rng, ray, &Interval::new(entry.t + self.hit_epsilon, f32::INFINITY))?;
+        let (start, end) = (entry.t.max(hit_interval.min).max(0.0), exit.t.min(hit_interval.max));
+        let max_density = self.density.max_density();
+        if start >= end || max_density <= 0.0 {
+            return None;
+        }
+        let length = ray.direction.length();
+        let mut t = start;
+        loop {
+            t += -(1.0 - rng.gen::<f32>()).ln() / (max_density * length);
+            if t >= end {
+                return None;
+            }
+            let position = ray.pos(t);
+            // The rest of the steps hit nothing at all, and the ray goes on unchanged
+            if rng.gen::<f32>() * max_density < self.density.density(position) {
+                let normal = -ray.direction / length;
+                let scatter = self.phase_function.scatter(rng, ray, position, normal, Vec3::ZERO, 0.0, 0.0);
+                let emitted = self.emit(0.0, 0.0, position, ray.time);
+                return Some(Hit::new(ray, t, position, normal, scatter, self.is_emitter(), emitted));
+            }
+        }
+    }
+
+    fn albedo(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        self.phase_function.albedo(u, v, position)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.emission.is_some()
+    }
+
+    // Only the absorbed share of the light arriving at a particle is given off again, the rest is scattered
+    fn emit(&self, u: f32, v: f32, position: Vec3, _time: f32) -> Color {
+        let absorption = (Color::ONE - self.phase_function.albedo(u, v, position)).max(Color::ZERO);
+        self.emission.as_ref().map_or(Color::ZERO, |emission| absorption * emission.radiance(position))
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.boundary.bounding_box()
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, tangent: Vec3, u: f32, v: f32) -> (Color, f32) {
+        let phase = self.phase_function.evaluate(incoming, scattered, position, normal, tangent, u, v);
+        (phase, self.phase_function.scattering_pdf(incoming, scattered, position, normal, tangent, u, v))
+    }
+
+    fn moved(&self, position: Vec3, from: f32, to: f32) -> Vec3 {
+        self.boundary.moved(position, from, to)
+    }
+}
+
+impl<T: Object, D: DensityField, M: Material> HeterogeneousMedium<T, D, M> {
+    /// Fills a closed boundary with particles spread out by a density field, which is in world space
+    pub fn new(boundary: T, density: D, phase_function: M) -> HeterogeneousMedium<T, D, M> {
+        HeterogeneousMedium { boundary, density, phase_function, emission: None, hit_epsilon: DEFAULT_HIT_EPSILON }
+    }
+
+    /// Looks for where rays leave the boundary this far past where they enter, which should be the camera's hit epsilon
+    pub fn with_hit_epsilon(mut self, hit_epsilon: f32) -> HeterogeneousMedium<T, D, M> {
+        self.hit_epsilon = hit_epsilon;
+        self
+    }
+
+    /// Makes the volume glow with the light of its temperature
+    pub fn with_emission(mut self, emission: BlackbodyEmission) -> HeterogeneousMedium<T, D, M> {
+        self.emission = Some(emission);
+        self
+    }
+}
+
+// Steps between keyframes the bounds are checked at, since smooth paths can swing past the keyframes
+const BOUNDS_STEPS: usize = 16;
+
+// A box around everywhere the object goes, from its corners at every keyframe and a few steps in between
+fn animated_bounds(local: &BoundingBox, track: &TransformTrack) -> BoundingBox {
+    let mut times = track.keyframe_times();
+    if times.is_empty() {
+        times.push(0.0);
+    }
+    let (min, max) = (local.min(), local.max());
+    let corners: Vec<Vec3> = (0..8)
+        .map(|i| Vec3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z }
+        ))
+        .collect();
+    let mut samples = vec![times[0]];
+    for pair in times.windows(2) {
+        samples.extend((1..=BOUNDS_STEPS).map(|step| pair[0] + (pair[1] - pair[0]) * step as f32 / BOUNDS_STEPS as f32));
+    }
+    samples.iter()
+        .flat_map(|&time| {
+            let to_world = track.sample(time);
+            corners.iter().map(move |&corner| to_world.transform_point3(corner))
+        })
+        .fold(BoundingBox::EMPTY, |bounds, point| BoundingBox::union(&bounds, &BoundingBox::from_points(point, point)))
+}
@@ -0,0 +1,33 @@
+// Orthonormal bases, for turning directions sampled around +z into directions around a normal
+
+use glam::Vec3;
+
+/// Three perpendicular unit vectors, with w along the normal the basis was built around
+#[derive(Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3
+}
+
+impl Onb {
+    /// A basis around a unit normal, from "Building an Orthonormal Basis, Revisited" by Duff et al.
+    pub fn new(normal: Vec3) -> Onb {
+        let sign = 1.0_f32.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+        let u = Vec3::new(1.0 + sign * normal.x.powi(2) * a, sign * b, -sign * normal.x);
+        let v = Vec3::new(b, sign + normal.y.powi(2) * a, -normal.y);
+        Onb { u, v, w: normal }
+    }
+
+    /// A vector given in the basis' coordinates, in world space
+    pub fn to_world(self, local: Vec3) -> Vec3 {
+        local.x * self.u + local.y * self.v + local.z * self.w
+    }
+
+    /// A world space vector in the basis' coordinates
+    pub fn to_local(self, vector: Vec3) -> Vec3 {
+        Vec3::new(vector.dot(self.u), vector.dot(self.v), vector.dot(self.w))
+    }
+}
@@ -1,11 +1,109 @@
 use std::{
     fs::File,
-    io::{Error, Write},
+    io::{BufWriter, Error, ErrorKind, Write},
 };
+use exr::prelude::{
+    write_rgb_file, write_rgba_file, f16, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes, IntegerBounds, Layer,
+    LayerAttributes, SmallVec, WritableImage
+};
+use glam::Vec3;
+use image::{codecs::{jpeg::JpegEncoder, png::PngEncoder}, ColorType, ImageEncoder};
+use rand::thread_rng;
+use crate::color::SrgbColor;
+use crate::framebuffer::Framebuffer;
+use crate::sampler::BlueNoiseMask;
+
+type Color = Vec3;
 
+#[derive(Clone, Copy)]
 pub enum Format {
     BMP,
-    TGA
+    TGA,
+    // TGA with runs of the same color stored once, much smaller for flat areas like backgrounds and masks
+    RleTGA,
+    PNG,
+    // PNG with 16 bits per channel, so smooth gradients can't band
+    PNG16,
+    // Lossy and small, with a quality from 1 to 100, for quick previews and sharing on the web
+    JPEG(u8),
+    // OpenEXR with 32 bit floats, keeping the light as it is before it's encoded so it can be graded without banding
+    EXR,
+    // The same with 16 bit floats, half the size and still plenty for images to look at
+    HalfEXR,
+    // Netpbm's binary pixmap, just a line of text and the bytes, which nearly anything can read
+    PPM,
+    // The same with every byte written out as a number, so images can be diffed and read by hand
+    AsciiPPM,
+    // Portable float map, the float version of PPM and about the simplest way to pass light between programs
+    PFM
+}
+
+impl Format {
+    /// A format by name: bmp, tga, tga-rle, png, png16, jpeg or jpeg:<quality>, exr, exr-half, ppm, ppm-ascii or pfm
+    pub fn parse(name: &str) -> Result<Format, String> {
+        // Formats with settings are written as name:settings
+        let (name, settings) = name.split_once(':').unwrap_or((name, ""));
+        match name {
+            "bmp" => Ok(Format::BMP),
+            "tga" => Ok(Format::TGA),
+            "tga-rle" => Ok(Format::RleTGA),
+            "png" => Ok(Format::PNG),
+            "png16" => Ok(Format::PNG16),
+            "jpeg" | "jpg" => match settings {
+                "" => Ok(Format::JPEG(90)),
+                _ => match settings.parse::<u8>() {
+                    Ok(quality) if (1..=100).contains(&quality) => Ok(Format::JPEG(quality)),
+                    _ => Err(format!("Invalid JPEG quality \"{}\", expected 1 to 100", settings))
+                }
+            },
+            "exr" => Ok(Format::EXR),
+            "exr-half" => Ok(Format::HalfEXR),
+            "ppm" => Ok(Format::PPM),
+            "ppm-ascii" => Ok(Format::AsciiPPM),
+            "pfm" => Ok(Format::PFM),
+            _ => Err(format!("Unknown image format \"{}\", expected bmp, tga, tga-rle, png, png16, jpeg, exr, exr-half, ppm, ppm-ascii or pfm", name))
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::BMP => "bmp",
+            Format::TGA | Format::RleTGA => "tga",
+            Format::PNG | Format::PNG16 => "png",
+            Format::JPEG(_) => "jpg",
+            Format::EXR | Format::HalfEXR => "exr",
+            Format::PPM | Format::AsciiPPM => "ppm",
+            Format::PFM => "pfm"
+        }
+    }
+
+    /// Whether the format stores more than a byte of every channel, the light as it is for the float formats
+    /// Images in these formats are written from the light of every pixel with write_float_image instead of row by row
+    pub fn is_float(&self) -> bool {
+        matches!(self, Format::PNG16 | Format::EXR | Format::HalfEXR | Format::PFM)
+    }
+
+    /// Whether the format can store an alpha channel, for images with a transparent background
+    pub fn has_alpha(&self) -> bool {
+        matches!(self, Format::TGA | Format::RleTGA | Format::PNG | Format::PNG16 | Format::EXR | Format::HalfEXR)
+    }
+
+    /// Whether images with an alpha channel can be written in the format, so renders can fail before they start instead of once they're done
+    pub fn check_alpha(&self) -> Result<(), Error> {
+        match self.has_alpha() {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} images can't have an alpha channel, use tga, png, png16 or exr", self.extension())
+            ))
+        }
+    }
+
+    /// Whether the light groups, render passes and the denoised image go into the same file as layers instead of files of their own
+    /// Those are written with write_layers, holding the values of the passes rather than how they look
+    pub fn is_layered(&self) -> bool {
+        matches!(self, Format::EXR | Format::HalfEXR)
+    }
 }
 
 // -- TGA parameters --
@@ -19,77 +117,579 @@ const TGA_DEFAULT_HEADER: [u8; 18] = [
     0x00, 0x00, // Width in pixels (we want to change this)
     0x00, 0x00, // Height in pixels (and this)
     0x18, // Bits per pixel: 24
-    0x00, // Random stuff we don't care about
+    0x00, // Image descriptor: bits of alpha per pixel, and some random stuff we don't care about
 ];
+const TGA_IMAGE_TYPE_INDEX: usize = 2;
 const TGA_WIDTH_INDEX: usize = 12;
 const TGA_HEIGHT_INDEX: usize = 14;
+const TGA_BITS_PER_PIXEL_INDEX: usize = 16;
+const TGA_DESCRIPTOR_INDEX: usize = 17;
+// Image type of run-length encoded true color
+const TGA_RLE_IMAGE_TYPE: u8 = 0x0A;
+// Most pixels a single TGA packet can hold
+const TGA_MAX_PACKET: usize = 128;
 
 // -- BMP parameters --
-// Standard bitmap header followed by BITMAPCOREHEADER
-const BMP_DEFAULT_HEADER: [u8; 26] = [
-    'B' as u8, 'M' as u8, // BMP identifier
+// Standard bitmap header followed by BITMAPINFOHEADER
+const BMP_DEFAULT_HEADER: [u8; 54] = [
+    b'B', b'M', // BMP identifier
     0x00, 0x00, 0x00, 0x00, // Filesize (we want to change this)
     0x00, 0x00, 0x00, 0x00, // Reserved (can be ignored)
-    0x1A, 0x00, 0x00, 0x00, // Image data offset (size of header)
-    0x0C, 0x00, 0x00, 0x00, // Header size: 12 bytes
-    0x00, 0x00, // Width in pixels (we want to change this)
-    0x00, 0x00, // Height in pixels (we want to change this too!)
+    0x36, 0x00, 0x00, 0x00, // Image data offset (size of header)
+    0x28, 0x00, 0x00, 0x00, // Header size: 40 bytes
+    0x00, 0x00, 0x00, 0x00, // Width in pixels (we want to change this)
+    0x00, 0x00, 0x00, 0x00, // Height in pixels, positive for rows stored bottom to top (we want to change this too!)
     0x01, 0x00, // Number of color planes, whatever that is (must be 1 anyway)
     0x18, 0x00, // Bits per pixel: 24
+    0x00, 0x00, 0x00, 0x00, // Compression: none
+    0x00, 0x00, 0x00, 0x00, // Size of the image data (and this)
+    0x13, 0x0B, 0x00, 0x00, // Horizontal resolution: 2835 pixels per meter, 72 DPI
+    0x13, 0x0B, 0x00, 0x00, // Vertical resolution: the same
+    0x00, 0x00, 0x00, 0x00, // Colors in the palette: none
+    0x00, 0x00, 0x00, 0x00, // Important colors: all of them
 ];
 const BMP_FILESIZE_INDEX: usize = 2;
 const BMP_WIDTH_INDEX: usize = 18;
-const BMP_HEIGHT_INDEX: usize = 20;
-
-// Output the generated image to a .tga file
-pub fn write_tga(
-    image_data: &[Vec<u8>],
-    filename: &str,
-) -> Result<(), Error> {
-    let mut header = TGA_DEFAULT_HEADER.clone().to_vec();
-    let height = image_data.len() as u16;
-    let width = (image_data[0].len() / 3) as u16;
-    // Put dimensions in the header
-    header.splice(TGA_WIDTH_INDEX..TGA_WIDTH_INDEX + 2, width.to_le_bytes());
-    header.splice(TGA_HEIGHT_INDEX..TGA_HEIGHT_INDEX + 2, height.to_le_bytes());
-    // Create and write the file
-    let mut output_file = File::create(filename)?;
-    output_file.write(&header)?;
-    for row in image_data {
-        output_file.write(row)?;
-    }
-    Ok(())
-}
-
-// Output the generated image to a .bmp file
-pub fn write_bmp(
-    image_data: &[Vec<u8>],
-    filename: &str,
-) -> Result<(), Error> {
-    let mut header = BMP_DEFAULT_HEADER.clone().to_vec();
-    let height = image_data.len() as u16;
-    let width = (image_data[0].len() / 3) as u16;
-    header.splice(BMP_WIDTH_INDEX..BMP_WIDTH_INDEX + 2, width.to_le_bytes());
-    header.splice(BMP_HEIGHT_INDEX..BMP_HEIGHT_INDEX + 2, height.to_le_bytes());
-    let padding: Vec<u8> = vec![0; ((width * 3) % 4) as usize]; // The length of every row of image data must be a multiple of 4
-    // Do the padding (i was tired when writing this)
-    let image_data = image_data
-        .to_owned()
-        .into_iter()
-        .map(|mut row| {
-            row.extend_from_slice(&padding);
-            row
-        })
-        .flatten()
-        .collect::<Vec<u8>>();
-    let filesize = (header.len() + image_data.len()) as u32;
-    // Put filesize and dimensions in the header
-    header.splice(
-        BMP_FILESIZE_INDEX..BMP_FILESIZE_INDEX + 4,
-        filesize.to_le_bytes(),
-    );
-    let mut output_file = File::create(filename)?;
-    output_file.write(&header)?;
-    output_file.write(&image_data)?;
-    Ok(())
+const BMP_HEIGHT_INDEX: usize = 22;
+const BMP_IMAGE_SIZE_INDEX: usize = 34;
+
+/// Receives an image one row at a time, bottom to top, so the whole image never has to be in memory
+pub trait RowWriter {
+    fn write_row(&mut self, row: &[u8]) -> Result<(), Error>;
+    // Flush everything to disk. Must be called after the last row
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+/// Creates the file and writes the header, ready to receive rows, which have an alpha byte after every pixel if alpha is set
+pub fn open_writer(format: &Format, filename: &str, width: u32, height: u32, alpha: bool) -> Result<Box<dyn RowWriter>, Error> {
+    if alpha {
+        format.check_alpha()?;
+    }
+    match format {
+        Format::BMP => Ok(Box::new(BmpWriter::new(filename, width, height)?)),
+        Format::TGA => Ok(Box::new(TgaWriter::new(filename, width, height, false, alpha)?)),
+        Format::RleTGA => Ok(Box::new(TgaWriter::new(filename, width, height, true, alpha)?)),
+        Format::PNG | Format::JPEG(_) => Ok(Box::new(EncodedWriter::new(filename, width, height, *format, alpha)?)),
+        Format::PPM => Ok(Box::new(PpmWriter::new(filename, width, height, false)?)),
+        Format::AsciiPPM => Ok(Box::new(PpmWriter::new(filename, width, height, true)?)),
+        Format::PNG16 | Format::EXR | Format::HalfEXR | Format::PFM => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} images hold light, not bytes, and can't be written row by row", format.extension())
+        ))
+    }
+}
+
+pub struct TgaWriter {
+    output_file: BufWriter<File>,
+    // Whether rows are run-length encoded
+    rle: bool,
+    // Bytes per pixel, 4 with alpha
+    channels: usize
+}
+
+impl TgaWriter {
+    pub fn new(filename: &str, width: u32, height: u32, rle: bool, alpha: bool) -> Result<TgaWriter, Error> {
+        let (width, height) = tga_dimensions(width, height)?;
+        let mut header = TGA_DEFAULT_HEADER.to_vec();
+        if rle {
+            header[TGA_IMAGE_TYPE_INDEX] = TGA_RLE_IMAGE_TYPE;
+        }
+        // 32 bits per pixel, 8 of them alpha
+        if alpha {
+            header[TGA_BITS_PER_PIXEL_INDEX] = 0x20;
+            header[TGA_DESCRIPTOR_INDEX] = 0x08;
+        }
+        // Put dimensions in the header
+        header.splice(TGA_WIDTH_INDEX..TGA_WIDTH_INDEX + 2, width.to_le_bytes());
+        header.splice(TGA_HEIGHT_INDEX..TGA_HEIGHT_INDEX + 2, height.to_le_bytes());
+        // Create the file and write the header
+        let mut output_file = BufWriter::new(File::create(filename)?);
+        output_file.write_all(&header)?;
+        Ok(TgaWriter { output_file, rle, channels: if alpha { 4 } else { 3 } })
+    }
+
+    // Writes a row as packets of up to 128 pixels, each either a run of one color stored once or pixels stored as they are
+    // Packets never reach past the end of the row, which is what readers expect
+    fn write_rle_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        let channels = self.channels;
+        let pixels: Vec<&[u8]> = row.chunks_exact(channels).collect();
+        let mut start = 0;
+        while start < pixels.len() {
+            let run = pixels[start..].iter().take(TGA_MAX_PACKET).take_while(|&&pixel| pixel == pixels[start]).count();
+            if run > 1 {
+                self.output_file.write_all(&[0x80 | (run - 1) as u8])?;
+                self.output_file.write_all(pixels[start])?;
+                start += run;
+                continue;
+            }
+            // Raw pixels go on until two in a row are the same, which start the next run
+            let mut end = start + 1;
+            while end < pixels.len() && end - start < TGA_MAX_PACKET && (end + 1 >= pixels.len() || pixels[end] != pixels[end + 1]) {
+                end += 1;
+            }
+            self.output_file.write_all(&[(end - start - 1) as u8])?;
+            self.output_file.write_all(&row[start * channels..end * channels])?;
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+impl RowWriter for TgaWriter {
+    fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        match self.rle {
+            true => self.write_rle_row(row),
+            false => self.output_file.write_all(row)
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.output_file.flush()
+    }
+}
+
+pub struct BmpWriter {
+    output_file: BufWriter<File>,
+    padding: Vec<u8>
+}
+
+impl BmpWriter {
+    pub fn new(filename: &str, width: u32, height: u32) -> Result<BmpWriter, Error> {
+        let mut header = BMP_DEFAULT_HEADER.to_vec();
+        // The length of every row of image data must be a multiple of 4
+        let padding: Vec<u8> = vec![0; (4 - (width as usize * 3) % 4) % 4];
+        // The sizes are known up front, since every row is the same length
+        let image_size = (width as u64 * 3 + padding.len() as u64) * height as u64;
+        let filesize = header.len() as u64 + image_size;
+        let (Ok(signed_width), Ok(signed_height), Ok(image_size), Ok(filesize)) =
+            (i32::try_from(width), i32::try_from(height), u32::try_from(image_size), u32::try_from(filesize)) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("BMP files can be at most 4 GB, a {}x{} image doesn't fit", width, height)
+            ));
+        };
+        header.splice(BMP_WIDTH_INDEX..BMP_WIDTH_INDEX + 4, signed_width.to_le_bytes());
+        header.splice(BMP_HEIGHT_INDEX..BMP_HEIGHT_INDEX + 4, signed_height.to_le_bytes());
+        header.splice(BMP_IMAGE_SIZE_INDEX..BMP_IMAGE_SIZE_INDEX + 4, image_size.to_le_bytes());
+        header.splice(
+            BMP_FILESIZE_INDEX..BMP_FILESIZE_INDEX + 4,
+            filesize.to_le_bytes(),
+        );
+        let mut output_file = BufWriter::new(File::create(filename)?);
+        output_file.write_all(&header)?;
+        Ok(BmpWriter { output_file, padding })
+    }
+}
+
+impl RowWriter for BmpWriter {
+    fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        self.output_file.write_all(row)?;
+        self.output_file.write_all(&self.padding)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.output_file.flush()
+    }
+}
+
+/// PNG and JPEG images, compressed by the image crate. They store rows top to bottom and pixels in RGB or RGBA order,
+/// so the rows are kept until the last one is in and turned around then
+/// Streaming to them saves no memory, but the file is still created up front
+pub struct EncodedWriter {
+    output_file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    format: Format,
+    // Bytes per pixel, 4 with alpha
+    channels: usize,
+    // Rows received so far in RGB or RGBA order, bottom to top
+    data: Vec<u8>
+}
+
+impl EncodedWriter {
+    pub fn new(filename: &str, width: u32, height: u32, format: Format, alpha: bool) -> Result<EncodedWriter, Error> {
+        let output_file = BufWriter::new(File::create(filename)?);
+        let channels = if alpha { 4 } else { 3 };
+        Ok(EncodedWriter { output_file, width, height, format, channels, data: Vec::with_capacity(width as usize * height as usize * channels) })
+    }
+}
+
+impl RowWriter for EncodedWriter {
+    fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        for pixel in row.chunks_exact(self.channels) {
+            self.data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            self.data.extend_from_slice(&pixel[3..]);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let top_to_bottom = flip_rows(&self.data, self.width as usize * self.channels);
+        let color_type = match self.channels {
+            4 => ColorType::Rgba8,
+            _ => ColorType::Rgb8
+        };
+        let result = match self.format {
+            Format::JPEG(quality) => JpegEncoder::new_with_quality(&mut self.output_file, quality)
+                .write_image(&top_to_bottom, self.width, self.height, color_type),
+            _ => PngEncoder::new(&mut self.output_file).write_image(&top_to_bottom, self.width, self.height, color_type)
+        };
+        result.map_err(Error::other)?;
+        self.output_file.flush()
+    }
+}
+
+/// PPM stores rows top to bottom in RGB order like PNG, so the rows are kept until the last one is in too
+/// The ASCII flavor writes one row of numbers per line of text
+pub struct PpmWriter {
+    output_file: BufWriter<File>,
+    width: u32,
+    ascii: bool,
+    // Rows received so far in RGB order, bottom to top
+    data: Vec<u8>
+}
+
+impl PpmWriter {
+    pub fn new(filename: &str, width: u32, height: u32, ascii: bool) -> Result<PpmWriter, Error> {
+        let mut output_file = BufWriter::new(File::create(filename)?);
+        // The magic number, the size and the largest value of a byte
+        let magic = if ascii { "P3" } else { "P6" };
+        write!(output_file, "{}\n{} {}\n255\n", magic, width, height)?;
+        Ok(PpmWriter { output_file, width, ascii, data: Vec::with_capacity(width as usize * height as usize * 3) })
+    }
+}
+
+impl RowWriter for PpmWriter {
+    fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        for pixel in row.chunks_exact(3) {
+            self.data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let stride = self.width as usize * 3;
+        let top_to_bottom = flip_rows(&self.data, stride);
+        match self.ascii {
+            true => {
+                for row in top_to_bottom.chunks_exact(stride.max(1)) {
+                    let numbers: Vec<String> = row.iter().map(|byte| byte.to_string()).collect();
+                    writeln!(self.output_file, "{}", numbers.join(" "))?;
+                }
+            },
+            false => self.output_file.write_all(&top_to_bottom)?
+        }
+        self.output_file.flush()
+    }
+}
+
+// Turns rows of stride bytes around, from bottom to top to top to bottom
+fn flip_rows(data: &[u8], stride: usize) -> Vec<u8> {
+    match stride {
+        0 => vec![],
+        _ => data.chunks_exact(stride).rev().flatten().copied().collect()
+    }
+}
+
+/// How light is rounded to bytes. Rounding every pixel the same way turns smooth gradients into bands,
+/// so the dithered ones round pixels up or down by a pattern, trading the bands for a fine even grain
+pub enum Dither {
+    // Always round down
+    None,
+    // A Bayer matrix, a regular crosshatch that's cheap and compresses well
+    Ordered,
+    // A blue noise mask, an even grain without any pattern
+    BlueNoise(BlueNoiseMask)
+}
+
+// Thresholds of an 8x8 Bayer matrix, each pixel of a 2x2 block of them spread over the whole matrix
+const BAYER_MATRIX: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21]
+];
+
+impl Dither {
+    /// A way of dithering by name: none, ordered or blue-noise
+    pub fn parse(name: &str) -> Result<Dither, String> {
+        match name {
+            "none" => Ok(Dither::None),
+            "ordered" => Ok(Dither::Ordered),
+            "blue-noise" => Ok(Dither::BlueNoise(BlueNoiseMask::new(&mut thread_rng()))),
+            _ => Err(format!("Unknown dither \"{}\", expected none, ordered or blue-noise", name))
+        }
+    }
+
+    /// How much of a byte is added at a pixel before rounding down, from 0 to 1
+    pub fn threshold(&self, x: u32, y: u32) -> f32 {
+        match self {
+            Dither::None => 0.0,
+            Dither::Ordered => (BAYER_MATRIX[y as usize % 8][x as usize % 8] as f32 + 0.5) / 64.0,
+            Dither::BlueNoise(mask) => mask.value(x, y, 0)
+        }
+    }
+}
+
+/// The color of whatever covers a pixel, from light that's been scaled by how much of the pixel it covers
+/// PNG and TGA store colors like this, EXR stores the light as it is
+pub fn unpremultiplied(color: Color, alpha: f32) -> Color {
+    match alpha > 0.0 {
+        true => color / alpha,
+        false => Color::ZERO
+    }
+}
+
+// The TGA header stores the image size in 16 bits
+fn tga_dimensions(width: u32, height: u32) -> Result<(u16, u16), Error> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("TGA images can be at most {} pixels wide and high, got {}x{}", u16::MAX, width, height)
+        ))
+    }
+}
+
+// Output a finished image in the given format
+pub fn write_image(image: &Framebuffer, format: &Format, filename: &str) -> Result<(), Error> {
+    let mut writer = open_writer(format, filename, image.width as u32, image.height as u32, image.has_alpha())?;
+    for row in image.rows() {
+        writer.write_row(row)?;
+    }
+    writer.finish()
+}
+
+// Output linear light, stored bottom to top, in one of the float formats, with the alpha of every pixel if there is one
+// The light is premultiplied, already scaled by the alpha, which is how EXR stores it too
+pub fn write_float_image(width: usize, height: usize, colors: &[Color], alpha: Option<&[f32]>, format: &Format, filename: &str) -> Result<(), Error> {
+    if alpha.is_some() {
+        format.check_alpha()?;
+    }
+    // EXR stores rows top to bottom
+    let pixel = |x: usize, y: usize| colors[(height - 1 - y) * width + x];
+    let alpha_at = |x: usize, y: usize| alpha.map(|alpha| alpha[(height - 1 - y) * width + x]);
+    let result = match (format, alpha.is_some()) {
+        (Format::EXR, false) => write_rgb_file(filename, width, height, |x, y| {
+            let color = pixel(x, y);
+            (color.x, color.y, color.z)
+        }),
+        (Format::HalfEXR, false) => write_rgb_file(filename, width, height, |x, y| {
+            let color = pixel(x, y);
+            (f16::from_f32(color.x), f16::from_f32(color.y), f16::from_f32(color.z))
+        }),
+        (Format::EXR, true) => write_rgba_file(filename, width, height, |x, y| {
+            let color = pixel(x, y);
+            (color.x, color.y, color.z, alpha_at(x, y).unwrap_or(1.0))
+        }),
+        (Format::HalfEXR, true) => write_rgba_file(filename, width, height, |x, y| {
+            let color = pixel(x, y);
+            let alpha = alpha_at(x, y).unwrap_or(1.0);
+            (f16::from_f32(color.x), f16::from_f32(color.y), f16::from_f32(color.z), f16::from_f32(alpha))
+        }),
+        (Format::PFM, _) => return write_pfm(width, height, colors, filename),
+        (Format::PNG16, _) => return write_png16(width, height, colors, alpha, filename),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("{} images are bytes, not light", format.extension())))
+    };
+    result.map_err(Error::other)
+}
+
+/// Channels of a layer of light
+pub const RGB_CHANNELS: &[&str] = &["R", "G", "B"];
+
+/// One layer of a layered image, with a channel for every name, taking the x, y and z of the pixels in that order
+/// Integer layers hold whole numbers like ids, which are stored exactly, and layers of light can have an alpha channel
+pub struct ImageLayer<'a> {
+    pub name: &'a str,
+    pub channels: &'static [&'static str],
+    pub pixels: &'a [Color],
+    pub integer: bool,
+    pub alpha: Option<&'a [f32]>
+}
+
+/// Writes layers of the same size, bottom to top, into one multi-part EXR file that compositing programs read as one layer per part
+/// A single layer is written without a name, like an image that only has the one
+pub fn write_layers(width: usize, height: usize, layers: &[ImageLayer], format: &Format, filename: &str) -> Result<(), Error> {
+    if !format.is_layered() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} images can't hold layers", format.extension())));
+    }
+    let half = matches!(format, Format::HalfEXR);
+    let exr_layers: Vec<Layer<AnyChannels<FlatSamples>>> = layers.iter().map(|layer| {
+        let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = layer.channels.iter().enumerate().map(|(channel, &name)| {
+            // EXR stores rows top to bottom
+            let values = (0..height).rev().flat_map(|y| &layer.pixels[y * width..(y + 1) * width]).map(|pixel| pixel[channel]);
+            let samples = match (layer.integer, half) {
+                (true, _) => FlatSamples::U32(values.map(|value| value as u32).collect()),
+                (false, true) => FlatSamples::F16(values.map(f16::from_f32).collect()),
+                (false, false) => FlatSamples::F32(values.collect())
+            };
+            AnyChannel::new(name, samples)
+        }).collect();
+        if let Some(alpha) = layer.alpha {
+            let values = (0..height).rev().flat_map(|y| &alpha[y * width..(y + 1) * width]).copied();
+            let samples = match half {
+                true => FlatSamples::F16(values.map(f16::from_f32).collect()),
+                false => FlatSamples::F32(values.collect())
+            };
+            channels.push(AnyChannel::new("A", samples));
+        }
+        let attributes = match layers.len() {
+            1 => LayerAttributes::default(),
+            _ => LayerAttributes::named(layer.name)
+        };
+        Layer::new((width, height), attributes, Encoding::FAST_LOSSLESS, AnyChannels::sort(channels))
+    }).collect();
+    let attributes = ImageAttributes::new(IntegerBounds::from_dimensions((width, height)));
+    Image::from_layers(attributes, exr_layers).write().to_file(filename).map_err(Error::other)
+}
+
+// Encoded like the 8 bit formats, top to bottom in RGB or RGBA order
+fn write_png16(width: usize, height: usize, colors: &[Color], alpha: Option<&[f32]>, filename: &str) -> Result<(), Error> {
+    let to_u16 = |value: f32| ((value.clamp(0.0, 1.0) * 65535.0).round() as u16).to_ne_bytes();
+    let mut data = Vec::with_capacity(width * height * 8);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let index = y * width + x;
+            let pixel_alpha = alpha.map(|alpha| alpha[index]);
+            let encoded = SrgbColor::encode(unpremultiplied(colors[index], pixel_alpha.unwrap_or(1.0)));
+            for channel in encoded.values().to_array() {
+                data.extend_from_slice(&to_u16(channel));
+            }
+            if let Some(pixel_alpha) = pixel_alpha {
+                data.extend_from_slice(&to_u16(pixel_alpha));
+            }
+        }
+    }
+    let color_type = match alpha {
+        Some(_) => ColorType::Rgba16,
+        None => ColorType::Rgb16
+    };
+    let mut output_file = BufWriter::new(File::create(filename)?);
+    PngEncoder::new(&mut output_file)
+        .write_image(&data, width as u32, height as u32, color_type)
+        .map_err(Error::other)?;
+    output_file.flush()
+}
+
+// PFM stores rows bottom to top like the colors are, as little endian floats since the scale in the header is negative
+fn write_pfm(width: usize, height: usize, colors: &[Color], filename: &str) -> Result<(), Error> {
+    let mut output_file = BufWriter::new(File::create(filename)?);
+    write!(output_file, "PF\n{} {}\n-1.0\n", width, height)?;
+    for color in &colors[..width * height] {
+        for channel in color.to_array() {
+            output_file.write_all(&channel.to_le_bytes())?;
+        }
+    }
+    output_file.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("sagakar-output-test-{}-{}", std::process::id(), name));
+        return path.to_str().unwrap().to_string();
+    }
+
+    // Writes the rows with a writer and reads the file back
+    fn written(name: &str, writer: impl FnOnce(&str) -> Result<Box<dyn RowWriter>, Error>, rows: &[Vec<u8>]) -> Vec<u8> {
+        let path = temporary_path(name);
+        let mut writer = writer(&path).unwrap();
+        for row in rows {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        return bytes;
+    }
+
+    // Decodes the packets of one row, checking none is longer than TGA allows or reaches into the next row
+    fn decode_rle_row(data: &mut &[u8], width: usize, channels: usize) -> Vec<u8> {
+        let mut row = Vec::new();
+        while row.len() < width * channels {
+            let (header, rest) = data.split_first().unwrap();
+            let count = (header & 0x7F) as usize + 1;
+            assert!(count <= TGA_MAX_PACKET);
+            let stored = match header & 0x80 != 0 {
+                true => channels,
+                false => count * channels
+            };
+            let (pixels, rest) = rest.split_at(stored);
+            match header & 0x80 != 0 {
+                true => (0..count).for_each(|_| row.extend_from_slice(pixels)),
+                false => row.extend_from_slice(pixels)
+            }
+            *data = rest;
+        }
+        assert_eq!(row.len(), width * channels, "a packet reaches past the end of the row");
+        return row;
+    }
+
+    #[test]
+    fn rle_tga_packs_runs_and_raw_pixels() {
+        let row = [[1, 2, 3], [1, 2, 3], [1, 2, 3], [4, 5, 6], [7, 8, 9]].concat();
+        let bytes = written("packets.tga", |path| Ok(Box::new(TgaWriter::new(path, 5, 1, true, false)?)), &[row]);
+        assert_eq!(bytes[TGA_IMAGE_TYPE_INDEX], TGA_RLE_IMAGE_TYPE);
+        assert_eq!(&bytes[TGA_WIDTH_INDEX..TGA_WIDTH_INDEX + 4], &[5, 0, 1, 0]);
+        assert_eq!(&bytes[18..], &[0x82, 1, 2, 3, 0x01, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn rle_tga_round_trips() {
+        let width = 300;
+        let pixel = |x: usize, y: usize| -> [u8; 4] {
+            match y {
+                // One long run, split into packets of 128
+                0 => [9, 9, 9, 255],
+                // No two pixels alike, split into raw packets of 128
+                1 => [x as u8, (x / 256) as u8, 0, 255],
+                // Runs growing from 1 to 29 pixels, then raw pixels to the end of the row
+                _ => [(x as f32).sqrt() as u8, (x > 200 && x.is_multiple_of(2)) as u8, 0, 128]
+            }
+        };
+        for channels in [3, 4] {
+            let rows: Vec<Vec<u8>> = (0..3).map(|y| (0..width).flat_map(|x| pixel(x, y)[..channels].to_vec()).collect()).collect();
+            let name = format!("round-trip-{}.tga", channels);
+            let bytes = written(&name, |path| Ok(Box::new(TgaWriter::new(path, width as u32, 3, true, channels == 4)?)), &rows);
+            let mut data = &bytes[18..];
+            for row in &rows {
+                assert_eq!(&decode_rle_row(&mut data, width, channels), row);
+            }
+            assert!(data.is_empty());
+        }
+    }
+
+    #[test]
+    fn bmp_rows_are_padded_to_four_bytes() {
+        for (width, padding) in [(4, 0), (5, 1), (6, 2), (7, 3)] {
+            let rows: Vec<Vec<u8>> = (0..2).map(|y| (0..width * 3).map(|i| (i + y * 100) as u8).collect()).collect();
+            let name = format!("padding-{}.bmp", width);
+            let bytes = written(&name, |path| Ok(Box::new(BmpWriter::new(path, width as u32, 2)?)), &rows);
+            let stride = width * 3 + padding;
+            let field = |index: usize| u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap()) as usize;
+            assert_eq!(bytes.len(), 54 + 2 * stride);
+            assert_eq!(field(BMP_FILESIZE_INDEX), bytes.len());
+            assert_eq!(field(BMP_IMAGE_SIZE_INDEX), 2 * stride);
+            assert_eq!((field(BMP_WIDTH_INDEX), field(BMP_HEIGHT_INDEX)), (width, 2));
+            for (y, row) in rows.iter().enumerate() {
+                let stored = &bytes[54 + y * stride..54 + (y + 1) * stride];
+                assert_eq!(&stored[..width * 3], row.as_slice());
+                assert!(stored[width * 3..].iter().all(|&byte| byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_images_are_errors() {
+        assert!(BmpWriter::new(&temporary_path("huge.bmp"), 1 << 20, 1 << 20).is_err());
+        assert!(TgaWriter::new(&temporary_path("huge.tga"), 70000, 1, false, false).is_err());
+    }
 }
@@ -0,0 +1,74 @@
+// Perlin noise as described in "Ray Tracing: The Next Week"
+// Random gradient vectors on a lattice, picked by hashing the lattice point with three permutation tables
+
+use rand::seq::SliceRandom;
+use glam::Vec3;
+use crate::material::random_unit_vector;
+
+const POINT_COUNT: usize = 256;
+
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    permutation_x: Vec<usize>,
+    permutation_y: Vec<usize>,
+    permutation_z: Vec<usize>
+}
+
+impl Perlin {
+    pub fn new() -> Perlin {
+        let mut rng = rand::thread_rng();
+        let gradients = (0..POINT_COUNT).map(|_| random_unit_vector(&mut rng)).collect();
+        let mut permutation = || {
+            let mut table: Vec<usize> = (0..POINT_COUNT).collect();
+            table.shuffle(&mut rng);
+            table
+        };
+        let permutation_x = permutation();
+        let permutation_y = permutation();
+        let permutation_z = permutation();
+        Perlin {
+            gradients,
+            permutation_x,
+            permutation_y,
+            permutation_z
+        }
+    }
+
+    /// Smooth noise in roughly [-1, 1]
+    pub fn noise(&self, point: Vec3) -> f32 {
+        let floor = point.floor();
+        let local = point - floor;
+        let (i, j, k) = (floor.x as i64, floor.y as i64, floor.z as i64);
+        // Hermite smoothing removes the grid artifacts of plain trilinear interpolation
+        let smooth = local * local * (3.0 - 2.0 * local);
+        let mut accumulated = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let gradient = self.gradients[
+                        self.permutation_x[((i + di) & 255) as usize]
+                        ^ self.permutation_y[((j + dj) & 255) as usize]
+                        ^ self.permutation_z[((k + dk) & 255) as usize]
+                    ];
+                    let corner = Vec3::new(di as f32, dj as f32, dk as f32);
+                    let weight = corner * smooth + (Vec3::ONE - corner) * (Vec3::ONE - smooth);
+                    accumulated += weight.x * weight.y * weight.z * gradient.dot(local - corner);
+                }
+            }
+        }
+        return accumulated;
+    }
+
+    /// Sum of octaves of noise, each at double the frequency and half the amplitude of the last
+    pub fn turbulence(&self, point: Vec3, octaves: u32) -> f32 {
+        let mut accumulated = 0.0;
+        let mut point = point;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            accumulated += weight * self.noise(point);
+            weight *= 0.5;
+            point *= 2.0;
+        }
+        return accumulated.abs();
+    }
+}
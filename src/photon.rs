@@ -0,0 +1,243 @@
+// Progressive photon mapping, which sends light out from the lights and gathers it up wherever the camera sees it
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Mutex;
+use glam::Vec3;
+use rand::Rng;
+use crate::camera::Camera;
+use crate::integrator::{Integrator, DirectLighting};
+use crate::interval::Interval;
+use crate::material::sample_cosine_hemisphere;
+use crate::object::Object;
+use crate::ray::{Ray, Hit};
+
+type Color = Vec3;
+
+// The most photon passes kept around at once, samples past this many go through them again
+const MAX_PASSES: u32 = 256;
+// How slowly the gathering radius shrinks from one pass to the next, between 0 and 1
+const RADIUS_ALPHA: f32 = 2.0 / 3.0;
+// Share of the size of the scene the first gathering radius is, when it isn't given
+const RADIUS_SCALE: f32 = 0.01;
+
+/// Sends photons out from the lights before rendering and gathers them up around the first diffuse surface the camera sees,
+/// so light that mirrors and glass focus onto diffuse surfaces, like caustics, shows up without waiting for paths from
+/// the camera to find their way back to the light through the glass
+/// Light reaching that surface straight from the lights is sampled as the direct integrator does, only bounced light comes from photons
+/// Every sample of a pixel gathers from a pass of photons of its own, each with a smaller radius than the last, so the blur
+/// of gathering goes away as samples add up. This is the probabilistic take on progressive photon mapping by Knaus and Zwicker,
+/// which needs nothing kept per pixel
+pub struct ProgressivePhotonMapper {
+    photons_per_pass: usize,
+    // Gathering radius of the first pass, None to fit it to the size of the scene
+    radius: Option<f32>,
+    // Shot again at the start of every render
+    passes: Mutex<Vec<PhotonMap>>
+}
+
+impl Integrator for ProgressivePhotonMapper {
+    // One pass for every sample a pixel gets
+    fn prepare(&self, camera: &mut Camera, objects: &[Box<dyn Object>]) {
+        let passes = camera.samples.clamp(1, MAX_PASSES);
+        let radius = self.radius.unwrap_or_else(|| scene_size(objects) * RADIUS_SCALE);
+        let mut radius_squared = radius * radius;
+        let mut maps = Vec::with_capacity(passes as usize);
+        for pass in 0..passes {
+            print!("\rShooting photons, {:3} passes remaining", passes - pass);
+            let photons = trace_photons(camera, objects, pass, self.photons_per_pass);
+            maps.push(PhotonMap::new(photons, self.photons_per_pass, radius_squared.sqrt()));
+            radius_squared *= (pass as f32 + 1.0 + RADIUS_ALPHA) / (pass as f32 + 2.0);
+        }
+        println!();
+        *self.passes.lock().expect("photon passes aren't shared between threads") = maps;
+    }
+
+    // Follows mirror-like bounces from the camera to the first surface that photons can be gathered on
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let mut ray = ray.clone();
+        let mut beta = Color::ONE;
+        let mut total = Color::ZERO;
+        for _ in 0..camera.max_depth {
+            let Some((hit, index)) = camera.intersect_objects(&ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+                let background = beta * camera.background_radiance(&ray);
+                camera.record_background_light(background);
+                return total + background;
+            };
+            beta *= ray.transmittance(hit.t);
+            if hit.is_specular {
+                camera.record_object_light(index, beta * hit.emitted);
+                total += beta * hit.emitted;
+                beta *= hit.albedo;
+                if beta == Color::ZERO {
+                    break;
+                }
+                ray = hit.spawn_ray(&hit.outgoing, camera.normal_offset);
+                continue;
+            }
+            let path_weight = camera.swap_path_weight(beta);
+            let direct = DirectLighting::at_hit(camera, &ray, &hit, index, objects);
+            camera.swap_path_weight(path_weight);
+            return total + beta * direct + self.gather(camera, &ray, &hit, index, objects, beta);
+        }
+        return total;
+    }
+}
+
+impl ProgressivePhotonMapper {
+    /// Shoots photons_per_pass photons for every pass, starting out gathering them within radius,
+    /// or within a radius fitting the size of the scene without one
+    pub fn new(photons_per_pass: usize, radius: Option<f32>) -> ProgressivePhotonMapper {
+        ProgressivePhotonMapper { photons_per_pass: photons_per_pass.max(1), radius, passes: Mutex::new(vec![]) }
+    }
+
+    /// Parses "[photons per pass[,radius]]", with 10000 photons and a radius fitting the scene by default
+    pub fn parse(spec: &str) -> Result<ProgressivePhotonMapper, String> {
+        let mut values = spec.split(',').filter(|value| !value.is_empty());
+        let photons = match values.next() {
+            Some(photons) => photons.parse().map_err(|_| format!("Invalid number of photons \"{}\"", photons))?,
+            None => 10000
+        };
+        let radius = match values.next() {
+            Some(radius) => Some(radius.parse().map_err(|_| format!("Invalid photon radius \"{}\"", radius))?),
+            None => None
+        };
+        return Ok(ProgressivePhotonMapper::new(photons, radius));
+    }
+
+    // Bounced light reaching a hit and carried on towards the camera, estimated from the photons of the current sample's pass
+    // landing around it. Photons record the light they bring with them, so the BSDF is taken without the cosine
+    fn gather(&self, camera: &mut Camera, ray: &Ray, hit: &Hit, index: usize, objects: &[Box<dyn Object>], beta: Color) -> Color {
+        let passes = self.passes.lock().expect("photon passes aren't shared between threads");
+        if passes.is_empty() {
+            return Color::ZERO;
+        }
+        let map = &passes[camera.sample_index() as usize % passes.len()];
+        let object = objects[index].as_ref();
+        let normal = hit.outward_normal();
+        let scale = beta / (map.emitted as f32 * PI * map.radius * map.radius);
+        let mut total = Color::ZERO;
+        for photon in map.gather(hit.position) {
+            let cosine = normal.dot(photon.direction).abs();
+            if cosine <= 0.0 {
+                continue;
+            }
+            // Light arrives from where the photon came from
+            let scattered = ray.continued(hit.position, -photon.direction);
+            let (bsdf, _) = object.evaluate(ray, &scattered, hit.position, normal, hit.tangent, hit.u, hit.v);
+            let light = bsdf / cosine * photon.power * scale;
+            camera.record_object_light(photon.light, light);
+            total += light;
+        }
+        return total;
+    }
+}
+
+// Light reaching a surface from a light after bouncing at least once
+struct Photon {
+    position: Vec3,
+    // The way it was going
+    direction: Vec3,
+    power: Color,
+    // Object index of the light it came from
+    light: usize
+}
+
+// The photons of a pass, sorted into a grid of cells as wide as the gathering radius,
+// so only the cells around a point need looking through
+struct PhotonMap {
+    photons: Vec<Photon>,
+    // Where the photons of every cell that has any start and end
+    cells: HashMap<(i32, i32, i32), (usize, usize)>,
+    radius: f32,
+    // Photons sent out, counting those that never landed anywhere
+    emitted: usize
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>, emitted: usize, radius: f32) -> PhotonMap {
+        let radius = radius.max(f32::EPSILON);
+        photons.sort_by_key(|photon| cell(photon.position, radius));
+        let mut cells = HashMap::new();
+        let mut start = 0;
+        for end in 1..=photons.len() {
+            let key = cell(photons[start].position, radius);
+            if end == photons.len() || cell(photons[end].position, radius) != key {
+                cells.insert(key, (start, end));
+                start = end;
+            }
+        }
+        PhotonMap { photons, cells, radius, emitted }
+    }
+
+    // Every photon within the radius of a point
+    fn gather(&self, point: Vec3) -> impl Iterator<Item = &Photon> + '_ {
+        let (x, y, z) = cell(point, self.radius);
+        let neighbours = (-1..=1).flat_map(move |dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (x + dx, y + dy, z + dz))));
+        neighbours
+            .filter_map(|key| self.cells.get(&key))
+            .flat_map(|&(start, end)| &self.photons[start..end])
+            .filter(move |photon| (photon.position - point).length_squared() <= self.radius * self.radius)
+    }
+}
+
+// The grid cell a point is in, for cells of a size
+fn cell(point: Vec3, size: f32) -> (i32, i32, i32) {
+    let scaled = (point / size).floor();
+    (scaled.x as i32, scaled.y as i32, scaled.z as i32)
+}
+
+// Sends photons out from lights picked by their power, keeping every one that lands on a surface that isn't mirror-like
+// after bouncing at least once. Light straight from the lights is sampled from the camera instead
+fn trace_photons(camera: &mut Camera, objects: &[Box<dyn Object>], pass: u32, count: usize) -> Vec<Photon> {
+    let mut photons = vec![];
+    for index in 0..count {
+        // Every photon of a pass is a sample of its own, so they're spread out evenly by the camera's sampler
+        camera.rng().start_sample(pass, u32::MAX, index as u32, count as u32);
+        let time = camera.shutter_open + (camera.shutter_close - camera.shutter_open) * camera.rng().gen::<f32>();
+        let u = camera.rng().gen::<f32>();
+        let Some((light, probability)) = camera.pick_light_by_power(u) else {
+            return photons;
+        };
+        let Some((point, normal, area_pdf)) = objects[light].light().and_then(|light| light.sample_surface(camera.rng(), time)) else {
+            continue;
+        };
+        let (direction, direction_pdf) = sample_cosine_hemisphere(camera.rng(), normal);
+        let pdf = probability * area_pdf * direction_pdf;
+        let emitted = objects[light].emission_towards(camera.rng(), point, point + direction, time);
+        if emitted == Color::ZERO || !(pdf > 0.0 && pdf.is_finite()) {
+            continue;
+        }
+        let mut power = emitted * normal.dot(direction) / pdf;
+        let mut ray = Ray::new(point + normal * camera.normal_offset, direction).at_time(time);
+        for bounce in 0..camera.max_depth {
+            let Some((hit, _)) = camera.intersect_objects(&ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+                break;
+            };
+            power *= ray.transmittance(hit.t);
+            if !hit.is_specular && bounce > 0 {
+                photons.push(Photon { position: hit.position, direction: ray.direction.normalize(), power, light });
+            }
+            power *= hit.albedo;
+            if power == Color::ZERO {
+                break;
+            }
+            ray = hit.spawn_ray(&hit.outgoing, camera.normal_offset);
+        }
+    }
+    return photons;
+}
+
+// The length of the diagonal of the box around every object, leaving out any that go on forever
+fn scene_size(objects: &[Box<dyn Object>]) -> f32 {
+    let size = objects.iter()
+        .map(|object| object.bounding_box())
+        .filter(|bounds| (bounds.max() - bounds.min()).is_finite())
+        .map(|bounds| (bounds.min(), bounds.max()))
+        .reduce(|(min, max), (other_min, other_max)| (min.min(other_min), max.max(other_max)))
+        .map_or(0.0, |(min, max)| (max - min).length());
+    match size > 0.0 {
+        true => size,
+        false => 1.0
+    }
+}
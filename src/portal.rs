@@ -0,0 +1,124 @@
+use glam::Vec3;
+use crate::sampler::SamplerRng;
+use crate::ray::{Ray, Hit};
+use crate::interval::Interval;
+use crate::bounding_box::BoundingBox;
+use crate::object::Object;
+use crate::material::ScatterRecord;
+
+type Color = Vec3;
+
+/// One side of a portal, a rectangle with the same layout as Rect
+/// Rays enter through the front, which is the side u × v points to
+struct PortalSurface {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    d: f32
+}
+
+impl PortalSurface {
+    fn new(origin: Vec3, u: Vec3, v: Vec3) -> PortalSurface {
+        let normal = u.cross(v).normalize();
+        PortalSurface {
+            origin,
+            u,
+            v,
+            normal,
+            d: normal.dot(origin)
+        }
+    }
+
+    /// Returns t and the position on the rectangle in units of u and v, if the front is hit
+    fn intersect(&self, ray: &Ray, hit_interval: &Interval) -> Option<(f32, f32, f32)> {
+        let divisor = self.normal.dot(ray.direction);
+        // The back side can't be entered, and near parallel rays miss
+        if divisor > -0.000001 {
+            return None;
+        }
+        let t = (self.d - self.normal.dot(ray.origin)) / divisor;
+        if !hit_interval.surrounds(t) {
+            return None;
+        }
+        let local_position = ray.pos(t) - self.origin;
+        let along_u = local_position.dot(self.u) / self.u.length_squared();
+        let along_v = local_position.dot(self.v) / self.v.length_squared();
+        let coordinate_range = Interval::new(0.0, 1.0);
+        if !coordinate_range.contains(along_u) || !coordinate_range.contains(along_v) {
+            return None;
+        }
+        return Some((t, along_u, along_v));
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let diagonal = BoundingBox::from_points(self.origin, self.origin + self.u + self.v);
+        let other_diagonal = BoundingBox::from_points(self.origin + self.u, self.origin + self.v);
+        BoundingBox::union(&diagonal, &other_diagonal)
+    }
+}
+
+/// A pair of linked rectangles. Rays entering the front of one come out of the front of the other,
+/// at the same relative position and turned the same way relative to the rectangle
+/// Both rectangles are invisible from behind
+pub struct Portal {
+    first: PortalSurface,
+    second: PortalSurface
+}
+
+impl Object for Portal {
+    fn intersect(&self, _rng: &mut SamplerRng, ray: &Ray, hit_interval: &Interval) -> Option<Hit> {
+        let through_first = self.first.intersect(ray, hit_interval).map(|hit| (hit, &self.first, &self.second));
+        let closest = Interval::new(hit_interval.min, through_first.map_or(hit_interval.max, |((t, _, _), _, _)| t));
+        let through_second = self.second.intersect(ray, &closest).map(|hit| (hit, &self.second, &self.first));
+        let ((t, along_u, along_v), entrance, exit) = through_second.or(through_first)?;
+        // Express the direction relative to the entrance, and rebuild it relative to the exit
+        // Going into the entrance's front means coming out of the exit's front, so the normal component flips
+        let unit_u = entrance.u.normalize();
+        let unit_v = entrance.v.normalize();
+        let local = Vec3::new(ray.direction.dot(unit_u), ray.direction.dot(unit_v), ray.direction.dot(entrance.normal));
+        let direction = local.x * exit.u.normalize() + local.y * exit.v.normalize() - local.z * exit.normal;
+        let position = exit.origin + along_u * exit.u + along_v * exit.v;
+        // The hit is reported on the exit side, so the bounced ray is pushed off the exit
+        let hit = Hit::new(
+            ray,
+            t,
+            position,
+            exit.normal,
+            ScatterRecord::specular(ray.continued(position, direction), Color::ONE),
+            false,
+            Color::ZERO
+        );
+        return Some(hit.with_uv(along_u, along_v));
+    }
+
+    fn albedo(&self, _u: f32, _v: f32, _position: Vec3, _time: f32) -> Color {
+        Color::ONE
+    }
+
+    fn is_emitter(&self) -> bool {
+        false
+    }
+
+    fn emit(&self, _u: f32, _v: f32, _position: Vec3, _time: f32) -> Color {
+        Color::ZERO
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::union(&self.first.bounding_box(), &self.second.bounding_box())
+    }
+}
+
+impl Portal {
+    /// Both rectangles are given like Rect, by an origin and two perpendicular sides
+    pub fn new(first: (Vec3, Vec3, Vec3), second: (Vec3, Vec3, Vec3)) -> Portal {
+        Portal {
+            first: PortalSurface::new(first.0, first.1, first.2),
+            second: PortalSurface::new(second.0, second.1, second.2)
+        }
+    }
+}
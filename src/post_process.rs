@@ -0,0 +1,113 @@
+// Effects applied to the finished image while it's still light, before it's encoded, like the lens and the film of a camera would
+
+use glam::Vec3;
+use crate::material::LUMINANCE_WEIGHTS;
+use crate::spectrum::blackbody;
+
+type Color = Vec3;
+
+// Color temperature that white balance leaves as it is, the white point of sRGB
+const NEUTRAL_TEMPERATURE: f32 = 6500.0;
+// Dimmest a channel of the light white balance corrects for can be, so very red light doesn't blow up the blue channel
+const WHITE_BALANCE_FLOOR: f32 = 0.01;
+
+/// An effect on the whole image. Effects are applied in order, after denoising, to the light of every pixel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PostProcess {
+    /// Light brighter than threshold spills over into the pixels around it, scaled by strength and spread out
+    /// by a gaussian with a width of radius times the image height, like the glow around lights seen through a lens
+    Bloom { threshold: f32, strength: f32, radius: f32 },
+    /// Darkens the image towards the corners, which end up strength darker
+    Vignette { strength: f32 },
+    /// Scales how colorful the image is, 0 for black and white and more than 1 for more vivid colors
+    Saturation { amount: f32 },
+    /// Makes things lit by light of a color temperature in kelvin look white, turning the image cooler below 6500 and warmer above it
+    /// Lowering it cools down a scene lit by warm bulbs, raising it warms up one lit by an overcast sky
+    WhiteBalance { kelvin: f32 }
+}
+
+impl PostProcess {
+    /// Parses bloom[:<threshold>[,<strength>[,<radius>]]], vignette[:<strength>], saturation:<amount> or white-balance:<kelvin>
+    pub fn parse(spec: &str) -> Result<PostProcess, String> {
+        let (name, settings) = spec.split_once(':').unwrap_or((spec, ""));
+        let values = settings.split(',')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.trim().parse::<f32>().map_err(|_| format!("Invalid number \"{}\" in {}", value, name)))
+            .collect::<Result<Vec<f32>, String>>()?;
+        let value = |index: usize, default: f32| values.get(index).copied().unwrap_or(default);
+        match (name, values.len()) {
+            ("bloom", 0..=3) => Ok(PostProcess::Bloom { threshold: value(0, 1.0), strength: value(1, 0.1), radius: value(2, 0.02) }),
+            ("vignette", 0..=1) => Ok(PostProcess::Vignette { strength: value(0, 0.5) }),
+            ("saturation", 1) => Ok(PostProcess::Saturation { amount: value(0, 1.0) }),
+            ("white-balance", 1) if (1000.0..=40000.0).contains(&value(0, 0.0)) => Ok(PostProcess::WhiteBalance { kelvin: value(0, 0.0) }),
+            ("bloom" | "vignette" | "saturation" | "white-balance", _) => Err(format!("Invalid settings \"{}\" for {}", settings, name)),
+            _ => Err(format!("Unknown effect \"{}\", expected bloom, vignette, saturation or white-balance", name))
+        }
+    }
+
+    /// Applies the effect to the light of every pixel of an image, stored bottom to top
+    pub fn apply(&self, width: usize, height: usize, colors: &mut [Color]) {
+        match *self {
+            PostProcess::Bloom { threshold, strength, radius } => bloom(width, height, colors, threshold, strength, radius),
+            PostProcess::Vignette { strength } => {
+                let center = Vec3::new(width as f32, height as f32, 0.0) / 2.0;
+                for (index, color) in colors.iter_mut().enumerate() {
+                    let pixel = Vec3::new((index % width) as f32 + 0.5, (index / width) as f32 + 0.5, 0.0);
+                    // 0 in the middle and 1 in the corners, falling off smoothly
+                    let distance = ((pixel - center) / center.length().max(1.0)).length_squared();
+                    *color *= 1.0 - strength.clamp(0.0, 1.0) * distance;
+                }
+            },
+            PostProcess::Saturation { amount } => {
+                for color in colors.iter_mut() {
+                    let luminance = Color::splat(color.dot(LUMINANCE_WEIGHTS));
+                    *color = (luminance + (*color - luminance) * amount).max(Color::ZERO);
+                }
+            },
+            PostProcess::WhiteBalance { kelvin } => {
+                // Scaling every channel by how much weaker it is in the light than in white light, keeping the brightness
+                let floor = Color::splat(WHITE_BALANCE_FLOOR);
+                let gains = blackbody(NEUTRAL_TEMPERATURE).max(floor) / blackbody(kelvin).max(floor);
+                let gains = gains / gains.dot(LUMINANCE_WEIGHTS);
+                for color in colors.iter_mut() {
+                    *color *= gains;
+                }
+            }
+        }
+    }
+}
+
+// Adds a blurred copy of the light above the threshold to the image
+// The gaussian is separable, so it's blurred across and then up, with the pixels at the edges repeated past them
+fn bloom(width: usize, height: usize, colors: &mut [Color], threshold: f32, strength: f32, radius: f32) {
+    let sigma = (radius * height as f32).max(0.5);
+    let reach = (3.0 * sigma).ceil() as i64;
+    let kernel: Vec<f32> = (-reach..=reach).map(|offset| (-(offset * offset) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    // Only the part of the light above the threshold glows, keeping its color
+    let bright: Vec<Color> = colors.iter().map(|&color| {
+        let luminance = color.dot(LUMINANCE_WEIGHTS);
+        match luminance > threshold {
+            true => color * ((luminance - threshold) / luminance),
+            false => Color::ZERO
+        }
+    }).collect();
+    let blur = |source: &[Color], step: (i64, i64)| -> Vec<Color> {
+        let mut blurred = vec![Color::ZERO; source.len()];
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let mut sum = Color::ZERO;
+                for (offset, &weight) in (-reach..=reach).zip(&kernel) {
+                    let (other_x, other_y) = ((x + offset * step.0).clamp(0, width as i64 - 1), (y + offset * step.1).clamp(0, height as i64 - 1));
+                    sum += source[other_y as usize * width + other_x as usize] * weight;
+                }
+                blurred[y as usize * width + x as usize] = sum / total;
+            }
+        }
+        blurred
+    };
+    let glow = blur(&blur(&bright, (1, 0)), (0, 1));
+    for (color, glow) in colors.iter_mut().zip(glow) {
+        *color += glow * strength;
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+use crate::framebuffer::Framebuffer;
+
+// The thumbnail is drawn with upper half blocks, so every character cell shows two pixels:
+// the top one as the foreground color and the bottom one as the background color
+const HALF_BLOCK: char = '▀';
+const THUMBNAIL_WIDTH: usize = 48;
+
+/// Prints a short summary of a finished render
+pub fn print_summary(width: u32, height: u32, samples: u32, max_depth: u32, elapsed: Duration, path: &str) {
+    println!();
+    println!("Rendered {}x{} pixels, {} samples per pixel, max depth {}", width, height, samples, max_depth);
+    println!("Took {:.2}s", elapsed.as_secs_f32());
+    println!("Saved to {}", path);
+}
+
+/// Prints a downscaled version of the image to the terminal using 24-bit ANSI colors
+pub fn print_thumbnail(image: &Framebuffer) {
+    if image.width == 0 || image.height == 0 {
+        return;
+    }
+    let columns = image.width.min(THUMBNAIL_WIDTH);
+    // Terminal cells are roughly twice as tall as they are wide, which the half blocks make up for
+    let rows = ((image.height * columns) / image.width).max(1);
+    let mut output = String::new();
+    for row in (0..rows).step_by(2) {
+        for column in 0..columns {
+            let top = sample(image, column, row, columns, rows);
+            let bottom = match row + 1 < rows {
+                true => sample(image, column, row + 1, columns, rows),
+                false => (0, 0, 0)
+            };
+            output += &format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m{}",
+                top.0, top.1, top.2, bottom.0, bottom.1, bottom.2, HALF_BLOCK
+            );
+        }
+        output += "\x1b[0m\n";
+    }
+    print!("{}", output);
+}
+
+/// Gets the (red, green, blue) color of the pixel under a thumbnail cell, counting rows from the top
+fn sample(image: &Framebuffer, column: usize, row: usize, columns: usize, rows: usize) -> (u8, u8, u8) {
+    let x = column * image.width / columns;
+    // Image rows are stored bottom to top
+    let y = image.height - 1 - row * image.height / rows;
+    return image.pixel(x, y);
+}
@@ -0,0 +1,388 @@
+// Camera models, turning points on the image into rays
+
+use std::f32::consts::PI;
+use rand::Rng;
+use crate::sampler::SamplerRng;
+use glam::Vec3;
+use crate::ray::Ray;
+
+/// Where the camera is and which way it's turned
+#[derive(Clone, Copy)]
+pub struct CameraFrame {
+    pub center: Vec3,
+    // Orthonormal basis with u to the right, v up and w pointing backwards out of the camera
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3
+}
+
+impl CameraFrame {
+    /// A camera at from, looking towards at, turned so that up points up in the image
+    pub fn look_at(from: Vec3, at: Vec3, up: Vec3) -> CameraFrame {
+        let w = (from - at).normalize();
+        let u = up.cross(w).normalize();
+        let v = w.cross(u);
+        CameraFrame { center: from, u, v, w }
+    }
+}
+
+/// How the two eyes of a stereo render are put into one image
+#[derive(Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    // Left eye in the left half of the image, right eye in the right half
+    SideBySide,
+    // Red from the left eye and green and blue from the right, for red/cyan glasses
+    Anaglyph
+}
+
+/// Renders the scene from two eyes side by side instead of from the camera center
+#[derive(Clone, Copy)]
+pub struct Stereo {
+    // Distance between the eyes in scene units
+    pub interocular: f32,
+    // Distance in front of the camera where the eyes' views cross, which ends up at screen depth
+    pub convergence: f32,
+    pub mode: StereoMode
+}
+
+impl Stereo {
+    pub fn new(interocular: f32, convergence: f32, mode: StereoMode) -> Stereo {
+        Stereo { interocular, convergence, mode }
+    }
+
+    /// The left and right eyes, both turned towards the convergence point straight ahead of the camera
+    pub fn eyes(&self, frame: &CameraFrame) -> (CameraFrame, CameraFrame) {
+        let target = frame.center - self.convergence * frame.w;
+        let offset = frame.u * self.interocular / 2.0;
+        let left = CameraFrame::look_at(frame.center - offset, target, frame.v);
+        let right = CameraFrame::look_at(frame.center + offset, target, frame.v);
+        return (left, right);
+    }
+}
+
+pub trait Projection {
+    // Get a ray through the image at (s, t), which go from 0 to 1 left to right and bottom to top
+    // Aspect is the image width over its height. None where the projection doesn't cover the image
+    fn generate_ray(&self, rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray>;
+
+    // The ray through (s, t) from the middle of the lens, for measuring what the image point sees
+    // Only projections that spread rays over a lens need to override it
+    fn center_ray(&self, rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        self.generate_ray(rng, frame, s, t, aspect)
+    }
+
+    // Move the plane in perfect focus to a distance in front of the camera
+    // Projections without a lens have everything in focus and ignore it
+    fn set_focus_distance(&mut self, _distance: f32) {}
+
+    // Change the vertical field of view in degrees, for projections that have one
+    fn set_vfov(&mut self, _degrees: f32) {}
+
+    // Where on the image (s, t) a point is seen from the middle of the lens, the other way around from center_ray
+    // None for points the projection doesn't see, like those behind a pinhole
+    fn project(&self, _frame: &CameraFrame, _point: Vec3, _aspect: f32) -> Option<(f32, f32)> {
+        None
+    }
+
+    // How much solid angle a unit of image area takes up around the direction towards a point, for tracing light
+    // straight into the camera. None for projections that can't be traced into, like those with a lens
+    fn solid_angle_per_area(&self, _frame: &CameraFrame, _point: Vec3, _aspect: f32) -> Option<f32> {
+        None
+    }
+}
+
+/// Every ray passes through a single point, so everything is in focus
+pub struct Pinhole {
+    // Vertical field of view in degrees
+    pub vfov: f32
+}
+
+impl Projection for Pinhole {
+    fn generate_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        let viewport_height = 2.0 * (self.vfov.to_radians() / 2.0).tan();
+        let viewport_width = viewport_height * aspect;
+        let direction = (s - 0.5) * viewport_width * frame.u + (t - 0.5) * viewport_height * frame.v - frame.w;
+        return Some(Ray::new(frame.center, direction));
+    }
+
+    fn set_vfov(&mut self, degrees: f32) {
+        self.vfov = degrees;
+    }
+
+    fn project(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<(f32, f32)> {
+        let viewport_height = 2.0 * (self.vfov.to_radians() / 2.0).tan();
+        return perspective_project(frame, point, viewport_height * aspect, viewport_height);
+    }
+
+    fn solid_angle_per_area(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<f32> {
+        let viewport_height = 2.0 * (self.vfov.to_radians() / 2.0).tan();
+        let (x, y, z) = camera_space(frame, point);
+        if z <= 0.0 {
+            return None;
+        }
+        // The viewport is a unit in front of the camera, so it's further away and more tilted off to the side,
+        // each by the cosine of the angle from the view direction
+        let cosine = z / (x * x + y * y + z * z).sqrt();
+        return Some(viewport_height * viewport_height * aspect * cosine.powi(3));
+    }
+}
+
+impl Pinhole {
+    pub fn new(vfov: f32) -> Pinhole {
+        Pinhole { vfov }
+    }
+}
+
+/// A lens of some size focused at a distance, blurring whatever is nearer or further away
+pub struct ThinLens {
+    // Vertical field of view in degrees
+    pub vfov: f32,
+    // Diameter of the lens, 0 makes it a pinhole
+    pub aperture: f32,
+    // Distance from the camera to the plane that's in perfect focus
+    pub focus_distance: f32,
+    // Degrees the focus plane is turned around the horizontal image axis, positive leaning its top away
+    // from the camera. A plane tilted away from the ground keeps only a band in focus, like a miniature
+    pub tilt: f32,
+    // How far the image is moved sideways and up, in image widths and heights. Shifting up instead of
+    // tilting the camera keeps vertical lines straight in architectural shots
+    pub shift_x: f32,
+    pub shift_y: f32
+}
+
+impl Projection for ThinLens {
+    fn generate_ray(&self, rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        let target = self.focus_point(frame, s, t, aspect);
+        // Start from a random point on the lens
+        let (x, y) = random_in_unit_disk(rng);
+        let radius = self.aperture / 2.0;
+        let origin = frame.center + x * radius * frame.u + y * radius * frame.v;
+        return Some(Ray::new(origin, target - origin));
+    }
+
+    fn center_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        return Some(Ray::new(frame.center, self.focus_point(frame, s, t, aspect) - frame.center));
+    }
+
+    fn set_focus_distance(&mut self, distance: f32) {
+        self.focus_distance = distance;
+    }
+
+    fn set_vfov(&mut self, degrees: f32) {
+        self.vfov = degrees;
+    }
+
+    // Tilting only moves where along the ray from the lens center the focus is, so it doesn't change where things are seen
+    fn project(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<(f32, f32)> {
+        let viewport_height = 2.0 * (self.vfov.to_radians() / 2.0).tan();
+        let (s, t) = perspective_project(frame, point, viewport_height * aspect, viewport_height)?;
+        return Some((s - self.shift_x, t - self.shift_y));
+    }
+}
+
+impl ThinLens {
+    pub fn new(vfov: f32, aperture: f32, focus_distance: f32) -> ThinLens {
+        ThinLens { vfov, aperture, focus_distance, tilt: 0.0, shift_x: 0.0, shift_y: 0.0 }
+    }
+
+    pub fn with_tilt(mut self, degrees: f32) -> ThinLens {
+        self.tilt = degrees;
+        self
+    }
+
+    pub fn with_shift(mut self, x: f32, y: f32) -> ThinLens {
+        self.shift_x = x;
+        self.shift_y = y;
+        self
+    }
+
+    // Where rays through (s, t) from anywhere on the lens meet, on the viewport that sits on the focus plane
+    fn focus_point(&self, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Vec3 {
+        let viewport_height = 2.0 * (self.vfov.to_radians() / 2.0).tan() * self.focus_distance;
+        let viewport_width = viewport_height * aspect;
+        let point = frame.center
+            + (s - 0.5 + self.shift_x) * viewport_width * frame.u
+            + (t - 0.5 + self.shift_y) * viewport_height * frame.v
+            - self.focus_distance * frame.w;
+        if self.tilt == 0.0 {
+            return point;
+        }
+        // Follow the ray from the lens center through the point until it meets the tilted plane instead
+        let (sin, cos) = self.tilt.to_radians().sin_cos();
+        let normal = cos * frame.w + sin * frame.v;
+        let direction = point - frame.center;
+        let distance = (-self.focus_distance * frame.w).dot(normal) / direction.dot(normal);
+        // Parts of the image looking past the plane's horizon keep the untilted focus
+        return match distance.is_finite() && distance > 0.0 {
+            true => frame.center + distance * direction,
+            false => point
+        };
+    }
+}
+
+/// Parallel rays, so things don't get smaller with distance. Good for technical views
+pub struct Orthographic {
+    // Height of the view in scene units
+    pub height: f32
+}
+
+impl Projection for Orthographic {
+    fn generate_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        let width = self.height * aspect;
+        let origin = frame.center + (s - 0.5) * width * frame.u + (t - 0.5) * self.height * frame.v;
+        return Some(Ray::new(origin, -frame.w));
+    }
+
+    fn project(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<(f32, f32)> {
+        let (x, y, _) = camera_space(frame, point);
+        return Some((x / (self.height * aspect) + 0.5, y / self.height + 0.5));
+    }
+}
+
+impl Orthographic {
+    pub fn new(height: f32) -> Orthographic {
+        Orthographic { height }
+    }
+}
+
+/// Angle from the view direction grows evenly with distance from the image center, inside a circle
+/// that fills the image height. Everything outside the circle is left black
+pub struct Fisheye {
+    // Angle of view across the circle in degrees, can go past 180 to see behind the camera
+    pub fov: f32
+}
+
+impl Projection for Fisheye {
+    fn generate_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        let (x, y) = ((2.0 * s - 1.0) * aspect, 2.0 * t - 1.0);
+        let radius = (x * x + y * y).sqrt();
+        if radius > 1.0 {
+            return None;
+        }
+        let theta = radius * self.fov.to_radians() / 2.0;
+        return Some(Ray::new(frame.center, direction_at(frame, x, y, radius, theta)));
+    }
+
+    fn project(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<(f32, f32)> {
+        let (theta, cos_phi, sin_phi) = angles_of(frame, point)?;
+        let radius = theta / (self.fov.to_radians() / 2.0);
+        if radius > 1.0 {
+            return None;
+        }
+        return Some(((radius * cos_phi / aspect + 1.0) / 2.0, (radius * sin_phi + 1.0) / 2.0));
+    }
+}
+
+impl Fisheye {
+    pub fn new(fov: f32) -> Fisheye {
+        Fisheye { fov }
+    }
+}
+
+/// Projects the view sphere onto a plane from its far pole. With a wide angle of view pointed at the
+/// ground, the horizon wraps around into a "little planet"
+pub struct Stereographic {
+    // Angle of view across the image height in degrees, below 360
+    pub fov: f32
+}
+
+impl Projection for Stereographic {
+    fn generate_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, aspect: f32) -> Option<Ray> {
+        let (x, y) = ((2.0 * s - 1.0) * aspect, 2.0 * t - 1.0);
+        let radius = (x * x + y * y).sqrt();
+        // Scaled so the top and bottom edges of the image are at half the angle of view
+        let theta = 2.0 * (radius * (self.fov.to_radians() / 4.0).tan()).atan();
+        return Some(Ray::new(frame.center, direction_at(frame, x, y, radius, theta)));
+    }
+
+    fn project(&self, frame: &CameraFrame, point: Vec3, aspect: f32) -> Option<(f32, f32)> {
+        let (theta, cos_phi, sin_phi) = angles_of(frame, point)?;
+        let radius = (theta / 2.0).tan() / (self.fov.to_radians() / 4.0).tan();
+        return Some(((radius * cos_phi / aspect + 1.0) / 2.0, (radius * sin_phi + 1.0) / 2.0));
+    }
+}
+
+impl Stereographic {
+    pub fn new(fov: f32) -> Stereographic {
+        Stereographic { fov }
+    }
+}
+
+/// Every direction around the camera, with longitude across the image and latitude up it
+/// Meant for 2:1 images, which can be used as environment maps or VR backgrounds
+pub struct Equirectangular;
+
+impl Projection for Equirectangular {
+    fn generate_ray(&self, _rng: &mut SamplerRng, frame: &CameraFrame, s: f32, t: f32, _aspect: f32) -> Option<Ray> {
+        // The view direction is in the middle of the image, the left and right edges are straight behind
+        let longitude = (s - 0.5) * 2.0 * PI;
+        let latitude = (t - 0.5) * PI;
+        let horizontal = longitude.sin() * frame.u - longitude.cos() * frame.w;
+        let direction = latitude.cos() * horizontal + latitude.sin() * frame.v;
+        return Some(Ray::new(frame.center, direction));
+    }
+
+    fn project(&self, frame: &CameraFrame, point: Vec3, _aspect: f32) -> Option<(f32, f32)> {
+        let (x, y, z) = camera_space(frame, point);
+        let length = (x * x + y * y + z * z).sqrt();
+        if length == 0.0 {
+            return None;
+        }
+        let longitude = x.atan2(z);
+        let latitude = (y / length).clamp(-1.0, 1.0).asin();
+        return Some((longitude / (2.0 * PI) + 0.5, latitude / PI + 0.5));
+    }
+}
+
+// The direction theta radians from the view direction, turned towards the image point (x, y) at radius from the center
+fn direction_at(frame: &CameraFrame, x: f32, y: f32, radius: f32, theta: f32) -> Vec3 {
+    let (cos_phi, sin_phi) = match radius > 0.0 {
+        true => (x / radius, y / radius),
+        false => (1.0, 0.0)
+    };
+    return theta.sin() * (cos_phi * frame.u + sin_phi * frame.v) - theta.cos() * frame.w;
+}
+
+// A point relative to the camera, to the right, up and straight ahead
+fn camera_space(frame: &CameraFrame, point: Vec3) -> (f32, f32, f32) {
+    let offset = point - frame.center;
+    return (offset.dot(frame.u), offset.dot(frame.v), -offset.dot(frame.w));
+}
+
+// Where a point in front of the camera is seen on a viewport of a size one unit in front of it
+fn perspective_project(frame: &CameraFrame, point: Vec3, viewport_width: f32, viewport_height: f32) -> Option<(f32, f32)> {
+    let (x, y, z) = camera_space(frame, point);
+    if z <= 0.0 {
+        return None;
+    }
+    return Some((x / z / viewport_width + 0.5, y / z / viewport_height + 0.5));
+}
+
+// The angle of a point from the view direction, and the cosine and sine of which way from the image center it's turned
+// The other way around from direction_at
+fn angles_of(frame: &CameraFrame, point: Vec3) -> Option<(f32, f32, f32)> {
+    let (x, y, z) = camera_space(frame, point);
+    let sideways = (x * x + y * y).sqrt();
+    if sideways == 0.0 && z <= 0.0 {
+        return None;
+    }
+    let theta = sideways.atan2(z);
+    return match sideways > 0.0 {
+        true => Some((theta, x / sideways, y / sideways)),
+        false => Some((theta, 1.0, 0.0))
+    };
+}
+
+// A uniformly random point in the unit disk, by Shirley and Chiu's concentric mapping of the square
+// It always takes exactly two numbers, so the lens stays in the same sample dimensions
+fn random_in_unit_disk(rng: &mut SamplerRng) -> (f32, f32) {
+    let (a, b): (f32, f32) = (rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, angle) = match a.abs() > b.abs() {
+        true => (a, PI / 4.0 * (b / a)),
+        false => (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    return (radius * angle.cos(), radius * angle.sin());
+}
@@ -1,10 +1,66 @@
 use glam::Vec3;
+use rand::Rng;
+use crate::sampler::SamplerRng;
+use crate::material::{RGB_WAVELENGTHS, ScatterRecord};
 
 type Color = Vec3;
 
+// Wavelength in nanometers that indices of refraction are usually given at
+const HELIUM_D_WAVELENGTH: f32 = 587.6;
+
+/// How far along a ray hits are ignored unless the camera is told otherwise, so rays don't hit the surface they left
+pub const DEFAULT_HIT_EPSILON: f32 = 0.0001;
+
+#[derive(Clone)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // The dielectrics the ray is currently inside, innermost last. Empty means air
+    pub media: Vec<Medium>,
+    // After passing through a dispersive material, the path only carries this color channel
+    pub channel: Option<usize>,
+    // When during the exposure the ray was sent, for motion blur
+    pub time: f32,
+}
+
+/// A dielectric medium some part of a path travels through
+#[derive(Clone, Copy)]
+pub struct Medium {
+    // Identifies which material the medium belongs to
+    pub id: usize,
+    pub ior: f32,
+    pub absorption: Color,
+    // How often light bounces off particles inside, per unit of distance
+    pub scattering: Color,
+    // Cauchy's B coefficient in nm², how much the index of refraction varies with wavelength
+    pub dispersion: f32,
+}
+
+impl Medium {
+    pub fn ior_at(&self, channel: Option<usize>) -> f32 {
+        dispersed_ior(self.ior, self.dispersion, channel)
+    }
+
+    pub fn scatters(&self) -> bool {
+        self.scattering != Color::ZERO
+    }
+
+    /// Samples how far light gets through the medium before scattering, if it scatters before max_distance
+    /// Also returns the weight of the sample: the transmittance and scattering over the sampling density
+    /// One channel is picked to sample the distance, and the density is averaged over all of them
+    pub fn sample_distance(&self, rng: &mut SamplerRng, max_distance: f32) -> (Option<f32>, Color) {
+        let extinction = self.absorption + self.scattering;
+        let channel = rng.gen_range(0..3);
+        let distance = -(1.0 - rng.gen::<f32>()).ln() / extinction[channel];
+        if distance < max_distance {
+            let transmittance = (-extinction * distance).exp();
+            let pdf = (extinction * transmittance).dot(Color::ONE) / 3.0;
+            return (Some(distance), self.scattering * transmittance / pdf);
+        }
+        let transmittance = (-extinction * max_distance).exp();
+        let pdf = transmittance.dot(Color::ONE) / 3.0;
+        return (None, transmittance / pdf);
+    }
 }
 
 impl Ray {
@@ -13,6 +69,46 @@ impl Ray {
         Ray {
             origin,
             direction,
+            media: vec![],
+            channel: None,
+            time: 0.0,
+        }
+    }
+
+    pub fn at_time(mut self, time: f32) -> Ray {
+        self.time = time;
+        self
+    }
+
+    // Creates a ray continuing the same path, so it's still inside the same media at the same time
+    pub fn continued(&self, origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            media: self.media.clone(),
+            channel: self.channel,
+            time: self.time,
+        }
+    }
+
+    // The index of refraction of whatever the ray is traveling through
+    pub fn current_ior(&self) -> f32 {
+        self.media.last().map_or(1.0, |medium| medium.ior_at(self.channel))
+    }
+
+    // Returns the proportion of light that makes it a distance t along the ray through its medium
+    pub fn transmittance(&self, t: f32) -> Color {
+        match self.media.last() {
+            Some(medium) => (-medium.absorption * t * self.direction.length()).exp(),
+            None => Color::ONE
+        }
+    }
+
+    // Returns the proportion of light that makes it a distance t along the ray without being absorbed or scattered away
+    pub fn shadow_transmittance(&self, t: f32) -> Color {
+        match self.media.last() {
+            Some(medium) => (-(medium.absorption + medium.scattering) * t * self.direction.length()).exp(),
+            None => Color::ONE
         }
     }
 
@@ -23,16 +119,36 @@ impl Ray {
 
 }
 
+/// The index of refraction for a color channel, by Cauchy's equation
+/// ior is the index at the standard yellow helium line, which is what material tables list
+/// Without a channel, the path still carries all colors and gets the plain ior
+pub fn dispersed_ior(ior: f32, dispersion: f32, channel: Option<usize>) -> f32 {
+    match channel {
+        Some(channel) => ior + dispersion * (1.0 / RGB_WAVELENGTHS[channel].powi(2) - 1.0 / HELIUM_D_WAVELENGTH.powi(2)),
+        None => ior
+    }
+}
+
 // Contains information on a ray-object intersection
 pub struct Hit {
     pub t: f32,
     pub position: Vec3,
     pub normal: Vec3,
     pub front_face: bool,
+    // Along increasing u, for materials that perturb the normal
+    pub tangent: Vec3,
+    // Surface coordinates of the hit, each in [0, 1]
+    pub u: f32,
+    pub v: f32,
     pub albedo: Color,
     pub outgoing: Ray,
+    // Density the outgoing direction was picked with, and whether it was a mirror-like bounce
+    pub pdf: f32,
+    pub is_specular: bool,
     pub is_emitter: bool,
-    pub emitted: Color
+    pub emitted: Color,
+    pub shadow_catcher: bool,
+    pub holdout: bool
 }
 
 impl Hit {
@@ -41,8 +157,7 @@ impl Hit {
         t: f32,
         position: Vec3,
         outward_normal: Vec3,
-        albedo: Color,
-        outgoing: Ray,
+        scatter: ScatterRecord,
         is_emitter: bool,
         emitted: Color
     ) -> Hit {
@@ -56,10 +171,56 @@ impl Hit {
             position,
             normal,
             front_face,
-            albedo,
-            outgoing,
+            tangent: Vec3::ZERO,
+            u: 0.0,
+            v: 0.0,
+            albedo: scatter.attenuation,
+            outgoing: scatter.scattered_ray,
+            pdf: scatter.pdf,
+            is_specular: scatter.is_specular,
             is_emitter,
-            emitted
+            emitted,
+            shadow_catcher: false,
+            holdout: false
         }
     }
-}
\ No newline at end of file
+
+    pub fn with_uv(mut self, u: f32, v: f32) -> Hit {
+        self.u = u;
+        self.v = v;
+        self
+    }
+
+    pub fn with_tangent(mut self, tangent: Vec3) -> Hit {
+        self.tangent = tangent;
+        self
+    }
+
+    // The normal on the side the object was built facing, whichever side the ray came from
+    pub fn outward_normal(&self) -> Vec3 {
+        match self.front_face {
+            true => self.normal,
+            false => -self.normal
+        }
+    }
+
+    pub fn with_shadow_catcher(mut self, shadow_catcher: bool) -> Hit {
+        self.shadow_catcher = shadow_catcher;
+        self
+    }
+
+    pub fn with_holdout(mut self, holdout: bool) -> Hit {
+        self.holdout = holdout;
+        self
+    }
+
+    // Creates a copy of a ray leaving the hit point, with its origin pushed off the surface
+    // to whichever side the direction points, so it can't hit the surface it starts on
+    pub fn spawn_ray(&self, ray: &Ray, offset: f32) -> Ray {
+        let side = match ray.direction.dot(self.normal) > 0.0 {
+            true => self.normal,
+            false => -self.normal
+        };
+        ray.continued(self.position + side * offset, ray.direction)
+    }
+}
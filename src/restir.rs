@@ -0,0 +1,302 @@
+// Direct lighting that picks which light to aim at out of many candidates, passing good picks on between samples and pixels
+
+use std::sync::Mutex;
+use glam::Vec3;
+use rand::Rng;
+use crate::camera::Camera;
+use crate::integrator::{Integrator, DirectLighting};
+use crate::interval::Interval;
+use crate::material::LUMINANCE_WEIGHTS;
+use crate::object::Object;
+use crate::ray::{Ray, Hit};
+
+type Color = Vec3;
+
+// How many more candidates than a fresh pick a reservoir carried over from earlier can stand for,
+// so it keeps being replaced by new picks instead of sticking around forever
+const HISTORY_LIMIT: f32 = 20.0;
+// How many already sampled pixels around a pixel picks are borrowed from, and how far away they can be
+const NEIGHBOURS: u32 = 3;
+const NEIGHBOUR_RADIUS: i32 = 10;
+// How alike the hits of neighbouring pixels have to be to share picks, as the cosine between their normals
+// and the difference in how far away they are
+const NEIGHBOUR_NORMAL: f32 = 0.9;
+const NEIGHBOUR_DEPTH: f32 = 0.1;
+
+/// Light reaching the first hit straight from the lights, like the direct integrator, but aimed at lights by resampling:
+/// several points on lights are tried for every sample and one of them is picked by how much light it would bring,
+/// before a single shadow ray is traced to it. Picks are kept in a reservoir for every pixel and combined with those
+/// of the pixel's earlier samples and of neighbouring pixels, so in scenes with many lights the ones that matter
+/// are found within a few samples (ReSTIR, by Bitterli et al)
+/// Sharing between pixels that see the lights a little differently darkens shadow edges slightly
+/// The background and the sun are only found by bouncing off the hit, so they light things noisily
+pub struct ResampledDirectLighting {
+    // Points on lights tried for every sample before any picks are shared
+    candidates: u32,
+    // The reservoir every pixel ended its last sample with, filled in as the image is rendered
+    reservoirs: Mutex<Reservoirs>
+}
+
+impl Integrator for ResampledDirectLighting {
+    fn prepare(&self, camera: &mut Camera, _objects: &[Box<dyn Object>]) {
+        let (width, height) = (camera.settings.width as usize, camera.settings.height as usize);
+        *self.reservoirs.lock().expect("reservoirs aren't shared between threads") = Reservoirs { width, pixels: vec![None; width * height] };
+    }
+
+    fn radiance(&self, camera: &mut Camera, ray: &Ray, objects: &[Box<dyn Object>]) -> Color {
+        let Some((hit, index)) = camera.intersect_objects(ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) else {
+            let background = camera.background_radiance(ray);
+            camera.record_background_light(background);
+            return background;
+        };
+        // Mirror-like surfaces can't be aimed at the lights, they only see what they reflect
+        if hit.is_specular {
+            return DirectLighting::at_hit(camera, ray, &hit, index, objects);
+        }
+        camera.record_object_light(index, hit.emitted);
+        let object = objects[index].as_ref();
+        let evaluate = |scattered: &Ray| object.evaluate(ray, scattered, hit.position, hit.outward_normal(), hit.tangent, hit.u, hit.v);
+        let punctual = camera.punctual_light(ray, &hit, &evaluate, objects);
+        let resampled = self.resampled_light(camera, ray, &hit, index, objects);
+        // The lights are all found by resampling, so bouncing is only for the background
+        let bounced_ray = hit.spawn_ray(&hit.outgoing, camera.normal_offset);
+        let background = match camera.intersect_objects(&bounced_ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) {
+            Some(_) => Color::ZERO,
+            None => {
+                let background = hit.albedo * camera.background_radiance(&bounced_ray) * camera.fog_transmittance(&bounced_ray, f32::INFINITY);
+                camera.record_background_light(background);
+                background
+            }
+        };
+        return hit.emitted + punctual + resampled + background;
+    }
+}
+
+impl ResampledDirectLighting {
+    pub fn new(candidates: u32) -> ResampledDirectLighting {
+        ResampledDirectLighting { candidates: candidates.max(1), reservoirs: Mutex::new(Reservoirs { width: 0, pixels: vec![] }) }
+    }
+
+    // Light from the scene's lights reaching a hit, through a point on a light picked out of fresh candidates,
+    // the pixel's last pick and the picks of neighbouring pixels
+    fn resampled_light(&self, camera: &mut Camera, ray: &Ray, hit: &Hit, index: usize, objects: &[Box<dyn Object>]) -> Color {
+        let object = objects[index].as_ref();
+        let here = ShadingPoint::new(ray, hit, index);
+        let mut reservoir = Reservoir::default();
+        for _ in 0..self.candidates {
+            let u = camera.rng().gen::<f32>();
+            let Some((light, probability)) = camera.pick_light(hit.position, u) else {
+                break;
+            };
+            let sampled = objects[light].light().and_then(|light| light.sample_surface(camera.rng(), ray.time));
+            let Some((point, normal, area_pdf)) = sampled else {
+                reservoir.count += 1.0;
+                continue;
+            };
+            let sample = LightSample { light, point, normal };
+            let target = target(camera, &here, &sample, objects);
+            let u = camera.rng().gen::<f32>();
+            reservoir.update(Some(sample), target / (probability * area_pdf), 1.0, target, u);
+        }
+        // Picks that turn out to be in shadow aren't worth passing on
+        if let Some(sample) = &reservoir.sample {
+            if light_seen(camera, ray, hit, sample, objects) == Color::ZERO {
+                reservoir.sample = None;
+                reservoir.weight_sum = 0.0;
+            }
+        }
+        let mut reservoirs = self.reservoirs.lock().expect("reservoirs aren't shared between threads");
+        let (x, y) = camera.pixel();
+        let fresh_count = reservoir.count;
+        let distance = hit.t * ray.direction.length();
+        // Where every merged reservoir was picked and how many candidates it stood for, to tell in the end
+        // how many of them could have picked what was picked
+        let mut merged: Vec<(ShadingPoint, f32)> = vec![];
+        if let Some(earlier) = reservoirs.get(x as i32, y as i32) {
+            let count = reservoir.merge(camera, &here, &earlier.reservoir, fresh_count * HISTORY_LIMIT, objects);
+            merged.push((earlier.point.clone(), count));
+        }
+        for _ in 0..NEIGHBOURS {
+            let (dx, dy) = (camera.rng().gen_range(-NEIGHBOUR_RADIUS..=NEIGHBOUR_RADIUS), camera.rng().gen_range(-NEIGHBOUR_RADIUS..=NEIGHBOUR_RADIUS));
+            // Only pixels that are already done have picks to give
+            if dy > 0 || (dy == 0 && dx >= 0) {
+                continue;
+            }
+            let Some(neighbour) = reservoirs.get(x as i32 + dx, y as i32 + dy) else {
+                continue;
+            };
+            if neighbour.normal.dot(hit.normal) < NEIGHBOUR_NORMAL || (neighbour.distance - distance).abs() > NEIGHBOUR_DEPTH * distance {
+                continue;
+            }
+            let (neighbour, point) = (neighbour.reservoir.clone(), neighbour.point.clone());
+            let count = reservoir.merge(camera, &here, &neighbour, fresh_count * HISTORY_LIMIT, objects);
+            merged.push((point, count));
+        }
+        // Candidates from reservoirs that could never have picked the pick would only water it down,
+        // darkening pixels whose neighbours see other lights
+        let mut count = fresh_count;
+        if let Some(sample) = &reservoir.sample {
+            for (point, merged_count) in &merged {
+                if target(camera, point, sample, objects) > 0.0 {
+                    count += merged_count;
+                }
+            }
+        }
+        reservoir.finish(count);
+        reservoirs.set(x, y, StoredReservoir { reservoir: reservoir.clone(), point: here, normal: hit.normal, distance });
+        drop(reservoirs);
+        let Some(sample) = &reservoir.sample else {
+            return Color::ZERO;
+        };
+        let weight = reservoir.contribution_weight;
+        if weight == 0.0 {
+            return Color::ZERO;
+        }
+        let to_light = sample.point - hit.position;
+        let scattered = ray.continued(hit.position, to_light);
+        let (bsdf, _) = object.evaluate(ray, &scattered, hit.position, hit.outward_normal(), hit.tangent, hit.u, hit.v);
+        let contribution = bsdf * light_seen(camera, ray, hit, sample, objects) * geometry(hit.position, sample) * weight;
+        camera.record_object_light(sample.light, contribution);
+        return contribution;
+    }
+}
+
+// A point on one of the scene's lights
+#[derive(Clone)]
+struct LightSample {
+    // Object index of the light
+    light: usize,
+    point: Vec3,
+    // The side of the light the point was picked on
+    normal: Vec3
+}
+
+// One pick out of a stream of candidates, each kept with a chance in proportion to its weight,
+// along with what's needed to weigh the pick against all of them
+#[derive(Clone, Default)]
+struct Reservoir {
+    sample: Option<LightSample>,
+    weight_sum: f32,
+    // How many candidates the pick stands for
+    count: f32,
+    // What the pick was worth where it was picked
+    target: f32,
+    // What the light through the pick is multiplied by, in place of dividing by the density of picking it,
+    // once all candidates are in
+    contribution_weight: f32
+}
+
+impl Reservoir {
+    // Takes in a candidate standing for count others, replacing the pick with a chance of its share of the weights so far
+    fn update(&mut self, sample: Option<LightSample>, weight: f32, count: f32, target: f32, u: f32) {
+        self.count += count;
+        if !(weight > 0.0 && weight.is_finite()) {
+            return;
+        }
+        self.weight_sum += weight;
+        if u * self.weight_sum < weight {
+            self.sample = sample;
+            self.target = target;
+        }
+    }
+
+    // Takes in the pick of another reservoir, worth what it is at this point, counting at most limit candidates,
+    // returning how many it was counted as
+    fn merge(&mut self, camera: &mut Camera, here: &ShadingPoint, other: &Reservoir, limit: f32, objects: &[Box<dyn Object>]) -> f32 {
+        let count = other.count.min(limit);
+        let target = match &other.sample {
+            Some(sample) => target(camera, here, sample, objects),
+            None => 0.0
+        };
+        let u = camera.rng().gen::<f32>();
+        self.update(other.sample.clone(), target * other.contribution_weight * count, count, target, u);
+        return count;
+    }
+
+    // Works out the contribution weight, out of the given number of candidates that could have been picked
+    fn finish(&mut self, count: f32) {
+        self.contribution_weight = match self.target > 0.0 && count > 0.0 {
+            true => self.weight_sum / (count * self.target),
+            false => 0.0
+        };
+    }
+}
+
+// Where a reservoir's candidates were weighed, to weigh picks from elsewhere the same way
+#[derive(Clone)]
+struct ShadingPoint {
+    ray: Ray,
+    position: Vec3,
+    outward_normal: Vec3,
+    tangent: Vec3,
+    u: f32,
+    v: f32,
+    // Object index of what was hit
+    object: usize
+}
+
+impl ShadingPoint {
+    fn new(ray: &Ray, hit: &Hit, object: usize) -> ShadingPoint {
+        ShadingPoint { ray: ray.clone(), position: hit.position, outward_normal: hit.outward_normal(), tangent: hit.tangent, u: hit.u, v: hit.v, object }
+    }
+}
+
+// A pixel's last reservoir and what its hit was like, to tell whether other pixels can use it
+#[derive(Clone)]
+struct StoredReservoir {
+    reservoir: Reservoir,
+    point: ShadingPoint,
+    normal: Vec3,
+    distance: f32
+}
+
+struct Reservoirs {
+    width: usize,
+    pixels: Vec<Option<StoredReservoir>>
+}
+
+impl Reservoirs {
+    fn get(&self, x: i32, y: i32) -> Option<&StoredReservoir> {
+        if x < 0 || y < 0 || x as usize >= self.width {
+            return None;
+        }
+        self.pixels.get(y as usize * self.width + x as usize).and_then(|stored| stored.as_ref())
+    }
+
+    fn set(&mut self, x: u32, y: u32, stored: StoredReservoir) {
+        if let Some(pixel) = self.pixels.get_mut(y as usize * self.width + x as usize) {
+            *pixel = Some(stored);
+        }
+    }
+}
+
+// How much light a point on a light would bring to a hit if nothing was in the way, as a single brightness
+// Picks are made in proportion to this, so the closest and brightest lights facing the hit are aimed at most
+fn target(camera: &mut Camera, point: &ShadingPoint, sample: &LightSample, objects: &[Box<dyn Object>]) -> f32 {
+    let scattered = point.ray.continued(point.position, sample.point - point.position);
+    let (bsdf, _) = objects[point.object].evaluate(&point.ray, &scattered, point.position, point.outward_normal, point.tangent, point.u, point.v);
+    if bsdf == Color::ZERO {
+        return 0.0;
+    }
+    let emitted = objects[sample.light].emission_towards(camera.rng(), sample.point, point.position, point.ray.time);
+    return (bsdf * emitted).dot(LUMINANCE_WEIGHTS) * geometry(point.position, sample);
+}
+
+// Turns light arriving from a point on a light into light per unit of the light's area
+fn geometry(position: Vec3, sample: &LightSample) -> f32 {
+    let to_light = sample.point - position;
+    let distance_squared = to_light.length_squared();
+    return sample.normal.dot(to_light).abs() / (distance_squared * distance_squared.sqrt());
+}
+
+// The light arriving at a hit from a point on a light, or nothing if something is in the way
+fn light_seen(camera: &mut Camera, ray: &Ray, hit: &Hit, sample: &LightSample, objects: &[Box<dyn Object>]) -> Color {
+    let shadow_ray = hit.spawn_ray(&ray.continued(hit.position, sample.point - hit.position), camera.normal_offset);
+    // The point is at t = 1, so it's seen if that's where the light is hit first
+    match camera.intersect_objects(&shadow_ray, objects, &Interval::new(camera.hit_epsilon, f32::MAX)) {
+        Some((light_hit, index)) if index == sample.light && (light_hit.t - 1.0).abs() < 0.001 => {
+            light_hit.emitted * shadow_ray.shadow_transmittance(light_hit.t) * camera.fog_transmittance(&shadow_ray, light_hit.t)
+        },
+        _ => Color::ZERO
+    }
+}
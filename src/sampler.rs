@@ -0,0 +1,314 @@
+// Where the random numbers for each sample come from
+// Every path asks for its numbers in the same order, so the nth number of every sample is one dimension:
+// the first two place the sample in the pixel, the next ones pick the time, the lens point and the bounces
+
+use rand::{rngs::ThreadRng, seq::SliceRandom, thread_rng, Rng, RngCore};
+
+// First primes, one Halton base per dimension. Dimensions past these fall back to plain random numbers
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131
+];
+
+pub trait Sampler {
+    // Start sample index of count for a pixel, going back to the first dimension
+    fn start_sample(&mut self, x: u32, y: u32, index: u32, count: u32);
+    // The next dimension of the current sample, in [0, 1)
+    fn next(&mut self) -> f32;
+}
+
+/// Independent random numbers, except for the position in the pixel which is jittered on a grid
+/// so the samples of a pixel can't clump together
+pub struct StratifiedSampler {
+    rng: ThreadRng,
+    // Grid cell of the current sample and the grid size, while the pixel position hasn't been drawn
+    cell: Option<(u32, u32, u32)>,
+    dimension: usize
+}
+
+impl StratifiedSampler {
+    pub fn new() -> StratifiedSampler {
+        StratifiedSampler { rng: thread_rng(), cell: None, dimension: 0 }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, _x: u32, _y: u32, index: u32, count: u32) {
+        // Samples left over when the count isn't a square land anywhere in the pixel
+        let grid_size = (count as f32).sqrt() as u32;
+        self.cell = match index < grid_size * grid_size {
+            true => Some((index % grid_size, index / grid_size, grid_size)),
+            false => None
+        };
+        self.dimension = 0;
+    }
+
+    fn next(&mut self) -> f32 {
+        let jitter = self.rng.gen::<f32>();
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match (self.cell, dimension) {
+            (Some((x, _, size)), 0) => (x as f32 + jitter) / size as f32,
+            (Some((_, y, size)), 1) => (y as f32 + jitter) / size as f32,
+            _ => jitter
+        }
+    }
+}
+
+/// The Halton sequence, which fills every dimension evenly and converges faster than random numbers
+/// The digits of every dimension are shuffled with a random permutation. That breaks up the correlation
+/// between dimensions with large bases, which would otherwise march along in lockstep at low sample counts
+/// Each pixel either gets permutations of its own, so neighbouring pixels don't repeat the same pattern,
+/// or every pixel shares them and is shifted by a blue noise mask instead
+pub struct HaltonSampler {
+    rng: ThreadRng,
+    // Digit permutation of every dimension, for the current pixel unless there's blue noise
+    permutations: Vec<Vec<u32>>,
+    blue_noise: Option<BlueNoiseMask>,
+    pixel: Option<(u32, u32)>,
+    index: u32,
+    dimension: usize
+}
+
+impl HaltonSampler {
+    pub fn new() -> HaltonSampler {
+        let permutations = PRIMES.iter().map(|&base| (0..base).collect()).collect();
+        HaltonSampler { rng: thread_rng(), permutations, blue_noise: None, pixel: None, index: 0, dimension: 0 }
+    }
+
+    /// Shares one set of samples between all pixels, shifted by blue noise so the noise left at low sample
+    /// counts is spread evenly over the image instead of clumping up
+    pub fn with_blue_noise() -> HaltonSampler {
+        let mut sampler = HaltonSampler::new();
+        for permutation in sampler.permutations.iter_mut() {
+            permutation.shuffle(&mut sampler.rng);
+        }
+        sampler.blue_noise = Some(BlueNoiseMask::new(&mut sampler.rng));
+        return sampler;
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u32, _count: u32) {
+        if self.blue_noise.is_none() && self.pixel != Some((x, y)) {
+            for permutation in self.permutations.iter_mut() {
+                permutation.shuffle(&mut self.rng);
+            }
+        }
+        self.pixel = Some((x, y));
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension >= PRIMES.len() {
+            return self.rng.gen();
+        }
+        let mut value = scrambled_radical_inverse(self.index, PRIMES[dimension], &self.permutations[dimension]);
+        // Shift every dimension by the mask, wrapping around (a Cranley-Patterson rotation)
+        if let (Some(mask), Some((x, y))) = (&self.blue_noise, self.pixel) {
+            value += mask.value(x, y, dimension);
+            value -= value.floor();
+        }
+        // Rounding to f32 could land on 1 itself
+        return value.min(1.0 - f32::EPSILON / 2.0);
+    }
+}
+
+// Side of the blue noise tile in pixels, it repeats over larger images
+const BLUE_NOISE_SIZE: usize = 64;
+// Width of the gaussian that measures how crowded a spot of the mask is
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+/// A tile of values in [0, 1) where similar values are never close together, so any threshold
+/// of it gives evenly spread out pixels without clumps or a regular pattern
+pub struct BlueNoiseMask {
+    values: Vec<f32>
+}
+
+impl BlueNoiseMask {
+    /// Ulichney's void and cluster method: rank every pixel by the order it's added in,
+    /// always filling the emptiest spot of what's there so far
+    pub fn new(rng: &mut ThreadRng) -> BlueNoiseMask {
+        let size = BLUE_NOISE_SIZE;
+        let count = size * size;
+        // Crowding of every pixel by one at the origin, wrapping around the tile
+        let kernel: Vec<f32> = (0..count)
+            .map(|i| {
+                let (dx, dy) = ((i % size).min(size - i % size), (i / size).min(size - i / size));
+                (-((dx * dx + dy * dy) as f32) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp()
+            })
+            .collect();
+        let mut pattern = VoidAndCluster { size, kernel, set: vec![false; count], energy: vec![0.0; count] };
+        // Start from a tenth of the pixels at random, then move the tightest clusters into the largest voids
+        // until that's where they came from
+        let mut initial: Vec<usize> = (0..count).collect();
+        initial.shuffle(rng);
+        for &index in &initial[..count / 10] {
+            pattern.toggle(index);
+        }
+        loop {
+            let cluster = pattern.tightest_cluster();
+            pattern.toggle(cluster);
+            let void = pattern.largest_void();
+            pattern.toggle(void);
+            if void == cluster {
+                break;
+            }
+        }
+        let initial_count = count / 10;
+        let mut ranks = vec![0; count];
+        // Take the initial points away tightest first, then fill the rest of the tile emptiest first
+        let mut removing = pattern.clone();
+        for rank in (0..initial_count).rev() {
+            let cluster = removing.tightest_cluster();
+            ranks[cluster] = rank;
+            removing.toggle(cluster);
+        }
+        for rank in initial_count..count {
+            let void = pattern.largest_void();
+            ranks[void] = rank;
+            pattern.toggle(void);
+        }
+        let values = ranks.iter().map(|&rank| (rank as f32 + 0.5) / count as f32).collect();
+        BlueNoiseMask { values }
+    }
+
+    // The mask at a pixel, with the tile moved around for every dimension so they don't line up
+    pub fn value(&self, x: u32, y: u32, dimension: usize) -> f32 {
+        // Offsets along the R2 sequence, which spreads them evenly over the tile
+        let offset_x = (dimension as f32 * 0.754_877_7 * BLUE_NOISE_SIZE as f32) as usize;
+        let offset_y = (dimension as f32 * 0.569_840_3 * BLUE_NOISE_SIZE as f32) as usize;
+        let x = (x as usize + offset_x) % BLUE_NOISE_SIZE;
+        let y = (y as usize + offset_y) % BLUE_NOISE_SIZE;
+        return self.values[y * BLUE_NOISE_SIZE + x];
+    }
+}
+
+// A binary pattern on the tile and how crowded every pixel of it is
+#[derive(Clone)]
+struct VoidAndCluster {
+    size: usize,
+    kernel: Vec<f32>,
+    set: Vec<bool>,
+    energy: Vec<f32>
+}
+
+impl VoidAndCluster {
+    fn toggle(&mut self, index: usize) {
+        let sign = if self.set[index] { -1.0 } else { 1.0 };
+        self.set[index] = !self.set[index];
+        let (x, y) = (index % self.size, index / self.size);
+        for (i, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (i % self.size + self.size - x) % self.size;
+            let dy = (i / self.size + self.size - y) % self.size;
+            *energy += sign * self.kernel[dy * self.size + dx];
+        }
+    }
+
+    // The set pixel with the most crowded surroundings
+    fn tightest_cluster(&self) -> usize {
+        (0..self.set.len())
+            .filter(|&i| self.set[i])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    // The unset pixel with the least crowded surroundings
+    fn largest_void(&self) -> usize {
+        (0..self.set.len())
+            .filter(|&i| !self.set[i])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+// Mirrors the digits of index in the given base around the decimal point, permuting every digit
+// The leading zeros are permuted too, as far as an f32 can tell them apart
+fn scrambled_radical_inverse(mut index: u32, base: u32, permutation: &[u32]) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let (mut result, mut scale) = (0.0_f64, inverse_base);
+    while scale > 1.0 / (1 << 24) as f64 {
+        result += permutation[(index % base) as usize] as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    return result as f32;
+}
+
+/// Hands out the numbers of a sampler through the rand traits, so anything that takes an rng can use it
+/// Every call to gen::<f32>() is one dimension
+pub struct SamplerRng {
+    sampler: Box<dyn Sampler>
+}
+
+impl SamplerRng {
+    pub fn new(sampler: Box<dyn Sampler>) -> SamplerRng {
+        SamplerRng { sampler }
+    }
+
+    pub fn start_sample(&mut self, x: u32, y: u32, index: u32, count: u32) {
+        self.sampler.start_sample(x, y, index, count);
+    }
+
+    /// Hands out the numbers of another sampler from now on, returning the one it replaces
+    pub fn replace(&mut self, sampler: Box<dyn Sampler>) -> Box<dyn Sampler> {
+        std::mem::replace(&mut self.sampler, sampler)
+    }
+}
+
+impl RngCore for SamplerRng {
+    // gen::<f32>() keeps the top 24 bits, which is all an f32 in [0, 1) has
+    fn next_u32(&mut self) -> u32 {
+        (self.sampler.next() as f64 * 4294967296.0) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radical_inverse_mirrors_the_digits() {
+        let identity = |base: u32| (0..base).collect::<Vec<u32>>();
+        for (index, expected) in [(0, 0.0), (1, 0.5), (2, 0.25), (3, 0.75), (6, 0.375)] {
+            assert_eq!(scrambled_radical_inverse(index, 2, &identity(2)), expected);
+        }
+        // 5 is 12 in base 3, mirrored to 0.21
+        assert!((scrambled_radical_inverse(5, 3, &identity(3)) - 7.0 / 9.0).abs() < 1e-6);
+    }
+
+    // Scrambling moves the points around but keeps them stratified: the first base^k points
+    // land one in every interval of length base^-k
+    #[test]
+    fn scrambled_radical_inverse_stays_stratified() {
+        for (base, permutation) in [(3, vec![2, 0, 1]), (5, vec![3, 0, 4, 1, 2])] {
+            let count = base * base * base;
+            let mut hits = vec![0; count as usize];
+            for index in 0..count {
+                let value = scrambled_radical_inverse(index, base, &permutation);
+                assert!((0.0..1.0).contains(&value));
+                hits[(value * count as f32) as usize] += 1;
+            }
+            assert!(hits.iter().all(|&hit| hit == 1), "base {} isn't stratified: {:?}", base, hits);
+        }
+    }
+}
@@ -0,0 +1,762 @@
+// Scenes read from a text file, so they can be set up without recompiling
+//
+// Lines starting with # are comments. A line like `name = kind arguments...` defines a texture, a material or a
+// transform track that later lines use by name, and every other line adds something to the scene as `kind arguments...`
+// Arguments are numbers, x,y,z vectors, r,g,b colors or a single gray value, names and paths relative to where the
+// renderer is run. Anything taking a texture also takes a plain color. Options come after the arguments, either as
+// key=value or as a word by itself
+//
+// Textures
+//   name = image <path> [data] [clamp] [nearest] [mip=<bias>]
+//   name = udim <path with <UDIM> in it>
+//   name = checker <scale> <even texture> <odd texture>
+//   name = grid <scale> <line texture> <fill texture> [width=<fraction of a cell>]
+//   name = turbulence <scale> <octaves>
+//   name = marble <scale> <octaves> [light=<color>] [dark=<color>]
+//   name = wood <scale> <octaves> [light=<color>] [dark=<color>]
+//
+// Materials
+//   name = diffuse <texture>
+//   name = lambertian <texture>
+//   name = metal <texture> <fuzz>
+//   name = ggx <texture> <roughness> [roughness-map=<texture>]
+//   name = pbr <texture> <metallic> <roughness> [metallic-map=<texture>] [roughness-map=<texture>]
+//          [emissive=<color>] [emission-map=<texture>]
+//   name = plastic <texture> <ior> <roughness>
+//   name = translucent <texture> <transmission>
+//   name = glass <ior> [tint=<color>] [tint-distance=<distance>] [abbe=<abbe number>]
+//   name = subsurface <ior> <albedo> <mean free path>
+//   name = light <texture> [strength=<factor>]
+//   name = blackbody <kelvin> <strength>
+//   name = shadow-catcher
+//   name = isotropic <texture>
+//   name = henyey-greenstein <texture> <g>
+//   name = clearcoat <material> <ior> <roughness>
+//   name = thin-film <material> <thickness in nm> <film ior> <base ior>
+//   name = mix <material> <material> <factor, or a texture defined in space to read it from>
+//   name = normal-mapped <material> <texture>
+//   name = bump-mapped <material> <texture> <strength>
+//   name = alpha-masked <material> <texture>
+//   name = graph <shader graph path>, see shader_graph.rs
+//
+// Transform tracks, keyed on lines of their own before the objects that follow them
+//   name = track linear|smooth
+//   key <track> <time> [translate=<vector>] [rotate=<axis x,y,z>,<degrees>] [scale=<vector or number>]
+//
+// Objects, which all take [holdout] [group=<light group>] [track=<track>]
+//   sphere <center> <radius> <material> [moving=<center at time 1>]
+//   rect <origin> <u> <v> <material> [one-sided] [one-sided-emission]
+//   mesh <obj path> <material> [watertight]
+//   sphere-light <center> <radius> <color> <power as 60W or 800lm>
+//   rect-light <origin> <u> <v> <color> <power as 60W or 800lm>
+//   portal <origin> <u> <v> <origin> <u> <v>
+//   medium <center> <radius> <density> <phase material>
+//   smoke <center> <radius> <density> <scale> <octaves> <threshold> <phase material> [fire=<kelvin>,<strength>]
+//
+// Lights and the world around the scene
+//   point <position> <intensity> [group=<light group>]
+//   spot <position> <target> <angle> <penumbra> <intensity> [group=<light group>]
+//   directional <direction> <irradiance> [group=<light group>]
+//   environment <path> [intensity=<factor>] [rotation=<degrees>]
+//   light-portal <origin> <u> <v>
+//   fog <density> <falloff> <height> [anisotropy=<g>] [albedo=<color>]
+//
+// Cameras, where the projection is one of pinhole <vfov>, lens <vfov> <aperture> <focus distance>
+// [tilt=<degrees>] [shift=<x>,<y>], orthographic <height>, fisheye <fov>, stereographic <fov> or equirectangular
+//   camera <from> <at> <projection> [up=<vector>]
+//   view <name> <from> <at> <projection> [up=<vector>]
+//   stereo <interocular> <convergence> [anaglyph]
+//
+// Image textures on lines after `texture-cache <megabytes>` read their pixels through a cache of that size
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+use std::sync::Arc;
+use glam::{Quat, Vec3};
+use crate::material::*;
+use crate::object::*;
+use crate::texture::*;
+use crate::texture_cache::{TextureCache, CachedImageTexture};
+use crate::mesh::{Mesh, TriangleIntersection};
+use crate::portal::Portal;
+use crate::light::PunctualLight;
+use crate::volume::{NoiseDensity, VoxelGrid, BlackbodyEmission};
+use crate::environment::{Background, EnvironmentMap, LightPortal};
+use crate::fog::HeightFog;
+use crate::camera::{Camera, View};
+use crate::projection::*;
+use crate::animation::{Interpolation, TransformTrack};
+use crate::shader_graph::GraphMaterial;
+
+type Color = Vec3;
+
+// Points along every axis of the temperature grid of a fire
+const FIRE_RESOLUTION: usize = 32;
+
+/// Reads a scene file, returning its objects and setting up the camera, lights and background it describes
+/// Anything the file doesn't mention is left as the camera had it
+pub fn load(path: &str, camera: &mut Camera) -> Result<Vec<Box<dyn Object>>, Error> {
+    parse(&fs::read_to_string(path)?, camera)
+}
+
+pub fn parse(source: &str, camera: &mut Camera) -> Result<Vec<Box<dyn Object>>, Error> {
+    let mut scene = Scene {
+        camera,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        tracks: HashMap::new(),
+        texture_cache: None,
+        objects: Vec::new()
+    };
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", number + 1, message));
+        // Options have equals signs too, but only after the kind
+        let result = match line.split_once('=') {
+            Some((name, definition)) if !name.trim().contains(char::is_whitespace) => scene.define(name.trim(), definition),
+            _ => scene.add(line)
+        };
+        result.map_err(|message| invalid(&message))?;
+    }
+    return Ok(scene.objects);
+}
+
+struct Scene<'a> {
+    camera: &'a mut Camera,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Rc<dyn Material>>,
+    tracks: HashMap<String, TransformTrack>,
+    texture_cache: Option<Arc<TextureCache>>,
+    objects: Vec<Box<dyn Object>>
+}
+
+// A texture argument, kept as a color when it is one since some materials have a simpler form for plain colors
+enum TextureArgument {
+    Color(Color),
+    Texture(Arc<dyn Texture>)
+}
+
+impl TextureArgument {
+    fn texture(self) -> Arc<dyn Texture> {
+        match self {
+            TextureArgument::Color(color) => Arc::new(SolidColor::from_color(color)),
+            TextureArgument::Texture(texture) => texture
+        }
+    }
+}
+
+impl Scene<'_> {
+    fn define(&mut self, name: &str, definition: &str) -> Result<(), String> {
+        let mut arguments = Arguments::new(definition)?;
+        match arguments.kind {
+            "image" | "udim" | "checker" | "grid" | "turbulence" | "marble" | "wood" => {
+                let texture = self.parse_texture(&mut arguments)?;
+                self.textures.insert(name.to_owned(), texture);
+            },
+            "track" => {
+                let interpolation = match arguments.next()? {
+                    "linear" => Interpolation::Linear,
+                    "smooth" => Interpolation::CatmullRom,
+                    word => return Err(format!("expected linear or smooth interpolation, got {}", word))
+                };
+                self.tracks.insert(name.to_owned(), TransformTrack::new(interpolation));
+            },
+            _ => {
+                let material = self.parse_material(&mut arguments)?;
+                self.materials.insert(name.to_owned(), material);
+            }
+        }
+        return arguments.finish();
+    }
+
+    fn parse_texture(&mut self, arguments: &mut Arguments) -> Result<Arc<dyn Texture>, String> {
+        let texture: Arc<dyn Texture> = match arguments.kind {
+            "image" => {
+                let path = arguments.next()?;
+                let data = arguments.flag("data");
+                let wrap = match arguments.flag("clamp") {
+                    true => WrapMode::Clamp,
+                    false => WrapMode::Repeat
+                };
+                let filter = match arguments.flag("nearest") {
+                    true => Filter::Nearest,
+                    false => Filter::Bilinear
+                };
+                match &self.texture_cache {
+                    Some(cache) => {
+                        let mut texture = match data {
+                            true => CachedImageTexture::open_data(path, cache),
+                            false => CachedImageTexture::open(path, cache)
+                        }.map_err(|error| error.to_string())?;
+                        if arguments.option("mip").is_some() {
+                            return Err("cached images have no mip levels".to_owned());
+                        }
+                        texture.wrap = wrap;
+                        texture.filter = filter;
+                        Arc::new(texture)
+                    },
+                    None => {
+                        let mut texture = match data {
+                            true => ImageTexture::load_data(path),
+                            false => ImageTexture::load(path)
+                        }.map_err(|error| error.to_string())?;
+                        texture.wrap = wrap;
+                        texture.filter = filter;
+                        texture.mip_bias = arguments.number_option("mip", 0.0)?;
+                        Arc::new(texture)
+                    }
+                }
+            },
+            "udim" => Arc::new(UdimTexture::load(arguments.next()?).map_err(|error| error.to_string())?),
+            "checker" => {
+                let scale = arguments.number()?;
+                match (self.texture(arguments.next()?)?, self.texture(arguments.next()?)?) {
+                    (TextureArgument::Color(even), TextureArgument::Color(odd)) => Arc::new(CheckerTexture::from_colors(scale, even, odd)),
+                    (even, odd) => Arc::new(CheckerTexture::new(scale, even.texture(), odd.texture()))
+                }
+            },
+            "grid" => {
+                let scale = arguments.number()?;
+                let mut grid = match (self.texture(arguments.next()?)?, self.texture(arguments.next()?)?) {
+                    (TextureArgument::Color(line), TextureArgument::Color(fill)) => GridTexture::from_colors(scale, line, fill),
+                    (line, fill) => GridTexture::new(scale, line.texture(), fill.texture())
+                };
+                grid.line_width = arguments.number_option("width", grid.line_width)?;
+                Arc::new(grid)
+            },
+            "turbulence" => Arc::new(TurbulenceTexture::new(arguments.number()?, arguments.whole()?)),
+            "marble" => {
+                let mut marble = MarbleTexture::new(arguments.number()?, arguments.whole()?);
+                marble.light = arguments.color_option("light", marble.light)?;
+                marble.dark = arguments.color_option("dark", marble.dark)?;
+                Arc::new(marble)
+            },
+            "wood" => {
+                let mut wood = WoodTexture::new(arguments.number()?, arguments.whole()?);
+                wood.light = arguments.color_option("light", wood.light)?;
+                wood.dark = arguments.color_option("dark", wood.dark)?;
+                Arc::new(wood)
+            },
+            kind => return Err(format!("unknown texture kind {}", kind))
+        };
+        return Ok(texture);
+    }
+
+    fn parse_material(&mut self, arguments: &mut Arguments) -> Result<Rc<dyn Material>, String> {
+        let material: Rc<dyn Material> = match arguments.kind {
+            "diffuse" => match self.texture(arguments.next()?)? {
+                TextureArgument::Color(color) => Rc::new(Diffuse::new(color.x, color.y, color.z)),
+                TextureArgument::Texture(texture) => Rc::new(Diffuse::textured(texture))
+            },
+            "lambertian" => match self.texture(arguments.next()?)? {
+                TextureArgument::Color(color) => Rc::new(Lambertian::new(color.x, color.y, color.z)),
+                TextureArgument::Texture(texture) => Rc::new(Lambertian::textured(texture))
+            },
+            "metal" => match (self.texture(arguments.next()?)?, arguments.number()?) {
+                (TextureArgument::Color(color), fuzz) => Rc::new(Metal::new(color, fuzz)),
+                (TextureArgument::Texture(texture), fuzz) => Rc::new(Metal::textured(texture, fuzz))
+            },
+            "ggx" => {
+                let ggx = match (self.texture(arguments.next()?)?, arguments.number()?) {
+                    (TextureArgument::Color(color), roughness) => Ggx::new(color, roughness),
+                    (TextureArgument::Texture(texture), roughness) => Ggx::textured(texture, roughness)
+                };
+                match arguments.option("roughness-map") {
+                    Some(word) => Rc::new(ggx.with_roughness_texture(self.texture(word)?.texture())),
+                    None => Rc::new(ggx)
+                }
+            },
+            "pbr" => {
+                let mut pbr = match (self.texture(arguments.next()?)?, arguments.number()?, arguments.number()?) {
+                    (TextureArgument::Color(color), metallic, roughness) => PbrMaterial::new(color, metallic, roughness),
+                    (TextureArgument::Texture(texture), metallic, roughness) => PbrMaterial::textured(texture, metallic, roughness)
+                };
+                if let Some(word) = arguments.option("metallic-map") {
+                    pbr = pbr.with_metallic_texture(self.texture(word)?.texture());
+                }
+                if let Some(word) = arguments.option("roughness-map") {
+                    pbr = pbr.with_roughness_texture(self.texture(word)?.texture());
+                }
+                if let Some(word) = arguments.option("emissive") {
+                    pbr = pbr.with_emissive(color(word)?);
+                }
+                if let Some(word) = arguments.option("emission-map") {
+                    pbr = pbr.with_emission_strength(self.texture(word)?.texture());
+                }
+                Rc::new(pbr)
+            },
+            "plastic" => match (self.texture(arguments.next()?)?, arguments.number()?, arguments.number()?) {
+                (TextureArgument::Color(color), ior, roughness) => Rc::new(Plastic::new(color, ior, roughness)),
+                (TextureArgument::Texture(texture), ior, roughness) => Rc::new(Plastic::textured(texture, ior, roughness))
+            },
+            "translucent" => match (self.texture(arguments.next()?)?, arguments.number()?) {
+                (TextureArgument::Color(color), transmission) => Rc::new(ThinTranslucent::new(color, transmission)),
+                (TextureArgument::Texture(texture), transmission) => Rc::new(ThinTranslucent::textured(texture, transmission))
+            },
+            "glass" => {
+                let ior = arguments.number()?;
+                match (arguments.option("tint"), arguments.option("abbe")) {
+                    (Some(tint), None) => Rc::new(Dielectric::tinted(ior, color(tint)?, arguments.number_option("tint-distance", 1.0)?)),
+                    (None, Some(abbe)) => Rc::new(Dielectric::dispersive(ior, number(abbe)?)),
+                    (None, None) => Rc::new(Dielectric::new(ior)),
+                    (Some(_), Some(_)) => return Err("glass can't be both tinted and dispersive".to_owned())
+                }
+            },
+            "subsurface" => Rc::new(Subsurface::new(arguments.number()?, arguments.color()?, arguments.color()?)),
+            "light" => {
+                let light = self.texture(arguments.next()?)?;
+                let strength = arguments.number_option("strength", 1.0)?;
+                match light {
+                    TextureArgument::Color(color) => {
+                        let color = color * strength;
+                        Rc::new(DiffuseLight::new(color.x, color.y, color.z))
+                    },
+                    TextureArgument::Texture(texture) => Rc::new(DiffuseLight::textured(texture, strength))
+                }
+            },
+            "blackbody" => Rc::new(DiffuseLight::blackbody(arguments.number()?, arguments.number()?)),
+            "shadow-catcher" => Rc::new(ShadowCatcher),
+            "isotropic" => match self.texture(arguments.next()?)? {
+                TextureArgument::Color(color) => Rc::new(Isotropic::new(color.x, color.y, color.z)),
+                TextureArgument::Texture(texture) => Rc::new(Isotropic::textured(texture))
+            },
+            "henyey-greenstein" => match (self.texture(arguments.next()?)?, arguments.number()?) {
+                (TextureArgument::Color(color), g) => Rc::new(HenyeyGreenstein::new(color.x, color.y, color.z, g)),
+                (TextureArgument::Texture(texture), g) => Rc::new(HenyeyGreenstein::textured(texture, g))
+            },
+            "clearcoat" => Rc::new(Clearcoat::new(self.material(arguments.next()?)?, arguments.number()?, arguments.number()?)),
+            "thin-film" => {
+                let base = self.material(arguments.next()?)?;
+                Rc::new(ThinFilm::new(base, arguments.number()?, arguments.number()?, arguments.number()?))
+            },
+            "mix" => {
+                let (first, second) = (self.material(arguments.next()?)?, self.material(arguments.next()?)?);
+                let factor = arguments.next()?;
+                match number(factor) {
+                    Ok(factor) => Rc::new(Mix::new(first, second, factor)),
+                    // Masks only see the hit position, so only textures defined in space vary over the surface
+                    Err(_) => {
+                        let mask = self.texture(factor)?.texture();
+                        Rc::new(Mix::masked(first, second, move |position| mask.gray(0.0, 0.0, position)))
+                    }
+                }
+            },
+            "normal-mapped" => {
+                let base = self.material(arguments.next()?)?;
+                Rc::new(NormalMapped::new(base, self.texture(arguments.next()?)?.texture()))
+            },
+            "bump-mapped" => {
+                let base = self.material(arguments.next()?)?;
+                Rc::new(BumpMapped::new(base, self.texture(arguments.next()?)?.texture(), arguments.number()?))
+            },
+            "alpha-masked" => {
+                let base = self.material(arguments.next()?)?;
+                Rc::new(AlphaMasked::new(base, self.texture(arguments.next()?)?.texture()))
+            },
+            "graph" => Rc::new(GraphMaterial::load(arguments.next()?).map_err(|error| error.to_string())?),
+            kind => return Err(format!("unknown kind {}", kind))
+        };
+        return Ok(material);
+    }
+
+    fn add(&mut self, line: &str) -> Result<(), String> {
+        let mut arguments = Arguments::new(line)?;
+        match arguments.kind {
+            "sphere" => {
+                let (center, radius) = (arguments.vector()?, arguments.number()?);
+                let (material, name) = self.named_material(arguments.next()?)?;
+                let sphere = match arguments.option("moving") {
+                    Some(end) => Sphere::moving(center, vector(end)?, radius, material),
+                    None => Sphere::new(center, radius, material)
+                };
+                self.add_object(Box::new(NamedMaterial::new(sphere, name)), &mut arguments)?;
+            },
+            "rect" => {
+                let (origin, u, v) = (arguments.vector()?, arguments.vector()?, arguments.vector()?);
+                let (material, name) = self.named_material(arguments.next()?)?;
+                let rect = Rect::new(origin, u, v, material)
+                    .with_two_sided(!arguments.flag("one-sided"))
+                    .with_one_sided_emission(arguments.flag("one-sided-emission"));
+                self.add_object(Box::new(NamedMaterial::new(rect, name)), &mut arguments)?;
+            },
+            "mesh" => {
+                let path = arguments.next()?;
+                let (material, name) = self.named_material(arguments.next()?)?;
+                let mut mesh = Mesh::load(path, material).map_err(|error| error.to_string())?;
+                if arguments.flag("watertight") {
+                    mesh.intersection = TriangleIntersection::Watertight;
+                }
+                self.add_object(Box::new(NamedMaterial::new(mesh, name)), &mut arguments)?;
+            },
+            "sphere-light" => {
+                let (center, radius) = (arguments.vector()?, arguments.number()?);
+                let light = Sphere::emitter(center, radius, arguments.color()?, power(arguments.next()?)?);
+                self.add_object(Box::new(light), &mut arguments)?;
+            },
+            "rect-light" => {
+                let (origin, u, v) = (arguments.vector()?, arguments.vector()?, arguments.vector()?);
+                let light = Rect::emitter(origin, u, v, arguments.color()?, power(arguments.next()?)?);
+                self.add_object(Box::new(light), &mut arguments)?;
+            },
+            "portal" => {
+                let first = (arguments.vector()?, arguments.vector()?, arguments.vector()?);
+                let second = (arguments.vector()?, arguments.vector()?, arguments.vector()?);
+                self.add_object(Box::new(Portal::new(first, second)), &mut arguments)?;
+            },
+            "medium" => {
+                let (center, radius, density) = (arguments.vector()?, arguments.number()?, arguments.number()?);
+                let phase_function = self.material(arguments.next()?)?;
+                let boundary = Sphere::new(center, radius, phase_function.clone());
+                let medium = ConstantMedium::new(boundary, density, phase_function).with_hit_epsilon(self.camera.hit_epsilon);
+                self.add_object(Box::new(medium), &mut arguments)?;
+            },
+            "smoke" => {
+                let (center, radius) = (arguments.vector()?, arguments.number()?);
+                let density = NoiseDensity::new(arguments.number()?, arguments.number()?, arguments.whole()?, arguments.number()?);
+                let phase_function = self.material(arguments.next()?)?;
+                let boundary = Sphere::new(center, radius, phase_function.clone());
+                let medium = HeterogeneousMedium::new(boundary, density, phase_function).with_hit_epsilon(self.camera.hit_epsilon);
+                let medium = match arguments.option("fire") {
+                    Some(fire) => {
+                        let [kelvin, strength] = numbers(fire)?[..] else {
+                            return Err(format!("expected fire as <kelvin>,<strength>, got {}", fire));
+                        };
+                        // Hottest in the middle, cooling off towards the edge
+                        let temperature = VoxelGrid::from_fn(center - radius, center + radius, [FIRE_RESOLUTION; 3], |point| {
+                            kelvin * (1.0 - point.distance(center) / radius).max(0.0)
+                        });
+                        medium.with_emission(BlackbodyEmission::new(temperature, strength))
+                    },
+                    None => medium
+                };
+                self.add_object(Box::new(medium), &mut arguments)?;
+            },
+            "key" => {
+                let name = arguments.next()?;
+                let time = arguments.number()?;
+                let mut track = self.tracks.remove(name).ok_or_else(|| format!("no track called {}", name))?;
+                if let Some(word) = arguments.option("translate") {
+                    track = track.with_translation(time, vector(word)?);
+                }
+                if let Some(word) = arguments.option("rotate") {
+                    let [x, y, z, degrees] = numbers(word)?[..] else {
+                        return Err(format!("expected a rotation as <axis x,y,z>,<degrees>, got {}", word));
+                    };
+                    let axis = Vec3::new(x, y, z).try_normalize().ok_or("rotation axes can't be zero")?;
+                    track = track.with_rotation(time, Quat::from_axis_angle(axis, degrees.to_radians()));
+                }
+                if let Some(word) = arguments.option("scale") {
+                    track = track.with_scale(time, number(word).map(Vec3::splat).or_else(|_| vector(word))?);
+                }
+                self.tracks.insert(name.to_owned(), track);
+            },
+            "point" => {
+                let light = PunctualLight::point(arguments.vector()?, arguments.color()?);
+                self.add_light(light, &mut arguments);
+            },
+            "spot" => {
+                let (position, target) = (arguments.vector()?, arguments.vector()?);
+                let light = PunctualLight::spot(position, target, arguments.number()?, arguments.number()?, arguments.color()?);
+                self.add_light(light, &mut arguments);
+            },
+            "directional" => {
+                let light = PunctualLight::directional(arguments.vector()?, arguments.color()?);
+                self.add_light(light, &mut arguments);
+            },
+            "environment" => {
+                let environment = EnvironmentMap::load(arguments.next()?)
+                    .map_err(|error| error.to_string())?
+                    .with_intensity(arguments.number_option("intensity", 1.0)?)
+                    .with_rotation(arguments.number_option("rotation", 0.0)?);
+                self.camera.background = Background::Image(environment);
+            },
+            "light-portal" => {
+                let portal = LightPortal::new(arguments.vector()?, arguments.vector()?, arguments.vector()?);
+                self.camera.light_portals.push(portal);
+            },
+            "fog" => {
+                let fog = HeightFog::new(arguments.number()?, arguments.number()?, arguments.number()?)
+                    .with_anisotropy(arguments.number_option("anisotropy", 0.0)?)
+                    .with_albedo(arguments.color_option("albedo", Color::ONE)?);
+                self.camera.fog = Some(fog);
+            },
+            "camera" => {
+                let (from, at) = (arguments.vector()?, arguments.vector()?);
+                self.camera.projection = parse_projection(&mut arguments)?;
+                self.camera.look_at(from, at, arguments.vector_option("up", Vec3::Y)?);
+            },
+            "view" => {
+                let (name, from, at) = (arguments.next()?, arguments.vector()?, arguments.vector()?);
+                let projection = parse_projection(&mut arguments)?;
+                let up = arguments.vector_option("up", Vec3::Y)?;
+                self.camera.views.push(View::new(name, from, at, up, projection));
+            },
+            "stereo" => {
+                let (interocular, convergence) = (arguments.number()?, arguments.number()?);
+                let mode = match arguments.flag("anaglyph") {
+                    true => StereoMode::Anaglyph,
+                    false => StereoMode::SideBySide
+                };
+                self.camera.stereo = Some(Stereo::new(interocular, convergence, mode));
+            },
+            "texture-cache" => {
+                let megabytes = arguments.number()?;
+                self.texture_cache = Some(TextureCache::new((megabytes * 1024.0 * 1024.0) as usize));
+            },
+            kind => return Err(format!("unknown kind {}", kind))
+        }
+        return arguments.finish();
+    }
+
+    // Puts the object into the scene, moved along a track, in a light group and held out as the options say
+    fn add_object(&mut self, mut object: Box<dyn Object>, arguments: &mut Arguments) -> Result<(), String> {
+        if let Some(name) = arguments.option("track") {
+            let track = self.tracks.get(name).ok_or_else(|| format!("no track called {}", name))?;
+            object = Box::new(Animated::new(object, track.clone()));
+        }
+        if let Some(name) = arguments.option("group") {
+            object = Box::new(LightGroup::new(object, name));
+        }
+        if arguments.flag("holdout") {
+            object = Box::new(Holdout::new(object));
+        }
+        self.objects.push(object);
+        return Ok(());
+    }
+
+    fn add_light(&mut self, light: PunctualLight, arguments: &mut Arguments) {
+        let light = match arguments.option("group") {
+            Some(name) => light.with_group(name),
+            None => light
+        };
+        self.camera.punctual_lights.push(light);
+    }
+
+    // A texture defined earlier, or a color
+    fn texture(&self, word: &str) -> Result<TextureArgument, String> {
+        if let Some(texture) = self.textures.get(word) {
+            return Ok(TextureArgument::Texture(texture.clone()));
+        }
+        return color(word)
+            .map(TextureArgument::Color)
+            .map_err(|_| format!("expected a texture name or a r,g,b color, got {}", word));
+    }
+
+    fn material(&self, word: &str) -> Result<Rc<dyn Material>, String> {
+        self.materials.get(word).cloned().ok_or_else(|| format!("no material called {}", word))
+    }
+
+    // Objects are named after their material, so objects sharing one also share a material id
+    fn named_material<'w>(&self, word: &'w str) -> Result<(Rc<dyn Material>, &'w str), String> {
+        Ok((self.material(word)?, word))
+    }
+}
+
+fn parse_projection(arguments: &mut Arguments) -> Result<Box<dyn Projection>, String> {
+    let projection: Box<dyn Projection> = match arguments.next()? {
+        "pinhole" => Box::new(Pinhole::new(arguments.number()?)),
+        "lens" => {
+            let lens = ThinLens::new(arguments.number()?, arguments.number()?, arguments.number()?)
+                .with_tilt(arguments.number_option("tilt", 0.0)?);
+            match arguments.option("shift") {
+                Some(word) => {
+                    let [x, y] = numbers(word)?[..] else {
+                        return Err(format!("expected a shift as <x>,<y>, got {}", word));
+                    };
+                    Box::new(lens.with_shift(x, y))
+                },
+                None => Box::new(lens)
+            }
+        },
+        "orthographic" => Box::new(Orthographic::new(arguments.number()?)),
+        "fisheye" => Box::new(Fisheye::new(arguments.number()?)),
+        "stereographic" => Box::new(Stereographic::new(arguments.number()?)),
+        "equirectangular" => Box::new(Equirectangular),
+        word => return Err(format!("unknown projection {}", word))
+    };
+    return Ok(projection);
+}
+
+/// The words of a line after its kind, as arguments in order and options
+struct Arguments<'a> {
+    kind: &'a str,
+    // Arguments not taken yet, followed by any options given as a word by themselves
+    words: VecDeque<&'a str>,
+    // Options given as key=value
+    options: Vec<(&'a str, &'a str)>
+}
+
+impl<'a> Arguments<'a> {
+    fn new(text: &'a str) -> Result<Arguments<'a>, String> {
+        let mut words = text.split_whitespace();
+        let kind = words.next().ok_or("missing kind")?;
+        let (options, words): (Vec<&str>, Vec<&str>) = words.partition(|word| word.contains('='));
+        let options = options.into_iter().filter_map(|option| option.split_once('=')).collect();
+        return Ok(Arguments { kind, words: words.into(), options });
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.words.pop_front().ok_or_else(|| format!("{} is missing arguments", self.kind))
+    }
+
+    fn number(&mut self) -> Result<f32, String> {
+        number(self.next()?)
+    }
+
+    fn whole(&mut self) -> Result<u32, String> {
+        let word = self.next()?;
+        word.parse().map_err(|_| format!("expected a whole number, got {}", word))
+    }
+
+    fn vector(&mut self) -> Result<Vec3, String> {
+        vector(self.next()?)
+    }
+
+    fn color(&mut self) -> Result<Color, String> {
+        color(self.next()?)
+    }
+
+    // Takes the value of a key=value option
+    fn option(&mut self, key: &str) -> Option<&'a str> {
+        let index = self.options.iter().position(|(name, _)| *name == key)?;
+        return Some(self.options.remove(index).1);
+    }
+
+    // Takes an option given as a word by itself, which has to come after every argument
+    fn flag(&mut self, name: &str) -> bool {
+        match self.words.iter().position(|word| *word == name) {
+            Some(index) => {
+                self.words.remove(index);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn number_option(&mut self, key: &str, default: f32) -> Result<f32, String> {
+        self.option(key).map_or(Ok(default), number)
+    }
+
+    fn vector_option(&mut self, key: &str, default: Vec3) -> Result<Vec3, String> {
+        self.option(key).map_or(Ok(default), vector)
+    }
+
+    fn color_option(&mut self, key: &str, default: Color) -> Result<Color, String> {
+        self.option(key).map_or(Ok(default), color)
+    }
+
+    // Anything left over wasn't understood
+    fn finish(&self) -> Result<(), String> {
+        if let Some(word) = self.words.front() {
+            return Err(format!("unexpected argument {} for {}", word, self.kind));
+        }
+        if let Some((key, _)) = self.options.first() {
+            return Err(format!("unknown option {} for {}", key, self.kind));
+        }
+        return Ok(());
+    }
+}
+
+fn number(word: &str) -> Result<f32, String> {
+    word.parse().map_err(|_| format!("expected a number, got {}", word))
+}
+
+fn numbers(word: &str) -> Result<Vec<f32>, String> {
+    word.split(',').map(number).collect()
+}
+
+fn vector(word: &str) -> Result<Vec3, String> {
+    match numbers(word).as_deref() {
+        Ok([x, y, z]) => Ok(Vec3::new(*x, *y, *z)),
+        _ => Err(format!("expected a x,y,z vector, got {}", word))
+    }
+}
+
+fn color(word: &str) -> Result<Color, String> {
+    match numbers(word).as_deref() {
+        Ok([gray]) => Ok(Color::splat(*gray)),
+        Ok([red, green, blue]) => Ok(Color::new(*red, *green, *blue)),
+        _ => Err(format!("expected a number or a r,g,b color, got {}", word))
+    }
+}
+
+// A light's total power, in watts or lumens
+fn power(word: &str) -> Result<LightPower, String> {
+    if let Some(lumens) = word.strip_suffix("lm") {
+        return Ok(LightPower::Lumens(number(lumens)?));
+    }
+    if let Some(watts) = word.strip_suffix('W') {
+        return Ok(LightPower::Watts(number(watts)?));
+    }
+    return Err(format!("expected a power like 60W or 800lm, got {}", word));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_objects_lights_and_cameras() {
+        let source = "
+            # A ball on a floor
+            red = plastic 0.8,0.1,0.1 1.5 0.2
+            floor = lambertian 0.5
+            sphere 0,0,-1 0.5 red group=ball
+            rect -1,-0.5,0 2,0,0 0,0,-2 floor one-sided holdout
+            spot 0,2,0 0,0,-1 30 5 10 group=key
+            directional 1,1,1 0.5
+            view top 0,2,-1 0,0,-1 orthographic 2 up=0,0,-1
+            stereo 0.065 2 anaglyph
+        ";
+        let mut camera = Camera::default();
+        let objects = parse(source, &mut camera).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].material_name(), Some("red"));
+        assert_eq!(objects[0].light_group(), Some("ball"));
+        assert!(objects[1].is_holdout());
+        assert_eq!(camera.punctual_lights.len(), 2);
+        assert_eq!(camera.punctual_lights[0].group.as_deref(), Some("key"));
+        assert_eq!(camera.views[0].name, "top");
+        assert!(camera.stereo.is_some_and(|stereo| stereo.mode == StereoMode::Anaglyph));
+    }
+
+    #[test]
+    fn tracks_move_the_objects_after_their_keys() {
+        let source = "
+            gray = diffuse 0.5
+            hop = track linear
+            key hop 0 translate=0,0,0
+            key hop 1 translate=0,2,0
+            sphere 0,0,0 1 gray track=hop
+        ";
+        let objects = parse(source, &mut Camera::default()).unwrap();
+        assert_eq!(objects[0].moved(Vec3::ZERO, 0.0, 0.5), Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn mistakes_report_their_line() {
+        let error = |source: &str| parse(source, &mut Camera::default()).err().unwrap().to_string();
+        assert_eq!(error("gray = diffuse 0.5\nsphere 0,0,0 1 grey"), "Line 2: no material called grey");
+        assert_eq!(error("gray = diffuse 0.5\n\nsphere 0,0,0 1 gray shiny"), "Line 3: unexpected argument shiny for sphere");
+        assert_eq!(error("gray = diffuse 0.5 colour=1"), "Line 1: unknown option colour for diffuse");
+        assert_eq!(error("sphere 0,0 1 gray"), "Line 1: expected a x,y,z vector, got 0,0");
+        assert_eq!(error("rect-light 0,0,0 1,0,0 0,1,0 1 bright"), "Line 1: expected a power like 60W or 800lm, got bright");
+    }
+
+    #[test]
+    fn graph_materials_are_read_from_their_own_file() {
+        let path = std::env::temp_dir().join(format!("sagakar-scene-test-{}-graph.txt", std::process::id()));
+        fs::write(&path, "base_color = 0.9,0.2,0.2\nroughness = 0.3\n").unwrap();
+        let source = format!("painted = graph {}\nsphere 0,0,0 1 painted", path.to_str().unwrap());
+        let objects = parse(&source, &mut Camera::default());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(objects.unwrap()[0].material_name(), Some("painted"));
+        let error = parse("painted = graph missing.txt", &mut Camera::default()).err().unwrap().to_string();
+        assert!(error.starts_with("Line 1: "));
+    }
+}
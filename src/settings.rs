@@ -0,0 +1,91 @@
+// Image size of a render, and the presets it's usually picked from
+
+// Largest number of pixels along either side, to catch typos before allocating a huge framebuffer
+const MAX_DIMENSION: u32 = 1 << 18;
+
+/// The size of the rendered image in pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32
+}
+
+impl RenderSettings {
+    /// An image of the given size, if both sides are between 1 and 262144 pixels
+    pub fn new(width: u32, height: u32) -> Result<RenderSettings, String> {
+        for (side, length) in [("width", width), ("height", height)] {
+            if length == 0 || length > MAX_DIMENSION {
+                return Err(format!("image {} must be between 1 and {} pixels, got {}", side, MAX_DIMENSION, length));
+            }
+        }
+        Ok(RenderSettings { width, height })
+    }
+
+    /// A width and either a height or an aspect ratio as w:h like 16:9, as given on the command line
+    pub fn parse(width: &str, height: &str) -> Result<RenderSettings, String> {
+        let width = width.parse().map_err(|_| format!("invalid width {}", width))?;
+        let Some((aspect_width, aspect_height)) = height.split_once(':') else {
+            return RenderSettings::new(width, height.parse().map_err(|_| format!("invalid height {}", height))?);
+        };
+        let invalid = || format!("invalid aspect ratio {}, expected w:h like 16:9", height);
+        let aspect_width: f32 = aspect_width.parse().map_err(|_| invalid())?;
+        let aspect_height: f32 = aspect_height.parse().map_err(|_| invalid())?;
+        RenderSettings::from_aspect(width, aspect_width / aspect_height)
+    }
+
+    /// An image of the given width, with the height following from width over height being aspect
+    pub fn from_aspect(width: u32, aspect: f32) -> Result<RenderSettings, String> {
+        if !(aspect.is_finite() && aspect > 0.0) {
+            return Err(format!("aspect ratio must be positive, got {}", aspect));
+        }
+        let height = (width as f32 / aspect).round().max(1.0) as u32;
+        RenderSettings::new(width, height)
+    }
+
+    /// A common resolution by name: 720p, 1080p, 4k or square
+    pub fn preset(name: &str) -> Result<RenderSettings, String> {
+        match name.to_lowercase().as_str() {
+            "720p" => RenderSettings::new(1280, 720),
+            "1080p" => RenderSettings::new(1920, 1080),
+            "4k" | "2160p" => RenderSettings::new(3840, 2160),
+            "square" => RenderSettings::new(1024, 1024),
+            _ => Err(format!("unknown resolution preset {}, expected 720p, 1080p, 4k or square", name))
+        }
+    }
+
+    /// Width over height
+    pub fn aspect(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings { width: 256, height: 256 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_height_or_an_aspect_ratio() {
+        assert_eq!(RenderSettings::parse("640", "480"), RenderSettings::new(640, 480));
+        assert_eq!(RenderSettings::parse("1920", "16:9"), RenderSettings::new(1920, 1080));
+        assert_eq!(RenderSettings::parse("1000", "2.39:1"), RenderSettings::new(1000, 418));
+        assert_eq!(RenderSettings::parse("512", "1:1"), RenderSettings::new(512, 512));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        for (width, height) in [("0", "480"), ("640", "0"), ("wide", "480"), ("640", "16:"), ("640", "0:9"), ("640", "16:0"), ("640", "-4:3")] {
+            assert!(RenderSettings::parse(width, height).is_err(), "{} {} should be rejected", width, height);
+        }
+    }
+
+    #[test]
+    fn tiny_heights_round_up_to_a_pixel() {
+        assert_eq!(RenderSettings::from_aspect(10, 100.0), RenderSettings::new(10, 1));
+    }
+}
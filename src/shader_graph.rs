@@ -0,0 +1,287 @@
+// Materials built from a small graph of nodes, read from a text file so they can be changed without recompiling
+//
+// Every line defines one node as `name = kind arguments...`, and lines starting with # are comments
+// Arguments are numbers, r,g,b colors, or the names of nodes defined on earlier lines
+//
+//   noise = noise <scale> <octaves>
+//   texture = texture <image path>
+//   fresnel = fresnel <ior>
+//   ramp = ramp <input> <position> <color> <position> <color> ...
+//   mix = mix <first> <second> <factor>
+//   multiply = multiply <first> <second>
+//   uv = uv
+//
+// The nodes called base_color, metallic and roughness drive a metallic-roughness material like PbrMaterial
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use crate::sampler::SamplerRng;
+use glam::Vec3;
+use crate::material::{self, Material, ScatterRecord};
+use crate::perlin::Perlin;
+use crate::ray::Ray;
+use crate::texture::{Texture, ImageTexture};
+
+type Color = Vec3;
+
+/// Where a node input comes from
+enum Socket {
+    Constant(Color),
+    // Index of an earlier node
+    Node(usize)
+}
+
+enum Node {
+    Socket(Socket),
+    Noise { noise: Perlin, scale: f32, octaves: u32 },
+    Texture(Arc<dyn Texture>),
+    // Reflectance of a dielectric of this index of refraction, towards the viewer
+    Fresnel(f32),
+    // Maps the gray level of the input onto a gradient through the stops
+    Ramp(Socket, Vec<(f32, Color)>),
+    Mix(Socket, Socket, Socket),
+    Multiply(Socket, Socket),
+    // The surface coordinates as red and green
+    Uv
+}
+
+/// What a node graph can see about the point it's evaluated at
+pub struct ShadingPoint {
+    pub u: f32,
+    pub v: f32,
+    pub position: Vec3,
+    // Outward normal and the direction the ray came in along, if known
+    pub normal: Option<Vec3>,
+    pub incoming: Option<Vec3>
+}
+
+pub struct ShaderGraph {
+    nodes: Vec<Node>,
+    names: Vec<String>
+}
+
+impl ShaderGraph {
+    pub fn load(path: &str) -> Result<ShaderGraph, Error> {
+        ShaderGraph::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<ShaderGraph, Error> {
+        let mut graph = ShaderGraph { nodes: Vec::new(), names: Vec::new() };
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", number + 1, message));
+            let (name, definition) = line.split_once('=').ok_or_else(|| invalid("expected `name = kind arguments`"))?;
+            let mut words = definition.split_whitespace();
+            let kind = words.next().ok_or_else(|| invalid("missing node kind"))?;
+            let arguments: Vec<&str> = words.collect();
+            let node = graph.parse_node(kind, &arguments).map_err(|message| invalid(&message))?;
+            graph.nodes.push(node);
+            graph.names.push(name.trim().to_string());
+        }
+        return Ok(graph);
+    }
+
+    fn parse_node(&self, kind: &str, arguments: &[&str]) -> Result<Node, String> {
+        let count = |expected: usize| match arguments.len() == expected {
+            true => Ok(()),
+            false => Err(format!("{} takes {} arguments, got {}", kind, expected, arguments.len()))
+        };
+        let number = |word: &str| word.parse::<f32>().map_err(|_| format!("expected a number, got {}", word));
+        let node = match kind {
+            "noise" => {
+                count(2)?;
+                let octaves = arguments[1].parse().map_err(|_| format!("expected a whole number of octaves, got {}", arguments[1]))?;
+                Node::Noise { noise: Perlin::new(), scale: number(arguments[0])?, octaves }
+            },
+            "texture" => {
+                count(1)?;
+                let texture = ImageTexture::load(arguments[0]).map_err(|error| error.to_string())?;
+                Node::Texture(Arc::new(texture))
+            },
+            "fresnel" => {
+                count(1)?;
+                Node::Fresnel(number(arguments[0])?)
+            },
+            "ramp" => {
+                if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+                    return Err("ramp takes an input followed by pairs of positions and colors".to_string());
+                }
+                let mut stops = Vec::new();
+                for pair in arguments[1..].chunks(2) {
+                    stops.push((number(pair[0])?, self.parse_color(pair[1])?));
+                }
+                stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Node::Ramp(self.parse_socket(arguments[0])?, stops)
+            },
+            "mix" => {
+                count(3)?;
+                Node::Mix(self.parse_socket(arguments[0])?, self.parse_socket(arguments[1])?, self.parse_socket(arguments[2])?)
+            },
+            "multiply" => {
+                count(2)?;
+                Node::Multiply(self.parse_socket(arguments[0])?, self.parse_socket(arguments[1])?)
+            },
+            "uv" => {
+                count(0)?;
+                Node::Uv
+            },
+            // A plain value, or another node under a new name
+            _ if arguments.is_empty() => Node::Socket(self.parse_socket(kind)?),
+            _ => return Err(format!("unknown node kind {}", kind))
+        };
+        return Ok(node);
+    }
+
+    // Only earlier nodes can be referenced, which keeps the graph free of cycles
+    // A name defined more than once means its latest definition, like in evaluate
+    fn parse_socket(&self, word: &str) -> Result<Socket, String> {
+        if let Some(index) = self.names.iter().rposition(|name| name == word) {
+            return Ok(Socket::Node(index));
+        }
+        return self.parse_color(word).map(Socket::Constant);
+    }
+
+    fn parse_color(&self, word: &str) -> Result<Color, String> {
+        let channels: Result<Vec<f32>, _> = word.split(',').map(|channel| channel.parse::<f32>()).collect();
+        match channels.as_deref() {
+            Ok([gray]) => Ok(Color::splat(*gray)),
+            Ok([red, green, blue]) => Ok(Color::new(*red, *green, *blue)),
+            _ => Err(format!("expected a node name, a number or a r,g,b color, got {}", word))
+        }
+    }
+
+    /// Evaluate the node with the given name, or None if there is no such node
+    pub fn evaluate(&self, name: &str, point: &ShadingPoint) -> Option<Color> {
+        let target = self.names.iter().rposition(|node_name| node_name == name)?;
+        // Nodes only depend on earlier ones, so evaluating them in order has every input ready
+        let mut values: Vec<Color> = Vec::with_capacity(target + 1);
+        for node in &self.nodes[..=target] {
+            let input = |socket: &Socket| match socket {
+                Socket::Constant(color) => *color,
+                Socket::Node(index) => values[*index]
+            };
+            let value = match node {
+                Node::Socket(socket) => input(socket),
+                Node::Noise { noise, scale, octaves } => Color::splat(noise.turbulence(point.position * *scale, *octaves)),
+                Node::Texture(texture) => texture.value(point.u, point.v, point.position),
+                Node::Fresnel(ior) => {
+                    // Without a view direction, assume the surface is seen head-on
+                    let cosine = match (point.normal, point.incoming) {
+                        (Some(normal), Some(incoming)) => incoming.normalize().dot(normal).abs(),
+                        _ => 1.0
+                    };
+                    Color::splat(material::schlick(cosine, 1.0 / ior))
+                },
+                Node::Ramp(socket, stops) => ramp(stops, input(socket).dot(Color::ONE) / 3.0),
+                Node::Mix(first, second, factor) => input(first).lerp(input(second), input(factor).dot(Color::ONE) / 3.0),
+                Node::Multiply(first, second) => input(first) * input(second),
+                Node::Uv => Color::new(point.u, point.v, 0.0)
+            };
+            values.push(value);
+        }
+        return values.pop();
+    }
+}
+
+fn ramp(stops: &[(f32, Color)], position: f32) -> Color {
+    let first = stops[0];
+    let last = stops[stops.len() - 1];
+    // Inputs like a fresnel of index 0 come out as NaN, which lies nowhere on the ramp
+    if position <= first.0 || !position.is_finite() {
+        return first.1;
+    }
+    if position >= last.0 {
+        return last.1;
+    }
+    let after = stops.iter().position(|stop| stop.0 > position).unwrap();
+    let (start, end) = (stops[after - 1], stops[after]);
+    return start.1.lerp(end.1, (position - start.0) / (end.0 - start.0));
+}
+
+/// A metallic-roughness material whose base color, metallic and roughness come from a shader graph
+pub struct GraphMaterial {
+    graph: ShaderGraph
+}
+
+impl Material for GraphMaterial {
+    fn albedo(&self, u: f32, v: f32, position: Vec3) -> Color {
+        let point = ShadingPoint { u, v, position, normal: None, incoming: None };
+        self.graph.evaluate("base_color", &point).unwrap_or(Color::splat(0.8))
+    }
+
+    fn scatter(&self, rng: &mut SamplerRng, incoming: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> ScatterRecord {
+        let (base_color, metallic, roughness) = self.parameters(incoming, position, normal, u, v);
+        material::metallic_roughness_scatter(rng, incoming, position, normal, base_color, metallic, roughness)
+    }
+
+    fn scattering_pdf(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> f32 {
+        let (base_color, metallic, roughness) = self.parameters(incoming, position, normal, u, v);
+        material::metallic_roughness_pdf(incoming.direction, scattered.direction, normal, base_color, metallic, roughness)
+    }
+
+    fn evaluate(&self, incoming: &Ray, scattered: &Ray, position: Vec3, normal: Vec3, _tangent: Vec3, u: f32, v: f32) -> Color {
+        let (base_color, metallic, roughness) = self.parameters(incoming, position, normal, u, v);
+        material::metallic_roughness_evaluate(incoming.direction, scattered.direction, normal, base_color, metallic, roughness)
+    }
+}
+
+impl GraphMaterial {
+    pub fn new(graph: ShaderGraph) -> GraphMaterial {
+        GraphMaterial { graph }
+    }
+
+    // Base color, metallic and roughness from the graph, with defaults for outputs it doesn't have
+    fn parameters(&self, incoming: &Ray, position: Vec3, normal: Vec3, u: f32, v: f32) -> (Color, f32, f32) {
+        let point = ShadingPoint { u, v, position, normal: Some(normal), incoming: Some(incoming.direction) };
+        let gray = |name: &str, default: f32| self.graph.evaluate(name, &point).map_or(default, |value| value.dot(Color::ONE) / 3.0);
+        let base_color = self.graph.evaluate("base_color", &point).unwrap_or(Color::splat(0.8));
+        let metallic = gray("metallic", 0.0).clamp(0.0, 1.0);
+        let roughness = gray("roughness", 0.5).clamp(0.0, 1.0);
+        return (base_color, metallic, roughness);
+    }
+
+    pub fn load(path: &str) -> Result<GraphMaterial, Error> {
+        Ok(GraphMaterial::new(ShaderGraph::load(path)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point() -> ShadingPoint {
+        ShadingPoint { u: 0.25, v: 0.75, position: Vec3::ZERO, normal: None, incoming: None }
+    }
+
+    #[test]
+    fn redefined_names_refer_to_the_latest_node() {
+        let graph = ShaderGraph::parse("a = 0.5\na = multiply a 2\nb = multiply a 3").unwrap();
+        assert_eq!(graph.evaluate("a", &point()), Some(Color::splat(1.0)));
+        assert_eq!(graph.evaluate("b", &point()), Some(Color::splat(3.0)));
+    }
+
+    #[test]
+    fn ramp_interpolates_between_stops() {
+        let graph = ShaderGraph::parse("uv = uv\nramp = ramp uv 0 0 0.5 1,0,0").unwrap();
+        // The gray level of the uv is a third of u + v
+        let expected = Color::new(2.0 / 3.0, 0.0, 0.0);
+        assert!((graph.evaluate("ramp", &point()).unwrap() - expected).length() < 1e-5);
+    }
+
+    #[test]
+    fn ramp_of_nan_gives_the_first_stop() {
+        let graph = ShaderGraph::parse("f = fresnel 0\nramp = ramp f 0 0.2 1 0.8").unwrap();
+        assert_eq!(graph.evaluate("ramp", &point()), Some(Color::splat(0.2)));
+    }
+
+    #[test]
+    fn unknown_kinds_report_their_line() {
+        let error = ShaderGraph::parse("# comment\n\nx = sparkle 1").err().unwrap();
+        assert_eq!(error.to_string(), "Line 3: unknown node kind sparkle");
+    }
+}
@@ -0,0 +1,152 @@
+// A clear daytime sky worked out from where the sun is, for outdoor scenes without a photo of the surroundings
+
+use std::f32::consts::PI;
+use glam::Vec3;
+use crate::environment::EnvironmentMap;
+use crate::onb::Onb;
+use crate::spectrum::{planck, spectrum_to_xyz, xyz_to_linear_srgb};
+
+type Color = Vec3;
+
+// Luminance of the sun's disc above the atmosphere, in cd/m²
+const SUN_LUMINANCE: f32 = 2.0e9;
+// Surface temperature of the sun in kelvin, its light is close to a black body's
+const SUN_TEMPERATURE: f32 = 5778.0;
+// Half the angle the sun's disc spans, in degrees
+const SUN_ANGULAR_RADIUS: f32 = 0.267;
+
+/// Preetham, Shirley and Smits' analytic model of the sky's color, from "A Practical Analytic Model for Daylight"
+/// The sun's disc isn't part of it, it's lit separately by the Sun it goes with
+pub struct Sky {
+    // Unit vector towards the sun
+    sun_direction: Vec3,
+    // How hazy the air is, from 2 on a very clear day to around 10 on a hazy one
+    turbidity: f32,
+    // Luminance in cd/m² and chromaticity straight up, as (Y, x, y)
+    zenith: Vec3,
+    // Perez coefficients A to E of the luminance and the two chromaticities
+    coefficients: [[f32; 5]; 3]
+}
+
+impl Sky {
+    /// A sky with the sun elevation degrees above the horizon, turned azimuth degrees from -z towards +x
+    /// The model only holds in daylight, so the sun is kept above the horizon
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Sky {
+        let (elevation, azimuth) = (elevation.clamp(0.0, 90.0).to_radians(), azimuth.to_radians());
+        let sun_direction = Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos());
+        let t = turbidity;
+        let coefficients = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]
+        ];
+        // The fits of the zenith are in the sun's angle from straight up
+        let theta = PI / 2.0 - elevation;
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * 1000.0;
+        let (theta2, theta3, t2) = (theta * theta, theta * theta * theta, t * t);
+        let x = t2 * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let y = t2 * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+        Sky { sun_direction, turbidity, zenith: Vec3::new(luminance.max(0.0), x, y), coefficients }
+    }
+
+    /// The light of the sky arriving from a direction, in cd/m². Below the horizon is left dark for the ground to fill in
+    pub fn radiance(&self, direction: Vec3) -> Color {
+        let direction = direction.normalize();
+        if direction.y <= 0.0 {
+            return Color::ZERO;
+        }
+        let cos_theta = direction.y;
+        let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let sun_theta = self.sun_direction.y.clamp(-1.0, 1.0).acos();
+        // Every quantity is its zenith value scaled by how the sky's distribution differs from straight up
+        let value = |index: usize| self.zenith[index] * perez(&self.coefficients[index], cos_theta, gamma) / perez(&self.coefficients[index], 1.0, sun_theta);
+        let (luminance, x, y) = (value(0), value(1), value(2));
+        let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        return xyz_to_linear_srgb(xyz).max(Color::ZERO);
+    }
+
+    /// The sky baked into an environment map, so it lights the scene and is sampled like one
+    pub fn environment(&self, width: usize, height: usize) -> EnvironmentMap {
+        EnvironmentMap::from_radiance(width, height, |direction| self.radiance(direction))
+    }
+
+    /// The sun of this sky, dimmed and reddened by the air its light passes through
+    pub fn sun(&self) -> Sun {
+        // How much air the light passes through relative to straight up, from Kasten and Young
+        let elevation = 90.0 - self.sun_direction.y.clamp(0.0, 1.0).acos().to_degrees();
+        let air_mass = 1.0 / (elevation.to_radians().sin() + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+        // Rayleigh scattering off air molecules and scattering off haze, with wavelengths in micrometers
+        let haze = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength: f32| {
+            let micrometers = wavelength / 1000.0;
+            (-air_mass * (0.008735 * micrometers.powf(-4.08) + haze * micrometers.powf(-1.3))).exp()
+        };
+        let unattenuated = spectrum_to_xyz(|wavelength| planck(wavelength, SUN_TEMPERATURE));
+        let attenuated = spectrum_to_xyz(|wavelength| planck(wavelength, SUN_TEMPERATURE) * transmittance(wavelength));
+        let radiance = xyz_to_linear_srgb(attenuated).max(Color::ZERO) / unattenuated.y * SUN_LUMINANCE;
+        return Sun::new(self.sun_direction, SUN_ANGULAR_RADIUS, radiance);
+    }
+}
+
+// Perez et al.'s formula for how bright the sky is theta from straight up and gamma from the sun, relative to some reference
+fn perez(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    return (1.0 + a * (b / cos_theta.max(0.01)).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2));
+}
+
+/// A light infinitely far away in one direction, covering a small disc of the sky
+/// Light from it arrives almost parallel, casting sharp shadows that soften with the size of the disc
+pub struct Sun {
+    // Unit vector towards the middle of the disc
+    direction: Vec3,
+    // 1 - the cosine of the disc's angular radius, kept apart to not lose it to rounding for tiny discs
+    one_minus_cos: f32,
+    radiance: Color
+}
+
+impl Sun {
+    /// A sun in a direction with a disc angular_radius degrees across from the middle to the edge, and the radiance of the disc
+    pub fn new(direction: Vec3, angular_radius: f32, radiance: Color) -> Sun {
+        let radius = angular_radius.to_radians();
+        Sun { direction: direction.normalize(), one_minus_cos: 2.0 * (radius / 2.0).sin().powi(2), radiance }
+    }
+
+    /// The light arriving from a direction, which doesn't need to be normalized
+    pub fn radiance(&self, direction: Vec3) -> Color {
+        match self.contains(direction) {
+            true => self.radiance,
+            false => Color::ZERO
+        }
+    }
+
+    /// Picks a direction on the disc with two uniform random numbers, returning it and its density per solid angle
+    pub fn sample_direction(&self, u1: f32, u2: f32) -> (Vec3, f32) {
+        let cos_theta = 1.0 - u1 * self.one_minus_cos;
+        let sin_theta = (1.0 - cos_theta.powi(2)).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let direction = Onb::new(self.direction).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+        return (direction, self.solid_angle_pdf());
+    }
+
+    /// The density per solid angle of sample_direction picking a direction
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        match self.contains(direction) {
+            true => self.solid_angle_pdf(),
+            false => 0.0
+        }
+    }
+
+    fn contains(&self, direction: Vec3) -> bool {
+        1.0 - direction.normalize().dot(self.direction) <= self.one_minus_cos
+    }
+
+    // Every direction on the disc is equally likely
+    fn solid_angle_pdf(&self) -> f32 {
+        1.0 / (2.0 * PI * self.one_minus_cos)
+    }
+}
@@ -0,0 +1,61 @@
+// Turning light spectra into the linear RGB the renderer works in
+
+use glam::Vec3;
+
+type Color = Vec3;
+
+// Second radiation constant hc/k, in meter kelvins
+const SECOND_RADIATION_CONSTANT: f32 = 1.4388e-2;
+
+/// The color of an ideal glowing body at a temperature in kelvin, scaled to a luminance of 1
+/// Candle flames are around 1900K, tungsten bulbs 3200K and overcast daylight 6500K
+pub fn blackbody(kelvin: f32) -> Color {
+    let xyz = spectrum_to_xyz(|wavelength| planck(wavelength, kelvin));
+    let rgb = xyz_to_linear_srgb(xyz).max(Color::ZERO);
+    return rgb / xyz.y;
+}
+
+/// The CIE XYZ color of a spectrum, given as its power at wavelengths in nanometers
+pub fn spectrum_to_xyz(spectrum: impl Fn(f32) -> f32) -> Vec3 {
+    let mut xyz = Vec3::ZERO;
+    // Sum the spectrum over the visible range against the CIE color matching functions
+    for step in 0..=80 {
+        let wavelength = 380.0 + 5.0 * step as f32;
+        xyz += color_matching(wavelength) * spectrum(wavelength);
+    }
+    return xyz;
+}
+
+/// Relative spectral radiance of a black body at a wavelength in nanometers, by Planck's law
+/// The constant factors are left out, as they cancel out when the color is normalized
+pub fn planck(wavelength: f32, kelvin: f32) -> f32 {
+    let meters = wavelength * 1e-9;
+    // Scaling by 1e-30 keeps the fifth power within floating point range
+    let fifth_power = (meters * 1e6).powi(5);
+    return 1.0 / (fifth_power * ((SECOND_RADIATION_CONSTANT / (meters * kelvin)).exp() - 1.0));
+}
+
+// The CIE 1931 color matching functions, from Wyman, Sloan and Shirley's multi-lobe Gaussian fit
+fn color_matching(wavelength: f32) -> Vec3 {
+    let lobe = |mean: f32, below: f32, above: f32| {
+        let spread = match wavelength < mean {
+            true => below,
+            false => above
+        };
+        (-0.5 * ((wavelength - mean) / spread).powi(2)).exp()
+    };
+    Vec3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8)
+    )
+}
+
+/// CIE XYZ to linear sRGB with a D65 white point
+pub fn xyz_to_linear_srgb(xyz: Vec3) -> Color {
+    Color::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    )
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use glam::Vec3;
+use crate::perlin::Perlin;
+use crate::color::srgb_to_linear;
+
+type Color = Vec3;
+
+// Textures are shared through Arc, so anything holding them can be shared too
+pub trait Texture: Send + Sync {
+    // Get the color at surface coordinates (u, v), or at the point p for textures defined in space
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Color;
+    // Get a single value as the average of the channels, for textures that drive a parameter rather than a color
+    fn gray(&self, u: f32, v: f32, p: Vec3) -> f32 {
+        self.value(u, v, p).dot(Color::ONE) / 3.0
+    }
+}
+
+/// The same color everywhere
+pub struct SolidColor {
+    color: Color
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f32, _v: f32, _p: Vec3) -> Color {
+        self.color
+    }
+}
+
+impl SolidColor {
+    pub fn new(red: f32, green: f32, blue: f32) -> SolidColor {
+        SolidColor { color: Color::new(red, green, blue) }
+    }
+
+    pub fn from_color(color: Color) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+/// A 3D checkerboard of two textures, with cubes of side scale
+pub struct CheckerTexture {
+    scale: f32,
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Color {
+        let cell = (p / self.scale).floor();
+        let sum = cell.x as i32 + cell.y as i32 + cell.z as i32;
+        match sum % 2 == 0 {
+            true => self.even.value(u, v, p),
+            false => self.odd.value(u, v, p)
+        }
+    }
+}
+
+impl CheckerTexture {
+    pub fn new(scale: f32, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> CheckerTexture {
+        CheckerTexture { scale, even, odd }
+    }
+
+    pub fn from_colors(scale: f32, even: Color, odd: Color) -> CheckerTexture {
+        CheckerTexture::new(scale, Arc::new(SolidColor::from_color(even)), Arc::new(SolidColor::from_color(odd)))
+    }
+}
+
+/// Lines over a fill, dividing the surface coordinates into scale by scale cells
+/// Handy for seeing how a texture is laid out on an object
+pub struct GridTexture {
+    scale: f32,
+    // Width of the lines as a fraction of a cell
+    pub line_width: f32,
+    line: Arc<dyn Texture>,
+    fill: Arc<dyn Texture>
+}
+
+impl Texture for GridTexture {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Color {
+        let cell_u = (u * self.scale).fract();
+        let cell_v = (v * self.scale).fract();
+        let half_width = self.line_width / 2.0;
+        let on_line = |coordinate: f32| coordinate < half_width || coordinate > 1.0 - half_width;
+        match on_line(cell_u) || on_line(cell_v) {
+            true => self.line.value(u, v, p),
+            false => self.fill.value(u, v, p)
+        }
+    }
+}
+
+impl GridTexture {
+    pub fn new(scale: f32, line: Arc<dyn Texture>, fill: Arc<dyn Texture>) -> GridTexture {
+        GridTexture { scale, line_width: 0.05, line, fill }
+    }
+
+    pub fn from_colors(scale: f32, line: Color, fill: Color) -> GridTexture {
+        GridTexture::new(scale, Arc::new(SolidColor::from_color(line)), Arc::new(SolidColor::from_color(fill)))
+    }
+}
+
+/// What an image texture does with surface coordinates outside [0, 1]
+#[derive(Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    // Tile the image
+    Repeat,
+    // Stretch the edge pixels outwards
+    Clamp
+}
+
+/// How an image texture reads between pixel centers
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    // The closest pixel, blocky up close
+    Nearest,
+    // A blend of the four closest pixels
+    Bilinear
+}
+
+// One level of a mip pyramid, with linear colors row by row from the top of the image
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>
+}
+
+impl MipLevel {
+    // Average 2x2 blocks into a level of half the size, repeating the last row or column of odd sizes
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (left, right) = ((2 * x).min(self.width - 1), (2 * x + 1).min(self.width - 1));
+                let (top, bottom) = ((2 * y).min(self.height - 1), (2 * y + 1).min(self.height - 1));
+                let sum = self.pixels[top * self.width + left] + self.pixels[top * self.width + right]
+                    + self.pixels[bottom * self.width + left] + self.pixels[bottom * self.width + right];
+                pixels.push(sum / 4.0);
+            }
+        }
+        MipLevel { width, height, pixels }
+    }
+}
+
+/// A PNG or JPEG image, stretched over the surface coordinates
+/// Far away surfaces should read a blurrier mip level so the image doesn't shimmer.
+/// There are no ray differentials to pick one per hit, so the level is set with a fixed bias
+pub struct ImageTexture {
+    // Mip pyramid from the full image down to a single pixel
+    levels: Vec<MipLevel>,
+    pub wrap: WrapMode,
+    pub filter: Filter,
+    // Mip level to read, where 0 is the full image and fractions blend neighbouring levels
+    pub mip_bias: f32
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Color {
+        let level = self.mip_bias.clamp(0.0, (self.levels.len() - 1) as f32);
+        let lower = level.floor() as usize;
+        let upper = (lower + 1).min(self.levels.len() - 1);
+        let blend = level - lower as f32;
+        self.sample(lower, u, v).lerp(self.sample(upper, u, v), blend)
+    }
+}
+
+impl ImageTexture {
+    /// Loads an image file, converting its sRGB colors to linear ones
+    pub fn load(path: &str) -> Result<ImageTexture, image::ImageError> {
+        ImageTexture::from_file(path, srgb_to_linear)
+    }
+
+    /// Loads an image file holding data rather than colors, like a normal map, leaving the values as they are
+    pub fn load_data(path: &str) -> Result<ImageTexture, image::ImageError> {
+        ImageTexture::from_file(path, |value| value)
+    }
+
+    fn from_file(path: &str, decode: fn(f32) -> f32) -> Result<ImageTexture, image::ImageError> {
+        let image = image::open(path)?.into_rgb8();
+        let (width, height) = image.dimensions();
+        let pixels = image
+            .pixels()
+            .map(|pixel| Color::new(
+                decode(pixel[0] as f32 / 255.0),
+                decode(pixel[1] as f32 / 255.0),
+                decode(pixel[2] as f32 / 255.0)
+            ))
+            .collect();
+        let mut levels = vec![MipLevel { width: width as usize, height: height as usize, pixels }];
+        loop {
+            let smallest = &levels[levels.len() - 1];
+            if smallest.width == 1 && smallest.height == 1 {
+                break;
+            }
+            let next = smallest.downsample();
+            levels.push(next);
+        }
+        Ok(ImageTexture {
+            levels,
+            wrap: WrapMode::Repeat,
+            filter: Filter::Bilinear,
+            mip_bias: 0.0
+        })
+    }
+
+    // Read one mip level at surface coordinates
+    fn sample(&self, level: usize, u: f32, v: f32) -> Color {
+        let mip = &self.levels[level];
+        let texel = |x: i64, y: i64| {
+            mip.pixels[self.wrap.apply(y, mip.height) * mip.width + self.wrap.apply(x, mip.width)]
+        };
+        self.filter.sample(u, v, mip.width, mip.height, texel)
+    }
+}
+
+impl WrapMode {
+    /// Bring a pixel coordinate into an image dimension of size
+    pub fn apply(&self, coordinate: i64, size: usize) -> usize {
+        match self {
+            WrapMode::Repeat => coordinate.rem_euclid(size as i64) as usize,
+            WrapMode::Clamp => coordinate.clamp(0, size as i64 - 1) as usize
+        }
+    }
+}
+
+impl Filter {
+    /// Read an image of width by height pixels at surface coordinates,
+    /// given a function that gets pixels by column and row and handles wrapping
+    pub fn sample(&self, u: f32, v: f32, width: usize, height: usize, texel: impl Fn(i64, i64) -> Color) -> Color {
+        // Images are stored top to bottom, but v goes upwards
+        let x = u * width as f32;
+        let y = (1.0 - v) * height as f32;
+        match self {
+            Filter::Nearest => texel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                // Pixel centers sit at half coordinates
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (left, top) = (x.floor() as i64, y.floor() as i64);
+                let (along_x, along_y) = (x - x.floor(), y - y.floor());
+                let upper_row = texel(left, top).lerp(texel(left + 1, top), along_x);
+                let lower_row = texel(left, top + 1).lerp(texel(left + 1, top + 1), along_x);
+                upper_row.lerp(lower_row, along_y)
+            }
+        }
+    }
+}
+
+/// Layered Perlin noise, from black to white
+pub struct TurbulenceTexture {
+    noise: Perlin,
+    scale: f32,
+    octaves: u32
+}
+
+impl Texture for TurbulenceTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Color {
+        Color::ONE * self.noise.turbulence(self.scale * p, self.octaves).min(1.0)
+    }
+}
+
+impl TurbulenceTexture {
+    pub fn new(scale: f32, octaves: u32) -> TurbulenceTexture {
+        TurbulenceTexture { noise: Perlin::new(), scale, octaves }
+    }
+}
+
+/// Stripes along z, bent out of shape by turbulence
+pub struct MarbleTexture {
+    noise: Perlin,
+    scale: f32,
+    octaves: u32,
+    pub light: Color,
+    pub dark: Color
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Color {
+        let phase = self.scale * p.z + 10.0 * self.noise.turbulence(p, self.octaves);
+        self.dark.lerp(self.light, 0.5 * (1.0 + phase.sin()))
+    }
+}
+
+impl MarbleTexture {
+    pub fn new(scale: f32, octaves: u32) -> MarbleTexture {
+        MarbleTexture {
+            noise: Perlin::new(),
+            scale,
+            octaves,
+            light: Color::ONE,
+            dark: Color::ZERO
+        }
+    }
+}
+
+/// Growth rings around the y axis, wobbled by turbulence
+pub struct WoodTexture {
+    noise: Perlin,
+    scale: f32,
+    octaves: u32,
+    pub light: Color,
+    pub dark: Color
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Color {
+        let radius = p.x.hypot(p.z) * self.scale;
+        let rings = (radius + 2.0 * self.noise.turbulence(p * self.scale, self.octaves)).fract();
+        self.dark.lerp(self.light, rings)
+    }
+}
+
+impl WoodTexture {
+    pub fn new(scale: f32, octaves: u32) -> WoodTexture {
+        WoodTexture {
+            noise: Perlin::new(),
+            scale,
+            octaves,
+            light: Color::new(0.75, 0.55, 0.3),
+            dark: Color::new(0.4, 0.22, 0.1)
+        }
+    }
+}
+
+/// A set of image textures covering neighbouring squares of surface coordinates, the way film and VFX assets lay them out
+/// Tile 1001 covers u and v in [0, 1], 1002 is to its right, and every row of ten tiles goes one step up in v
+pub struct UdimTexture {
+    tiles: HashMap<u32, ImageTexture>,
+    // Shown where there is no tile
+    pub missing: Color
+}
+
+impl Texture for UdimTexture {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Color {
+        let (column, row) = (u.floor(), v.floor());
+        if !(0.0..10.0).contains(&column) || row < 0.0 {
+            return self.missing;
+        }
+        let tile = 1001 + column as u32 + 10 * row as u32;
+        match self.tiles.get(&tile) {
+            Some(texture) => texture.value(u - column, v - row, p),
+            None => self.missing
+        }
+    }
+}
+
+impl UdimTexture {
+    /// Loads every tile that exists for a path containing <UDIM>, like "skin.<UDIM>.png"
+    pub fn load(pattern: &str) -> Result<UdimTexture, image::ImageError> {
+        let mut tiles = HashMap::new();
+        for tile in 1001..=1100 {
+            let path = pattern.replace("<UDIM>", &tile.to_string());
+            if !Path::new(&path).exists() {
+                continue;
+            }
+            let mut texture = ImageTexture::load(&path)?;
+            // Repeating would blend in the opposite edge of the tile instead of its neighbour
+            texture.wrap = WrapMode::Clamp;
+            tiles.insert(tile, texture);
+        }
+        Ok(UdimTexture { tiles, missing: Color::ZERO })
+    }
+}
@@ -0,0 +1,336 @@
+// A cache for scenes with more image texture data than fits comfortably in memory
+// Images are split into square tiles, and only the most recently used tiles are kept, up to a budget
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use glam::Vec3;
+use crate::texture::{Texture, WrapMode, Filter};
+use crate::color::srgb_to_linear;
+
+type Color = Vec3;
+
+// Side of a tile in pixels
+const TILE_SIZE: usize = 64;
+const TILE_BYTES: usize = TILE_SIZE * TILE_SIZE * std::mem::size_of::<Color>();
+// Room a tile takes up in a tile file, as 8 bit RGB. Tiles on the edges leave the end of theirs unused
+const STORED_TILE_BYTES: usize = TILE_SIZE * TILE_SIZE * 3;
+
+// Tile files made so far by this process, to give each one its own name
+static TILE_FILES: AtomicUsize = AtomicUsize::new(0);
+// Held while an image is decoded into its tile file, so only one whole image is ever in memory at a time
+static DECODING: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TileKey {
+    texture: usize,
+    x: usize,
+    y: usize
+}
+
+// Linear colors, row by row from the top of the tile. Tiles on the right and bottom edges may be smaller
+struct Tile {
+    width: usize,
+    pixels: Vec<Color>
+}
+
+struct CacheState {
+    // Every tile with the time it was last used
+    tiles: HashMap<TileKey, (Arc<Tile>, u64)>,
+    clock: u64,
+    texture_count: usize
+}
+
+/// Decoded image tiles shared by any number of CachedImageTextures
+/// When the budget is full the least recently used tile is thrown out, and loaded again if it's needed later
+pub struct TextureCache {
+    state: Mutex<CacheState>,
+    max_tiles: usize
+}
+
+impl TextureCache {
+    /// Budget is the most memory the decoded tiles may use, in bytes
+    pub fn new(budget: usize) -> Arc<TextureCache> {
+        Arc::new(TextureCache {
+            state: Mutex::new(CacheState {
+                tiles: HashMap::new(),
+                clock: 0,
+                texture_count: 0
+            }),
+            max_tiles: (budget / TILE_BYTES).max(1)
+        })
+    }
+
+    // Give a new texture an id to tell its tiles apart
+    fn register(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.texture_count += 1;
+        return state.texture_count - 1;
+    }
+
+    fn get(&self, key: TileKey) -> Option<Arc<Tile>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+        let (tile, last_used) = state.tiles.get_mut(&key)?;
+        *last_used = now;
+        return Some(tile.clone());
+    }
+
+    fn insert(&self, key: TileKey, tile: Arc<Tile>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.tiles.contains_key(&key) && state.tiles.len() >= self.max_tiles {
+            let oldest = state.tiles
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                state.tiles.remove(&oldest);
+            }
+        }
+        state.clock += 1;
+        let now = state.clock;
+        state.tiles.insert(key, (tile, now));
+    }
+
+    // Insert a tile only if it fits without throwing another one out, only making it if it does
+    // Returns whether there was room
+    fn insert_if_room(&self, key: TileKey, tile: impl FnOnce() -> Tile) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.tiles.len() >= self.max_tiles {
+            return false;
+        }
+        state.clock += 1;
+        let now = state.clock;
+        state.tiles.entry(key).or_insert_with(|| (Arc::new(tile()), now));
+        return true;
+    }
+}
+
+/// An image texture that reads its pixels through a TextureCache
+/// Opening one only reads the size of the image. The first time a pixel is needed, the image is decoded and split
+/// into tiles in a temporary file, which misses read single tiles back from. PNG and JPEG can't be decoded a piece
+/// at a time, so that takes one whole decoded image, but only one texture decodes at a time and textures that are
+/// never looked at are never decoded, so the tiles kept while rendering stay within the budget
+pub struct CachedImageTexture {
+    path: String,
+    width: usize,
+    height: usize,
+    // Converts stored values to linear ones
+    decode: fn(f32) -> f32,
+    id: usize,
+    cache: Arc<TextureCache>,
+    // Made the first time a pixel is needed, and None if the image couldn't be decoded then
+    tile_file: OnceLock<Option<Mutex<File>>>,
+    tile_path: PathBuf,
+    // Set once the tiles couldn't be made or read back, so the failure is only reported once
+    failed: AtomicBool,
+    pub wrap: WrapMode,
+    pub filter: Filter
+}
+
+impl Texture for CachedImageTexture {
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Color {
+        let texel = |x: i64, y: i64| self.texel(self.wrap.apply(x, self.width), self.wrap.apply(y, self.height));
+        self.filter.sample(u, v, self.width, self.height, texel)
+    }
+}
+
+impl Drop for CachedImageTexture {
+    fn drop(&mut self) {
+        if let Some(Some(_)) = self.tile_file.get() {
+            let _ = fs::remove_file(&self.tile_path);
+        }
+    }
+}
+
+impl CachedImageTexture {
+    /// Opens an image file of sRGB colors
+    pub fn open(path: &str, cache: &Arc<TextureCache>) -> Result<CachedImageTexture, ImageError> {
+        CachedImageTexture::from_file(path, cache, srgb_to_linear)
+    }
+
+    /// Opens an image file holding data rather than colors, like a normal map
+    pub fn open_data(path: &str, cache: &Arc<TextureCache>) -> Result<CachedImageTexture, ImageError> {
+        CachedImageTexture::from_file(path, cache, |value| value)
+    }
+
+    // Only the size is read until a pixel is needed, which also catches missing files and unknown formats
+    fn from_file(path: &str, cache: &Arc<TextureCache>, decode: fn(f32) -> f32) -> Result<CachedImageTexture, ImageError> {
+        let (width, height) = image::image_dimensions(path)?;
+        let tile_path = std::env::temp_dir().join(format!("sagakar-tiles-{}-{}", std::process::id(), TILE_FILES.fetch_add(1, Ordering::Relaxed)));
+        Ok(CachedImageTexture {
+            path: path.to_string(),
+            width: width as usize,
+            height: height as usize,
+            decode,
+            id: cache.register(),
+            cache: cache.clone(),
+            tile_file: OnceLock::new(),
+            tile_path,
+            failed: AtomicBool::new(false),
+            wrap: WrapMode::Repeat,
+            filter: Filter::Bilinear
+        })
+    }
+
+    // Decode the image and write it out tile by tile, caching the first tiles while they fit in the unused budget
+    fn write_tiles(&self) -> Result<File, ImageError> {
+        let _decoding = DECODING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let image = image::open(&self.path)?.into_rgb8();
+        // The file could have been swapped for another since it was opened
+        if (image.width() as usize, image.height() as usize) != (self.width, self.height) {
+            return Err(ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)));
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&self.tile_path)?;
+        let tiles_x = self.width.div_ceil(TILE_SIZE);
+        let tiles_y = self.height.div_ceil(TILE_SIZE);
+        let mut stored = vec![0; STORED_TILE_BYTES];
+        let mut room = true;
+        for tile_y in 0..tiles_y {
+            for tile_x in 0..tiles_x {
+                let (tile_width, tile_height) = self.tile_size(tile_x, tile_y);
+                for y in 0..tile_height {
+                    let start = ((tile_y * TILE_SIZE + y) * self.width + tile_x * TILE_SIZE) * 3;
+                    stored[y * tile_width * 3..(y + 1) * tile_width * 3].copy_from_slice(&image.as_raw()[start..start + tile_width * 3]);
+                }
+                file.write_all(&stored)?;
+                if room {
+                    let key = TileKey { texture: self.id, x: tile_x, y: tile_y };
+                    room = self.cache.insert_if_room(key, || self.decode_tile(tile_width, &stored[..tile_width * tile_height * 3]));
+                }
+            }
+        }
+        file.flush()?;
+        return Ok(file);
+    }
+
+    // The tile file, made on first use
+    fn tile_file(&self) -> Option<&Mutex<File>> {
+        let file = self.tile_file.get_or_init(|| match self.write_tiles() {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(error) => {
+                self.report(&error.to_string());
+                None
+            }
+        });
+        return file.as_ref();
+    }
+
+    // The texture can't stop the render, so it says what went wrong once and the tiles are left black
+    fn report(&self, error: &str) {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            eprintln!("Couldn't load the tiles of texture {}, leaving them black: {}", self.path, error);
+        }
+    }
+
+    fn texel(&self, x: usize, y: usize) -> Color {
+        let key = TileKey { texture: self.id, x: x / TILE_SIZE, y: y / TILE_SIZE };
+        let tile = match self.cache.get(key) {
+            Some(tile) => tile,
+            None => self.load_tile(key)
+        };
+        tile.pixels[(y % TILE_SIZE) * tile.width + x % TILE_SIZE]
+    }
+
+    // Size in pixels of a tile, smaller than the others on the right and bottom edges
+    fn tile_size(&self, tile_x: usize, tile_y: usize) -> (usize, usize) {
+        (TILE_SIZE.min(self.width - tile_x * TILE_SIZE), TILE_SIZE.min(self.height - tile_y * TILE_SIZE))
+    }
+
+    fn decode_tile(&self, width: usize, stored: &[u8]) -> Tile {
+        let pixels = stored
+            .chunks_exact(3)
+            .map(|pixel| Color::new(
+                (self.decode)(pixel[0] as f32 / 255.0),
+                (self.decode)(pixel[1] as f32 / 255.0),
+                (self.decode)(pixel[2] as f32 / 255.0)
+            ))
+            .collect();
+        Tile { width, pixels }
+    }
+
+    // Read a tile back from the tile file and cache it, making the file first if this is the first tile needed
+    fn load_tile(&self, key: TileKey) -> Arc<Tile> {
+        let (width, height) = self.tile_size(key.x, key.y);
+        let index = key.y * self.width.div_ceil(TILE_SIZE) + key.x;
+        let mut stored = vec![0; width * height * 3];
+        // Making the file may have cached this very tile
+        let file = self.tile_file();
+        if let Some(tile) = self.cache.get(key) {
+            return tile;
+        }
+        if let Some(file) = file {
+            let mut file = file.lock().unwrap();
+            let read = file.seek(SeekFrom::Start((index * STORED_TILE_BYTES) as u64)).and_then(|_| file.read_exact(&mut stored));
+            if let Err(error) = read {
+                self.report(&error.to_string());
+                stored.fill(0);
+            }
+        }
+        let tile = Arc::new(self.decode_tile(width, &stored));
+        self.cache.insert(key, tile.clone());
+        return tile;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl TextureCache {
+        // How many bytes the cached tiles use right now
+        fn memory_used(&self) -> usize {
+            self.state.lock().unwrap().tiles.values().map(|(tile, _)| tile.pixels.len() * std::mem::size_of::<Color>()).sum()
+        }
+    }
+
+    // An image a little over two tiles wide and one tall, with every pixel telling where it is
+    fn write_test_image(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("sagakar-cache-test-{}-{}.png", std::process::id(), name));
+        image::RgbImage::from_fn(150, 70, |x, y| image::Rgb([x as u8, y as u8, 7])).save(&path).unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn tiles_are_decoded_on_first_use_and_stay_within_the_budget() {
+        let path = write_test_image("budget");
+        let cache = TextureCache::new(TILE_BYTES);
+        let texture = CachedImageTexture::open_data(&path, &cache).unwrap();
+        assert!(texture.tile_file.get().is_none());
+        assert_eq!(cache.memory_used(), 0);
+        for (x, y) in [(0, 0), (149, 69), (64, 10), (130, 65), (3, 4)] {
+            let expected = Color::new(x as f32, y as f32, 7.0) / 255.0;
+            assert!((texture.texel(x, y) - expected).length() < 1e-6);
+            assert!(cache.memory_used() <= TILE_BYTES);
+        }
+        drop(texture);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unreadable_images_are_errors() {
+        let path = std::env::temp_dir().join(format!("sagakar-cache-test-{}-garbage.png", std::process::id()));
+        fs::write(&path, b"not an image").unwrap();
+        let cache = TextureCache::new(TILE_BYTES);
+        assert!(CachedImageTexture::open(path.to_str().unwrap(), &cache).is_err());
+        assert!(CachedImageTexture::open("no such texture.png", &cache).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    // An image that goes bad after it's opened is left black instead of stopping the render
+    #[test]
+    fn images_broken_after_opening_are_black() {
+        let path = write_test_image("broken");
+        let cache = TextureCache::new(TILE_BYTES);
+        let texture = CachedImageTexture::open_data(&path, &cache).unwrap();
+        fs::write(&path, b"not an image anymore").unwrap();
+        assert_eq!(texture.texel(10, 10), Color::ZERO);
+        assert!(texture.failed.load(Ordering::Relaxed));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,180 @@
+// Quantities that vary through a volume, like how thick smoke is from one spot to the next
+
+use glam::Vec3;
+use crate::perlin::Perlin;
+use crate::spectrum::{planck, spectrum_to_xyz, xyz_to_linear_srgb};
+
+type Color = Vec3;
+
+// Temperature in kelvin that an emission's strength is the luminance at
+const REFERENCE_TEMPERATURE: f32 = 1500.0;
+// Steps of the blackbody ramp between zero and the hottest temperature of the grid
+const RAMP_STEPS: usize = 256;
+
+/// How densely particles fill space at every point, for media that aren't the same throughout
+pub trait DensityField {
+    // Chance of hitting a particle per unit of distance at a point
+    fn density(&self, point: Vec3) -> f32;
+    // The density is never more than this anywhere, which is what tracking through it steps by
+    fn max_density(&self) -> f32;
+}
+
+/// Wispy smoke or clouds from layered Perlin noise, empty wherever the noise is below a threshold
+pub struct NoiseDensity {
+    noise: Perlin,
+    scale: f32,
+    octaves: u32,
+    // Noise below this is left empty, higher values give sparser, more broken up puffs
+    threshold: f32,
+    // Density where the noise is strongest
+    density: f32
+}
+
+impl DensityField for NoiseDensity {
+    fn density(&self, point: Vec3) -> f32 {
+        let noise = self.noise.turbulence(self.scale * point, self.octaves).min(1.0);
+        self.density * ((noise - self.threshold) / (1.0 - self.threshold)).max(0.0)
+    }
+
+    fn max_density(&self) -> f32 {
+        self.density
+    }
+}
+
+impl NoiseDensity {
+    pub fn new(density: f32, scale: f32, octaves: u32, threshold: f32) -> NoiseDensity {
+        NoiseDensity { noise: Perlin::new(), scale, octaves, threshold: threshold.clamp(0.0, 0.99), density }
+    }
+}
+
+/// Values on a regular grid of points filling a box, blended smoothly in between and zero outside
+pub struct VoxelGrid {
+    min: Vec3,
+    max: Vec3,
+    // Number of points along x, y and z
+    resolution: [usize; 3],
+    // Along x first, then y, then z
+    values: Vec<f32>,
+    // Largest of the values, kept so it doesn't need to be looked for
+    max_value: f32
+}
+
+impl VoxelGrid {
+    /// A grid of values between two corners, listed along x first, then y, then z
+    /// Every axis needs at least one point, and there needs to be a value for each of them
+    pub fn new(min: Vec3, max: Vec3, resolution: [usize; 3], values: Vec<f32>) -> Result<VoxelGrid, String> {
+        if resolution.contains(&0) {
+            return Err("Voxel grids need at least one point along every axis".to_owned());
+        }
+        let count = resolution[0] * resolution[1] * resolution[2];
+        if values.len() != count {
+            return Err(format!("Expected {} voxel values, got {}", count, values.len()));
+        }
+        let max_value = values.iter().copied().fold(0.0, f32::max);
+        return Ok(VoxelGrid { min: min.min(max), max: min.max(max), resolution, values, max_value });
+    }
+
+    /// A grid of values worked out from the position of every point
+    pub fn from_fn(min: Vec3, max: Vec3, resolution: [usize; 3], value: impl Fn(Vec3) -> f32) -> VoxelGrid {
+        let resolution = resolution.map(|count| count.max(1));
+        let step = |index: usize, axis: usize| match resolution[axis] > 1 {
+            true => index as f32 / (resolution[axis] - 1) as f32,
+            false => 0.5
+        };
+        let mut values = Vec::with_capacity(resolution[0] * resolution[1] * resolution[2]);
+        for z in 0..resolution[2] {
+            for y in 0..resolution[1] {
+                for x in 0..resolution[0] {
+                    values.push(value(min + (max - min) * Vec3::new(step(x, 0), step(y, 1), step(z, 2))));
+                }
+            }
+        }
+        return VoxelGrid::new(min, max, resolution, values).expect("grid is filled to its resolution");
+    }
+
+    /// The value at a point, trilinearly interpolated between the eight grid points around it
+    pub fn sample(&self, point: Vec3) -> f32 {
+        if point.cmplt(self.min).any() || point.cmpgt(self.max).any() {
+            return 0.0;
+        }
+        let size = (self.max - self.min).max(Vec3::splat(f32::EPSILON));
+        let last = Vec3::new(self.resolution[0] as f32, self.resolution[1] as f32, self.resolution[2] as f32) - Vec3::ONE;
+        let grid = (point - self.min) / size * last;
+        let base = grid.floor().min((last - Vec3::ONE).max(Vec3::ZERO));
+        let local = (grid - base).clamp(Vec3::ZERO, Vec3::ONE);
+        let (i, j, k) = (base.x as usize, base.y as usize, base.z as usize);
+        let mut accumulated = 0.0;
+        for dk in 0..2 {
+            for dj in 0..2 {
+                for di in 0..2 {
+                    let corner = Vec3::new(di as f32, dj as f32, dk as f32);
+                    let weight = corner * local + (Vec3::ONE - corner) * (Vec3::ONE - local);
+                    accumulated += weight.x * weight.y * weight.z * self.value(i + di, j + dj, k + dk);
+                }
+            }
+        }
+        return accumulated;
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.max_value
+    }
+
+    // The value at a grid point, with indices past the last point clamped to it
+    fn value(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [nx, ny, nz] = self.resolution;
+        let (x, y, z) = (x.min(nx - 1), y.min(ny - 1), z.min(nz - 1));
+        self.values[(z * ny + y) * nx + x]
+    }
+}
+
+/// Grids of densities, for smoke simulated elsewhere or made by hand
+impl DensityField for VoxelGrid {
+    fn density(&self, point: Vec3) -> f32 {
+        self.sample(point).max(0.0)
+    }
+
+    fn max_density(&self) -> f32 {
+        self.max_value
+    }
+}
+
+/// Light given off by a volume as hot as a temperature grid says, like a flame or an explosion
+/// Every point glows like a black body, so hotter parts shift from dull red to yellow and white
+/// and get far brighter, with too little heat to see glowing below around 800K
+pub struct BlackbodyEmission {
+    // Temperatures in kelvin
+    temperature: VoxelGrid,
+    // Radiance at evenly spaced temperatures from zero to the hottest of the grid,
+    // since working out a spectrum at every sample would be too slow
+    ramp: Vec<Color>
+}
+
+impl BlackbodyEmission {
+    /// Glows with a luminance of strength where the temperature is 1500K, and as a black body would elsewhere
+    pub fn new(temperature: VoxelGrid, strength: f32) -> BlackbodyEmission {
+        let reference = spectrum_to_xyz(|wavelength| planck(wavelength, REFERENCE_TEMPERATURE)).y;
+        let hottest = temperature.max_value();
+        let ramp = (0..RAMP_STEPS)
+            .map(|step| {
+                let kelvin = hottest * step as f32 / (RAMP_STEPS - 1) as f32;
+                match kelvin > 0.0 {
+                    true => xyz_to_linear_srgb(spectrum_to_xyz(|wavelength| planck(wavelength, kelvin))).max(Color::ZERO) / reference * strength,
+                    false => Color::ZERO
+                }
+            })
+            .collect();
+        BlackbodyEmission { temperature, ramp }
+    }
+
+    /// The light given off at a point, interpolated along the ramp
+    pub fn radiance(&self, point: Vec3) -> Color {
+        let hottest = self.temperature.max_value();
+        if hottest <= 0.0 {
+            return Color::ZERO;
+        }
+        let position = (self.temperature.sample(point) / hottest).clamp(0.0, 1.0) * (RAMP_STEPS - 1) as f32;
+        let index = (position as usize).min(RAMP_STEPS - 2);
+        return self.ramp[index].lerp(self.ramp[index + 1], position - index as f32);
+    }
+}